@@ -0,0 +1,189 @@
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+const OPCODE_TABLE: &str = "src/cpu/opcodes.txt";
+
+const BYTE_DESCRIPTORS: [&str; 16] = [
+    "A", "B", "C", "D", "E", "H", "L",
+    "BC", "DE", "HL", "HLi", "HLd",
+    "Immediate", "Indirect", "HighAddress", "HighAddressC",
+];
+
+const WORD_DESCRIPTORS: [&str; 8] = ["AF", "BC", "DE", "HL", "SP", "PC", "Immediate", "Indirect"];
+
+const CONDITIONS: [&str; 4] = ["NZ", "Z", "NC", "C"];
+
+/// Operand kinds an operation can take.
+#[derive(Clone, Copy)]
+enum Operand {
+    Byte,
+    Word,
+    Condition,
+    Bit,
+    Vector,
+}
+
+fn operands(operation: &str) -> Option<&'static [Operand]> {
+    use Operand::*;
+    Some(match operation {
+        "Nop" | "Stop" | "Halt" | "Di" | "Ei" | "Daa" | "Cpl" | "Scf" | "Ccf"
+        | "Rlca" | "Rrca" | "Rla" | "Rra" | "Reti" | "JpHl" | "AddSp" | "LdHlSp"
+        | "Prefix" | "Illegal" => &[],
+        "Ld" => &[Byte, Byte],
+        "Inc" | "Dec" | "Add" | "Adc" | "Sub" | "Sbc" | "And" | "Xor" | "Or" | "Cp"
+        | "Rlc" | "Rrc" | "Rl" | "Rr" | "Sla" | "Sra" | "Swap" | "Srl" => &[Byte],
+        "Ld16" => &[Word, Word],
+        "Inc16" | "Dec16" | "Add16" | "Push" | "Pop" => &[Word],
+        "Jp" | "Jr" | "Call" | "Ret" => &[Condition],
+        "Bit" | "Res" | "Set" => &[Bit, Byte],
+        "Rst" => &[Vector],
+        _ => return None,
+    })
+}
+
+fn operand(kind: Operand, value: &str) -> Result<String, String> {
+    let check = |names: &[&str], prefix: &str| {
+        if names.contains(&value) {
+            Ok(format!("{prefix}::{value}"))
+        }
+        else {
+            Err(format!("unknown {prefix} {value}"))
+        }
+    };
+
+    match kind {
+        Operand::Byte => check(&BYTE_DESCRIPTORS, "ByteDescriptor"),
+        Operand::Word => check(&WORD_DESCRIPTORS, "WordDescriptor"),
+        Operand::Condition => check(&CONDITIONS, "Condition"),
+        Operand::Bit => match value.parse::<u8>() {
+            Ok(bit) if bit < 8 => Ok(value.to_string()),
+            _ => Err(format!("invalid bit {value}")),
+        },
+        Operand::Vector => match value.strip_prefix("0x").map(|v| u16::from_str_radix(v, 16)) {
+            Some(Ok(vector)) if vector & !0x38 == 0 => Ok(format!("0x{vector:04X}")),
+            _ => Err(format!("invalid restart vector {value}")),
+        },
+    }
+}
+
+/// Turns `Ld(B, Immediate)` into `Operation::Ld(ByteDescriptor::B, ByteDescriptor::Immediate)`.
+/// Jumps, calls and returns without a condition are unconditional.
+fn operation(text: &str) -> Result<String, String> {
+    let (name, args) = match text.split_once('(') {
+        Some((name, args)) => match args.strip_suffix(')') {
+            Some(args) => (name, args.split(',').map(str::trim).collect()),
+            None => return Err(format!("unbalanced parentheses in {text}")),
+        },
+        None => (text, Vec::new()),
+    };
+
+    let kinds = operands(name).ok_or_else(|| format!("unknown operation {name}"))?;
+
+    if let [Operand::Condition] = kinds {
+        return match args[..] {
+            [] => Ok(format!("Operation::{name}(None)")),
+            [condition] => Ok(format!("Operation::{name}(Some({}))", operand(Operand::Condition, condition)?)),
+            _ => Err(format!("{name} takes at most one condition")),
+        };
+    }
+
+    if args.len() != kinds.len() {
+        return Err(format!("{name} takes {} operand(s), got {}", kinds.len(), args.len()));
+    }
+
+    if kinds.is_empty() {
+        return Ok(format!("Operation::{name}"));
+    }
+
+    let args = kinds.iter()
+        .zip(args)
+        .map(|(kind, value)| operand(*kind, value))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(format!("Operation::{name}({})", args.join(", ")))
+}
+
+fn instruction(columns: &[&str]) -> Result<String, String> {
+    let [_, mnemonic, op, length, cycles, flags] = columns else {
+        return Err(format!("expected 6 columns, got {}", columns.len()));
+    };
+
+    let length: u8 = length.parse().map_err(|_| format!("invalid length {length}"))?;
+
+    let (taken, not_taken) = cycles.split_once('/').unwrap_or((cycles, cycles));
+    let taken: u8 = taken.parse().map_err(|_| format!("invalid cycles {cycles}"))?;
+    let not_taken: u8 = not_taken.parse().map_err(|_| format!("invalid cycles {cycles}"))?;
+
+    let valid_flags = flags.len() == 4 && flags.chars().zip("ZNHC".chars()).all(|(flag, name)| {
+        flag == name || flag == '0' || flag == '1' || flag == '-'
+    });
+
+    if !valid_flags {
+        return Err(format!("invalid flags {flags}"));
+    }
+
+    Ok(format!(
+        "    Instruction {{ mnemonic: {mnemonic:?}, operation: {}, length: {length}, \
+         cycles: {taken}, cycles_not_taken: {not_taken}, flags: {flags:?} }},\n",
+        operation(op)?,
+    ))
+}
+
+fn table(name: &str, instructions: &[Option<String>]) -> String {
+    let mut out = format!("pub static {name}: [Instruction; 256] = [\n");
+    for instruction in instructions {
+        out.push_str(instruction.as_ref().unwrap());
+    }
+    out.push_str("];\n\n");
+    out
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={OPCODE_TABLE}");
+
+    let spec = fs::read_to_string(OPCODE_TABLE).expect("Couldn't read opcode table");
+
+    let mut instructions: Vec<Option<String>> = vec![None; 256];
+    let mut cb_instructions: Vec<Option<String>> = vec![None; 256];
+
+    for (number, line) in spec.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let columns: Vec<&str> = line.split('|').map(str::trim).collect();
+        let fail = |error: String| -> ! { panic!("{OPCODE_TABLE}:{}: {error}", number + 1) };
+
+        let (table, opcode) = match columns[0].strip_prefix("CB ") {
+            Some(opcode) => (&mut cb_instructions, opcode),
+            None => (&mut instructions, columns[0]),
+        };
+
+        let opcode = u8::from_str_radix(opcode, 16)
+            .unwrap_or_else(|_| fail(format!("invalid opcode {}", columns[0])));
+
+        if table[opcode as usize].is_some() {
+            fail(format!("duplicate opcode {}", columns[0]));
+        }
+
+        table[opcode as usize] = Some(instruction(&columns).unwrap_or_else(|e| fail(e)));
+    }
+
+    for (prefix, table) in [("", &instructions), ("CB ", &cb_instructions)] {
+        if let Some(opcode) = table.iter().position(Option::is_none) {
+            panic!("{OPCODE_TABLE}: missing opcode {prefix}{opcode:02X}");
+        }
+    }
+
+    let mut out = String::new();
+    writeln!(out, "// Generated by build.rs from {OPCODE_TABLE}. Do not edit.\n").unwrap();
+    out.push_str(&table("INSTRUCTIONS", &instructions));
+    out.push_str(&table("CB_INSTRUCTIONS", &cb_instructions));
+
+    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("opcodes.rs");
+    fs::write(path, out).expect("Couldn't write opcode table");
+}
@@ -0,0 +1,298 @@
+//! Runs every test ROM in a directory without a display and reports which ones pass.
+//!
+//! Blargg's tests print "Passed" or "Failed" over the serial port. Mooneye's tests execute
+//! `LD B, B` once done, with the Fibonacci numbers in B, C, D, E, H and L on success and 0x42 in
+//! all of them on failure. Anything else is stopped once the timeout has passed in emulated time,
+//! however fast the host runs it. ROMs the emulator can't load, such as unsupported cartridge
+//! types, are reported as errors.
+//!
+//! Usage: test-roms [--timeout SECONDS] [--json REPORT] DIRECTORY
+
+use emerald::{GameBoy, StepOutcome};
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const LD_B_B: u8 = 0x40;
+const MOONEYE_PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAILED: [u8; 6] = [0x42; 6];
+
+const CYCLES_PER_SECOND: u64 = 4194304;
+const DEFAULT_TIMEOUT_SECONDS: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+    Passed,
+    Failed,
+    Timeout,
+
+    /// The emulator could not run the ROM, e.g. an unsupported cartridge or a CPU lock-up.
+    Error(String),
+}
+
+impl Outcome {
+    fn name(&self) -> &'static str {
+        match self {
+            Outcome::Passed => "passed",
+            Outcome::Failed => "failed",
+            Outcome::Timeout => "timeout",
+            Outcome::Error(_) => "error",
+        }
+    }
+}
+
+struct TestResult {
+    rom: String,
+    outcome: Outcome,
+    frames: u64,
+}
+
+/// Runs the ROM until it reports a result or `timeout` T-cycles have passed. Returns the outcome
+/// and the number of frames run.
+fn run_rom(rom: Vec<u8>, timeout: u64) -> (Outcome, u64) {
+    let mut gameboy = match GameBoy::from_rom(rom) {
+        Ok(gameboy) => gameboy,
+        Err(e) => return (Outcome::Error(e.to_string()), 0),
+    };
+
+    while gameboy.cycles() < timeout {
+        let frames = gameboy.frames();
+        let opcode = gameboy.read_byte(gameboy.registers().pc);
+
+        if let StepOutcome::Locked { opcode, address } = gameboy.step_instruction() {
+            let error = format!("CPU locked up: illegal instruction {opcode:02X} at {address:04X}");
+            return (Outcome::Error(error), gameboy.frames());
+        }
+
+        if opcode == LD_B_B {
+            let r = gameboy.registers();
+
+            match [r.b, r.c, r.d, r.e, r.h, r.l] {
+                MOONEYE_PASSED => return (Outcome::Passed, gameboy.frames()),
+                MOONEYE_FAILED => return (Outcome::Failed, gameboy.frames()),
+                _ => {},
+            }
+        }
+
+        // Serial output is only checked once per frame, as it only ever grows.
+        if gameboy.frames() != frames {
+            let output = String::from_utf8_lossy(&gameboy.serial_output()).into_owned();
+
+            if output.contains("Passed") {
+                return (Outcome::Passed, gameboy.frames());
+            }
+            if output.contains("Failed") {
+                return (Outcome::Failed, gameboy.frames());
+            }
+        }
+    }
+
+    (Outcome::Timeout, gameboy.frames())
+}
+
+fn run_file(path: &Path, timeout: u64) -> (Outcome, u64) {
+    match fs::read(path) {
+        Ok(rom) => run_rom(rom, timeout),
+        Err(e) => (Outcome::Error(e.to_string()), 0),
+    }
+}
+
+/// Test ROMs in `directory` and its subdirectories, in order.
+fn find_roms(directory: &Path, roms: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(directory)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            find_roms(&path, roms)?;
+        }
+        else if matches!(path.extension().and_then(|e| e.to_str()), Some("gb" | "gbc")) {
+            roms.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_table(results: &[TestResult]) {
+    let width = results.iter().map(|r| r.rom.len()).max().unwrap_or(0).max(3);
+
+    println!("{:<width$}  {:<7}  {:>6}", "ROM", "RESULT", "FRAMES");
+
+    for result in results {
+        print!("{:<width$}  {:<7}  {:>6}", result.rom, result.outcome.name(), result.frames);
+
+        if let Outcome::Error(error) = &result.outcome {
+            print!("  {error}");
+        }
+        println!();
+    }
+
+    let passed = results.iter().filter(|r| r.outcome == Outcome::Passed).count();
+    println!("\n{passed}/{} passed", results.len());
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+fn write_json(results: &[TestResult], timeout: u64, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"timeout_cycles\": {timeout},")?;
+    writeln!(out, "  \"results\": [")?;
+
+    for (i, result) in results.iter().enumerate() {
+        let separator = if i + 1 < results.len() { "," } else { "" };
+        let error = match &result.outcome {
+            Outcome::Error(error) => json_string(error),
+            _ => String::from("null"),
+        };
+
+        writeln!(
+            out,
+            "    {{\"rom\": {}, \"result\": \"{}\", \"frames\": {}, \"error\": {}}}{}",
+            json_string(&result.rom), result.outcome.name(), result.frames, error, separator,
+        )?;
+    }
+
+    writeln!(out, "  ]")?;
+    writeln!(out, "}}")
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let mut timeout = DEFAULT_TIMEOUT_SECONDS * CYCLES_PER_SECOND;
+    let mut json_path = None;
+    let mut directory = None;
+
+    let mut args_iter = args.iter();
+
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--timeout" => {
+                let seconds: u64 = args_iter.next().and_then(|s| s.parse().ok())
+                    .expect("Expected a number of seconds after --timeout.");
+                timeout = seconds * CYCLES_PER_SECOND;
+            },
+            "--json" => {
+                let path = args_iter.next().expect("Expected a path after --json.");
+                json_path = Some(path.clone());
+            },
+            _ => directory = Some(PathBuf::from(arg)),
+        }
+    }
+
+    let directory = directory.expect("Usage: test-roms [--timeout SECONDS] [--json REPORT] DIRECTORY");
+
+    let mut roms = Vec::new();
+    find_roms(&directory, &mut roms).expect("Failed to read the test ROM directory.");
+
+    let results: Vec<TestResult> = roms.iter().map(|path| {
+        let (outcome, frames) = run_file(path, timeout);
+        let rom = path.strip_prefix(&directory).unwrap_or(path).display().to_string();
+
+        TestResult { rom, outcome, frames }
+    }).collect();
+
+    print_table(&results);
+
+    if let Some(path) = &json_path {
+        let mut file = File::create(path).expect("Failed to create JSON report.");
+        write_json(&results, timeout, &mut file).expect("Failed to write JSON report.");
+    }
+
+    if results.iter().any(|r| r.outcome != Outcome::Passed) {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run_rom, Outcome, CYCLES_PER_SECOND};
+
+    /// ROM-only cartridge with the given program at the entry point.
+    fn rom(program: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        rom
+    }
+
+    #[test]
+    fn test_mooneye_signature() {
+        let (outcome, _) = run_rom(rom(&[
+            0x06, 3,    // ld B, $03
+            0x0E, 5,    // ld C, $05
+            0x16, 8,    // ld D, $08
+            0x1E, 13,   // ld E, $0D
+            0x26, 21,   // ld H, $15
+            0x2E, 34,   // ld L, $22
+            0x40,       // ld B, B
+            0x18, 0xFE, // jr $010D
+        ]), CYCLES_PER_SECOND);
+
+        assert_eq!(outcome, Outcome::Passed);
+    }
+
+    #[test]
+    fn test_blargg_serial() {
+        let mut rom = rom(&[
+            0x21, 0x50, 0x01, // ld HL, $0150
+            0x2A,             // ld A, (HL+)
+            0xB7,             // or A, A
+            0x28, 0x0E,       // jr z, $0115
+            0xE0, 0x01,       // ld ($FF01), A
+            0x3E, 0x81,       // ld A, $81
+            0xE0, 0x02,       // ld ($FF02), A
+            0xF0, 0x02,       // ld A, ($FF02)
+            0xCB, 0x7F,       // bit 7, A
+            0x20, 0xFA,       // jr nz, $010D
+            0x18, 0xEE,       // jr $0103
+            0x18, 0xFE,       // jr $0115
+        ]);
+        rom[0x150..0x158].copy_from_slice(b"Failed\n\0");
+
+        let (outcome, _) = run_rom(rom, CYCLES_PER_SECOND);
+        assert_eq!(outcome, Outcome::Failed);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (outcome, _) = run_rom(rom(&[0x18, 0xFE]), CYCLES_PER_SECOND);
+        assert_eq!(outcome, Outcome::Timeout);
+
+        // Time keeps passing in STOP mode, which no button press ends here.
+        let (outcome, _) = run_rom(rom(&[0x10, 0x00]), CYCLES_PER_SECOND);
+        assert_eq!(outcome, Outcome::Timeout);
+    }
+
+    #[test]
+    fn test_unsupported_cartridge() {
+        let mut rom = rom(&[0x18, 0xFE]);
+        rom[0x147] = 0xFC; // Pocket Camera
+
+        let (outcome, frames) = run_rom(rom, CYCLES_PER_SECOND);
+        assert_eq!(outcome, Outcome::Error(String::from("cartridge type FC is not supported")));
+        assert_eq!(frames, 0);
+    }
+}
@@ -46,6 +46,9 @@ pub struct Bus {
     callbacks: Vec<Weak<BusListenerCell>>,
     callback_addresses: [Option<usize>; 0x100],
     register_addresses: [Option<usize>; 0x100],
+
+    // Source address and current value of a running OAM DMA transfer.
+    oam_dma: Option<(Address, Byte)>,
 }
 
 impl Bus {
@@ -54,6 +57,7 @@ impl Bus {
             callbacks: Vec::new(),
             callback_addresses: [None; 0x100],
             register_addresses: [None; 0x100],
+            oam_dma: None,
         }
     }
 
@@ -125,7 +129,44 @@ impl Bus {
         &self.callbacks[callback_index]
     }
 
+    /// Whether an IO register has a component behind it. Accessing one that does not panics.
+    pub fn is_register_attached(&self, address: Address) -> bool {
+        self.register_addresses[address as usize & 0xFF].is_some()
+    }
+
+    /// Called by the OAM DMA unit whenever a transfer starts, progresses or ends.
+    pub fn set_oam_dma(&mut self, state: Option<(Address, Byte)>) {
+        self.oam_dma = state;
+    }
+
+    /// While OAM DMA is running the CPU loses access to OAM and to the bus that DMA reads from.
+    /// Returns the value seen by the CPU if the access conflicts with the transfer.
+    fn oam_dma_conflict(&self, address: Address) -> Option<Byte> {
+        let (source, value) = self.oam_dma?;
+
+        // VRAM sits on its own bus; everything else below OAM shares the external bus.
+        let is_video_bus = |address: Address| (0x8000..=0x9FFF).contains(&address);
+
+        match address {
+            // IO registers and HRAM are on the CPU's internal bus.
+            0xFF00..=0xFFFF => None,
+            0xFE00..=0xFEFF => Some(0xFF),
+            _ if is_video_bus(address) == is_video_bus(source) => Some(value),
+            _ => None,
+        }
+    }
+
     pub fn read_byte(&self, address: Address) -> Byte {
+        if let Some(value) = self.oam_dma_conflict(address) {
+            trace!("Read from {:04X} conflicts with OAM DMA.", address);
+            return value;
+        }
+
+        self.dma_read_byte(address)
+    }
+
+    /// Reads a byte without checking for conflicts with OAM DMA.
+    pub fn dma_read_byte(&self, address: Address) -> Byte {
         trace!("Reading from address {:04X}...", address);
         self.resolve_address(address)
             .upgrade()
@@ -135,6 +176,11 @@ impl Bus {
     }
 
     pub fn write_byte(&mut self, address: Address, value: Byte) {
+        if self.oam_dma_conflict(address).is_some() {
+            trace!("Write to {:04X} conflicts with OAM DMA.", address);
+            return;
+        }
+
         trace!("Writing value {:02X} to address {:04X}", value, address);
         self.resolve_address(address)
             .upgrade()
@@ -151,4 +197,63 @@ impl Bus {
         self.write_byte(address, value as Byte);
         self.write_byte(address + 1, (value >> 8) as Byte);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::DummyRAM;
+    use super::Bus;
+
+    /// Bus with ROM, VRAM, work RAM and HRAM, each filled with its own value. The bus only holds
+    /// weak references, so the memories are returned as well.
+    fn init_bus() -> (Bus, Vec<Rc<RefCell<DummyRAM>>>) {
+        let mut bus = Bus::new();
+        let mut memories = Vec::new();
+
+        for (start, end, is_register, value) in [(0x00, 0x7F, false, 0x01), (0x80, 0x9F, false, 0x02),
+                                                 (0xC0, 0xDF, false, 0x03), (0xFF, 0xFF, false, 0x04)] {
+            let mut ram = DummyRAM::new(start, end, is_register);
+            ram.data.fill(value);
+
+            let ram = Rc::new(RefCell::new(ram));
+            bus.attach(ram.clone());
+            memories.push(ram);
+        }
+
+        (bus, memories)
+    }
+
+    #[test]
+    fn test_oam_dma_conflicts() {
+        let (mut bus, _memories) = init_bus();
+
+        // A transfer from work RAM takes over the external bus, which ROM shares.
+        bus.set_oam_dma(Some((0xC000, 0x42)));
+        assert_eq!(bus.read_byte(0x0150), 0x42);
+        assert_eq!(bus.read_byte(0xD123), 0x42);
+        assert_eq!(bus.read_byte(0x8000), 0x02);
+
+        // OAM reads FF and ignores writes.
+        assert_eq!(bus.read_byte(0xFE00), 0xFF);
+        bus.write_byte(0xFE00, 0x10);
+
+        // HRAM stays accessible.
+        assert_eq!(bus.read_byte(0xFF80), 0x04);
+        bus.write_byte(0xFF80, 0x10);
+        assert_eq!(bus.read_byte(0xFF80), 0x10);
+
+        // Writes to the conflicting bus are lost.
+        bus.write_byte(0xC000, 0x10);
+
+        // A transfer from VRAM only takes over the video bus.
+        bus.set_oam_dma(Some((0x8000, 0x24)));
+        assert_eq!(bus.read_byte(0x9FFF), 0x24);
+        assert_eq!(bus.read_byte(0x0150), 0x01);
+        assert_eq!(bus.read_byte(0xC000), 0x03);
+
+        bus.set_oam_dma(None);
+        assert_eq!(bus.read_byte(0x8000), 0x02);
+        assert_eq!(bus.read_byte(0xC000), 0x03);
+    }
+}
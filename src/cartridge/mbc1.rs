@@ -0,0 +1,111 @@
+use crate::{Address, Attach, Bus, BusListener, Byte};
+use crate::cartridge::{load_checksums, ram_enable, ram_offset, read_rom, save_checksums, Cartridge, RAM_ENABLE};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+/// MBC1: up to 2 MiB of ROM and 32 KiB of RAM. The 2-bit secondary bank register selects either
+/// the upper ROM bank bits or, in mode 1, the RAM bank (and the bank mapped at 0000-3FFF).
+pub struct Mbc1Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+
+    ram_enabled: bool,
+    rom_bank: u8,
+    secondary_bank: u8,
+    mode: bool,
+}
+
+impl Mbc1Cartridge {
+    pub(crate) fn new(rom: Vec<u8>, ram: Vec<u8>) -> Self {
+        Self {
+            rom,
+            ram,
+            ram_enabled: false,
+            rom_bank: 1,
+            secondary_bank: 0,
+            mode: false,
+        }
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.mode { self.secondary_bank as usize } else { 0 }
+    }
+}
+
+impl BusListener for Mbc1Cartridge {
+    fn bus_attach(&mut self) -> Vec<Attach> {
+        vec![Attach::BlockRange(0, 0x7F), Attach::BlockRange(0xA0, 0xBF)]
+    }
+
+    fn bus_read(&self, address: Address) -> Byte {
+        match address {
+            0x0000..=0x3FFF => {
+                let bank = if self.mode { (self.secondary_bank as usize) << 5 } else { 0 };
+                read_rom(&self.rom, bank, address)
+            },
+            0x4000..=0x7FFF => {
+                let bank = (self.secondary_bank as usize) << 5 | self.rom_bank as usize;
+                read_rom(&self.rom, bank, address)
+            },
+            _ => match ram_offset(&self.ram, self.ram_bank(), address) {
+                Some(offset) if self.ram_enabled => self.ram[offset],
+                _ => 0xFF,
+            },
+        }
+    }
+
+    fn bus_write(&mut self, _bus: &mut Bus, address: Address, value: Byte) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == RAM_ENABLE,
+            // Bank 0 can't be selected here; it becomes bank 1.
+            0x2000..=0x3FFF => self.rom_bank = (value & 0x1F).max(1),
+            0x4000..=0x5FFF => self.secondary_bank = value & 0x03,
+            0x6000..=0x7FFF => self.mode = value & 0x01 != 0,
+            _ => {
+                if let Some(offset) = ram_offset(&self.ram, self.ram_bank(), address) {
+                    if self.ram_enabled {
+                        self.ram[offset] = value;
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl Cartridge for Mbc1Cartridge {
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn bank_writes(&self) -> Vec<(Address, Byte)> {
+        vec![
+            (0x0000, ram_enable(self.ram_enabled)),
+            (0x2000, self.rom_bank),
+            (0x4000, self.secondary_bank),
+            (0x6000, self.mode as Byte),
+        ]
+    }
+}
+
+impl SaveState for Mbc1Cartridge {
+    fn save_state(&self, out: &mut StateWriter) {
+        save_checksums(&self.rom, out);
+        out.bool(self.ram_enabled);
+        out.u8(self.rom_bank);
+        out.u8(self.secondary_bank);
+        out.bool(self.mode);
+        out.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        load_checksums(&self.rom, input)?;
+        self.ram_enabled = input.bool()?;
+        self.rom_bank = input.u8()?;
+        self.secondary_bank = input.u8()?;
+        self.mode = input.bool()?;
+        input.bytes(&mut self.ram)
+    }
+}
@@ -0,0 +1,140 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::{Address, Attach, Bus, BusListener, Byte};
+use crate::cartridge::{load_checksums, ram_enable, ram_offset, read_rom, save_checksums, Cartridge, Rtc, RAM_ENABLE};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// Values of the RAM bank register that map an RTC register at A000-BFFF instead.
+const RTC_SECONDS: Byte = 0x08;
+const RTC_DAY_HIGH: Byte = 0x0C;
+
+/// MBC3: up to 2 MiB of ROM and 32 KiB of RAM, and optionally a real-time clock whose registers
+/// are mapped in place of the RAM.
+pub struct Mbc3Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<Rc<RefCell<Rtc>>>,
+
+    ram_enabled: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+
+    // Last value written to 6000-7FFF; writing 0 and then 1 latches the clock.
+    latch: u8,
+}
+
+impl Mbc3Cartridge {
+    pub(crate) fn new(rom: Vec<u8>, ram: Vec<u8>, rtc: bool) -> Self {
+        Self {
+            rom,
+            ram,
+            rtc: rtc.then(|| Rc::new(RefCell::new(Rtc::new()))),
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            latch: 0xFF,
+        }
+    }
+}
+
+impl BusListener for Mbc3Cartridge {
+    fn bus_attach(&mut self) -> Vec<Attach> {
+        vec![Attach::BlockRange(0, 0x7F), Attach::BlockRange(0xA0, 0xBF)]
+    }
+
+    fn bus_read(&self, address: Address) -> Byte {
+        match address {
+            0x0000..=0x3FFF => read_rom(&self.rom, 0, address),
+            0x4000..=0x7FFF => read_rom(&self.rom, self.rom_bank as usize, address),
+            _ if !self.ram_enabled => 0xFF,
+            _ => match (self.ram_bank, &self.rtc) {
+                (RTC_SECONDS..=RTC_DAY_HIGH, Some(rtc)) => rtc.borrow().read((self.ram_bank - RTC_SECONDS) as usize),
+                (RTC_SECONDS..=RTC_DAY_HIGH, None) => 0xFF,
+                (bank, _) => match ram_offset(&self.ram, bank as usize, address) {
+                    Some(offset) => self.ram[offset],
+                    None => 0xFF,
+                },
+            },
+        }
+    }
+
+    fn bus_write(&mut self, _bus: &mut Bus, address: Address, value: Byte) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == RAM_ENABLE,
+            // Bank 0 can't be selected here; it becomes bank 1.
+            0x2000..=0x3FFF => self.rom_bank = (value & 0x7F).max(1),
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            0x6000..=0x7FFF => {
+                if let (0x00, 0x01, Some(rtc)) = (self.latch, value, &self.rtc) {
+                    rtc.borrow_mut().latch();
+                }
+                self.latch = value;
+            },
+            _ if !self.ram_enabled => {},
+            _ => match (self.ram_bank, &self.rtc) {
+                (RTC_SECONDS..=RTC_DAY_HIGH, Some(rtc)) => {
+                    rtc.borrow_mut().write((self.ram_bank - RTC_SECONDS) as usize, value);
+                },
+                (RTC_SECONDS..=RTC_DAY_HIGH, None) => {},
+                (bank, _) => {
+                    if let Some(offset) = ram_offset(&self.ram, bank as usize, address) {
+                        self.ram[offset] = value;
+                    }
+                },
+            },
+        }
+    }
+}
+
+impl Cartridge for Mbc3Cartridge {
+    fn rtc(&self) -> Option<Rc<RefCell<Rtc>>> {
+        self.rtc.clone()
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    /// The latch register is left out, as writing it could latch the clock.
+    fn bank_writes(&self) -> Vec<(Address, Byte)> {
+        vec![
+            (0x0000, ram_enable(self.ram_enabled)),
+            (0x2000, self.rom_bank),
+            (0x4000, self.ram_bank),
+        ]
+    }
+}
+
+impl SaveState for Mbc3Cartridge {
+    fn save_state(&self, out: &mut StateWriter) {
+        save_checksums(&self.rom, out);
+        out.bool(self.ram_enabled);
+        out.u8(self.rom_bank);
+        out.u8(self.ram_bank);
+        out.u8(self.latch);
+        out.bytes(&self.ram);
+
+        if let Some(rtc) = &self.rtc {
+            rtc.borrow().save_state(out);
+        }
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        load_checksums(&self.rom, input)?;
+        self.ram_enabled = input.bool()?;
+        self.rom_bank = input.u8()?;
+        self.ram_bank = input.u8()?;
+        self.latch = input.u8()?;
+        input.bytes(&mut self.ram)?;
+
+        match &self.rtc {
+            Some(rtc) => rtc.borrow_mut().load_state(input),
+            None => Ok(()),
+        }
+    }
+}
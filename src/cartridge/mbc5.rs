@@ -0,0 +1,98 @@
+use crate::{Address, Attach, Bus, BusListener, Byte};
+use crate::cartridge::{load_checksums, ram_enable, ram_offset, read_rom, save_checksums, Cartridge, RAM_ENABLE};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+/// MBC5: up to 8 MiB of ROM through a 9-bit bank number, and 128 KiB of RAM. Unlike the older
+/// controllers, bank 0 can be mapped at 4000-7FFF.
+pub struct Mbc5Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+}
+
+impl Mbc5Cartridge {
+    pub(crate) fn new(rom: Vec<u8>, ram: Vec<u8>) -> Self {
+        Self {
+            rom,
+            ram,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+}
+
+impl BusListener for Mbc5Cartridge {
+    fn bus_attach(&mut self) -> Vec<Attach> {
+        vec![Attach::BlockRange(0, 0x7F), Attach::BlockRange(0xA0, 0xBF)]
+    }
+
+    fn bus_read(&self, address: Address) -> Byte {
+        match address {
+            0x0000..=0x3FFF => read_rom(&self.rom, 0, address),
+            0x4000..=0x7FFF => read_rom(&self.rom, self.rom_bank as usize, address),
+            _ => match ram_offset(&self.ram, self.ram_bank as usize, address) {
+                Some(offset) if self.ram_enabled => self.ram[offset],
+                _ => 0xFF,
+            },
+        }
+    }
+
+    fn bus_write(&mut self, _bus: &mut Bus, address: Address, value: Byte) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == RAM_ENABLE,
+            0x2000..=0x2FFF => self.rom_bank = self.rom_bank & 0x100 | value as u16,
+            0x3000..=0x3FFF => self.rom_bank = self.rom_bank & 0xFF | (value as u16 & 0x01) << 8,
+            // Bit 3 drives the motor on rumble cartridges.
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            0x6000..=0x7FFF => {},
+            _ => {
+                if let Some(offset) = ram_offset(&self.ram, self.ram_bank as usize, address) {
+                    if self.ram_enabled {
+                        self.ram[offset] = value;
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl Cartridge for Mbc5Cartridge {
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn bank_writes(&self) -> Vec<(Address, Byte)> {
+        vec![
+            (0x0000, ram_enable(self.ram_enabled)),
+            (0x2000, self.rom_bank as Byte),
+            (0x3000, (self.rom_bank >> 8) as Byte),
+            (0x4000, self.ram_bank),
+        ]
+    }
+}
+
+impl SaveState for Mbc5Cartridge {
+    fn save_state(&self, out: &mut StateWriter) {
+        save_checksums(&self.rom, out);
+        out.bool(self.ram_enabled);
+        out.u16(self.rom_bank);
+        out.u8(self.ram_bank);
+        out.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        load_checksums(&self.rom, input)?;
+        self.ram_enabled = input.bool()?;
+        self.rom_bank = input.u16()?;
+        self.ram_bank = input.u8()?;
+        input.bytes(&mut self.ram)
+    }
+}
@@ -1,37 +1,164 @@
 mod rom_only;
+mod mbc1;
+mod mbc3;
+mod mbc5;
+mod rtc;
 
 use std::cell::RefCell;
-use crate::BusListener;
+use crate::{Address, BusListener, Byte};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 use rom_only::RomOnlyCartridge;
+use mbc1::Mbc1Cartridge;
+use mbc3::Mbc3Cartridge;
+use mbc5::Mbc5Cartridge;
 
-use std::fs;
-use std::fs::File;
-use std::io::{BufReader, Read};
+pub use rtc::Rtc;
+
+use std::fmt;
 use std::rc::Rc;
 use log::info;
 
-// Here lies impl Cartridge: BusListener; killed by a lack of trait upcasting support...
+// The header ends at 0x150; anything shorter is not a cartridge.
+const HEADER_END: usize = 0x150;
+
+// Bit 7 of the CGB flag is set by cartridges that support CGB mode.
+const CGB_FLAG: usize = 0x143;
+const CGB_SUPPORT: u8 = 0x80;
+
+// Header and global checksums, which identify the cartridge a save state belongs to.
+const CHECKSUM_START: usize = 0x14D;
+const CHECKSUM_END: usize = 0x150;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+// Writing this to the lower nibble of 0000-1FFF enables external RAM (and the RTC).
+const RAM_ENABLE: Byte = 0x0A;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CartridgeError {
+    TooSmall(usize),
+    UnsupportedType(u8),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::TooSmall(size) => {
+                write!(f, "cartridge is too small ({size} bytes, the header alone is {HEADER_END})")
+            },
+            CartridgeError::UnsupportedType(ty) => write!(f, "cartridge type {ty:02X} is not supported"),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+/// A cartridge sits on the bus like any other component, and saves its own banking and external
+/// RAM state.
+pub trait Cartridge: BusListener + SaveState {
+    /// Real-time clock, which has to be clocked along with the rest of the system.
+    fn rtc(&self) -> Option<Rc<RefCell<Rtc>>> {
+        None
+    }
+
+    /// External RAM, across all of its banks.
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    /// Writes to the banking registers that put a freshly loaded cartridge in the current
+    /// banking state, as exchanged with other emulators.
+    fn bank_writes(&self) -> Vec<(Address, Byte)> {
+        Vec::new()
+    }
+}
+
+/// Value written to 0000-1FFF to enable or disable external RAM.
+fn ram_enable(enabled: bool) -> Byte {
+    if enabled { RAM_ENABLE } else { 0x00 }
+}
 
-#[repr(u8)]
 enum CartridgeType {
-    ROM_ONLY = 0x00,
+    ROM_ONLY,
+    MBC1,
+    MBC3 { rtc: bool },
+    MBC5,
 }
 
 impl CartridgeType {
-    fn from(ty: u8) -> Self {
+    fn from(ty: u8) -> Result<Self, CartridgeError> {
         match ty {
-            ROM_ONLY=> CartridgeType::ROM_ONLY,
-            _ => panic!("Cartridge type is not implemented."),
+            0x00 => Ok(CartridgeType::ROM_ONLY),
+            0x01..=0x03 => Ok(CartridgeType::MBC1),
+            0x0F | 0x10 => Ok(CartridgeType::MBC3 { rtc: true }),
+            0x11..=0x13 => Ok(CartridgeType::MBC3 { rtc: false }),
+            0x19..=0x1E => Ok(CartridgeType::MBC5),
+            _ => Err(CartridgeError::UnsupportedType(ty)),
         }
     }
 
-    fn to_cartridge(&self, bytes: Vec<u8>) -> Rc<RefCell<dyn BusListener>> {
+    fn to_cartridge(&self, bytes: Vec<u8>) -> Rc<RefCell<dyn Cartridge>> {
+        let ram = vec![0; ram_size(bytes[0x149])];
+
         match self {
             CartridgeType::ROM_ONLY => Rc::new(RefCell::new(RomOnlyCartridge::new(bytes))),
+            CartridgeType::MBC1 => Rc::new(RefCell::new(Mbc1Cartridge::new(bytes, ram))),
+            CartridgeType::MBC3 { rtc } => Rc::new(RefCell::new(Mbc3Cartridge::new(bytes, ram, *rtc))),
+            CartridgeType::MBC5 => Rc::new(RefCell::new(Mbc5Cartridge::new(bytes, ram))),
         }
     }
 }
 
+/// External RAM size from the header.
+fn ram_size(code: u8) -> usize {
+    match code {
+        0x01 => 0x800,
+        0x02 => 0x2000,
+        0x03 => 0x8000,
+        0x04 => 0x20000,
+        0x05 => 0x10000,
+        _ => 0,
+    }
+}
+
+/// Reads from a 16 KiB ROM bank. The bank number wraps around at the size of the ROM, as its
+/// upper bits are not connected; reads past the end of a truncated ROM return 0xFF.
+fn read_rom(rom: &[u8], bank: usize, address: Address) -> Byte {
+    let banks = (rom.len() / ROM_BANK_SIZE).max(1).next_power_of_two();
+    let offset = (bank % banks) * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1));
+    rom.get(offset).copied().unwrap_or(0xFF)
+}
+
+/// Offset of an address in an 8 KiB bank of external RAM, which is mirrored if it is smaller
+/// than the banks it is addressed through. None if the cartridge has no RAM.
+fn ram_offset(ram: &[u8], bank: usize, address: Address) -> Option<usize> {
+    if ram.is_empty() {
+        return None;
+    }
+
+    Some((bank * RAM_BANK_SIZE + (address as usize & (RAM_BANK_SIZE - 1))) % ram.len())
+}
+
+/// Saves the checksums, to catch states from other games.
+fn save_checksums(rom: &[u8], out: &mut StateWriter) {
+    out.bytes(&rom[CHECKSUM_START..CHECKSUM_END]);
+}
+
+fn load_checksums(rom: &[u8], input: &mut StateReader<'_>) -> Result<(), StateError> {
+    let mut checksums = [0; CHECKSUM_END - CHECKSUM_START];
+    input.bytes(&mut checksums)?;
+
+    if checksums != rom[CHECKSUM_START..CHECKSUM_END] {
+        return Err(StateError::Invalid(String::from("the state belongs to a different cartridge")));
+    }
+    Ok(())
+}
+
 fn read_cartridge_name(bytes: &Vec<u8>) -> String {
     let mut title = &bytes[0x134..=0x143];
     let end = title.iter().position(|&c| c == 0);
@@ -40,32 +167,172 @@ fn read_cartridge_name(bytes: &Vec<u8>) -> String {
         title = &title[..x];
     }
 
-    let title = std::str::from_utf8(&title).expect("Invalid title.");
-    String::from(title)
+    // Later cartridges use the end of the title for the manufacturer code and CGB flag.
+    String::from_utf8_lossy(title).into_owned()
 }
 
-pub fn load(path: &str) -> Rc<RefCell<dyn BusListener>> {
-    let file = File::open(path).expect("Failed to open {path}.");
-    let mut reader = BufReader::new(file);
-
-    let mut bytes: Vec<u8> = vec![];
-    let mut head = reader.by_ref().take(0x150);
-
-    head.read_to_end(&mut bytes).expect("Cartridge file is too small.");
+/// Whether the cartridge runs in CGB mode, which enables the CGB-only registers.
+pub fn is_cgb(bytes: &[u8]) -> bool {
+    bytes.get(CGB_FLAG).is_some_and(|&flag| flag & CGB_SUPPORT != 0)
+}
 
-    //let bytes = fs::read(path).expect("Failed to read cartridge.");
-    assert!(bytes.len() >= 0x150, "Cartridge is too small.");
+pub fn load(bytes: Vec<u8>) -> Result<Rc<RefCell<dyn Cartridge>>, CartridgeError> {
+    if bytes.len() < HEADER_END {
+        return Err(CartridgeError::TooSmall(bytes.len()));
+    }
 
     let cartridge_name = read_cartridge_name(&bytes);
-    let cartridge_type = CartridgeType::from(bytes[0x147]);
+    let cartridge_type = CartridgeType::from(bytes[0x147])?;
 
     // TODO: Checksum goes here.
 
     info!("Loading \"{}\"", cartridge_name);
 
-    reader.read_to_end(&mut bytes).expect("Error reading cartridge.");
-
     let cartridge = cartridge_type.to_cartridge(bytes);
 
-    cartridge
+    Ok(cartridge)
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::{Address, Bus, Byte};
+    use crate::clock::ClockListener;
+    use crate::save_state::{write_state, StateFile};
+    use super::{load, Cartridge};
+
+    type CartridgeCell = Rc<RefCell<dyn Cartridge>>;
+
+    const SECOND_CYCLES: u32 = 4194304;
+
+    /// Cartridge of the given type and size, with each ROM bank starting with its number.
+    fn cartridge(ty: u8, rom_banks: usize, ram_size: u8) -> CartridgeCell {
+        let mut rom = vec![0; rom_banks * 0x4000];
+        for bank in 0..rom_banks {
+            rom[bank * 0x4000] = bank as u8;
+            rom[bank * 0x4000 + 1] = (bank >> 8) as u8;
+        }
+        rom[0x147] = ty;
+        rom[0x149] = ram_size;
+
+        load(rom).unwrap()
+    }
+
+    fn read(cartridge: &CartridgeCell, address: Address) -> Byte {
+        cartridge.borrow().bus_read(address)
+    }
+
+    fn write(cartridge: &CartridgeCell, address: Address, value: Byte) {
+        cartridge.borrow_mut().bus_write(&mut Bus::new(), address, value);
+    }
+
+    /// Number of the ROM bank mapped at `address`.
+    fn bank(cartridge: &CartridgeCell, address: Address) -> usize {
+        read(cartridge, address) as usize | (read(cartridge, address + 1) as usize) << 8
+    }
+
+    #[test]
+    fn test_mbc1() {
+        let cartridge = cartridge(0x03, 128, 0x03);
+        assert_eq!(bank(&cartridge, 0x4000), 1);
+
+        write(&cartridge, 0x2000, 0x00);
+        assert_eq!(bank(&cartridge, 0x4000), 1);
+        write(&cartridge, 0x2000, 0x25);
+        assert_eq!(bank(&cartridge, 0x4000), 0x05);
+        write(&cartridge, 0x4000, 0x01);
+        assert_eq!(bank(&cartridge, 0x4000), 0x25);
+        assert_eq!(bank(&cartridge, 0x0000), 0x00);
+
+        // Mode 1 also applies the secondary bank to 0000-3FFF and RAM.
+        write(&cartridge, 0x6000, 0x01);
+        assert_eq!(bank(&cartridge, 0x0000), 0x20);
+
+        assert_eq!(read(&cartridge, 0xA000), 0xFF);
+        write(&cartridge, 0x0000, 0x0A);
+        write(&cartridge, 0xA000, 0x12);
+        assert_eq!(read(&cartridge, 0xA000), 0x12);
+
+        let state = write_state(&[(*b"CART", &*cartridge.borrow())]);
+
+        write(&cartridge, 0x4000, 0x02);
+        assert_eq!(read(&cartridge, 0xA000), 0x00);
+        assert_eq!(bank(&cartridge, 0x4000), 0x45);
+
+        StateFile::parse(&state).unwrap().load(*b"CART", &mut *cartridge.borrow_mut()).unwrap();
+        assert_eq!(read(&cartridge, 0xA000), 0x12);
+        assert_eq!(bank(&cartridge, 0x4000), 0x25);
+    }
+
+    #[test]
+    fn test_mbc3_rtc() {
+        assert!(cartridge(0x13, 4, 0x03).borrow().rtc().is_none());
+
+        let cartridge = cartridge(0x10, 4, 0x03);
+        let rtc = cartridge.borrow().rtc().unwrap();
+        let run = |cycles: u32| rtc.borrow_mut().callback(&mut Bus::new(), cycles);
+        let latch = || {
+            write(&cartridge, 0x6000, 0x00);
+            write(&cartridge, 0x6000, 0x01);
+        };
+        let read_register = |register: Byte| {
+            write(&cartridge, 0x4000, register);
+            read(&cartridge, 0xA000)
+        };
+
+        write(&cartridge, 0x0000, 0x0A);
+        run(61 * SECOND_CYCLES + 5);
+
+        // Reads see the registers as last latched.
+        assert_eq!(read_register(0x08), 0);
+        latch();
+        assert_eq!(read_register(0x08), 1);
+        assert_eq!(read_register(0x09), 1);
+
+        // While halted, the clock doesn't count.
+        write(&cartridge, 0x4000, 0x0C);
+        write(&cartridge, 0xA000, 0x40);
+        run(2 * SECOND_CYCLES);
+        latch();
+        assert_eq!(read_register(0x08), 1);
+
+        // The day counter overflows after 511 days.
+        for (register, value) in [(0x08, 59), (0x09, 59), (0x0A, 23), (0x0B, 0xFF), (0x0C, 0x01)] {
+            write(&cartridge, 0x4000, register);
+            write(&cartridge, 0xA000, value);
+        }
+        run(SECOND_CYCLES);
+        latch();
+        assert_eq!(read_register(0x0A), 0);
+        assert_eq!(read_register(0x0B), 0);
+        assert_eq!(read_register(0x0C), 0x80);
+
+        // The RAM banks are still there.
+        write(&cartridge, 0x4000, 0x03);
+        write(&cartridge, 0xA000, 0x34);
+        assert_eq!(read(&cartridge, 0xA000), 0x34);
+        assert_eq!(read_register(0x00), 0x00);
+    }
+
+    #[test]
+    fn test_mbc5() {
+        let cartridge = cartridge(0x1B, 512, 0x04);
+        assert_eq!(bank(&cartridge, 0x4000), 1);
+
+        // Unlike MBC1 and MBC3, bank 0 can be mapped at 4000-7FFF.
+        write(&cartridge, 0x2000, 0x00);
+        assert_eq!(bank(&cartridge, 0x4000), 0);
+        write(&cartridge, 0x3000, 0x01);
+        write(&cartridge, 0x2000, 0x23);
+        assert_eq!(bank(&cartridge, 0x4000), 0x123);
+
+        write(&cartridge, 0x0000, 0x0A);
+        write(&cartridge, 0x4000, 0x0F);
+        write(&cartridge, 0xA000, 0x56);
+        write(&cartridge, 0x4000, 0x00);
+        assert_eq!(read(&cartridge, 0xA000), 0x00);
+        write(&cartridge, 0x4000, 0x0F);
+        assert_eq!(read(&cartridge, 0xA000), 0x56);
+    }
 }
@@ -1,4 +1,6 @@
 use crate::{Address, Attach, Bus, BusListener, Byte};
+use crate::cartridge::{load_checksums, save_checksums, Cartridge};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 use log::warn;
 
@@ -30,7 +32,9 @@ impl BusListener for RomOnlyCartridge {
         if address & RAM_BIT != 0 {
             return 0xFF;
         }
-        self.bytes[address as usize]
+
+        // Smaller ROMs leave the rest of the address space unmapped.
+        self.bytes.get(address as usize).copied().unwrap_or(0xFF)
     }
 
     fn bus_write(&mut self, bus: &mut Bus, address: Address, value: Byte) {
@@ -42,4 +46,17 @@ impl BusListener for RomOnlyCartridge {
         }
         //panic!("Cartridge address {:04X} is read-only!", address);
     }
-}
\ No newline at end of file
+}
+
+impl Cartridge for RomOnlyCartridge {}
+
+/// There are no banks or external RAM; the checksums are saved to catch states from other games.
+impl SaveState for RomOnlyCartridge {
+    fn save_state(&self, out: &mut StateWriter) {
+        save_checksums(&self.bytes, out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        load_checksums(&self.bytes, input)
+    }
+}
@@ -0,0 +1,146 @@
+use crate::{Bus, Byte};
+use crate::clock::ClockListener;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// T-cycles per second. The RTC has its own 32768 Hz crystal, which is tied to the system clock.
+const SECOND_CYCLES: u32 = 4194304;
+
+const SECONDS: usize = 0;
+const DAY_HIGH: usize = 4;
+
+// Bits of DH, the upper day register.
+const DH_DAY: Byte = 1 << 0;
+const DH_HALT: Byte = 1 << 6;
+const DH_DAY_CARRY: Byte = 1 << 7;
+
+// Writable bits of S, M, H, DL and DH.
+const REGISTER_MASKS: [Byte; 5] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+
+/// MBC3 real-time clock. It counts emulated rather than wall-clock time, so that it runs the same
+/// across save states, rewinds and movies.
+#[derive(Debug)]
+pub struct Rtc {
+    // S, M, H, DL and DH as they count, and as last latched for reading.
+    registers: [Byte; 5],
+    latched: [Byte; 5],
+
+    // T-cycles into the current second.
+    cycles: u32,
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Self {
+            registers: [0; 5],
+            latched: [0; 5],
+            cycles: 0,
+        }
+    }
+
+    fn halted(&self) -> bool {
+        self.registers[DAY_HIGH] & DH_HALT != 0
+    }
+
+    /// Copies the counting registers to the ones that are read.
+    pub fn latch(&mut self) {
+        self.latched = self.registers;
+    }
+
+    pub fn read(&self, register: usize) -> Byte {
+        self.latched[register]
+    }
+
+    /// The counting and the latched registers.
+    pub fn registers(&self) -> ([Byte; 5], [Byte; 5]) {
+        (self.registers, self.latched)
+    }
+
+    /// Sets both sets of registers, starting a new second.
+    pub fn restore(&mut self, registers: [Byte; 5], latched: [Byte; 5]) {
+        self.registers = std::array::from_fn(|i| registers[i] & REGISTER_MASKS[i]);
+        self.latched = std::array::from_fn(|i| latched[i] & REGISTER_MASKS[i]);
+        self.cycles = 0;
+    }
+
+    /// Writes go to the counting registers and are readable straight away. Writing the seconds
+    /// restarts the current second.
+    pub fn write(&mut self, register: usize, value: Byte) {
+        if register == SECONDS {
+            self.cycles = 0;
+        }
+
+        self.registers[register] = value & REGISTER_MASKS[register];
+        self.latched[register] = self.registers[register];
+    }
+
+    /// Each counter wraps around at its bit width if it was set out of range, without carrying.
+    fn tick_second(&mut self) {
+        let [seconds, minutes, hours, day_low, day_high] = &mut self.registers;
+
+        *seconds = (*seconds + 1) & REGISTER_MASKS[0];
+        if *seconds != 60 {
+            return;
+        }
+        *seconds = 0;
+
+        *minutes = (*minutes + 1) & REGISTER_MASKS[1];
+        if *minutes != 60 {
+            return;
+        }
+        *minutes = 0;
+
+        *hours = (*hours + 1) & REGISTER_MASKS[2];
+        if *hours != 24 {
+            return;
+        }
+        *hours = 0;
+
+        let days = ((*day_high & DH_DAY) as u16) << 8 | *day_low as u16;
+        let (days, carry) = if days == 511 { (0, true) } else { (days + 1, false) };
+
+        *day_low = days as Byte;
+        *day_high = *day_high & !DH_DAY | (days >> 8) as Byte;
+
+        if carry {
+            *day_high |= DH_DAY_CARRY;
+        }
+    }
+}
+
+impl ClockListener for Rtc {
+    fn callback(&mut self, _bus: &mut Bus, cycles: u32) {
+        if self.halted() {
+            return;
+        }
+
+        self.cycles += cycles;
+
+        while self.cycles >= SECOND_CYCLES {
+            self.cycles -= SECOND_CYCLES;
+            self.tick_second();
+        }
+    }
+
+    fn next_event(&self) -> u32 {
+        if self.halted() {
+            return u32::MAX;
+        }
+
+        SECOND_CYCLES - self.cycles
+    }
+}
+
+impl SaveState for Rtc {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.bytes(&self.registers);
+        out.bytes(&self.latched);
+        out.u32(self.cycles);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        input.bytes(&mut self.registers)?;
+        input.bytes(&mut self.latched)?;
+        self.cycles = input.u32()?;
+        Ok(())
+    }
+}
@@ -1,49 +1,274 @@
 use std::cell::RefCell;
 use std::rc::{Rc, Weak};
+use std::thread;
 use std::time::{Duration, Instant};
 
 use crate::bus::*;
 
 const CYCLE_DURATION: Duration = Duration::from_nanos(239); // 4194304 Hz ~ 238.663 ns / cycle
 
+// 70224 cycles per frame, ~59.73 frames per second.
+const FRAME_DURATION: Duration = Duration::from_nanos(16_742_706);
+
+// Pacing stops trying to catch up once it falls this far behind, e.g. after the debugger or a
+// pause stopped the system, and carries on from the present instead.
+const MAX_PACING_LAG: Duration = Duration::from_millis(100);
+
+/// How fast the system runs compared to the hardware.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Speed {
+    Multiplier(f64),
+    Unthrottled,
+}
+
+// Listeners are run at least this often, which keeps the cycles passed to them in range.
+const MAX_EVENT_DISTANCE: u32 = 1 << 24;
+
 pub trait ClockListener {
-    fn callback(&mut self, bus: &mut Bus, cycles: u8);
+    /// Runs the listener for the T-cycles that passed since it last ran.
+    fn callback(&mut self, bus: &mut Bus, cycles: u32);
+
+    /// T-cycles until the listener has to run because of something the rest of the system
+    /// notices without accessing it, such as an interrupt. By default, it runs every M-cycle.
+    fn next_event(&self) -> u32 {
+        1
+    }
+
+    /// Whether the listener is clocked by the CPU, and so runs twice as fast in CGB double speed
+    /// mode. Everything else, like the PPU, keeps running at the same rate.
+    fn cpu_clocked(&self) -> bool {
+        false
+    }
 }
 
 type ClockListenerCell = RefCell<dyn ClockListener>;
 
+/// A listener, the cycle it has been run up to and the cycle it next has to run at.
+struct Scheduled {
+    listener: Weak<ClockListenerCell>,
+    cpu_clocked: bool,
+    last_run: u64,
+    due: u64,
+}
+
+/// Schedules the components clocked alongside the CPU. Rather than running every listener
+/// each M-cycle, each one runs when its next event is due, or when the CPU is about to access
+/// one of its addresses, and then catches up on all the cycles since it last ran.
 pub struct Clock {
-    callbacks: Vec<Weak<ClockListenerCell>>,
+    callbacks: Vec<Scheduled>,
+
+    // Listener behind each address block and IO register, as attached to the bus.
+    block_listeners: [Option<usize>; 0x100],
+    register_listeners: [Option<usize>; 0x100],
+
+    // T-cycles since power-on, and the earliest cycle at which a listener is due.
+    now: u64,
+    next_due: u64,
+
+    // In CGB double speed mode, CPU clocked listeners see two T-cycles for each one that passes.
+    double_speed: bool,
+
     cycles: u16,
     start_instant: Instant,
     // sleeper: SpinSleeper,
+
+    speed: Speed,
+
+    // Frames presented since `pacing_start`.
+    paced_frames: u32,
+    pacing_start: Instant,
 }
 
 impl Clock {
     pub fn new() -> Self {
         Clock {
             callbacks: Vec::new(),
+            block_listeners: [None; 0x100],
+            register_listeners: [None; 0x100],
+            now: 0,
+            next_due: u64::MAX,
+            double_speed: false,
             cycles: 0,
             start_instant: Instant::now(),
             // sleeper: SpinSleeper::default(),
+            speed: Speed::Unthrottled,
+            paced_frames: 0,
+            pacing_start: Instant::now(),
         }
     }
 
-    pub fn attach(&mut self, listener: Rc<ClockListenerCell>) {
-        self.callbacks.push(Rc::downgrade(&listener));
+    /// The listener is caught up whenever one of the addresses it attaches to on the bus is
+    /// accessed through `sync`.
+    pub fn attach<T: ClockListener + BusListener + 'static>(&mut self, listener: Rc<RefCell<T>>) {
+        let attachments = listener.borrow_mut().bus_attach();
+        self.attach_at(listener, attachments);
+    }
+
+    /// Attaches a listener that is not on the bus itself, such as a cartridge's real-time clock.
+    /// It is caught up whenever one of the given addresses is accessed through `sync`.
+    pub fn attach_at(&mut self, listener: Rc<ClockListenerCell>, attachments: Vec<Attach>) {
+        let callback_index = self.callbacks.len();
+
+        for attachment in attachments {
+            use Attach::*;
+            match attachment {
+                Block(block) => self.block_listeners[block as usize] = Some(callback_index),
+                BlockRange(start, end) => {
+                    for block in start..=end {
+                        self.block_listeners[block as usize] = Some(callback_index);
+                    }
+                },
+                Register(register) => self.register_listeners[register as usize] = Some(callback_index),
+                RegisterRange(start, end) => {
+                    for register in start..=end {
+                        self.register_listeners[register as usize] = Some(callback_index);
+                    }
+                },
+            }
+        }
+
+        let cpu_clocked = listener.borrow().cpu_clocked();
+        self.callbacks.push(Scheduled {
+            listener: Rc::downgrade(&listener),
+            cpu_clocked,
+            last_run: self.now,
+            due: self.now,
+        });
+        self.schedule(callback_index, &*listener.borrow());
     }
 
     #[inline(always)]
     pub fn increment(&mut self, bus: &mut Bus, cycles: u8) {
         // self.cycles += cycles as u16;
         self.cycles = cycles as u16;
+        self.now += cycles as u64;
+
+        if self.next_due <= self.now {
+            for callback_index in 0..self.callbacks.len() {
+                if self.callbacks[callback_index].due <= self.now {
+                    self.run(bus, callback_index);
+                }
+            }
+        }
+    }
+
+    /// Catches up the listener behind `address`, if there is one, so that it can be accessed.
+    /// Writes may move its next event, so it has to be synced again after them as well.
+    #[inline(always)]
+    pub fn sync(&mut self, bus: &mut Bus, address: Address) {
+        let callback_index = match address {
+            0xFF00..=0xFF7F | 0xFFFF => self.register_listeners[address as usize & 0xFF],
+            _ => self.block_listeners[(address >> 8) as usize],
+        };
+
+        if let Some(callback_index) = callback_index {
+            self.run(bus, callback_index);
+        }
+    }
+
+    /// Catches up every listener, e.g. before their state is saved.
+    pub fn sync_all(&mut self, bus: &mut Bus) {
+        for callback_index in 0..self.callbacks.len() {
+            self.run(bus, callback_index);
+        }
+    }
+
+    /// Schedules every listener from the present, after their state was replaced.
+    pub fn reschedule(&mut self) {
+        for callback_index in 0..self.callbacks.len() {
+            let listener = self.callbacks[callback_index].listener.upgrade().unwrap();
+            self.callbacks[callback_index].last_run = self.now;
+            self.schedule(callback_index, &*listener.borrow());
+        }
+    }
+
+    fn run(&mut self, bus: &mut Bus, callback_index: usize) {
+        let scheduled = &mut self.callbacks[callback_index];
+        let listener = scheduled.listener.upgrade().unwrap();
+        let mut listener = listener.borrow_mut();
+
+        let mut cycles = self.now - scheduled.last_run;
+        if scheduled.cpu_clocked && self.double_speed {
+            cycles *= 2;
+        }
+
+        if cycles > 0 {
+            listener.callback(bus, cycles as u32);
+        }
+
+        scheduled.last_run = self.now;
+        self.schedule(callback_index, &*listener);
+    }
+
+    fn schedule(&mut self, callback_index: usize, listener: &dyn ClockListener) {
+        let scheduled = &mut self.callbacks[callback_index];
+
+        let mut next_event = listener.next_event().clamp(1, MAX_EVENT_DISTANCE);
+        if scheduled.cpu_clocked && self.double_speed {
+            next_event = next_event.div_ceil(2);
+        }
+
+        scheduled.due = self.now + next_event as u64;
+        self.next_due = self.callbacks.iter().map(|scheduled| scheduled.due).min().unwrap_or(u64::MAX);
+    }
+
+    /// T-cycles since power-on.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    /// Switches CGB double speed mode. Listeners have to be synced before the switch and
+    /// rescheduled after it, so that each one sees the cycles before it at the old rate.
+    pub fn set_double_speed(&mut self, double_speed: bool) {
+        self.double_speed = double_speed;
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.reset_pacing();
+    }
+
+    fn reset_pacing(&mut self) {
+        self.paced_frames = 0;
+        self.pacing_start = Instant::now();
+    }
 
-        for listener in &mut self.callbacks {
-            listener
-                .upgrade()
-                .unwrap()
-                .borrow_mut()
-                .callback(bus, cycles);
+    /// How long after the start of pacing the frames paced so far are due at the current speed.
+    /// None when unthrottled.
+    pub fn pace_target(&self) -> Option<Duration> {
+        let Speed::Multiplier(multiplier) = self.speed else {
+            return None;
+        };
+
+        Some(FRAME_DURATION.mul_f64(self.paced_frames as f64 / multiplier))
+    }
+
+    /// Called once per frame; sleeps until the frame is due at the current speed. Frames are
+    /// paced rather than cycles, so that games run at the right speed even where the PPU's
+    /// timing is off.
+    pub fn pace(&mut self) {
+        if self.speed == Speed::Unthrottled {
+            return;
+        }
+
+        self.paced_frames += 1;
+
+        let emulated = self.pace_target().unwrap();
+        let elapsed = self.pacing_start.elapsed();
+
+        if emulated > elapsed {
+            thread::sleep(emulated - elapsed);
+        }
+        else if elapsed - emulated > MAX_PACING_LAG {
+            self.reset_pacing();
         }
     }
 
@@ -1,2678 +1,1062 @@
-use log::trace;
-use crate::{Address, Bus, ByteDescriptor, CPU, Word, WordDescriptor};
-use crate::cpu::{Direction, flag::Flag};
-
-impl CPU {
-    #[inline(always)]
-    fn ld(&mut self, bus: &mut Bus, dest: ByteDescriptor, src: ByteDescriptor) {
-        let value = self.read_byte(bus, src);
-        self.write_byte(bus, dest, value);
-    }
-
-    #[inline(always)]
-    fn ld_16(&mut self, bus: &mut Bus, dest: WordDescriptor, src: WordDescriptor) {
-        let value = self.read_word(bus, src);
-        self.write_word(bus, dest, value);
-    }
-
-    fn test_condition(&mut self, condition: Option<(Flag, bool)>) -> bool {
-        match condition {
-            Some((flag, value)) => self.test_flag(flag) == value,
-            None => true,
-        }
-    }
-
-    #[inline(always)]
-    fn jp(&mut self, bus: &mut Bus, condition: Option<(Flag, bool)>) -> bool {
-        let address = self.read_word(bus, WordDescriptor::Immediate);
-        if self.test_condition(condition) {
-            trace!("Jumping to {address:04X}");
-            self.pc = address;
-            return true;
-        }
-        return false;
-    }
-
-    #[inline(always)]
-    fn jr(&mut self, bus: &mut Bus, condition: Option<(Flag, bool)>) -> bool {
-        let offset = self.read_byte(bus, ByteDescriptor::Immediate) as i8;
-        if self.test_condition(condition) {
-            if offset < 0 {
-                self.pc -= (offset * -1) as u16;
-            } else {
-                self.pc += offset as u16;
-            }
-            return true;
-        }
-        return false;
-    }
-
-    fn call(&mut self, bus: &mut Bus, condition: Option<(Flag, bool)>) -> bool {
-        let target = self.read_word(bus, WordDescriptor::Immediate);
-        if self.test_condition(condition) {
-            trace!("Call to {:04X}", self.pc);
-            self.push(bus, WordDescriptor::PC);
-            self.pc = target;
-            return true;
-        }
-        return false;
-    }
-
-    #[inline(always)]
-    fn rst(&mut self, bus: &mut Bus, address: Address) {
-        self.push(bus, WordDescriptor::PC);
-        self.pc = address;
-    }
-
-    fn ret(&mut self, bus: &mut Bus, condition: Option<(Flag, bool)>) -> bool {
-        if self.test_condition(condition) {
-            self.pop(bus, WordDescriptor::PC);
-            trace!("Return to {:04X}", self.pc);
-            return true;
-        }
-        return false;
-    }
-
-    #[inline(always)]
-    fn reti(&mut self, bus: &mut Bus) {
-        self.interrupt_registers.as_ref().borrow_mut().master_enable = true;
-        self.pop(bus, WordDescriptor::PC);
-        trace!("Return to {:04X}, enabling interrupts", self.pc);
-    }
-
-    #[inline(always)]
-    fn push(&mut self, bus: &mut Bus, src: WordDescriptor) {
-        self.sp -= 2;
-        let mut value = self.read_word(bus, src);
-
-        //if src == WordDescriptor::AF {
-        //    value &= 0xFF00;
-        //}
-
-        trace!("Stack push {:04X} to {:04X}", value, self.sp);
-        bus.write_word(self.sp, value);
-    }
-
-    #[inline(always)]
-    fn pop(&mut self, bus: &mut Bus, dest: WordDescriptor) {
-        let mut value = bus.read_word(self.sp);
-        trace!("Stack pop {:04X} from {:04X}", value, self.sp);
-
-        if dest == WordDescriptor::AF {
-            value &= 0xFFF0;
-        }
-
-        self.write_word(bus, dest, value);
-        self.sp += 2;
-    }
-
-    fn add(&mut self, bus: &mut Bus, register: ByteDescriptor, with_carry: bool) {
-        let a_value = *self.af.left();
-        let value = self.read_byte(bus, register);
-        let carry = if with_carry && self.test_flag(Flag::Carry) {
-            1
-        } else {
-            0
-        };
-        let (result, carry) = a_value.overflowing_add(value + carry);
-
-        self.clear_all_flags();
-        self.set_flag_if(Flag::HalfCarry, (a_value ^ value ^ result) & 0x10 != 0);
-        self.set_flag_if(Flag::Carry, carry);
-        self.set_flag_if(Flag::Zero, result == 0);
-
-        *self.af.left() = result;
-    }
-
-    fn add_16(&mut self, bus: &mut Bus, register: WordDescriptor) {
-        let hl_value = self.hl.value();
-        let value = self.read_word(bus, register);
-        let (result, carry) = hl_value.overflowing_add(value);
-
-        self.clear_flag(Flag::Subtract);
-        self.set_flag_if(Flag::HalfCarry, (hl_value ^ value ^ result) & 0x1000 != 0);
-        self.set_flag_if(Flag::Carry, carry);
-        *self.hl.word() = result;
-    }
-
-    fn relative_sp(&mut self, bus: &mut Bus) -> Word {
-        let sp_value = self.sp;
-        let value = self.read_byte(bus, ByteDescriptor::Immediate) as i8;
-
-        self.clear_all_flags();
-
-        if value >= 0 {
-            let value = value as u16;
-            let (result, carry) = sp_value.overflowing_add(value);
-            self.set_flag_if(Flag::HalfCarry, (sp_value ^ value ^ result) & 0x10 != 0);
-            self.set_flag_if(Flag::Carry, (sp_value ^ value ^ result) & 0x100 != 0);
-            result
-        } else {
-            let value = (-1 * value) as u16;
-            let (result, carry) = sp_value.overflowing_sub(value);
-            // self.set_flag_if(Flag::HalfCarry, result & 0x0F <= sp_value & 0x0F);
-            // self.set_flag_if(Flag::Carry, carry);
-            // self.set_flag_if(Flag::HalfCarry, (sp_value ^ value ^ result) & 0x1000 != 0);
-            // self.set_flag_if(Flag::Carry, (sp_value ^ value ^ result) & 0x10 != 0);
-            result
-        }
-    }
-
-    fn add_sp(&mut self, bus: &mut Bus) {
-        self.sp = self.relative_sp(bus);
-    }
-
-    fn ld_sp(&mut self, bus: &mut Bus) {
-        let address = self.relative_sp(bus);
-        self.write_word(bus, WordDescriptor::HL, address);
-    }
-
-    fn sub(&mut self, bus: &mut Bus, register: ByteDescriptor, with_carry: bool) {
-        let value = self.read_byte(bus, register);
-        let a_value = *self.af.left();
-        let carry = if with_carry && self.test_flag(Flag::Carry) {
-            1
-        } else {
-            0
-        };
-        let (result, carry) = a_value.overflowing_sub(value + carry);
-
-        self.clear_all_flags();
-        self.set_flag(Flag::Subtract);
-        self.set_flag_if(Flag::HalfCarry, result & 0x0F >= a_value & 0x0F);
-        self.set_flag_if(Flag::Carry, carry);
-        self.set_flag_if(Flag::Zero, result == 0);
-        *self.af.left() = result;
-    }
-
-    fn cp(&mut self, bus: &mut Bus, register: ByteDescriptor) {
-        let value = self.read_byte(bus, register);
-        let a_value = *self.af.left();
-        let (result, carry) = a_value.overflowing_sub(value);
-
-        self.clear_all_flags();
-        self.set_flag(Flag::Subtract);
-        self.set_flag_if(Flag::HalfCarry, (a_value ^ value ^ result) & 0x10 != 0);
-        self.set_flag_if(Flag::Carry, carry);
-        self.set_flag_if(Flag::Zero, result == 0);
-    }
-
-    #[inline(always)]
-    fn cpl(&mut self) {
-        *self.af.left() = !*self.af.left();
-        self.set_flag(Flag::Subtract);
-        self.set_flag(Flag::HalfCarry);
-    }
-
-    fn ccf(&mut self) {
-        if self.test_flag(Flag::Carry) {
-            self.clear_flag(Flag::Carry);
-        } else {
-            self.set_flag(Flag::Carry);
-        }
-        self.clear_flag(Flag::HalfCarry);
-        self.clear_flag(Flag::Subtract);
-    }
-
-    fn scf(&mut self) {
-        self.set_flag(Flag::Carry);
-        self.clear_flag(Flag::HalfCarry);
-        self.clear_flag(Flag::Subtract);
-    }
-
-    fn inc(&mut self, bus: &mut Bus, register: ByteDescriptor) {
-        let value = self.read_byte(bus, register);
-        let (result, carry) = value.overflowing_add(1);
-
-        self.clear_flag(Flag::Subtract);
-        self.set_flag_if(Flag::HalfCarry, value & 0x0F == 0x0F);
-        self.set_flag_if(Flag::Zero, carry);
-        self.write_byte(bus, register, result);
-    }
-
-    fn inc_16(&mut self, bus: &mut Bus, register: WordDescriptor) {
-        let (value, _) = self.read_word(bus, register).overflowing_add(1);
-        self.write_word(bus, register, value);
-    }
-
-    fn dec(&mut self, bus: &mut Bus, register: ByteDescriptor) {
-        let value = self.read_byte(bus, register);
-        let (result, _) = value.overflowing_sub(1);
-
-        self.set_flag(Flag::Subtract);
-        self.set_flag_if(Flag::HalfCarry, (value ^ result) & 0x10 != 0);
-        self.set_flag_if(Flag::Zero, result == 0);
-
-        self.write_byte(bus, register, result);
-    }
-
-    fn dec_16(&mut self, bus: &mut Bus, register: WordDescriptor) {
-        let (value, _) = self.read_word(bus, register).overflowing_sub(1);
-        self.write_word(bus, register, value);
-    }
-
-    fn and(&mut self, bus: &mut Bus, register: ByteDescriptor) {
-        let value = self.read_byte(bus, register);
-        let result = *self.af.left() & value;
-
-        self.clear_all_flags();
-        self.set_flag(Flag::HalfCarry);
-        self.set_flag_if(Flag::Zero, result == 0);
-        *self.af.left() = result;
-    }
-
-    fn or(&mut self, bus: &mut Bus, register: ByteDescriptor) {
-        let value = self.read_byte(bus, register);
-        let result = *self.af.left() | value;
-
-        self.clear_all_flags();
-        self.set_flag_if(Flag::Zero, result == 0);
-        *self.af.left() = result;
-    }
-
-    fn xor(&mut self, bus: &mut Bus, register: ByteDescriptor) {
-        let value = self.read_byte(bus, register);
-        let result = *self.af.left() ^ value;
-
-        self.clear_all_flags();
-        self.set_flag_if(Flag::Zero, result == 0);
-        *self.af.left() = result;
-    }
-
-    fn rotate(
-        &mut self,
-        bus: &mut Bus,
-        register: ByteDescriptor,
-        direction: Direction,
-        with_carry: bool,
-        with_zero: bool,
-    ) {
-        let value = self.read_byte(bus, register);
-
-        let (mut result, _) = match direction {
-            Direction::Left => value.overflowing_shl(1),
-            Direction::Right => value.overflowing_shr(1),
-        };
-
-        let carry = 0 != match direction {
-            Direction::Left => value & 0x80,
-            Direction::Right => value & 0x01,
-        };
-
-        match direction {
-            Direction::Right => assert_eq!(result & 0x80, 0, "Assert!"),
-            _ => {},
-        }
-
-        if with_carry && self.test_flag(Flag::Carry) { //|| (!with_carry && carry) {
-            match direction {
-                Direction::Left => result |= 1,
-                Direction::Right => result |= 0x80,
-            }
-        }
-
-        self.clear_all_flags();
-        self.set_flag_if(Flag::Carry, carry);
-        self.set_flag_if(Flag::Zero, with_zero && result == 0);
-        self.write_byte(bus, register, result);
-    }
-
-    fn shift(
-        &mut self,
-        bus: &mut Bus,
-        register: ByteDescriptor,
-        direction: Direction,
-        arithmetic_shift: bool,
-    ) {
-        let value = self.read_byte(bus, register);
-        let (result, carry) = match direction {
-            Direction::Left => value.overflowing_shl(1),
-            Direction::Right => {
-                let msb = if arithmetic_shift { 1u8 } else { 0u8 } << 7;
-                let (result, _) = value.overflowing_shr(1);
-                let carry = value & 1 != 0;
-                (msb | result, carry)
-            }
-        };
-
-        self.clear_all_flags();
-        self.set_flag_if(Flag::Zero, result == 0);
-        self.set_flag_if(Flag::Carry, carry);
-        self.write_byte(bus, register, result);
-    }
-
-    fn swap(&mut self, bus: &mut Bus, register: ByteDescriptor) {
-        let mut tmp_value = self.read_byte(bus, register);
-        tmp_value = ((tmp_value & 0x0F) << 4) | (tmp_value >> 4);
-        self.clear_all_flags();
-        self.set_flag_if(Flag::Zero, tmp_value == 0);
-        self.write_byte(bus, register, tmp_value);
-    }
-
-    #[inline(always)]
-    fn test_bit(&mut self, bus: &mut Bus, register: ByteDescriptor, bit: u8) {
-        let value = self.read_byte(bus, register) & (1u8 << bit);
-        self.clear_flag(Flag::Subtract);
-        self.set_flag(Flag::HalfCarry);
-        self.set_flag_if(Flag::Zero, value == 0);
-    }
-
-    #[inline(always)]
-    fn set_bit(&mut self, bus: &mut Bus, register: ByteDescriptor, bit: u8) {
-        let value = self.read_byte(bus, register) | (1u8 << bit);
-        self.write_byte(bus, register, value);
-    }
-
-    #[inline(always)]
-    fn clear_bit(&mut self, bus: &mut Bus, register: ByteDescriptor, bit: u8) {
-        let value = self.read_byte(bus, register) & !(1u8 << bit);
-        self.write_byte(bus, register, value);
-    }
-
-    fn daa(&mut self) {
-        let mut value = *self.af.left();
-
-        if self.test_flag(Flag::Subtract) {
-            if self.test_flag(Flag::Carry) {
-                value = value.overflowing_sub(0x60).0;
-            }
-            if self.test_flag(Flag::HalfCarry) {
-                value = value.overflowing_sub(0x06).0;
-            }
-        } else {
-            if self.test_flag(Flag::Carry) || value > 0x99 {
-                value = value.overflowing_add(0x60).0;
-                self.set_flag(Flag::Carry);
-            }
-            if self.test_flag(Flag::HalfCarry) || value & 0x0F > 0x09 {
-                value = value.overflowing_add(0x06).0;
-            }
-        }
-
-        self.set_flag_if(Flag::Zero, value == 0);
-        self.clear_flag(Flag::HalfCarry);
-        *self.af.left() = value;
-    }
-}
-
-impl CPU {
-    pub fn step(&mut self, bus: &mut Bus) -> u8 {
-        trace!("");
-        trace!("Begin step");
-        let mut cycles = 0;
-        cycles += self.handle_interrupts(bus);
-        cycles += self._step(bus);
-        trace!("End step after {} cycles.", cycles);
-        trace!("");
-        cycles << 2
-    }
-
-    fn handle_interrupts(&mut self, bus: &mut Bus) -> u8 {
-        let interrupt = *self.interrupt_registers.borrow();
-
-        // Ignore if interrupts are disabled
-        if !interrupt.master_enable {
-            trace!("Interrupts are disabled.");
-            return 0;
-        }
-
-        // Mask out disabled interrupts
-        let interrupts: u8 = interrupt.flags & interrupt.enable;
-
-        // Return if there are no interrupts to handle
-        if interrupts == 0 {
-            trace!("No interrupts to handle (IF {:02X} IE {:02X}).", interrupt.flags, interrupt.enable);
-            return 0;
-        }
-
-        // Priority is low to high bit
-        for bit in 0..5 {
-            if interrupts & (1 << bit) == 0 {
-                continue;
-            }
-
-            trace!("Handling interrupt {:08b}.", 1 << bit);
-
-            // Acknowledge interrupt
-            self.interrupt_registers.as_ref().borrow_mut().flags ^= 1 << bit;
-
-            // Disable interrupts and jump to interrupt handler
-            self.interrupt_registers.as_ref().borrow_mut().master_enable = false;
-            self.push(bus, WordDescriptor::PC);
-            self.pc = 0x40 + (bit << 3);
-
-            return 5
-        }
-
-        // This return should never be reached.
-        assert!(false);
-        0
-    }
-
-    pub fn print_trace(&self, bus: &Bus) {
-        print!("A:{:02X} F:{:02X} \
-                B:{:02X} C:{:02X} \
-                D:{:02X} E:{:02X} \
-                H:{:02X} L:{:02X} \
-                SP:{:04X} PC:{:04X} PCMEM:",
-               self.af.value() >> 8, self.af.value() & 0xFF,
-               self.bc.value() >> 8, self.bc.value() & 0xFF,
-               self.de.value() >> 8, self.de.value() & 0xFF,
-               self.hl.value() >> 8, self.hl.value() & 0xFF,
-               self.sp, self.pc);
-
-        for i in 0..4 {
-            print!("{:02X}", bus.read_byte(self.pc + i));
-
-            if i < 3 {
-                print!(",");
-            }
-        }
-
-        println!();
-    }
-
-    fn _step(&mut self, bus: &mut Bus) -> u8 {
-        let opcode = self.read_byte(bus, ByteDescriptor::Immediate);
-        trace!("PC {:04X} OP {:02X}", self.pc - 1, opcode);
-
-        type Byte = ByteDescriptor;
-        type Word = WordDescriptor;
-
-        match opcode {
-            0x00 => 1, // NOP
-            0x01 => {
-                self.ld_16(bus, Word::BC, Word::Immediate);
-                3
-            } // ld BC, u16
-            0x02 => {
-                self.ld(bus, Byte::BC, Byte::A);
-                2
-            } // ld (BC), A
-            0x03 => {
-                self.inc_16(bus, Word::BC);
-                2
-            } // inc BC
-            0x04 => {
-                self.inc(bus, Byte::B);
-                1
-            } // inc B
-            0x05 => {
-                self.dec(bus, Byte::B);
-                1
-            } // dec B
-            0x06 => {
-                self.ld(bus, Byte::B, Byte::Immediate);
-                2
-            } // ld B, u8
-            0x07 => {
-                self.rotate(bus, Byte::A, Direction::Left, false, false);
-                1
-            } // rlca
-
-            0x08 => {
-                self.ld_16(bus, Word::Indirect, Word::SP);
-                5
-            } // ld (u16), SP
-            0x09 => {
-                self.add_16(bus, Word::BC);
-                2
-            } // add HL, BC
-            0x0A => {
-                self.ld(bus, Byte::A, Byte::BC);
-                2
-            } // ld A, (BC)
-            0x0B => {
-                self.dec_16(bus, Word::BC);
-                2
-            } // dec BC
-            0x0C => {
-                self.inc(bus, Byte::C);
-                1
-            } // inc C
-            0x0D => {
-                self.dec(bus, Byte::C);
-                1
-            } // dec C
-            0x0E => {
-                self.ld(bus, Byte::C, Byte::Immediate);
-                2
-            } // ld C, u8
-            0x0F => {
-                self.rotate(bus, Byte::A, Direction::Right, false, false);
-                1
-            } // rrca
-
-            //0x10 => { self.stopped = 1 }, // STOP
-            0x11 => {
-                self.ld_16(bus, Word::DE, Word::Immediate);
-                3
-            } // ld DE, u16
-            0x12 => {
-                self.ld(bus, Byte::DE, Byte::A);
-                2
-            } // ld (DE), A
-            0x13 => {
-                self.inc_16(bus, Word::DE);
-                2
-            } // inc DE
-            0x14 => {
-                self.inc(bus, Byte::D);
-                1
-            } // inc D
-            0x15 => {
-                self.dec(bus, Byte::D);
-                1
-            } // dec D
-            0x16 => {
-                self.ld(bus, Byte::D, Byte::Immediate);
-                2
-            } // ld D, u8
-            0x17 => {
-                self.rotate(bus, Byte::A, Direction::Left, true, false);
-                1
-            } // rla
-
-            0x18 => {
-                self.jr(bus, None);
-                3
-            } // jr i8
-            0x19 => {
-                self.add_16(bus, Word::DE);
-                2
-            } // add HL, DE
-            0x1A => {
-                self.ld(bus, Byte::A, Byte::DE);
-                2
-            } // ld A, (DE)
-            0x1B => {
-                self.dec_16(bus, Word::DE);
-                2
-            } // dec DE
-            0x1C => {
-                self.inc(bus, Byte::E);
-                1
-            } // inc E
-            0x1D => {
-                self.dec(bus, Byte::E);
-                1
-            } // dec E
-            0x1E => {
-                self.ld(bus, Byte::E, Byte::Immediate);
-                2
-            } // ld C, u8
-            0x1F => {
-                self.rotate(bus, Byte::A, Direction::Right, true, false);
-                1
-            } // rra
-
-            0x20 => {
-                if self.jr(bus, Some((Flag::Zero, false))) {
-                    3
-                } else {
-                    2
-                }
-            } // jr NZ, i8
-            0x21 => {
-                self.ld_16(bus, Word::HL, Word::Immediate);
-                3
-            } // ld HL, u16
-            0x22 => {
-                self.ld(bus, Byte::HLi, Byte::A);
-                2
-            } // ld (HL+), A
-            0x23 => {
-                self.inc_16(bus, Word::HL);
-                2
-            } // inc HL
-            0x24 => {
-                self.inc(bus, Byte::H);
-                1
-            } // inc H
-            0x25 => {
-                self.dec(bus, Byte::H);
-                1
-            } // dec H
-            0x26 => {
-                self.ld(bus, Byte::H, Byte::Immediate);
-                2
-            } // ld H, u8
-            0x27 => {
-                self.daa();
-                1
-            } // daa
-
-            0x28 => {
-                if self.jr(bus, Some((Flag::Zero, true))) {
-                    3
-                } else {
-                    2
-                }
-            } // jr Z, i8
-            0x29 => {
-                self.add_16(bus, Word::HL);
-                2
-            } // add HL, HL
-            0x2A => {
-                self.ld(bus, Byte::A, Byte::HLi);
-                2
-            } // ld A, (HL+)
-            0x2B => {
-                self.dec_16(bus, Word::HL);
-                2
-            } // dec HL
-            0x2C => {
-                self.inc(bus, Byte::L);
-                1
-            } // inc L
-            0x2D => {
-                self.dec(bus, Byte::L);
-                1
-            } // dec L
-            0x2E => {
-                self.ld(bus, Byte::L, Byte::Immediate);
-                2
-            } // ld L, u8
-            0x2F => {
-                self.cpl();
-                1
-            } // cpl
-
-            0x30 => {
-                if self.jr(bus, Some((Flag::Carry, false))) {
-                    3
-                } else {
-                    2
-                }
-            } // jr NC, i8
-            0x31 => {
-                self.ld_16(bus, Word::SP, Word::Immediate);
-                3
-            } // ld SP, u16
-            0x32 => {
-                self.ld(bus, Byte::HLd, Byte::A);
-                2
-            } // ld (HL-), A
-            0x33 => {
-                self.inc_16(bus, Word::SP);
-                2
-            } // inc SP
-            0x34 => {
-                self.inc(bus, Byte::HL);
-                1
-            } // inc (HL)
-            0x35 => {
-                self.dec(bus, Byte::HL);
-                1
-            } // dec (HL)
-            0x36 => {
-                self.ld(bus, Byte::HL, Byte::Immediate);
-                2
-            } // ld (HL), u8
-            0x37 => {
-                self.scf();
-                1
-            } // scf
-
-            0x38 => {
-                if self.jr(bus, Some((Flag::Carry, true))) {
-                    3
-                } else {
-                    2
-                }
-            } // jr C, i8
-            0x39 => {
-                self.add_16(bus, Word::SP);
-                2
-            } // add HL, SP
-            0x3A => {
-                self.ld(bus, Byte::A, Byte::HLd);
-                2
-            } // ld A, (HL-)
-            0x3B => {
-                self.dec_16(bus, Word::SP);
-                2
-            } // dec SP
-            0x3C => {
-                self.inc(bus, Byte::A);
-                1
-            } // inc A
-            0x3D => {
-                self.dec(bus, Byte::A);
-                1
-            } // dec A
-            0x3E => {
-                self.ld(bus, Byte::A, Byte::Immediate);
-                2
-            } // ld L, u8
-            0x3F => {
-                self.ccf();
-                1
-            } // ccf
-
-            0x40 => {
-                self.ld(bus, Byte::B, Byte::B);
-                1
-            }
-            0x41 => {
-                self.ld(bus, Byte::B, Byte::C);
-                1
-            }
-            0x42 => {
-                self.ld(bus, Byte::B, Byte::D);
-                1
-            }
-            0x43 => {
-                self.ld(bus, Byte::B, Byte::E);
-                1
-            }
-            0x44 => {
-                self.ld(bus, Byte::B, Byte::H);
-                1
-            }
-            0x45 => {
-                self.ld(bus, Byte::B, Byte::L);
-                1
-            }
-            0x46 => {
-                self.ld(bus, Byte::B, Byte::HL);
-                2
-            }
-            0x47 => {
-                self.ld(bus, Byte::B, Byte::A);
-                1
-            }
-
-            0x48 => {
-                self.ld(bus, Byte::C, Byte::B);
-                1
-            }
-            0x49 => {
-                self.ld(bus, Byte::C, Byte::C);
-                1
-            }
-            0x4A => {
-                self.ld(bus, Byte::C, Byte::D);
-                1
-            }
-            0x4B => {
-                self.ld(bus, Byte::C, Byte::E);
-                1
-            }
-            0x4C => {
-                self.ld(bus, Byte::C, Byte::H);
-                1
-            }
-            0x4D => {
-                self.ld(bus, Byte::C, Byte::L);
-                1
-            }
-            0x4E => {
-                self.ld(bus, Byte::C, Byte::HL);
-                2
-            }
-            0x4F => {
-                self.ld(bus, Byte::C, Byte::A);
-                1
-            }
-
-            0x50 => {
-                self.ld(bus, Byte::D, Byte::B);
-                1
-            }
-            0x51 => {
-                self.ld(bus, Byte::D, Byte::C);
-                1
-            }
-            0x52 => {
-                self.ld(bus, Byte::D, Byte::D);
-                1
-            }
-            0x53 => {
-                self.ld(bus, Byte::D, Byte::E);
-                1
-            }
-            0x54 => {
-                self.ld(bus, Byte::D, Byte::H);
-                1
-            }
-            0x55 => {
-                self.ld(bus, Byte::D, Byte::L);
-                1
-            }
-            0x56 => {
-                self.ld(bus, Byte::D, Byte::HL);
-                2
-            }
-            0x57 => {
-                self.ld(bus, Byte::D, Byte::A);
-                1
-            }
-
-            0x58 => {
-                self.ld(bus, Byte::E, Byte::B);
-                1
-            }
-            0x59 => {
-                self.ld(bus, Byte::E, Byte::C);
-                1
-            }
-            0x5A => {
-                self.ld(bus, Byte::E, Byte::D);
-                1
-            }
-            0x5B => {
-                self.ld(bus, Byte::E, Byte::E);
-                1
-            }
-            0x5C => {
-                self.ld(bus, Byte::E, Byte::H);
-                1
-            }
-            0x5D => {
-                self.ld(bus, Byte::E, Byte::L);
-                1
-            }
-            0x5E => {
-                self.ld(bus, Byte::E, Byte::HL);
-                2
-            }
-            0x5F => {
-                self.ld(bus, Byte::E, Byte::A);
-                1
-            }
-
-            0x60 => {
-                self.ld(bus, Byte::H, Byte::B);
-                1
-            }
-            0x61 => {
-                self.ld(bus, Byte::H, Byte::C);
-                1
-            }
-            0x62 => {
-                self.ld(bus, Byte::H, Byte::D);
-                1
-            }
-            0x63 => {
-                self.ld(bus, Byte::H, Byte::E);
-                1
-            }
-            0x64 => {
-                self.ld(bus, Byte::H, Byte::H);
-                1
-            }
-            0x65 => {
-                self.ld(bus, Byte::H, Byte::L);
-                1
-            }
-            0x66 => {
-                self.ld(bus, Byte::H, Byte::HL);
-                2
-            }
-            0x67 => {
-                self.ld(bus, Byte::H, Byte::A);
-                1
-            }
-
-            0x68 => {
-                self.ld(bus, Byte::L, Byte::B);
-                1
-            }
-            0x69 => {
-                self.ld(bus, Byte::L, Byte::C);
-                1
-            }
-            0x6A => {
-                self.ld(bus, Byte::L, Byte::D);
-                1
-            }
-            0x6B => {
-                self.ld(bus, Byte::L, Byte::E);
-                1
-            }
-            0x6C => {
-                self.ld(bus, Byte::L, Byte::H);
-                1
-            }
-            0x6D => {
-                self.ld(bus, Byte::L, Byte::L);
-                1
-            }
-            0x6E => {
-                self.ld(bus, Byte::L, Byte::HL);
-                2
-            }
-            0x6F => {
-                self.ld(bus, Byte::L, Byte::A);
-                1
-            }
-
-            0x70 => {
-                self.ld(bus, Byte::HL, Byte::B);
-                1
-            }
-            0x71 => {
-                self.ld(bus, Byte::HL, Byte::C);
-                1
-            }
-            0x72 => {
-                self.ld(bus, Byte::HL, Byte::D);
-                1
-            }
-            0x73 => {
-                self.ld(bus, Byte::HL, Byte::E);
-                1
-            }
-            0x74 => {
-                self.ld(bus, Byte::HL, Byte::H);
-                1
-            }
-            0x75 => {
-                self.ld(bus, Byte::HL, Byte::L);
-                1
-            }
-            0x76 => {
-                self.halted = true;
-                1
-            }
-            0x77 => {
-                self.ld(bus, Byte::HL, Byte::A);
-                1
-            }
-
-            0x78 => {
-                self.ld(bus, Byte::A, Byte::B);
-                1
-            }
-            0x79 => {
-                self.ld(bus, Byte::A, Byte::C);
-                1
-            }
-            0x7A => {
-                self.ld(bus, Byte::A, Byte::D);
-                1
-            }
-            0x7B => {
-                self.ld(bus, Byte::A, Byte::E);
-                1
-            }
-            0x7C => {
-                self.ld(bus, Byte::A, Byte::H);
-                1
-            }
-            0x7D => {
-                self.ld(bus, Byte::A, Byte::L);
-                1
-            }
-            0x7E => {
-                self.ld(bus, Byte::A, Byte::HL);
-                2
-            }
-            0x7F => {
-                self.ld(bus, Byte::A, Byte::A);
-                1
-            }
-
-            0x80 => {
-                self.add(bus, Byte::B, false);
-                1
-            }
-            0x81 => {
-                self.add(bus, Byte::C, false);
-                1
-            }
-            0x82 => {
-                self.add(bus, Byte::D, false);
-                1
-            }
-            0x83 => {
-                self.add(bus, Byte::E, false);
-                1
-            }
-            0x84 => {
-                self.add(bus, Byte::H, false);
-                1
-            }
-            0x85 => {
-                self.add(bus, Byte::L, false);
-                1
-            }
-            0x86 => {
-                self.add(bus, Byte::HL, false);
-                2
-            }
-            0x87 => {
-                self.add(bus, Byte::A, false);
-                1
-            } // Flags 1100
-
-            0x88 => {
-                self.add(bus, Byte::B, true);
-                1
-            }
-            0x89 => {
-                self.add(bus, Byte::C, true);
-                1
-            }
-            0x8A => {
-                self.add(bus, Byte::D, true);
-                1
-            }
-            0x8B => {
-                self.add(bus, Byte::E, true);
-                1
-            }
-            0x8C => {
-                self.add(bus, Byte::H, true);
-                1
-            }
-            0x8D => {
-                self.add(bus, Byte::L, true);
-                1
-            }
-            0x8E => {
-                self.add(bus, Byte::HL, true);
-                2
-            }
-            0x8F => {
-                self.add(bus, Byte::A, true);
-                1
-            }
-
-            0x90 => {
-                self.sub(bus, Byte::B, false);
-                1
-            }
-            0x91 => {
-                self.sub(bus, Byte::C, false);
-                1
-            }
-            0x92 => {
-                self.sub(bus, Byte::D, false);
-                1
-            }
-            0x93 => {
-                self.sub(bus, Byte::E, false);
-                1
-            }
-            0x94 => {
-                self.sub(bus, Byte::H, false);
-                1
-            }
-            0x95 => {
-                self.sub(bus, Byte::L, false);
-                1
-            }
-            0x96 => {
-                self.sub(bus, Byte::HL, false);
-                2
-            }
-            0x97 => {
-                self.sub(bus, Byte::A, false);
-                1
-            }
-
-            0x98 => {
-                self.sub(bus, Byte::B, true);
-                1
-            }
-            0x99 => {
-                self.sub(bus, Byte::C, true);
-                1
-            }
-            0x9A => {
-                self.sub(bus, Byte::D, true);
-                1
-            }
-            0x9B => {
-                self.sub(bus, Byte::E, true);
-                1
-            }
-            0x9C => {
-                self.sub(bus, Byte::H, true);
-                1
-            }
-            0x9D => {
-                self.sub(bus, Byte::L, true);
-                1
-            }
-            0x9E => {
-                self.sub(bus, Byte::HL, true);
-                2
-            }
-            0x9F => {
-                self.sub(bus, Byte::A, true);
-                1
-            }
-
-            0xA0 => {
-                self.and(bus, Byte::B);
-                1
-            }
-            0xA1 => {
-                self.and(bus, Byte::C);
-                1
-            }
-            0xA2 => {
-                self.and(bus, Byte::D);
-                1
-            }
-            0xA3 => {
-                self.and(bus, Byte::E);
-                1
-            }
-            0xA4 => {
-                self.and(bus, Byte::H);
-                1
-            }
-            0xA5 => {
-                self.and(bus, Byte::L);
-                1
-            }
-            0xA6 => {
-                self.and(bus, Byte::HL);
-                2
-            }
-            0xA7 => {
-                self.and(bus, Byte::A);
-                1
-            }
-
-            0xA8 => {
-                self.xor(bus, Byte::B);
-                1
-            }
-            0xA9 => {
-                self.xor(bus, Byte::C);
-                1
-            }
-            0xAA => {
-                self.xor(bus, Byte::D);
-                1
-            }
-            0xAB => {
-                self.xor(bus, Byte::E);
-                1
-            }
-            0xAC => {
-                self.xor(bus, Byte::H);
-                1
-            }
-            0xAD => {
-                self.xor(bus, Byte::L);
-                1
-            }
-            0xAE => {
-                self.xor(bus, Byte::HL);
-                2
-            }
-            0xAF => {
-                self.xor(bus, Byte::A);
-                1
-            }
-
-            0xB0 => {
-                self.or(bus, Byte::B);
-                1
-            }
-            0xB1 => {
-                self.or(bus, Byte::C);
-                1
-            }
-            0xB2 => {
-                self.or(bus, Byte::D);
-                1
-            }
-            0xB3 => {
-                self.or(bus, Byte::E);
-                1
-            }
-            0xB4 => {
-                self.or(bus, Byte::H);
-                1
-            }
-            0xB5 => {
-                self.or(bus, Byte::L);
-                1
-            }
-            0xB6 => {
-                self.or(bus, Byte::HL);
-                2
-            }
-            0xB7 => {
-                self.or(bus, Byte::A);
-                1
-            }
-
-            0xB8 => {
-                self.cp(bus, Byte::B);
-                1
-            }
-            0xB9 => {
-                self.cp(bus, Byte::C);
-                1
-            }
-            0xBA => {
-                self.cp(bus, Byte::D);
-                1
-            }
-            0xBB => {
-                self.cp(bus, Byte::E);
-                1
-            }
-            0xBC => {
-                self.cp(bus, Byte::H);
-                1
-            }
-            0xBD => {
-                self.cp(bus, Byte::L);
-                1
-            }
-            0xBE => {
-                self.cp(bus, Byte::HL);
-                2
-            }
-            0xBF => {
-                self.cp(bus, Byte::A);
-                1
-            }
-
-            0xC0 => {
-                if self.ret(bus, Some((Flag::Zero, false))) {
-                    4
-                } else {
-                    2
-                }
-            }
-            0xC1 => {
-                self.pop(bus, Word::BC);
-                3
-            }
-            0xC2 => {
-                if self.jp(bus, Some((Flag::Zero, false))) {
-                    4
-                } else {
-                    3
-                }
-            }
-            0xC3 => {
-                self.jp(bus, None);
-                4
-            }
-            0xC4 => {
-                if self.call(bus, Some((Flag::Zero, false))) {
-                    6
-                } else {
-                    3
-                }
-            }
-            0xC5 => {
-                self.push(bus, Word::BC);
-                4
-            }
-            0xC6 => {
-                self.add(bus, Byte::Immediate, false);
-                2
-            }
-            0xC7 => {
-                self.rst(bus, 0x00);
-                4
-            }
-
-            0xC8 => {
-                if self.ret(bus, Some((Flag::Zero, true))) {
-                    4
-                } else {
-                    2
-                }
-            }
-            0xC9 => {
-                self.ret(bus, None);
-                4
-            }
-            0xCA => {
-                if self.jp(bus, Some((Flag::Zero, true))) {
-                    4
-                } else {
-                    3
-                }
-            }
-            0xCB => self.cb(bus) + 1,
-            0xCC => {
-                if self.call(bus, Some((Flag::Zero, true))) {
-                    6
-                } else {
-                    3
-                }
-            }
-            0xCD => {
-                self.call(bus, None);
-                6
-            }
-            0xCE => {
-                self.add(bus, Byte::Immediate, true);
-                2
-            }
-            0xCF => {
-                self.rst(bus, 0x08);
-                4
-            }
-
-            0xD0 => {
-                if self.ret(bus, Some((Flag::Carry, false))) {
-                    4
-                } else {
-                    2
-                }
-            }
-            0xD1 => {
-                self.pop(bus, Word::DE);
-                3
-            }
-            0xD2 => {
-                if self.jp(bus, Some((Flag::Carry, false))) {
-                    4
-                } else {
-                    3
-                }
-            }
-            // 0xD3 => Illegal
-            0xD4 => {
-                if self.call(bus, Some((Flag::Carry, false))) {
-                    6
-                } else {
-                    3
-                }
-            }
-            0xD5 => {
-                self.push(bus, Word::DE);
-                4
-            }
-            0xD6 => {
-                self.sub(bus, Byte::Immediate, false);
-                2
-            }
-            0xD7 => {
-                self.rst(bus, 0x10);
-                4
-            }
-
-            0xD8 => {
-                if self.ret(bus, Some((Flag::Carry, true))) {
-                    4
-                } else {
-                    2
-                }
-            }
-            0xD9 => {
-                self.reti(bus);
-                4
-            }
-            0xDA => {
-                if self.jp(bus, Some((Flag::Carry, true))) {
-                    4
-                } else {
-                    3
-                }
-            }
-            // 0xDB => Illegal
-            0xDC => {
-                if self.call(bus, Some((Flag::Carry, true))) {
-                    6
-                } else {
-                    3
-                }
-            }
-            // 0xDD => Illegal
-            0xDE => {
-                self.sub(bus, Byte::Immediate, true);
-                2
-            }
-            0xDF => {
-                self.rst(bus, 0x18);
-                4
-            }
-
-            0xE0 => {
-                self.ld(bus, Byte::HighAddress, Byte::A);
-                3
-            }
-            0xE1 => {
-                self.pop(bus, Word::HL);
-                3
-            }
-            0xE2 => {
-                self.ld(bus, Byte::HighAddressC, Byte::A);
-                2
-            }
-            // 0xE3 => Illegal
-            // 0xE4 => Illegal
-            0xE5 => {
-                self.push(bus, Word::HL);
-                4
-            }
-            0xE6 => {
-                self.and(bus, Byte::Immediate);
-                2
-            }
-            0xE7 => {
-                self.rst(bus, 0x20);
-                4
-            }
-
-            0xE8 => {
-                self.add_sp(bus);
-                4
-            }
-            0xE9 => {
-                self.pc = self.hl.value();
-                1
-            }
-            0xEA => {
-                self.ld(bus, Byte::Indirect, Byte::A);
-                4
-            }
-            // 0xEB => Illegal
-            // 0xEC => Illegal
-            // 0xED => Illegal
-            0xEE => {
-                self.xor(bus, Byte::Immediate);
-                2
-            }
-            0xEF => {
-                self.rst(bus, 0x28);
-                4
-            }
-
-            0xF0 => {
-                self.ld(bus, Byte::A, Byte::HighAddress);
-                3
-            }
-            0xF1 => {
-                self.pop(bus, Word::AF);
-                3
-            }
-            0xF2 => {
-                self.ld(bus, Byte::A, Byte::HighAddressC);
-                2
-            }
-            0xF3 => {
-                self.interrupt_registers.as_ref().borrow_mut().master_enable = false;
-                1
-            }
-            // 0xF4 => Illegal
-            0xF5 => {
-                self.push(bus, Word::AF);
-                4
-            }
-            0xF6 => {
-                self.or(bus, Byte::Immediate);
-                2
-            }
-            0xF7 => {
-                self.rst(bus, 0x20);
-                4
-            }
-
-            0xF8 => {
-                self.ld_sp(bus);
-                3
-            }
-            0xF9 => {
-                self.ld_16(bus, Word::SP, Word::HL);
-                2
-            }
-            0xFA => {
-                self.ld(bus, Byte::A, Byte::Indirect);
-                4
-            }
-            0xFB => {
-                self.interrupt_registers.as_ref().borrow_mut().master_enable = true;
-                1
-            }
-            // 0xFC => Illegal
-            // 0xFD => Illegal
-            0xFE => {
-                self.cp(bus, Byte::Immediate);
-                2
-            }
-            0xFF => {
-                self.rst(bus, 0x38);
-                4
-            }
-
-            _ => panic!("Illegal Instruction ({:02X})", opcode),
-        }
-    }
-
-    fn cb(&mut self, bus: &mut Bus) -> u8 {
-        let opcode = self.read_byte(bus, ByteDescriptor::Immediate);
-        //println!("{:04X} {:02X}", self.pc - 1, opcode);
-
-        type Byte = ByteDescriptor;
-        type Word = WordDescriptor;
-
-        // TODO: Write a macro that to reduce repetition
-        match opcode {
-            0x00 => {
-                self.rotate(bus, Byte::B, Direction::Left, false, true);
-                2
-            }
-            0x01 => {
-                self.rotate(bus, Byte::C, Direction::Left, false, true);
-                2
-            }
-            0x02 => {
-                self.rotate(bus, Byte::D, Direction::Left, false, true);
-                2
-            }
-            0x03 => {
-                self.rotate(bus, Byte::E, Direction::Left, false, true);
-                2
-            }
-            0x04 => {
-                self.rotate(bus, Byte::H, Direction::Left, false, true);
-                2
-            }
-            0x05 => {
-                self.rotate(bus, Byte::L, Direction::Left, false, true);
-                2
-            }
-            0x06 => {
-                self.rotate(bus, Byte::HL, Direction::Left, false, true);
-                4
-            }
-            0x07 => {
-                self.rotate(bus, Byte::A, Direction::Left, true, true);
-                2
-            }
-
-            0x08 => {
-                self.rotate(bus, Byte::B, Direction::Right, true, true);
-                2
-            }
-            0x09 => {
-                self.rotate(bus, Byte::C, Direction::Right, false, true);
-                2
-            }
-            0x0A => {
-                self.rotate(bus, Byte::D, Direction::Right, false, true);
-                2
-            }
-            0x0B => {
-                self.rotate(bus, Byte::E, Direction::Right, false, true);
-                2
-            }
-            0x0C => {
-                self.rotate(bus, Byte::H, Direction::Right, false, true);
-                2
-            }
-            0x0D => {
-                self.rotate(bus, Byte::L, Direction::Right, false, true);
-                2
-            }
-            0x0E => {
-                self.rotate(bus, Byte::HL, Direction::Right, false, true);
-                4
-            }
-            0x0F => {
-                self.rotate(bus, Byte::A, Direction::Right, false, true);
-                2
-            }
-
-            0x10 => {
-                self.rotate(bus, Byte::B, Direction::Left, true, true);
-                2
-            }
-            0x11 => {
-                self.rotate(bus, Byte::C, Direction::Left, true, true);
-                2
-            }
-            0x12 => {
-                self.rotate(bus, Byte::D, Direction::Left, true, true);
-                2
-            }
-            0x13 => {
-                self.rotate(bus, Byte::E, Direction::Left, true, true);
-                2
-            }
-            0x14 => {
-                self.rotate(bus, Byte::H, Direction::Left, true, true);
-                2
-            }
-            0x15 => {
-                self.rotate(bus, Byte::L, Direction::Left, true, true);
-                2
-            }
-            0x16 => {
-                self.rotate(bus, Byte::HL, Direction::Left, true, true);
-                4
-            }
-            0x17 => {
-                self.rotate(bus, Byte::A, Direction::Left, true, true);
-                2
-            }
-
-            0x18 => {
-                self.rotate(bus, Byte::B, Direction::Right, true, true);
-                2
-            }
-            0x19 => {
-                self.rotate(bus, Byte::C, Direction::Right, true, true);
-                2
-            }
-            0x1A => {
-                self.rotate(bus, Byte::D, Direction::Right, true, true);
-                2
-            }
-            0x1B => {
-                self.rotate(bus, Byte::E, Direction::Right, true, true);
-                2
-            }
-            0x1C => {
-                self.rotate(bus, Byte::H, Direction::Right, true, true);
-                2
-            }
-            0x1D => {
-                self.rotate(bus, Byte::L, Direction::Right, true, true);
-                2
-            }
-            0x1E => {
-                self.rotate(bus, Byte::HL, Direction::Right, true, true);
-                4
-            }
-            0x1F => {
-                self.rotate(bus, Byte::A, Direction::Right, true, true);
-                2
-            }
-
-            0x20 => {
-                self.shift(bus, Byte::B, Direction::Left, false);
-                2
-            }
-            0x21 => {
-                self.shift(bus, Byte::C, Direction::Left, false);
-                2
-            }
-            0x22 => {
-                self.shift(bus, Byte::D, Direction::Left, false);
-                2
-            }
-            0x23 => {
-                self.shift(bus, Byte::E, Direction::Left, false);
-                2
-            }
-            0x24 => {
-                self.shift(bus, Byte::H, Direction::Left, false);
-                2
-            }
-            0x25 => {
-                self.shift(bus, Byte::L, Direction::Left, false);
-                2
-            }
-            0x26 => {
-                self.shift(bus, Byte::HL, Direction::Left, false);
-                4
-            }
-            0x27 => {
-                self.shift(bus, Byte::A, Direction::Left, false);
-                2
-            }
-
-            0x28 => {
-                self.shift(bus, Byte::B, Direction::Right, false);
-                2
-            }
-            0x29 => {
-                self.shift(bus, Byte::C, Direction::Right, false);
-                2
-            }
-            0x2A => {
-                self.shift(bus, Byte::D, Direction::Right, false);
-                2
-            }
-            0x2B => {
-                self.shift(bus, Byte::E, Direction::Right, false);
-                2
-            }
-            0x2C => {
-                self.shift(bus, Byte::H, Direction::Right, false);
-                2
-            }
-            0x2D => {
-                self.shift(bus, Byte::L, Direction::Right, false);
-                2
-            }
-            0x2E => {
-                self.shift(bus, Byte::HL, Direction::Right, false);
-                4
-            }
-            0x2F => {
-                self.shift(bus, Byte::A, Direction::Right, false);
-                2
-            }
-
-            0x30 => {
-                self.swap(bus, Byte::B);
-                2
-            }
-            0x31 => {
-                self.swap(bus, Byte::C);
-                2
-            }
-            0x32 => {
-                self.swap(bus, Byte::D);
-                2
-            }
-            0x33 => {
-                self.swap(bus, Byte::E);
-                2
-            }
-            0x34 => {
-                self.swap(bus, Byte::H);
-                2
-            }
-            0x35 => {
-                self.swap(bus, Byte::L);
-                2
-            }
-            0x36 => {
-                self.swap(bus, Byte::HL);
-                4
-            }
-            0x37 => {
-                self.swap(bus, Byte::A);
-                2
-            }
-
-            0x38 => {
-                self.shift(bus, Byte::B, Direction::Right, false);
-                2
-            }
-            0x39 => {
-                self.shift(bus, Byte::C, Direction::Right, false);
-                2
-            }
-            0x3A => {
-                self.shift(bus, Byte::D, Direction::Right, false);
-                2
-            }
-            0x3B => {
-                self.shift(bus, Byte::E, Direction::Right, false);
-                2
-            }
-            0x3C => {
-                self.shift(bus, Byte::H, Direction::Right, false);
-                2
-            }
-            0x3D => {
-                self.shift(bus, Byte::L, Direction::Right, false);
-                2
-            }
-            0x3E => {
-                self.shift(bus, Byte::HL, Direction::Right, false);
-                4
-            }
-            0x3F => {
-                self.shift(bus, Byte::A, Direction::Right, false);
-                2
-            }
-
-            0x40 => {
-                self.test_bit(bus, Byte::B, 0);
-                2
-            }
-            0x41 => {
-                self.test_bit(bus, Byte::C, 0);
-                2
-            }
-            0x42 => {
-                self.test_bit(bus, Byte::D, 0);
-                2
-            }
-            0x43 => {
-                self.test_bit(bus, Byte::E, 0);
-                2
-            }
-            0x44 => {
-                self.test_bit(bus, Byte::H, 0);
-                2
-            }
-            0x45 => {
-                self.test_bit(bus, Byte::L, 0);
-                2
-            }
-            0x46 => {
-                self.test_bit(bus, Byte::HL, 0);
-                3
-            }
-            0x47 => {
-                self.test_bit(bus, Byte::A, 0);
-                2
-            }
-            0x48 => {
-                self.test_bit(bus, Byte::B, 1);
-                2
-            }
-            0x49 => {
-                self.test_bit(bus, Byte::C, 1);
-                2
-            }
-            0x4A => {
-                self.test_bit(bus, Byte::D, 1);
-                2
-            }
-            0x4B => {
-                self.test_bit(bus, Byte::E, 1);
-                2
-            }
-            0x4C => {
-                self.test_bit(bus, Byte::H, 1);
-                2
-            }
-            0x4D => {
-                self.test_bit(bus, Byte::L, 1);
-                2
-            }
-            0x4E => {
-                self.test_bit(bus, Byte::HL, 1);
-                3
-            }
-            0x4F => {
-                self.test_bit(bus, Byte::A, 1);
-                2
-            }
-            0x50 => {
-                self.test_bit(bus, Byte::B, 2);
-                2
-            }
-            0x51 => {
-                self.test_bit(bus, Byte::C, 2);
-                2
-            }
-            0x52 => {
-                self.test_bit(bus, Byte::D, 2);
-                2
-            }
-            0x53 => {
-                self.test_bit(bus, Byte::E, 2);
-                2
-            }
-            0x54 => {
-                self.test_bit(bus, Byte::H, 2);
-                2
-            }
-            0x55 => {
-                self.test_bit(bus, Byte::L, 2);
-                2
-            }
-            0x56 => {
-                self.test_bit(bus, Byte::HL, 2);
-                3
-            }
-            0x57 => {
-                self.test_bit(bus, Byte::A, 2);
-                2
-            }
-            0x58 => {
-                self.test_bit(bus, Byte::B, 3);
-                2
-            }
-            0x59 => {
-                self.test_bit(bus, Byte::C, 3);
-                2
-            }
-            0x5A => {
-                self.test_bit(bus, Byte::D, 3);
-                2
-            }
-            0x5B => {
-                self.test_bit(bus, Byte::E, 3);
-                2
-            }
-            0x5C => {
-                self.test_bit(bus, Byte::H, 3);
-                2
-            }
-            0x5D => {
-                self.test_bit(bus, Byte::L, 3);
-                2
-            }
-            0x5E => {
-                self.test_bit(bus, Byte::HL, 3);
-                3
-            }
-            0x5F => {
-                self.test_bit(bus, Byte::A, 3);
-                2
-            }
-            0x60 => {
-                self.test_bit(bus, Byte::B, 4);
-                2
-            }
-            0x61 => {
-                self.test_bit(bus, Byte::C, 4);
-                2
-            }
-            0x62 => {
-                self.test_bit(bus, Byte::D, 4);
-                2
-            }
-            0x63 => {
-                self.test_bit(bus, Byte::E, 4);
-                2
-            }
-            0x64 => {
-                self.test_bit(bus, Byte::H, 4);
-                2
-            }
-            0x65 => {
-                self.test_bit(bus, Byte::L, 4);
-                2
-            }
-            0x66 => {
-                self.test_bit(bus, Byte::HL, 4);
-                3
-            }
-            0x67 => {
-                self.test_bit(bus, Byte::A, 4);
-                2
-            }
-            0x68 => {
-                self.test_bit(bus, Byte::B, 5);
-                2
-            }
-            0x69 => {
-                self.test_bit(bus, Byte::C, 5);
-                2
-            }
-            0x6A => {
-                self.test_bit(bus, Byte::D, 5);
-                2
-            }
-            0x6B => {
-                self.test_bit(bus, Byte::E, 5);
-                2
-            }
-            0x6C => {
-                self.test_bit(bus, Byte::H, 5);
-                2
-            }
-            0x6D => {
-                self.test_bit(bus, Byte::L, 5);
-                2
-            }
-            0x6E => {
-                self.test_bit(bus, Byte::HL, 5);
-                3
-            }
-            0x6F => {
-                self.test_bit(bus, Byte::A, 5);
-                2
-            }
-            0x70 => {
-                self.test_bit(bus, Byte::B, 6);
-                2
-            }
-            0x71 => {
-                self.test_bit(bus, Byte::C, 6);
-                2
-            }
-            0x72 => {
-                self.test_bit(bus, Byte::D, 6);
-                2
-            }
-            0x73 => {
-                self.test_bit(bus, Byte::E, 6);
-                2
-            }
-            0x74 => {
-                self.test_bit(bus, Byte::H, 6);
-                2
-            }
-            0x75 => {
-                self.test_bit(bus, Byte::L, 6);
-                2
-            }
-            0x76 => {
-                self.test_bit(bus, Byte::HL, 6);
-                3
-            }
-            0x77 => {
-                self.test_bit(bus, Byte::A, 6);
-                2
-            }
-            0x78 => {
-                self.test_bit(bus, Byte::B, 7);
-                2
-            }
-            0x79 => {
-                self.test_bit(bus, Byte::C, 7);
-                2
-            }
-            0x7A => {
-                self.test_bit(bus, Byte::D, 7);
-                2
-            }
-            0x7B => {
-                self.test_bit(bus, Byte::E, 7);
-                2
-            }
-            0x7C => {
-                self.test_bit(bus, Byte::H, 7);
-                2
-            }
-            0x7D => {
-                self.test_bit(bus, Byte::L, 7);
-                2
-            }
-            0x7E => {
-                self.test_bit(bus, Byte::HL, 7);
-                3
-            }
-            0x7F => {
-                self.test_bit(bus, Byte::A, 7);
-                2
-            }
-
-            0x80 => {
-                self.clear_bit(bus, Byte::B, 0);
-                2
-            }
-            0x81 => {
-                self.clear_bit(bus, Byte::C, 0);
-                2
-            }
-            0x82 => {
-                self.clear_bit(bus, Byte::D, 0);
-                2
-            }
-            0x83 => {
-                self.clear_bit(bus, Byte::E, 0);
-                2
-            }
-            0x84 => {
-                self.clear_bit(bus, Byte::H, 0);
-                2
-            }
-            0x85 => {
-                self.clear_bit(bus, Byte::L, 0);
-                2
-            }
-            0x86 => {
-                self.clear_bit(bus, Byte::HL, 0);
-                4
-            }
-            0x87 => {
-                self.clear_bit(bus, Byte::A, 0);
-                2
-            }
-            0x88 => {
-                self.clear_bit(bus, Byte::B, 1);
-                2
-            }
-            0x89 => {
-                self.clear_bit(bus, Byte::C, 1);
-                2
-            }
-            0x8A => {
-                self.clear_bit(bus, Byte::D, 1);
-                2
-            }
-            0x8B => {
-                self.clear_bit(bus, Byte::E, 1);
-                2
-            }
-            0x8C => {
-                self.clear_bit(bus, Byte::H, 1);
-                2
-            }
-            0x8D => {
-                self.clear_bit(bus, Byte::L, 1);
-                2
-            }
-            0x8E => {
-                self.clear_bit(bus, Byte::HL, 1);
-                4
-            }
-            0x8F => {
-                self.clear_bit(bus, Byte::A, 1);
-                2
-            }
-            0x90 => {
-                self.clear_bit(bus, Byte::B, 2);
-                2
-            }
-            0x91 => {
-                self.clear_bit(bus, Byte::C, 2);
-                2
-            }
-            0x92 => {
-                self.clear_bit(bus, Byte::D, 2);
-                2
-            }
-            0x93 => {
-                self.clear_bit(bus, Byte::E, 2);
-                2
-            }
-            0x94 => {
-                self.clear_bit(bus, Byte::H, 2);
-                2
-            }
-            0x95 => {
-                self.clear_bit(bus, Byte::L, 2);
-                2
-            }
-            0x96 => {
-                self.clear_bit(bus, Byte::HL, 2);
-                4
-            }
-            0x97 => {
-                self.clear_bit(bus, Byte::A, 2);
-                2
-            }
-            0x98 => {
-                self.clear_bit(bus, Byte::B, 3);
-                2
-            }
-            0x99 => {
-                self.clear_bit(bus, Byte::C, 3);
-                2
-            }
-            0x9A => {
-                self.clear_bit(bus, Byte::D, 3);
-                2
-            }
-            0x9B => {
-                self.clear_bit(bus, Byte::E, 3);
-                2
-            }
-            0x9C => {
-                self.clear_bit(bus, Byte::H, 3);
-                2
-            }
-            0x9D => {
-                self.clear_bit(bus, Byte::L, 3);
-                2
-            }
-            0x9E => {
-                self.clear_bit(bus, Byte::HL, 3);
-                4
-            }
-            0x9F => {
-                self.clear_bit(bus, Byte::A, 3);
-                2
-            }
-            0xA0 => {
-                self.clear_bit(bus, Byte::B, 4);
-                2
-            }
-            0xA1 => {
-                self.clear_bit(bus, Byte::C, 4);
-                2
-            }
-            0xA2 => {
-                self.clear_bit(bus, Byte::D, 4);
-                2
-            }
-            0xA3 => {
-                self.clear_bit(bus, Byte::E, 4);
-                2
-            }
-            0xA4 => {
-                self.clear_bit(bus, Byte::H, 4);
-                2
-            }
-            0xA5 => {
-                self.clear_bit(bus, Byte::L, 4);
-                2
-            }
-            0xA6 => {
-                self.clear_bit(bus, Byte::HL, 4);
-                4
-            }
-            0xA7 => {
-                self.clear_bit(bus, Byte::A, 4);
-                2
-            }
-            0xA8 => {
-                self.clear_bit(bus, Byte::B, 5);
-                2
-            }
-            0xA9 => {
-                self.clear_bit(bus, Byte::C, 5);
-                2
-            }
-            0xAA => {
-                self.clear_bit(bus, Byte::D, 5);
-                2
-            }
-            0xAB => {
-                self.clear_bit(bus, Byte::E, 5);
-                2
-            }
-            0xAC => {
-                self.clear_bit(bus, Byte::H, 5);
-                2
-            }
-            0xAD => {
-                self.clear_bit(bus, Byte::L, 5);
-                2
-            }
-            0xAE => {
-                self.clear_bit(bus, Byte::HL, 5);
-                4
-            }
-            0xAF => {
-                self.clear_bit(bus, Byte::A, 5);
-                2
-            }
-            0xB0 => {
-                self.clear_bit(bus, Byte::B, 6);
-                2
-            }
-            0xB1 => {
-                self.clear_bit(bus, Byte::C, 6);
-                2
-            }
-            0xB2 => {
-                self.clear_bit(bus, Byte::D, 6);
-                2
-            }
-            0xB3 => {
-                self.clear_bit(bus, Byte::E, 6);
-                2
-            }
-            0xB4 => {
-                self.clear_bit(bus, Byte::H, 6);
-                2
-            }
-            0xB5 => {
-                self.clear_bit(bus, Byte::L, 6);
-                2
-            }
-            0xB6 => {
-                self.clear_bit(bus, Byte::HL, 6);
-                4
-            }
-            0xB7 => {
-                self.clear_bit(bus, Byte::A, 6);
-                2
-            }
-            0xB8 => {
-                self.clear_bit(bus, Byte::B, 7);
-                2
-            }
-            0xB9 => {
-                self.clear_bit(bus, Byte::C, 7);
-                2
-            }
-            0xBA => {
-                self.clear_bit(bus, Byte::D, 7);
-                2
-            }
-            0xBB => {
-                self.clear_bit(bus, Byte::E, 7);
-                2
-            }
-            0xBC => {
-                self.clear_bit(bus, Byte::H, 7);
-                2
-            }
-            0xBD => {
-                self.clear_bit(bus, Byte::L, 7);
-                2
-            }
-            0xBE => {
-                self.clear_bit(bus, Byte::HL, 7);
-                4
-            }
-            0xBF => {
-                self.clear_bit(bus, Byte::A, 7);
-                2
-            }
-
-            0xC0 => {
-                self.set_bit(bus, Byte::B, 0);
-                2
-            }
-            0xC1 => {
-                self.set_bit(bus, Byte::C, 0);
-                2
-            }
-            0xC2 => {
-                self.set_bit(bus, Byte::D, 0);
-                2
-            }
-            0xC3 => {
-                self.set_bit(bus, Byte::E, 0);
-                2
-            }
-            0xC4 => {
-                self.set_bit(bus, Byte::H, 0);
-                2
-            }
-            0xC5 => {
-                self.set_bit(bus, Byte::L, 0);
-                2
-            }
-            0xC6 => {
-                self.set_bit(bus, Byte::HL, 0);
-                4
-            }
-            0xC7 => {
-                self.set_bit(bus, Byte::A, 0);
-                2
-            }
-            0xC8 => {
-                self.set_bit(bus, Byte::B, 1);
-                2
-            }
-            0xC9 => {
-                self.set_bit(bus, Byte::C, 1);
-                2
-            }
-            0xCA => {
-                self.set_bit(bus, Byte::D, 1);
-                2
-            }
-            0xCB => {
-                self.set_bit(bus, Byte::E, 1);
-                2
-            }
-            0xCC => {
-                self.set_bit(bus, Byte::H, 1);
-                2
-            }
-            0xCD => {
-                self.set_bit(bus, Byte::L, 1);
-                2
-            }
-            0xCE => {
-                self.set_bit(bus, Byte::HL, 1);
-                4
-            }
-            0xCF => {
-                self.set_bit(bus, Byte::A, 1);
-                2
-            }
-            0xD0 => {
-                self.set_bit(bus, Byte::B, 2);
-                2
-            }
-            0xD1 => {
-                self.set_bit(bus, Byte::C, 2);
-                2
-            }
-            0xD2 => {
-                self.set_bit(bus, Byte::D, 2);
-                2
-            }
-            0xD3 => {
-                self.set_bit(bus, Byte::E, 2);
-                2
-            }
-            0xD4 => {
-                self.set_bit(bus, Byte::H, 2);
-                2
-            }
-            0xD5 => {
-                self.set_bit(bus, Byte::L, 2);
-                2
-            }
-            0xD6 => {
-                self.set_bit(bus, Byte::HL, 2);
-                4
-            }
-            0xD7 => {
-                self.set_bit(bus, Byte::A, 2);
-                2
-            }
-            0xD8 => {
-                self.set_bit(bus, Byte::B, 3);
-                2
-            }
-            0xD9 => {
-                self.set_bit(bus, Byte::C, 3);
-                2
-            }
-            0xDA => {
-                self.set_bit(bus, Byte::D, 3);
-                2
-            }
-            0xDB => {
-                self.set_bit(bus, Byte::E, 3);
-                2
-            }
-            0xDC => {
-                self.set_bit(bus, Byte::H, 3);
-                2
-            }
-            0xDD => {
-                self.set_bit(bus, Byte::L, 3);
-                2
-            }
-            0xDE => {
-                self.set_bit(bus, Byte::HL, 3);
-                4
-            }
-            0xDF => {
-                self.set_bit(bus, Byte::A, 3);
-                2
-            }
-            0xE0 => {
-                self.set_bit(bus, Byte::B, 4);
-                2
-            }
-            0xE1 => {
-                self.set_bit(bus, Byte::C, 4);
-                2
-            }
-            0xE2 => {
-                self.set_bit(bus, Byte::D, 4);
-                2
-            }
-            0xE3 => {
-                self.set_bit(bus, Byte::E, 4);
-                2
-            }
-            0xE4 => {
-                self.set_bit(bus, Byte::H, 4);
-                2
-            }
-            0xE5 => {
-                self.set_bit(bus, Byte::L, 4);
-                2
-            }
-            0xE6 => {
-                self.set_bit(bus, Byte::HL, 4);
-                4
-            }
-            0xE7 => {
-                self.set_bit(bus, Byte::A, 4);
-                2
-            }
-            0xE8 => {
-                self.set_bit(bus, Byte::B, 5);
-                2
-            }
-            0xE9 => {
-                self.set_bit(bus, Byte::C, 5);
-                2
-            }
-            0xEA => {
-                self.set_bit(bus, Byte::D, 5);
-                2
-            }
-            0xEB => {
-                self.set_bit(bus, Byte::E, 5);
-                2
-            }
-            0xEC => {
-                self.set_bit(bus, Byte::H, 5);
-                2
-            }
-            0xED => {
-                self.set_bit(bus, Byte::L, 5);
-                2
-            }
-            0xEE => {
-                self.set_bit(bus, Byte::HL, 5);
-                4
-            }
-            0xEF => {
-                self.set_bit(bus, Byte::A, 5);
-                2
-            }
-            0xF0 => {
-                self.set_bit(bus, Byte::B, 6);
-                2
-            }
-            0xF1 => {
-                self.set_bit(bus, Byte::C, 6);
-                2
-            }
-            0xF2 => {
-                self.set_bit(bus, Byte::D, 6);
-                2
-            }
-            0xF3 => {
-                self.set_bit(bus, Byte::E, 6);
-                2
-            }
-            0xF4 => {
-                self.set_bit(bus, Byte::H, 6);
-                2
-            }
-            0xF5 => {
-                self.set_bit(bus, Byte::L, 6);
-                2
-            }
-            0xF6 => {
-                self.set_bit(bus, Byte::HL, 6);
-                4
-            }
-            0xF7 => {
-                self.set_bit(bus, Byte::A, 6);
-                2
-            }
-            0xF8 => {
-                self.set_bit(bus, Byte::B, 7);
-                2
-            }
-            0xF9 => {
-                self.set_bit(bus, Byte::C, 7);
-                2
-            }
-            0xFA => {
-                self.set_bit(bus, Byte::D, 7);
-                2
-            }
-            0xFB => {
-                self.set_bit(bus, Byte::E, 7);
-                2
-            }
-            0xFC => {
-                self.set_bit(bus, Byte::H, 7);
-                2
-            }
-            0xFD => {
-                self.set_bit(bus, Byte::L, 7);
-                2
-            }
-            0xFE => {
-                self.set_bit(bus, Byte::HL, 7);
-                4
-            }
-            0xFF => {
-                self.set_bit(bus, Byte::A, 7);
-                2
-            }
-
-            _ => panic!("Illegal Instruction ({:02X})", opcode),
-        }
-    }
-}
-
-// TODO: Move test implementation to its own file.
-#[cfg(test)]
-mod test {
-    use std::cell::RefCell;
-    use std::rc::Rc;
-    use crate::{Bus, DummyRAM, RAM, Word, WordDescriptor};
-    use crate::r#mod::Register;
-    use super::CPU;
-
-    type RcRC<T> = Rc<RefCell<T>>;
-
-    fn init_cpu() -> (Bus, CPU, RcRC<DummyRAM>) {
-        let mut bus = Bus::new();
-        let mut cpu = CPU::new();
-        let mut ram = Rc::new(RefCell::new(DummyRAM::new(0x00, 0x20, false)));
-        cpu.attach_to_bus(&mut bus);
-        bus.attach(ram.clone());
-        (bus, cpu, ram)
-    }
-
-    #[test]
-    fn test_ld_word_direct() {
-        let (mut bus, mut cpu, ram) = init_cpu();
-
-        let instrs: Vec<(u8, WordDescriptor)> = vec![
-            (0x01, WordDescriptor::BC),
-            (0x11, WordDescriptor::DE),
-            (0x21, WordDescriptor::HL),
-            (0x31, WordDescriptor::SP),
-        ];
-
-        for (opcode, desc) in instrs {
-            for value in 0x0000..0xFFFF {
-                {
-                    let mut r = ram.as_ref().borrow_mut();
-                    r.data[0] = opcode;
-                    r.data[1] = value as u8;
-                    r.data[2] = (value >> 8) as u8;
-                }
-
-                cpu.pc = 0;
-                cpu.step(&mut bus);
-
-                let result = cpu.read_word(&bus, desc);
-                assert_eq!(result, value, "Test {desc:?}: Expected {value:04X}, got {result:04X}.");
-            }
-
-        }
-    }
-
-    #[test]
-    fn test_st_indirect_byte() {
-        let (mut bus, mut cpu, ram) = init_cpu();
-
-        let instrs: Vec<(u8, WordDescriptor)> = vec![
-            (0x02, WordDescriptor::BC),
-            (0x12, WordDescriptor::DE),
-        ];
-
-        for (opcode, desc) in instrs {
-            {
-                let mut r = ram.as_ref().borrow_mut();
-                r.data[0] = opcode;
-            }
-
-            for value in 0u8..0xFF {
-                let address = 0x1000 + value as u16;
-
-                cpu.pc = 0;
-                cpu.write_word(&mut bus, desc, address);
-                unsafe { *cpu.af.left() = value };
-                cpu.step(&mut bus);
-
-                let result = ram.as_ref().borrow().data[address as usize];
-
-                assert_eq!(result, value, "Test {desc:?}: Expected {value:02X} at {address:04X}, got {result:02X}.");
-            }
-
-        }
-    }
-}
+use log::{trace, warn};
+use crate::{Address, Bus, ByteDescriptor, CPU, StepOutcome, Word, WordDescriptor};
+use crate::cpu::{Direction, flag::Flag};
+use crate::cpu::opcode::{CB_INSTRUCTIONS, Condition, INSTRUCTIONS, Operation};
+
+const JOYP_ADDRESS: Address = 0xFF00;
+const DIV_ADDRESS: Address = 0xFF04;
+
+impl CPU {
+    #[inline(always)]
+    fn ld(&mut self, bus: &mut Bus, dest: ByteDescriptor, src: ByteDescriptor) {
+        let value = self.read_byte(bus, src);
+        self.write_byte(bus, dest, value);
+    }
+
+    #[inline(always)]
+    fn ld_16(&mut self, bus: &mut Bus, dest: WordDescriptor, src: WordDescriptor) {
+        let value = self.read_word(bus, src);
+
+        // LD SP, HL copies the register during an internal cycle.
+        if (dest, src) == (WordDescriptor::SP, WordDescriptor::HL) {
+            self.tick(bus);
+        }
+
+        self.write_word(bus, dest, value);
+    }
+
+    fn test_condition(&mut self, condition: Option<Condition>) -> bool {
+        match condition {
+            Some(condition) => {
+                let (flag, value) = condition.flag();
+                self.test_flag(flag) == value
+            }
+            None => true,
+        }
+    }
+
+    #[inline(always)]
+    fn jp(&mut self, bus: &mut Bus, condition: Option<Condition>) -> bool {
+        let address = self.read_word(bus, WordDescriptor::Immediate);
+        if self.test_condition(condition) {
+            trace!("Jumping to {address:04X}");
+            self.tick(bus);
+            self.pc = address;
+            return true;
+        }
+        return false;
+    }
+
+    #[inline(always)]
+    fn jr(&mut self, bus: &mut Bus, condition: Option<Condition>) -> bool {
+        let offset = self.read_byte(bus, ByteDescriptor::Immediate) as i8;
+        if self.test_condition(condition) {
+            self.tick(bus);
+            self.pc = self.pc.wrapping_add(offset as i16 as u16);
+            return true;
+        }
+        return false;
+    }
+
+    fn call(&mut self, bus: &mut Bus, condition: Option<Condition>) -> bool {
+        let target = self.read_word(bus, WordDescriptor::Immediate);
+        if self.test_condition(condition) {
+            trace!("Call to {:04X}", self.pc);
+            self.push(bus, WordDescriptor::PC);
+            self.pc = target;
+            return true;
+        }
+        return false;
+    }
+
+    #[inline(always)]
+    fn rst(&mut self, bus: &mut Bus, address: Address) {
+        self.push(bus, WordDescriptor::PC);
+        self.pc = address;
+    }
+
+    fn ret(&mut self, bus: &mut Bus, condition: Option<Condition>) -> bool {
+        // A conditional return checks its condition during an internal cycle.
+        if condition.is_some() {
+            self.tick(bus);
+        }
+
+        if self.test_condition(condition) {
+            self.pop(bus, WordDescriptor::PC);
+            self.tick(bus);
+            trace!("Return to {:04X}", self.pc);
+            return true;
+        }
+        return false;
+    }
+
+    /// Unlike EI, RETI enables interrupts immediately.
+    #[inline(always)]
+    fn reti(&mut self, bus: &mut Bus) {
+        self.interrupt_registers.as_ref().borrow_mut().master_enable = true;
+        self.pop(bus, WordDescriptor::PC);
+        self.tick(bus);
+        trace!("Return to {:04X}, enabling interrupts", self.pc);
+    }
+
+    fn halt(&mut self) {
+        let interrupt = *self.interrupt_registers.borrow();
+
+        if !interrupt.master_enable && interrupt.pending() != 0 {
+            trace!("HALT bug triggered.");
+            self.halt_bug = true;
+        }
+        else {
+            self.halted = true;
+        }
+    }
+
+    fn stop(&mut self, bus: &mut Bus) {
+        // STOP is two bytes long; the second byte is skipped without being fetched.
+        self.pc = self.pc.wrapping_add(1);
+
+        // On CGB, a prepared speed switch is performed instead of entering STOP mode.
+        if self.speed_switch.as_ref().borrow_mut().switch() {
+            trace!("Speed switch.");
+
+            if let Some(clock) = &self.clock {
+                let mut clock = clock.as_ref().borrow_mut();
+                clock.sync_all(bus);
+                clock.set_double_speed(self.speed_switch.borrow().double_speed);
+                clock.reschedule();
+            }
+            return;
+        }
+
+        trace!("Entering STOP.");
+        self.stopped = true;
+
+        // DIV is reset when entering STOP mode.
+        self.sync(bus, DIV_ADDRESS);
+        bus.write_byte(DIV_ADDRESS, 0);
+        self.sync(bus, DIV_ADDRESS);
+    }
+
+    #[inline(always)]
+    fn push(&mut self, bus: &mut Bus, src: WordDescriptor) {
+        let value = self.read_word(bus, src);
+
+        // SP is decremented during an internal cycle before the high byte is written.
+        self.tick(bus);
+
+        self.sp = self.sp.wrapping_sub(1);
+        self.bus_write(bus, self.sp, (value >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.bus_write(bus, self.sp, value as u8);
+
+        trace!("Stack push {:04X} to {:04X}", value, self.sp);
+    }
+
+    #[inline(always)]
+    fn pop(&mut self, bus: &mut Bus, dest: WordDescriptor) {
+        let lo = self.bus_read(bus, self.sp) as Word;
+        let hi = self.bus_read(bus, self.sp.wrapping_add(1)) as Word;
+        let mut value = (hi << 8) | lo;
+        trace!("Stack pop {:04X} from {:04X}", value, self.sp);
+
+        if dest == WordDescriptor::AF {
+            value &= 0xFFF0;
+        }
+
+        self.write_word(bus, dest, value);
+        self.sp = self.sp.wrapping_add(2);
+    }
+
+    fn add(&mut self, bus: &mut Bus, register: ByteDescriptor, with_carry: bool) {
+        let a_value = *self.af.left();
+        let value = self.read_byte(bus, register);
+        let carry = if with_carry && self.test_flag(Flag::Carry) {
+            1
+        } else {
+            0
+        };
+        let (result, carry) = a_value.overflowing_add(value + carry);
+
+        self.clear_all_flags();
+        self.set_flag_if(Flag::HalfCarry, (a_value ^ value ^ result) & 0x10 != 0);
+        self.set_flag_if(Flag::Carry, carry);
+        self.set_flag_if(Flag::Zero, result == 0);
+
+        *self.af.left() = result;
+    }
+
+    fn add_16(&mut self, bus: &mut Bus, register: WordDescriptor) {
+        let hl_value = self.hl.value();
+        let value = self.read_word(bus, register);
+        let (result, carry) = hl_value.overflowing_add(value);
+
+        // The 16-bit adder takes an extra cycle.
+        self.tick(bus);
+
+        self.clear_flag(Flag::Subtract);
+        self.set_flag_if(Flag::HalfCarry, (hl_value ^ value ^ result) & 0x1000 != 0);
+        self.set_flag_if(Flag::Carry, carry);
+        *self.hl.word() = result;
+    }
+
+    fn relative_sp(&mut self, bus: &mut Bus) -> Word {
+        let sp_value = self.sp;
+        let value = self.read_byte(bus, ByteDescriptor::Immediate) as i8 as i16 as u16;
+        let result = sp_value.wrapping_add(value);
+
+        // The flags come from adding the offset to the low byte, whatever its sign.
+        self.clear_all_flags();
+        self.set_flag_if(Flag::HalfCarry, (sp_value ^ value ^ result) & 0x10 != 0);
+        self.set_flag_if(Flag::Carry, (sp_value ^ value ^ result) & 0x100 != 0);
+        result
+    }
+
+    fn add_sp(&mut self, bus: &mut Bus) {
+        let value = self.relative_sp(bus);
+
+        // Each byte of SP is written back during its own internal cycle.
+        self.tick(bus);
+        self.tick(bus);
+        self.sp = value;
+    }
+
+    fn ld_sp(&mut self, bus: &mut Bus) {
+        let address = self.relative_sp(bus);
+        self.tick(bus);
+        self.write_word(bus, WordDescriptor::HL, address);
+    }
+
+    fn sub(&mut self, bus: &mut Bus, register: ByteDescriptor, with_carry: bool) {
+        let value = self.read_byte(bus, register);
+        let a_value = *self.af.left();
+        let carry = if with_carry && self.test_flag(Flag::Carry) {
+            1
+        } else {
+            0
+        };
+        let (result, carry) = a_value.overflowing_sub(value + carry);
+
+        self.clear_all_flags();
+        self.set_flag(Flag::Subtract);
+        self.set_flag_if(Flag::HalfCarry, result & 0x0F >= a_value & 0x0F);
+        self.set_flag_if(Flag::Carry, carry);
+        self.set_flag_if(Flag::Zero, result == 0);
+        *self.af.left() = result;
+    }
+
+    fn cp(&mut self, bus: &mut Bus, register: ByteDescriptor) {
+        let value = self.read_byte(bus, register);
+        let a_value = *self.af.left();
+        let (result, carry) = a_value.overflowing_sub(value);
+
+        self.clear_all_flags();
+        self.set_flag(Flag::Subtract);
+        self.set_flag_if(Flag::HalfCarry, (a_value ^ value ^ result) & 0x10 != 0);
+        self.set_flag_if(Flag::Carry, carry);
+        self.set_flag_if(Flag::Zero, result == 0);
+    }
+
+    #[inline(always)]
+    fn cpl(&mut self) {
+        *self.af.left() = !*self.af.left();
+        self.set_flag(Flag::Subtract);
+        self.set_flag(Flag::HalfCarry);
+    }
+
+    fn ccf(&mut self) {
+        if self.test_flag(Flag::Carry) {
+            self.clear_flag(Flag::Carry);
+        } else {
+            self.set_flag(Flag::Carry);
+        }
+        self.clear_flag(Flag::HalfCarry);
+        self.clear_flag(Flag::Subtract);
+    }
+
+    fn scf(&mut self) {
+        self.set_flag(Flag::Carry);
+        self.clear_flag(Flag::HalfCarry);
+        self.clear_flag(Flag::Subtract);
+    }
+
+    fn inc(&mut self, bus: &mut Bus, register: ByteDescriptor) {
+        let value = self.read_byte(bus, register);
+        let (result, carry) = value.overflowing_add(1);
+
+        self.clear_flag(Flag::Subtract);
+        self.set_flag_if(Flag::HalfCarry, value & 0x0F == 0x0F);
+        self.set_flag_if(Flag::Zero, carry);
+        self.write_byte(bus, register, result);
+    }
+
+    fn inc_16(&mut self, bus: &mut Bus, register: WordDescriptor) {
+        let (value, _) = self.read_word(bus, register).overflowing_add(1);
+        self.tick(bus);
+        self.write_word(bus, register, value);
+    }
+
+    fn dec(&mut self, bus: &mut Bus, register: ByteDescriptor) {
+        let value = self.read_byte(bus, register);
+        let (result, _) = value.overflowing_sub(1);
+
+        self.set_flag(Flag::Subtract);
+        self.set_flag_if(Flag::HalfCarry, (value ^ result) & 0x10 != 0);
+        self.set_flag_if(Flag::Zero, result == 0);
+
+        self.write_byte(bus, register, result);
+    }
+
+    fn dec_16(&mut self, bus: &mut Bus, register: WordDescriptor) {
+        let (value, _) = self.read_word(bus, register).overflowing_sub(1);
+        self.tick(bus);
+        self.write_word(bus, register, value);
+    }
+
+    fn and(&mut self, bus: &mut Bus, register: ByteDescriptor) {
+        let value = self.read_byte(bus, register);
+        let result = *self.af.left() & value;
+
+        self.clear_all_flags();
+        self.set_flag(Flag::HalfCarry);
+        self.set_flag_if(Flag::Zero, result == 0);
+        *self.af.left() = result;
+    }
+
+    fn or(&mut self, bus: &mut Bus, register: ByteDescriptor) {
+        let value = self.read_byte(bus, register);
+        let result = *self.af.left() | value;
+
+        self.clear_all_flags();
+        self.set_flag_if(Flag::Zero, result == 0);
+        *self.af.left() = result;
+    }
+
+    fn xor(&mut self, bus: &mut Bus, register: ByteDescriptor) {
+        let value = self.read_byte(bus, register);
+        let result = *self.af.left() ^ value;
+
+        self.clear_all_flags();
+        self.set_flag_if(Flag::Zero, result == 0);
+        *self.af.left() = result;
+    }
+
+    fn rotate(
+        &mut self,
+        bus: &mut Bus,
+        register: ByteDescriptor,
+        direction: Direction,
+        with_carry: bool,
+        with_zero: bool,
+    ) {
+        let value = self.read_byte(bus, register);
+
+        let (mut result, _) = match direction {
+            Direction::Left => value.overflowing_shl(1),
+            Direction::Right => value.overflowing_shr(1),
+        };
+
+        let carry = 0 != match direction {
+            Direction::Left => value & 0x80,
+            Direction::Right => value & 0x01,
+        };
+
+        match direction {
+            Direction::Right => assert_eq!(result & 0x80, 0, "Assert!"),
+            _ => {},
+        }
+
+        // RLC/RRC rotate the outgoing bit back in, RL/RR rotate through the carry flag.
+        if (with_carry && self.test_flag(Flag::Carry)) || (!with_carry && carry) {
+            match direction {
+                Direction::Left => result |= 1,
+                Direction::Right => result |= 0x80,
+            }
+        }
+
+        self.clear_all_flags();
+        self.set_flag_if(Flag::Carry, carry);
+        self.set_flag_if(Flag::Zero, with_zero && result == 0);
+        self.write_byte(bus, register, result);
+    }
+
+    fn shift(
+        &mut self,
+        bus: &mut Bus,
+        register: ByteDescriptor,
+        direction: Direction,
+        arithmetic_shift: bool,
+    ) {
+        let value = self.read_byte(bus, register);
+        let (result, carry) = match direction {
+            Direction::Left => value.overflowing_shl(1),
+            Direction::Right => {
+                // SRA keeps bit 7, SRL clears it.
+                let msb = if arithmetic_shift { value & 0x80 } else { 0 };
+                let (result, _) = value.overflowing_shr(1);
+                let carry = value & 1 != 0;
+                (msb | result, carry)
+            }
+        };
+
+        self.clear_all_flags();
+        self.set_flag_if(Flag::Zero, result == 0);
+        self.set_flag_if(Flag::Carry, carry);
+        self.write_byte(bus, register, result);
+    }
+
+    fn swap(&mut self, bus: &mut Bus, register: ByteDescriptor) {
+        let mut tmp_value = self.read_byte(bus, register);
+        tmp_value = ((tmp_value & 0x0F) << 4) | (tmp_value >> 4);
+        self.clear_all_flags();
+        self.set_flag_if(Flag::Zero, tmp_value == 0);
+        self.write_byte(bus, register, tmp_value);
+    }
+
+    #[inline(always)]
+    fn test_bit(&mut self, bus: &mut Bus, register: ByteDescriptor, bit: u8) {
+        let value = self.read_byte(bus, register) & (1u8 << bit);
+        self.clear_flag(Flag::Subtract);
+        self.set_flag(Flag::HalfCarry);
+        self.set_flag_if(Flag::Zero, value == 0);
+    }
+
+    #[inline(always)]
+    fn set_bit(&mut self, bus: &mut Bus, register: ByteDescriptor, bit: u8) {
+        let value = self.read_byte(bus, register) | (1u8 << bit);
+        self.write_byte(bus, register, value);
+    }
+
+    #[inline(always)]
+    fn clear_bit(&mut self, bus: &mut Bus, register: ByteDescriptor, bit: u8) {
+        let value = self.read_byte(bus, register) & !(1u8 << bit);
+        self.write_byte(bus, register, value);
+    }
+
+    fn daa(&mut self) {
+        let mut value = *self.af.left();
+
+        if self.test_flag(Flag::Subtract) {
+            if self.test_flag(Flag::Carry) {
+                value = value.overflowing_sub(0x60).0;
+            }
+            if self.test_flag(Flag::HalfCarry) {
+                value = value.overflowing_sub(0x06).0;
+            }
+        } else {
+            if self.test_flag(Flag::Carry) || value > 0x99 {
+                value = value.overflowing_add(0x60).0;
+                self.set_flag(Flag::Carry);
+            }
+            if self.test_flag(Flag::HalfCarry) || value & 0x0F > 0x09 {
+                value = value.overflowing_add(0x06).0;
+            }
+        }
+
+        self.set_flag_if(Flag::Zero, value == 0);
+        self.clear_flag(Flag::HalfCarry);
+        *self.af.left() = value;
+    }
+}
+
+impl CPU {
+    /// Executes one instruction (servicing a pending interrupt first).
+    /// The rest of the system is clocked as the instruction performs its memory accesses;
+    /// the returned cycle count is for information only.
+    pub fn step(&mut self, bus: &mut Bus) -> StepOutcome {
+        if let Some((opcode, address)) = self.locked {
+            return StepOutcome::Locked { opcode, address };
+        }
+
+        trace!("");
+        trace!("Begin step");
+        let mut cycles = 0;
+        self.ticks = 0;
+
+        if self.stopped {
+            // Any selected joypad line going low ends STOP mode. Nothing is clocked until then.
+            if bus.read_byte(JOYP_ADDRESS) & 0x0F == 0x0F {
+                return StepOutcome::Cycles(0);
+            }
+
+            trace!("Leaving STOP.");
+            self.stopped = false;
+        }
+
+        if self.halted {
+            // The CPU idles until an interrupt is both requested and enabled. It then resumes,
+            // servicing the interrupt only if IME is set.
+            if self.interrupt_registers.borrow().pending() == 0 {
+                trace!("Halted.");
+                self.tick(bus);
+                return StepOutcome::Cycles(1 << 2);
+            }
+
+            trace!("Leaving HALT.");
+            self.halted = false;
+        }
+
+        // EI takes effect after the instruction following it, unless that instruction is DI.
+        let enable_interrupts = self.ei_pending;
+
+        cycles += self.handle_interrupts(bus);
+        cycles += self._step(bus);
+
+        if let Some((opcode, address)) = self.locked {
+            return StepOutcome::Locked { opcode, address };
+        }
+
+        // Internal cycles are issued by the instructions themselves, where they take place.
+        debug_assert_eq!(self.ticks, cycles, "Instruction took {} M-cycles instead of {cycles}.", self.ticks);
+
+        if enable_interrupts && self.ei_pending {
+            trace!("Interrupts enabled.");
+            self.ei_pending = false;
+            self.interrupt_registers.as_ref().borrow_mut().master_enable = true;
+        }
+
+        trace!("End step after {} cycles.", self.ticks);
+        trace!("");
+        StepOutcome::Cycles(self.ticks << 2)
+    }
+
+    fn handle_interrupts(&mut self, bus: &mut Bus) -> u8 {
+        let interrupt = *self.interrupt_registers.borrow();
+
+        // Ignore if interrupts are disabled
+        if !interrupt.master_enable {
+            trace!("Interrupts are disabled.");
+            return 0;
+        }
+
+        // Return if there are no interrupts to handle
+        if interrupt.pending() == 0 {
+            trace!("No interrupts to handle (IF {:02X} IE {:02X}).", interrupt.flags, interrupt.enable);
+            return 0;
+        }
+
+        self.dispatch_interrupt(bus)
+    }
+
+    /// Interrupt dispatch takes 5 M-cycles: two idle cycles, two cycles pushing PC and one
+    /// cycle jumping to the vector.
+    fn dispatch_interrupt(&mut self, bus: &mut Bus) -> u8 {
+        self.interrupt_registers.as_ref().borrow_mut().master_enable = false;
+
+        // An interrupt taken straight out of the HALT bug returns to the HALT instruction.
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
+        }
+
+        self.tick(bus);
+        self.tick(bus);
+
+        self.sp = self.sp.wrapping_sub(1);
+        self.bus_write(bus, self.sp, (self.pc >> 8) as u8);
+
+        // The interrupt to service is only chosen after the high byte of PC has been pushed.
+        // If that push overwrote IE (SP = 0x0000) and nothing is pending any more,
+        // the dispatch is cancelled and execution continues at 0x0000.
+        let interrupts = self.interrupt_registers.borrow().pending();
+
+        self.sp = self.sp.wrapping_sub(1);
+        self.bus_write(bus, self.sp, self.pc as u8);
+
+        self.tick(bus);
+
+        if interrupts == 0 {
+            trace!("Interrupt dispatch cancelled.");
+            self.pc = 0x0000;
+            return 5;
+        }
+
+        // Priority is low to high bit
+        let bit = interrupts.trailing_zeros() as u16;
+        trace!("Handling interrupt {:08b}.", 1 << bit);
+
+        // Acknowledge interrupt
+        self.interrupt_registers.as_ref().borrow_mut().flags ^= 1 << bit;
+        self.pc = 0x40 + (bit << 3);
+
+        5
+    }
+
+    pub fn print_trace(&self, bus: &Bus) {
+        print!("A:{:02X} F:{:02X} \
+                B:{:02X} C:{:02X} \
+                D:{:02X} E:{:02X} \
+                H:{:02X} L:{:02X} \
+                SP:{:04X} PC:{:04X} PCMEM:",
+               self.af.value() >> 8, self.af.value() & 0xFF,
+               self.bc.value() >> 8, self.bc.value() & 0xFF,
+               self.de.value() >> 8, self.de.value() & 0xFF,
+               self.hl.value() >> 8, self.hl.value() & 0xFF,
+               self.sp, self.pc);
+
+        for i in 0..4 {
+            print!("{:02X}", bus.read_byte(self.pc + i));
+
+            if i < 3 {
+                print!(",");
+            }
+        }
+
+        println!();
+    }
+
+    fn _step(&mut self, bus: &mut Bus) -> u8 {
+        let address = self.pc;
+        let opcode = self.read_byte(bus, ByteDescriptor::Immediate);
+
+        if self.halt_bug {
+            // The byte after HALT is read twice.
+            self.halt_bug = false;
+            self.pc -= 1;
+        }
+
+        let mut instruction = &INSTRUCTIONS[opcode as usize];
+
+        if instruction.operation == Operation::Prefix {
+            let opcode = self.read_byte(bus, ByteDescriptor::Immediate);
+            instruction = &CB_INSTRUCTIONS[opcode as usize];
+        }
+
+        trace!("{:04X}  {}", address, instruction.disassemble(bus, address));
+
+        if instruction.operation == Operation::Illegal {
+            warn!("Illegal instruction {:02X} at {:04X}, CPU locked.", opcode, address);
+            self.locked = Some((opcode, address));
+            return 1;
+        }
+
+        if self.execute(bus, instruction.operation) {
+            instruction.cycles
+        } else {
+            instruction.cycles_not_taken
+        }
+    }
+
+    /// Returns false if the condition of a jump, call or return did not hold.
+    fn execute(&mut self, bus: &mut Bus, operation: Operation) -> bool {
+        use Operation::*;
+        use Direction::{Left, Right};
+
+        match operation {
+            Nop => {}
+            Stop => self.stop(bus),
+            Halt => self.halt(),
+            Di => {
+                self.ei_pending = false;
+                self.interrupt_registers.as_ref().borrow_mut().master_enable = false;
+            }
+            Ei => self.ei_pending = true,
+            Daa => self.daa(),
+            Cpl => self.cpl(),
+            Scf => self.scf(),
+            Ccf => self.ccf(),
+
+            Rlca => self.rotate(bus, ByteDescriptor::A, Left, false, false),
+            Rrca => self.rotate(bus, ByteDescriptor::A, Right, false, false),
+            Rla => self.rotate(bus, ByteDescriptor::A, Left, true, false),
+            Rra => self.rotate(bus, ByteDescriptor::A, Right, true, false),
+
+            Ld(dest, src) => self.ld(bus, dest, src),
+            Ld16(dest, src) => self.ld_16(bus, dest, src),
+            LdHlSp => self.ld_sp(bus),
+
+            Inc(register) => self.inc(bus, register),
+            Dec(register) => self.dec(bus, register),
+            Inc16(register) => self.inc_16(bus, register),
+            Dec16(register) => self.dec_16(bus, register),
+
+            Add(register) => self.add(bus, register, false),
+            Adc(register) => self.add(bus, register, true),
+            Sub(register) => self.sub(bus, register, false),
+            Sbc(register) => self.sub(bus, register, true),
+            And(register) => self.and(bus, register),
+            Xor(register) => self.xor(bus, register),
+            Or(register) => self.or(bus, register),
+            Cp(register) => self.cp(bus, register),
+            Add16(register) => self.add_16(bus, register),
+            AddSp => self.add_sp(bus),
+
+            Jp(condition) => return self.jp(bus, condition),
+            JpHl => self.pc = self.hl.value(),
+            Jr(condition) => return self.jr(bus, condition),
+            Call(condition) => return self.call(bus, condition),
+            Ret(condition) => return self.ret(bus, condition),
+            Reti => self.reti(bus),
+            Rst(address) => self.rst(bus, address),
+
+            Push(register) => self.push(bus, register),
+            Pop(register) => self.pop(bus, register),
+
+            Prefix | Illegal => unreachable!("{:?} is handled by the decoder", operation),
+
+            Rlc(register) => self.rotate(bus, register, Left, false, true),
+            Rrc(register) => self.rotate(bus, register, Right, false, true),
+            Rl(register) => self.rotate(bus, register, Left, true, true),
+            Rr(register) => self.rotate(bus, register, Right, true, true),
+            Sla(register) => self.shift(bus, register, Left, false),
+            Sra(register) => self.shift(bus, register, Right, true),
+            Srl(register) => self.shift(bus, register, Right, false),
+            Swap(register) => self.swap(bus, register),
+            Bit(bit, register) => self.test_bit(bus, register, bit),
+            Res(bit, register) => self.clear_bit(bus, register, bit),
+            Set(bit, register) => self.set_bit(bus, register, bit),
+        }
+
+        true
+    }
+}
+
+// TODO: Move test implementation to its own file.
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::{Bus, DummyRAM, StepOutcome, Word, WordDescriptor};
+    use crate::cpu::flag::Flag;
+    use crate::cpu::opcode::{CB_INSTRUCTIONS, INSTRUCTIONS, Instruction, Operation};
+    use super::CPU;
+
+    type RcRC<T> = Rc<RefCell<T>>;
+
+    fn init_cpu() -> (Bus, CPU, RcRC<DummyRAM>) {
+        let mut bus = Bus::new();
+        let cpu = CPU::new();
+        let ram = Rc::new(RefCell::new(DummyRAM::new(0x00, 0x20, false)));
+        cpu.attach_to_bus(&mut bus);
+        bus.attach(ram.clone());
+        (bus, cpu, ram)
+    }
+
+    #[test]
+    fn test_ld_word_direct() {
+        let (mut bus, mut cpu, ram) = init_cpu();
+
+        let instrs: Vec<(u8, WordDescriptor)> = vec![
+            (0x01, WordDescriptor::BC),
+            (0x11, WordDescriptor::DE),
+            (0x21, WordDescriptor::HL),
+            (0x31, WordDescriptor::SP),
+        ];
+
+        for (opcode, desc) in instrs {
+            for value in 0x0000..0xFFFF {
+                {
+                    let mut r = ram.as_ref().borrow_mut();
+                    r.data[0] = opcode;
+                    r.data[1] = value as u8;
+                    r.data[2] = (value >> 8) as u8;
+                }
+
+                cpu.pc = 0;
+                cpu.step(&mut bus);
+
+                let result = cpu.read_word(&mut bus, desc);
+                assert_eq!(result, value, "Test {desc:?}: Expected {value:04X}, got {result:04X}.");
+            }
+
+        }
+    }
+
+    #[test]
+    fn test_st_indirect_byte() {
+        let (mut bus, mut cpu, ram) = init_cpu();
+
+        let instrs: Vec<(u8, WordDescriptor)> = vec![
+            (0x02, WordDescriptor::BC),
+            (0x12, WordDescriptor::DE),
+        ];
+
+        for (opcode, desc) in instrs {
+            {
+                let mut r = ram.as_ref().borrow_mut();
+                r.data[0] = opcode;
+            }
+
+            for value in 0u8..0xFF {
+                let address = 0x1000 + value as u16;
+
+                cpu.pc = 0;
+                cpu.write_word(&mut bus, desc, address);
+                *cpu.af.left() = value;
+                cpu.step(&mut bus);
+
+                let result = ram.as_ref().borrow().data[address as usize];
+
+                assert_eq!(result, value, "Test {desc:?}: Expected {value:02X} at {address:04X}, got {result:02X}.");
+            }
+
+        }
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        let (mut bus, mut cpu, ram) = init_cpu();
+
+        {
+            let mut r = ram.as_ref().borrow_mut();
+            r.data[0] = 0x76; // halt
+            r.data[1] = 0x04; // inc B
+        }
+
+        {
+            let mut interrupt = cpu.interrupt_registers.as_ref().borrow_mut();
+            interrupt.master_enable = false;
+            interrupt.enable = 0x01;
+            interrupt.flags = 0x01;
+        }
+
+        cpu.pc = 0;
+        *cpu.bc.left() = 0;
+
+        // With IME=0 and an interrupt pending, HALT exits immediately and `inc B` runs twice.
+        for _ in 0..3 {
+            cpu.step(&mut bus);
+        }
+
+        assert!(!cpu.halted);
+        assert_eq!(*cpu.bc.left(), 2);
+        assert_eq!(cpu.pc, 2);
+    }
+
+    #[test]
+    fn test_rotate_accumulator() {
+        let (mut bus, mut cpu, ram) = init_cpu();
+
+        // (opcode, carry in, result, carry out) for A = 0x85.
+        let instrs: Vec<(u8, bool, u8, bool)> = vec![
+            (0x07, false, 0x0B, true),  // rlca
+            (0x0F, false, 0xC2, true),  // rrca
+            (0x17, false, 0x0A, true),  // rla
+            (0x17, true, 0x0B, true),   // rla
+            (0x1F, false, 0x42, true),  // rra
+            (0x1F, true, 0xC2, true),   // rra
+        ];
+
+        for (opcode, carry_in, expected, carry_out) in instrs {
+            ram.as_ref().borrow_mut().data[0] = opcode;
+
+            cpu.pc = 0;
+            *cpu.af.left() = 0x85;
+            cpu.clear_all_flags();
+            cpu.set_flag_if(Flag::Carry, carry_in);
+            cpu.step(&mut bus);
+
+            let result = *cpu.af.left();
+            assert_eq!(result, expected, "Test {opcode:02X}: Expected {expected:02X}, got {result:02X}.");
+            assert_eq!(cpu.test_flag(Flag::Carry), carry_out, "Test {opcode:02X}: Wrong carry.");
+        }
+    }
+
+    #[test]
+    fn test_jr() {
+        let (mut bus, mut cpu, ram) = init_cpu();
+
+        // (address, offset, target); the offset is relative to the end of the instruction.
+        let instrs: Vec<(u16, u8, u16)> = vec![
+            (0x0100, 0x7F, 0x0181),
+            (0x0100, 0x80, 0x0082),
+            (0x0100, 0xFE, 0x0100),
+            (0x0000, 0x80, 0xFF82),
+        ];
+
+        for (address, offset, target) in instrs {
+            {
+                let mut r = ram.as_ref().borrow_mut();
+                r.data[address as usize] = 0x18;
+                r.data[address as usize + 1] = offset;
+            }
+
+            cpu.pc = address;
+            cpu.step(&mut bus);
+
+            assert_eq!(cpu.pc, target, "Test {offset:02X}: Expected {target:04X}, got {:04X}.", cpu.pc);
+        }
+    }
+
+    #[test]
+    fn test_relative_sp() {
+        let (mut bus, mut cpu, ram) = init_cpu();
+
+        // (sp, offset, result, half carry, carry)
+        let cases: Vec<(u16, u8, u16, bool, bool)> = vec![
+            (0x0005, 0x01, 0x0006, false, false),
+            (0x000F, 0x01, 0x0010, true, false),
+            (0x00FF, 0x01, 0x0100, true, true),
+            (0x0005, 0xFF, 0x0004, true, true),
+            (0x0000, 0xFF, 0xFFFF, false, false),
+            (0xFFF8, 0x80, 0xFF78, false, true),
+            (0x0010, 0xF8, 0x0008, false, true),
+        ];
+
+        // add sp, e and ld hl, sp+e
+        for opcode in [0xE8, 0xF8] {
+            for &(sp, offset, expected, half_carry, carry) in &cases {
+                {
+                    let mut r = ram.as_ref().borrow_mut();
+                    r.data[0] = opcode;
+                    r.data[1] = offset;
+                }
+
+                cpu.pc = 0;
+                cpu.sp = sp;
+                *cpu.af.right() = 0xF0;
+                cpu.step(&mut bus);
+
+                let result = if opcode == 0xE8 { cpu.sp } else { cpu.hl.value() };
+                assert_eq!(result, expected, "Test {opcode:02X} {sp:04X} {offset:02X}: Expected {expected:04X}, got {result:04X}.");
+                assert_eq!(cpu.test_flag(Flag::HalfCarry), half_carry, "Test {opcode:02X} {sp:04X} {offset:02X}: Wrong half carry.");
+                assert_eq!(cpu.test_flag(Flag::Carry), carry, "Test {opcode:02X} {sp:04X} {offset:02X}: Wrong carry.");
+                assert!(!cpu.test_flag(Flag::Zero) && !cpu.test_flag(Flag::Subtract));
+            }
+        }
+    }
+
+    #[test]
+    fn test_ei_delay() {
+        let (mut bus, mut cpu, ram) = init_cpu();
+
+        {
+            let mut r = ram.as_ref().borrow_mut();
+            r.data[0] = 0xFB; // ei
+            r.data[1] = 0xF3; // di
+            r.data[2] = 0xFB; // ei
+            r.data[3] = 0x00; // nop
+            r.data[4] = 0x00; // nop
+        }
+
+        {
+            let mut interrupt = cpu.interrupt_registers.as_ref().borrow_mut();
+            interrupt.master_enable = false;
+            interrupt.enable = 0x01;
+            interrupt.flags = 0x01;
+        }
+
+        cpu.pc = 0;
+        cpu.sp = 0x1000;
+
+        // EI; DI never enables interrupts.
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert!(!cpu.interrupt_registers.borrow().master_enable);
+
+        // EI; NOP: the interrupt is only taken after the NOP.
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc, 4);
+        assert!(cpu.interrupt_registers.borrow().master_enable);
+
+        // The dispatch is followed by the first instruction of the handler (a NOP).
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc, 0x41);
+        assert_eq!(bus.read_word(cpu.sp), 4);
+        assert_eq!(cpu.interrupt_registers.borrow().flags, 0);
+    }
+
+    /// Encodings of all instructions that fall through to the next one.
+    fn sequential_instructions() -> Vec<(Vec<u8>, &'static Instruction)> {
+        use Operation::*;
+
+        let instructions = INSTRUCTIONS.iter()
+            .enumerate()
+            .map(|(opcode, instruction)| (vec![opcode as u8], instruction))
+            .chain(CB_INSTRUCTIONS.iter()
+                .enumerate()
+                .map(|(opcode, instruction)| (vec![0xCB, opcode as u8], instruction)));
+
+        instructions
+            .filter(|(_, instruction)| !matches!(instruction.operation,
+                Jp(_) | JpHl | Jr(_) | Call(_) | Ret(_) | Reti | Rst(_) | Prefix | Illegal))
+            .collect()
+    }
+
+    fn run_instruction(bytes: &[u8], flags: u8) -> CPU {
+        step_instruction(bytes, flags).0
+    }
+
+    fn step_instruction(bytes: &[u8], flags: u8) -> (CPU, StepOutcome) {
+        let (mut bus, mut cpu, ram) = init_cpu();
+
+        // IO registers around IF, for LDH and STOP.
+        let registers = [
+            Rc::new(RefCell::new(DummyRAM::new(0x00, 0x0E, true))),
+            Rc::new(RefCell::new(DummyRAM::new(0x10, 0x7F, true))),
+        ];
+
+        for register in &registers {
+            bus.attach(register.clone());
+        }
+
+        ram.as_ref().borrow_mut().data[..bytes.len()].copy_from_slice(bytes);
+
+        cpu.pc = 0;
+        cpu.sp = 0x1000;
+        *cpu.af.right() = flags;
+        let outcome = cpu.step(&mut bus);
+        (cpu, outcome)
+    }
+
+    #[test]
+    fn test_instruction_length() {
+        for (bytes, instruction) in sequential_instructions() {
+            let cpu = run_instruction(&bytes, 0x00);
+            assert_eq!(cpu.pc, instruction.length as Word, "Test {}: wrong length.", instruction.mnemonic);
+        }
+    }
+
+    #[test]
+    fn test_instruction_flags() {
+        for (bytes, instruction) in sequential_instructions() {
+            for flags in [0x00, 0xF0] {
+                let mut cpu = run_instruction(&bytes, flags);
+                let result = *cpu.af.right();
+
+                for (i, effect) in instruction.flags.chars().enumerate() {
+                    let mask = 0x80 >> i;
+                    let expected = match effect {
+                        '0' => 0,
+                        '1' => mask,
+                        '-' => flags & mask,
+                        _ => continue,
+                    };
+
+                    assert_eq!(result & mask, expected,
+                               "Test {}: flags {flags:02X} became {result:02X}.", instruction.mnemonic);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_instruction_cycles() {
+        use Operation::*;
+
+        let instructions = INSTRUCTIONS.iter()
+            .enumerate()
+            .map(|(opcode, instruction)| (vec![opcode as u8], instruction))
+            .chain(CB_INSTRUCTIONS.iter()
+                .enumerate()
+                .map(|(opcode, instruction)| (vec![0xCB, opcode as u8], instruction)))
+            .filter(|(_, instruction)| !matches!(instruction.operation, Prefix | Illegal));
+
+        // Every condition holds for one of the flag values and fails for the other.
+        for (bytes, instruction) in instructions {
+            let mut cycles: Vec<u8> = [0x00, 0xF0].iter()
+                .map(|&flags| match step_instruction(&bytes, flags).1 {
+                    StepOutcome::Cycles(cycles) => cycles,
+                    outcome => panic!("Test {}: {outcome:?}.", instruction.mnemonic),
+                })
+                .collect();
+            cycles.sort();
+            cycles.dedup();
+
+            let mut expected = vec![instruction.cycles_not_taken * 4, instruction.cycles * 4];
+            expected.dedup();
+
+            assert_eq!(cycles, expected, "Test {}: wrong cycle count.", instruction.mnemonic);
+        }
+    }
+}
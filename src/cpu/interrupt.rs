@@ -1,4 +1,5 @@
 use crate::{Address, Attach, Bus, BusListener, Byte};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 #[derive(Clone, Copy, Debug)]
 pub struct InterruptRegisters {
@@ -30,6 +31,28 @@ impl BusListener for InterruptRegisters {
     }
 }
 
+impl SaveState for InterruptRegisters {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.bool(self.master_enable);
+        out.u8(self.enable);
+        out.u8(self.flags);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.master_enable = input.bool()?;
+        self.enable = input.u8()?;
+        self.flags = input.u8()?;
+        Ok(())
+    }
+}
+
+impl InterruptRegisters {
+    /// Interrupts which are both requested and enabled, regardless of IME.
+    pub fn pending(&self) -> Byte {
+        self.flags & self.enable & 0x1F
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, PartialEq)]
 pub enum InterruptType {
@@ -2,13 +2,19 @@ mod registers;
 mod flag;
 mod interrupt;
 mod instructions;
+mod speed_switch;
+mod opcode;
 
 use flag::Flag;
 use registers::Register;
 use interrupt::InterruptRegisters;
+use speed_switch::SpeedSwitch;
 pub use interrupt::{InterruptType, interrupt};
+pub use opcode::Instruction;
 
 use crate::bus::*;
+use crate::clock::Clock;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 use crate::timer::*;
 use std::fmt;
 use log::trace;
@@ -67,6 +73,33 @@ pub enum Direction {
     Right,
 }
 
+/// Result of a single CPU step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepOutcome {
+    /// The step completed after the given number of cycles.
+    Cycles(u8),
+
+    /// An illegal opcode was fetched. Like real hardware, the CPU hangs and will not
+    /// execute anything else, including interrupts.
+    Locked { opcode: Byte, address: Address },
+}
+
+/// Programmer-visible CPU state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisterState {
+    pub a: Byte,
+    pub f: Byte,
+    pub b: Byte,
+    pub c: Byte,
+    pub d: Byte,
+    pub e: Byte,
+    pub h: Byte,
+    pub l: Byte,
+    pub sp: Word,
+    pub pc: Word,
+    pub ime: bool,
+}
+
 #[derive(Debug)]
 pub struct CPU {
     // General Purpose Registers
@@ -81,9 +114,27 @@ pub struct CPU {
 
     halted: bool,
 
+    // Set when HALT is executed with IME=0 and an interrupt already pending. The CPU does not
+    // halt, but fails to increment PC after the next opcode fetch (the "HALT bug").
+    halt_bug: bool,
+
+    // Set by EI; IME is enabled once the following instruction has completed.
+    ei_pending: bool,
+
+    // Opcode and address of the illegal instruction that locked up the CPU.
+    locked: Option<(Byte, Address)>,
+
+    // Set by STOP; the system clock is stopped until a joypad line goes low.
+    stopped: bool,
+
     // A separate struct is used to hold all interrupt registers as
     // the CPU cannot be borrowed mut on the bus while also being stepped.
     interrupt_registers: Rc<RefCell<InterruptRegisters>>,
+    speed_switch: Rc<RefCell<SpeedSwitch>>,
+
+    // Clock driving the rest of the system, and M-cycles elapsed during the current step.
+    clock: Option<Rc<RefCell<Clock>>>,
+    ticks: u8,
 }
 
 impl CPU {
@@ -102,7 +153,17 @@ impl CPU {
             sp: 0xFFFE,
             pc: 0x0100,
             halted: false,
+            halt_bug: false,
+            ei_pending: false,
+            locked: None,
+            stopped: false,
             interrupt_registers,
+            speed_switch: Rc::new(RefCell::new(SpeedSwitch {
+                double_speed: false,
+                prepare: false,
+            })),
+            clock: None,
+            ticks: 0,
         }
     }
 
@@ -110,6 +171,64 @@ impl CPU {
         bus.attach(self.interrupt_registers.clone());
     }
 
+    /// Attaches KEY1 (FF4D). The register must not be bound to anything else.
+    pub fn attach_speed_switch(&self, bus: &mut Bus) {
+        bus.attach(self.speed_switch.clone());
+    }
+
+    #[inline(always)]
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn set_halted(&mut self, halted: bool) {
+        self.halted = halted;
+    }
+
+    pub fn set_stopped(&mut self, stopped: bool) {
+        self.stopped = stopped;
+    }
+
+    /// Whether a CGB speed switch put the CPU in double speed mode.
+    pub fn is_double_speed(&self) -> bool {
+        self.speed_switch.borrow().double_speed
+    }
+
+    pub fn registers(&self) -> RegisterState {
+        let split = |register: &Register| ((register.value() >> 8) as Byte, register.value() as Byte);
+        let (a, f) = split(&self.af);
+        let (b, c) = split(&self.bc);
+        let (d, e) = split(&self.de);
+        let (h, l) = split(&self.hl);
+
+        RegisterState {
+            a, f, b, c, d, e, h, l,
+            sp: self.sp,
+            pc: self.pc,
+            ime: self.interrupt_registers.borrow().master_enable,
+        }
+    }
+
+    pub fn set_registers(&mut self, state: &RegisterState) {
+        let join = |hi: Byte, lo: Byte| Register::new(((hi as Word) << 8) | lo as Word);
+        self.af = join(state.a, state.f & 0xF0);
+        self.bc = join(state.b, state.c);
+        self.de = join(state.d, state.e);
+        self.hl = join(state.h, state.l);
+        self.sp = state.sp;
+        self.pc = state.pc;
+        self.interrupt_registers.as_ref().borrow_mut().master_enable = state.ime;
+    }
+
+    /// Opcode and address of the illegal instruction that locked up the CPU, if any.
+    pub fn locked(&self) -> Option<(Byte, Address)> {
+        self.locked
+    }
+
     #[inline(always)]
     fn set_flag_if(&mut self, flag: Flag, cond: bool) {
         if cond {
@@ -139,12 +258,45 @@ impl CPU {
         *self.af.right() = 0x00
     }
 
-    #[inline(always)]
-    pub fn is_halted(&self) -> bool {
-        self.halted
+    /// Memory accesses (and internal delays) advance the rest of the system by one M-cycle
+    /// before they take place, so that timers, DMA and the PPU observe them at the right time.
+    pub fn attach_clock(&mut self, clock: Rc<RefCell<Clock>>) {
+        self.clock = Some(clock);
+    }
+
+    fn tick(&mut self, bus: &mut Bus) {
+        self.ticks += 1;
+
+        if let Some(clock) = &self.clock {
+            // In double speed mode, an M-cycle only takes half as long for the rest of the system.
+            let mut clock = clock.as_ref().borrow_mut();
+            let cycles = if clock.is_double_speed() { 2 } else { 4 };
+            clock.increment(bus, cycles);
+        }
+    }
+
+    /// Catches up the component behind `address` before it is accessed. Components are only
+    /// clocked when they have something to do, see `Clock`.
+    fn sync(&mut self, bus: &mut Bus, address: Address) {
+        if let Some(clock) = &self.clock {
+            clock.as_ref().borrow_mut().sync(bus, address);
+        }
+    }
+
+    fn bus_read(&mut self, bus: &mut Bus, address: Address) -> Byte {
+        self.tick(bus);
+        self.sync(bus, address);
+        bus.read_byte(address)
+    }
+
+    fn bus_write(&mut self, bus: &mut Bus, address: Address, value: Byte) {
+        self.tick(bus);
+        self.sync(bus, address);
+        bus.write_byte(address, value);
+        self.sync(bus, address);
     }
 
-    fn read_byte(&mut self, bus: &Bus, desc: ByteDescriptor) -> u8 {
+    fn read_byte(&mut self, bus: &mut Bus, desc: ByteDescriptor) -> u8 {
         use ByteDescriptor::*;
         match desc {
             A => *self.af.left(),
@@ -154,35 +306,33 @@ impl CPU {
             E => *self.de.right(),
             H => *self.hl.left(),
             L => *self.hl.right(),
-            BC => bus.read_byte(*self.bc.word()),
-            DE => bus.read_byte(*self.de.word()),
-            HL => bus.read_byte(*self.hl.word()),
+            BC => self.bus_read(bus, self.bc.value()),
+            DE => self.bus_read(bus, self.de.value()),
+            HL => self.bus_read(bus, self.hl.value()),
             HLi => {
-                let byte = bus.read_byte(*self.hl.word());
+                let byte = self.bus_read(bus, self.hl.value());
                 self.hl.inc_word();
                 byte
             }
             HLd => {
-                let byte = bus.read_byte(*self.hl.word());
+                let byte = self.bus_read(bus, self.hl.value());
                 self.hl.dec_word();
                 byte
             }
             Immediate => {
-                let byte = bus.read_byte(self.pc);
+                let byte = self.bus_read(bus, self.pc);
                 self.pc += 1;
                 byte
             }
             Indirect => {
-                let addr = bus.read_word(self.pc);
-                self.pc += 2;
-                bus.read_byte(addr)
+                let addr = self.read_word(bus, WordDescriptor::Immediate);
+                self.bus_read(bus, addr)
             }
             HighAddress => {
-                let addr = bus.read_byte(self.pc) as u16;
-                self.pc += 1;
-                bus.read_byte(0xFF00 | addr)
+                let addr = self.read_byte(bus, Immediate) as u16;
+                self.bus_read(bus, 0xFF00 | addr)
             }
-            HighAddressC => bus.read_byte(0xFF00 | (*self.bc.right() as u16)),
+            HighAddressC => self.bus_read(bus, 0xFF00 | (self.bc.value() & 0xFF)),
         }
     }
 
@@ -196,35 +346,33 @@ impl CPU {
             E => *self.de.right() = value,
             H => *self.hl.left() = value,
             L => *self.hl.right() = value,
-            BC => bus.write_byte(*self.bc.word(), value),
-            DE => bus.write_byte(*self.de.word(), value),
-            HL => bus.write_byte(*self.hl.word(), value),
+            BC => self.bus_write(bus, self.bc.value(), value),
+            DE => self.bus_write(bus, self.de.value(), value),
+            HL => self.bus_write(bus, self.hl.value(), value),
             HLi => {
-                bus.write_byte(*self.hl.word(), value);
+                self.bus_write(bus, self.hl.value(), value);
                 self.hl.inc_word();
             }
             HLd => {
-                bus.write_byte(*self.hl.word(), value);
+                self.bus_write(bus, self.hl.value(), value);
                 self.hl.dec_word();
             }
             Indirect => {
-                let addr = bus.read_word(self.pc);
-                self.pc += 2;
-                bus.write_byte(addr, value);
+                let addr = self.read_word(bus, WordDescriptor::Immediate);
+                self.bus_write(bus, addr, value);
             }
             HighAddress => {
-                let addr = bus.read_byte(self.pc) as u16;
-                self.pc += 1;
-                bus.write_byte(0xFF00 | addr, value);
+                let addr = self.read_byte(bus, Immediate) as u16;
+                self.bus_write(bus, 0xFF00 | addr, value);
             },
             HighAddressC => {
-                bus.write_byte(0xFF00 | (*self.bc.right() as u16), value);
+                self.bus_write(bus, 0xFF00 | (self.bc.value() & 0xFF), value);
             }
             _ => panic!("Invalid write_byte() descriptor: {:?}", desc),
         }
     }
 
-    fn read_word(&mut self, bus: &Bus, desc: WordDescriptor) -> u16 {
+    fn read_word(&mut self, bus: &mut Bus, desc: WordDescriptor) -> u16 {
         use WordDescriptor::*;
         match desc {
             AF => *self.af.word(),
@@ -234,15 +382,15 @@ impl CPU {
             SP => self.sp,
             PC => self.pc,
             Immediate => {
-                let word = bus.read_word(self.pc);
-                self.pc += 2;
-                word
+                let lo = self.read_byte(bus, ByteDescriptor::Immediate) as Word;
+                let hi = self.read_byte(bus, ByteDescriptor::Immediate) as Word;
+                (hi << 8) | lo
             }
             Indirect => {
-                let addr = bus.read_word(self.pc);
-                let word = bus.read_word(addr);
-                self.pc += 2;
-                word
+                let addr = self.read_word(bus, Immediate);
+                let lo = self.bus_read(bus, addr) as Word;
+                let hi = self.bus_read(bus, addr.wrapping_add(1)) as Word;
+                (hi << 8) | lo
             }
         }
     }
@@ -257,9 +405,9 @@ impl CPU {
             SP => self.sp = value,
             PC => self.pc = value,
             Indirect => {
-                let addr = bus.read_word(self.pc);
-                self.pc += 2;
-                bus.write_word(addr, value);
+                let addr = self.read_word(bus, Immediate);
+                self.bus_write(bus, addr, value as Byte);
+                self.bus_write(bus, addr.wrapping_add(1), (value >> 8) as Byte);
             }
             _ => panic!("Invalid write_word() descriptor"),
         }
@@ -280,6 +428,57 @@ impl CPU {
     }
 }
 
+impl SaveState for CPU {
+    fn save_state(&self, out: &mut StateWriter) {
+        let r = self.registers();
+        for value in [r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l] {
+            out.u8(value);
+        }
+        out.u16(r.sp);
+        out.u16(r.pc);
+
+        out.bool(self.halted);
+        out.bool(self.halt_bug);
+        out.bool(self.ei_pending);
+        out.bool(self.stopped);
+
+        let (opcode, address) = self.locked.unwrap_or((0, 0));
+        out.bool(self.locked.is_some());
+        out.u8(opcode);
+        out.u16(address);
+
+        self.interrupt_registers.borrow().save_state(out);
+        self.speed_switch.borrow().save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        let mut bytes = [0; 8];
+        input.bytes(&mut bytes)?;
+        let [a, f, b, c, d, e, h, l] = bytes;
+
+        // IME is restored along with the interrupt registers below.
+        self.set_registers(&RegisterState {
+            a, f, b, c, d, e, h, l,
+            sp: input.u16()?,
+            pc: input.u16()?,
+            ime: false,
+        });
+
+        self.halted = input.bool()?;
+        self.halt_bug = input.bool()?;
+        self.ei_pending = input.bool()?;
+        self.stopped = input.bool()?;
+
+        let locked = input.bool()?;
+        let opcode = input.u8()?;
+        let address = input.u16()?;
+        self.locked = if locked { Some((opcode, address)) } else { None };
+
+        self.interrupt_registers.as_ref().borrow_mut().load_state(input)?;
+        self.speed_switch.as_ref().borrow_mut().load_state(input)
+    }
+}
+
 impl fmt::Display for CPU {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let int = *self.interrupt_registers.borrow();
@@ -287,9 +486,11 @@ impl fmt::Display for CPU {
             concat! {
                 "CPU | PC {:04X}  SP {:04X}  FE {:02X}{:02X}  F  {}\n",
                 "    | AF {}  BC {}  DE {}  HL {}\n",
+                "    | IME {}  HALT {}  STOP {}  LOCK {}\n",
             },
             self.pc, self.sp, int.flags, int.enable, Flag::fmt(self.af.value() as u8),
-            self.af, self.bc, self.de, self.hl
+            self.af, self.bc, self.de, self.hl,
+            int.master_enable as u8, self.halted as u8, self.stopped as u8, self.locked.is_some() as u8,
         }
     }
 }
@@ -0,0 +1,149 @@
+use crate::bus::*;
+use crate::cpu::flag::Flag;
+use crate::cpu::{ByteDescriptor, WordDescriptor};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    NZ,
+    Z,
+    NC,
+    C,
+}
+
+impl Condition {
+    /// Flag to test and the value it must have for the condition to hold.
+    pub fn flag(self) -> (Flag, bool) {
+        match self {
+            Condition::NZ => (Flag::Zero, false),
+            Condition::Z => (Flag::Zero, true),
+            Condition::NC => (Flag::Carry, false),
+            Condition::C => (Flag::Carry, true),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Nop,
+    Stop,
+    Halt,
+    Di,
+    Ei,
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+
+    // Rotations of A; unlike their CB counterparts these always clear Z.
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+
+    Ld(ByteDescriptor, ByteDescriptor),
+    Ld16(WordDescriptor, WordDescriptor),
+    LdHlSp,
+
+    Inc(ByteDescriptor),
+    Dec(ByteDescriptor),
+    Inc16(WordDescriptor),
+    Dec16(WordDescriptor),
+
+    Add(ByteDescriptor),
+    Adc(ByteDescriptor),
+    Sub(ByteDescriptor),
+    Sbc(ByteDescriptor),
+    And(ByteDescriptor),
+    Xor(ByteDescriptor),
+    Or(ByteDescriptor),
+    Cp(ByteDescriptor),
+    Add16(WordDescriptor),
+    AddSp,
+
+    Jp(Option<Condition>),
+    JpHl,
+    Jr(Option<Condition>),
+    Call(Option<Condition>),
+    Ret(Option<Condition>),
+    Reti,
+    Rst(Address),
+
+    Push(WordDescriptor),
+    Pop(WordDescriptor),
+
+    // Prefix for the CB table.
+    Prefix,
+    Illegal,
+
+    Rlc(ByteDescriptor),
+    Rrc(ByteDescriptor),
+    Rl(ByteDescriptor),
+    Rr(ByteDescriptor),
+    Sla(ByteDescriptor),
+    Sra(ByteDescriptor),
+    Swap(ByteDescriptor),
+    Srl(ByteDescriptor),
+    Bit(u8, ByteDescriptor),
+    Res(u8, ByteDescriptor),
+    Set(u8, ByteDescriptor),
+}
+
+/// An entry of the opcode table in `opcodes.txt`.
+#[derive(Debug)]
+pub struct Instruction {
+    pub mnemonic: &'static str,
+    pub operation: Operation,
+
+    /// Length in bytes, including the opcode and CB prefix.
+    pub length: u8,
+
+    /// M-cycles, including the opcode fetch. Conditional instructions take
+    /// `cycles_not_taken` when the condition does not hold.
+    pub cycles: u8,
+    pub cycles_not_taken: u8,
+
+    /// Effect on Z, N, H and C: the flag name if it depends on the result,
+    /// 0/1 if it is always reset/set and - if it is left unaffected.
+    pub flags: &'static str,
+}
+
+include!(concat!(env!("OUT_DIR"), "/opcodes.rs"));
+
+impl Instruction {
+    /// Looks up the instruction at `address` without executing it.
+    pub fn decode(bus: &Bus, address: Address) -> &'static Instruction {
+        let instruction = &INSTRUCTIONS[bus.read_byte(address) as usize];
+
+        if instruction.operation == Operation::Prefix {
+            &CB_INSTRUCTIONS[bus.read_byte(address.wrapping_add(1)) as usize]
+        }
+        else {
+            instruction
+        }
+    }
+
+    /// Formats the instruction at `address`, filling in its immediate operands.
+    /// Relative jumps show their target address.
+    pub fn disassemble(&self, bus: &Bus, address: Address) -> String {
+        let operand = address.wrapping_add(1);
+        let byte = bus.read_byte(operand);
+        let word = bus.read_word(operand);
+
+        if self.mnemonic.contains("u16") {
+            self.mnemonic.replace("u16", &format!("${word:04X}"))
+        }
+        else if self.mnemonic.contains("u8") {
+            self.mnemonic.replace("u8", &format!("${byte:02X}"))
+        }
+        else if let Operation::Jr(_) = self.operation {
+            let target = address.wrapping_add(self.length as Address).wrapping_add(byte as i8 as Address);
+            self.mnemonic.replace("i8", &format!("${target:04X}"))
+        }
+        else if self.mnemonic.contains("+i8") {
+            self.mnemonic.replace("+i8", &format!("{:+}", byte as i8))
+        }
+        else {
+            self.mnemonic.replace("i8", &format!("{:+}", byte as i8))
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{Address, Attach, Bus, BusListener, Byte};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+const KEY1_DOUBLE_SPEED: u8 = 1 << 7;
+const KEY1_PREPARE: u8 = 1 << 0;
+
+/// CGB speed switch register (KEY1).
+/// Only CGB software sets the prepare bit; otherwise STOP always stops.
+#[derive(Clone, Copy, Debug)]
+pub struct SpeedSwitch {
+    pub double_speed: bool,
+    pub prepare: bool,
+}
+
+impl SpeedSwitch {
+    /// Invoked by STOP. Returns true if a prepared speed switch was performed instead of stopping.
+    pub fn switch(&mut self) -> bool {
+        if !self.prepare {
+            return false;
+        }
+
+        self.prepare = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+}
+
+impl BusListener for SpeedSwitch {
+    fn bus_attach(&mut self) -> Vec<Attach> {
+        vec![Attach::Register(0x4D)]
+    }
+
+    fn bus_read(&self, address: Address) -> Byte {
+        match address {
+            0xFF4D => {
+                let speed = if self.double_speed { KEY1_DOUBLE_SPEED } else { 0 };
+                let prepare = if self.prepare { KEY1_PREPARE } else { 0 };
+                0x7E | speed | prepare
+            },
+            _ => panic!("Address {:4X} is not KEY1.", address)
+        }
+    }
+
+    fn bus_write(&mut self, _bus: &mut Bus, address: Address, value: Byte) {
+        match address {
+            // Only the prepare bit is writable; the current speed changes on STOP.
+            0xFF4D => self.prepare = value & KEY1_PREPARE != 0,
+            _ => panic!("Address {:4X} is not KEY1.", address)
+        }
+    }
+}
+
+impl SaveState for SpeedSwitch {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.bool(self.double_speed);
+        out.bool(self.prepare);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.double_speed = input.bool()?;
+        self.prepare = input.bool()?;
+        Ok(())
+    }
+}
@@ -3,7 +3,7 @@ use std::io;
 use std::io::Write;
 use std::num::ParseIntError;
 use log::info;
-use crate::{Address, Bus, CPU, PPU};
+use crate::{Address, Bus, CPU, Instruction, PPU};
 
 pub struct Debugger {
     step: bool,
@@ -29,12 +29,21 @@ impl Debugger {
         }
     }
 
+    /// Whether `step` will stop at the given address.
+    pub fn will_stop(&self, pc: Address) -> bool {
+        self.step || self.breakpoints.contains(&pc)
+    }
+
     pub fn step(&mut self, bus: &mut Bus, cpu: &mut CPU, ppu: &PPU) -> bool {
-        if !(self.step || self.breakpoints.contains(&cpu.pc)) {
+        if !self.will_stop(cpu.pc) {
             return false;
         }
         self.step |= self.step_on_breakpoint;
         println!("\n-- PAUSE ON {:04X} --\n\n{}", cpu.pc, cpu);
+        if let Some((opcode, address)) = cpu.locked() {
+            println!("CPU locked by illegal instruction {opcode:02X} at {address:04X}.");
+        }
+        Self::disassemble(bus, cpu.pc, 1);
         println!("self.step = {}", self.step);
         println!("self.breakpoints = {:?}", self.breakpoints);
         self.prompt(bus, cpu, ppu);
@@ -114,6 +123,15 @@ impl Debugger {
                             Err(e) => println!("{e}")
                         }
                     },
+                    "dis" => {
+                        let address = split.next().map_or(Ok(cpu.pc), |s| to_addr(Some(s)));
+                        let count = split.next().map_or(Ok(10), |s| s.parse::<usize>());
+                        match (address, count) {
+                            (Ok(address), Ok(count)) => Self::disassemble(bus, address, count),
+                            (Err(e), _) => println!("{e}"),
+                            (_, Err(e)) => println!("{e}"),
+                        }
+                    },
                     "peek" => {
                         match to_addr(split.next()) {
                             Ok(address) => println!("{:02X}", bus.read_byte(address)),
@@ -150,4 +168,13 @@ impl Debugger {
         print!("Removing breakpoint {:04X}... ", address);
         println!("{}", if self.breakpoints.remove(&address) { "Ok" } else { "Fail" });
     }
+
+    /// Prints `count` instructions starting at `address`, along with the flags they affect.
+    fn disassemble(bus: &Bus, mut address: Address, count: usize) {
+        for _ in 0..count {
+            let instruction = Instruction::decode(bus, address);
+            println!("{:04X}  {:<20} {}", address, instruction.disassemble(bus, address), instruction.flags);
+            address = address.wrapping_add(instruction.length as Address);
+        }
+    }
 }
\ No newline at end of file
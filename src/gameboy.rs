@@ -0,0 +1,640 @@
+use std::cell::{Ref, RefCell, RefMut};
+use std::rc::Rc;
+
+use crate::bus::*;
+use crate::cartridge;
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::clock::{Clock, Speed};
+use crate::cpu::{CPU, RegisterState, StepOutcome};
+use crate::debug::Debugger;
+use crate::graphics_driver::GraphicsDriver;
+use crate::joypad::{Joypad, JoypadButtons};
+use crate::ppu::{DISPLAY_HEIGHT, DISPLAY_WIDTH, PPU};
+use crate::ram::{DummyRAM, RAM, RegisterHoles};
+use crate::save_state::{write_state, SaveState, StateError, StateFile, StateReader, StateWriter, Tag};
+use crate::serial::SerialInterface;
+use crate::timer::Timer;
+
+mod bess;
+
+// Cycles to advance the rest of the system by for each step while the CPU is locked up.
+const LOCKED_CYCLES: u8 = 4;
+
+// Cycles that pass for each step while the system clock is stopped, see `PPU::idle`.
+const STOPPED_CYCLES: u32 = 4;
+
+// Save state sections.
+const SYSTEM_TAG: Tag = *b"SYS ";
+const CPU_TAG: Tag = *b"CPU ";
+const PPU_TAG: Tag = *b"PPU ";
+const TIMER_TAG: Tag = *b"TIMR";
+const SERIAL_TAG: Tag = *b"SERL";
+const JOYPAD_TAG: Tag = *b"JOYP";
+const AUDIO_TAG: Tag = *b"AUD ";
+const RAM_TAG: Tag = *b"WRAM";
+const HRAM_TAG: Tag = *b"HRAM";
+const CARTRIDGE_TAG: Tag = *b"CART";
+
+fn rc<T>(t: T) -> Rc<RefCell<T>> {
+    Rc::new(RefCell::new(t))
+}
+
+/// Copy of the last frame presented by the PPU.
+struct Frame {
+    pixels: Vec<u32>,
+}
+
+impl GraphicsDriver for Frame {
+    fn draw(&mut self, y: u16, x: u16, colour: u32) {
+        self.pixels[y as usize * DISPLAY_WIDTH as usize + x as usize] = colour;
+    }
+
+    fn render(&mut self, pixel_buffer: &[u32]) {
+        self.pixels.copy_from_slice(pixel_buffer);
+    }
+
+    fn blank(&mut self, colour: u32) {
+        self.pixels.fill(colour);
+    }
+
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+/// A complete DMG, wired together and ready to run a cartridge.
+pub struct GameBoy {
+    // The bus handles the "wiring" between all components.
+    bus: Bus,
+    clock: Rc<RefCell<Clock>>,
+    cpu: CPU,
+
+    // The bus and clock only hold weak references, so every component is owned here.
+    ppu: Rc<RefCell<PPU>>,
+    timer: Rc<RefCell<Timer>>,
+    cartridge: Rc<RefCell<dyn Cartridge>>,
+    joypad: Rc<RefCell<Joypad>>,
+    audio: Rc<RefCell<DummyRAM>>,
+    serial: Rc<RefCell<SerialInterface>>,
+    ram: Rc<RefCell<RAM>>,
+    hram: Rc<RefCell<DummyRAM>>,
+    #[allow(dead_code)]
+    unused_registers: Rc<RefCell<RegisterHoles>>,
+
+    frame: Frame,
+
+    // Frames presented since power-on, and whether the last one is yet to be passed to `present`.
+    frames: u64,
+    frame_pending: bool,
+
+    // T-cycles that passed while the system clock was stopped.
+    stopped_cycles: u64,
+}
+
+impl GameBoy {
+    /// Fails if the ROM is not a cartridge this emulator supports.
+    pub fn from_rom(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        let cgb = cartridge::is_cgb(&rom);
+        let cartridge = cartridge::load(rom)?;
+
+        let mut bus = Bus::new();
+        let clock = rc(Clock::new());
+
+        let mut cpu = CPU::new();
+        cpu.attach_to_bus(&mut bus);
+        cpu.attach_clock(clock.clone());
+
+        // The speed switch is only there in CGB mode; otherwise STOP always stops.
+        if cgb {
+            cpu.attach_speed_switch(&mut bus);
+        }
+
+        // Graphics processor.
+        let ppu = rc(PPU::new());
+        bus.attach(ppu.clone());
+        clock.borrow_mut().attach(ppu.clone());
+
+        let timer = rc(Timer::new());
+        bus.attach(timer.clone());
+        clock.borrow_mut().attach(timer.clone());
+
+        bus.attach(cartridge.clone());
+
+        // A real-time clock is caught up whenever its registers may be accessed.
+        if let Some(rtc) = cartridge.borrow().rtc() {
+            clock.borrow_mut().attach_at(rtc, vec![Attach::BlockRange(0xA0, 0xBF)]);
+        }
+
+        let joypad = rc(Joypad::new());
+        bus.attach(joypad.clone());
+
+        let audio = rc(DummyRAM::new(0x10, 0x3F, true));
+        bus.attach(audio.clone());
+
+        let serial = rc(SerialInterface::new(true, cgb));
+        bus.attach(serial.clone());
+        clock.borrow_mut().attach(serial.clone());
+
+        let ram = rc(RAM::new());
+        bus.attach(ram.clone());
+
+        let unused_registers = rc(RegisterHoles::new(cgb));
+        bus.attach(unused_registers.clone());
+
+        let hram = rc(DummyRAM::new(0xFF, 0xFF, false));
+        bus.attach(hram.clone());
+
+        let mut gameboy = Self {
+            bus,
+            clock,
+            cpu,
+            ppu,
+            timer,
+            cartridge,
+            joypad,
+            audio,
+            serial,
+            ram,
+            hram,
+            unused_registers,
+            frame: Frame {
+                pixels: vec![0; DISPLAY_WIDTH as usize * DISPLAY_HEIGHT as usize],
+            },
+            frames: 0,
+            frame_pending: false,
+            stopped_cycles: 0,
+        };
+
+        // The PPU starts with a frame pending; take it so that frames are only counted once the
+        // system is running.
+        gameboy.ppu.borrow_mut().update(&mut gameboy.frame);
+        Ok(gameboy)
+    }
+
+    /// Executes one instruction, clocking the rest of the system along with it. While the CPU
+    /// is locked up, the rest of the system keeps running.
+    pub fn step_instruction(&mut self) -> StepOutcome {
+        let outcome = self.cpu.step(&mut self.bus);
+
+        if let StepOutcome::Locked { .. } = outcome {
+            self.clock.borrow_mut().increment(&mut self.bus, LOCKED_CYCLES);
+        }
+
+        if self.cpu.is_stopped() {
+            // The LCD is blanked while the system clock is stopped, but frames still pass.
+            self.ppu.borrow_mut().idle(STOPPED_CYCLES);
+            self.stopped_cycles += STOPPED_CYCLES as u64;
+        }
+
+        let mut ppu = self.ppu.borrow_mut();
+
+        if ppu.frame_ready() {
+            ppu.update(&mut self.frame);
+            self.frames += 1;
+            self.frame_pending = true;
+
+            self.clock.borrow_mut().pace();
+        }
+
+        outcome
+    }
+
+    /// Runs until the PPU presents its next frame, which is then available from `framebuffer`.
+    pub fn run_frame(&mut self) {
+        let frames = self.frames;
+
+        while self.frames == frames {
+            self.step_instruction();
+        }
+    }
+
+    /// Unthrottled by default; at any other speed, each frame is held back until it is due.
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.clock.borrow_mut().set_speed(speed);
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.clock.borrow().speed()
+    }
+
+    /// Number of frames presented since power-on.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// T-cycles emulated since the system was created, including time spent in STOP mode. Unlike
+    /// `frames`, this is not restored by loading a state.
+    pub fn cycles(&self) -> u64 {
+        self.clock.borrow().now() + self.stopped_cycles
+    }
+
+    /// The last frame presented, as 160x144 RGB pixels.
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.frame.pixels
+    }
+
+    /// Passes a new frame on to the driver, for frontends stepping one instruction at a time.
+    pub fn present(&mut self, driver: &mut dyn GraphicsDriver) {
+        if self.frame_pending {
+            driver.render(&self.frame.pixels);
+            self.frame_pending = false;
+        }
+    }
+
+    pub fn set_buttons(&mut self, buttons: &[JoypadButtons]) {
+        self.joypad.borrow_mut().set_buttons(buttons);
+    }
+
+    /// The buttons currently held, as returned by `Joypad::buttons`.
+    pub fn buttons(&self) -> Byte {
+        self.joypad.borrow().buttons()
+    }
+
+    /// Every byte sent over the serial port so far.
+    pub fn serial_output(&self) -> Ref<'_, [Byte]> {
+        Ref::map(self.serial.borrow(), SerialInterface::output)
+    }
+
+    pub fn serial(&self) -> RefMut<'_, SerialInterface> {
+        self.serial.borrow_mut()
+    }
+
+    pub fn ppu(&self) -> RefMut<'_, PPU> {
+        self.ppu.borrow_mut()
+    }
+
+    /// Gives the debugger a chance to stop before the next instruction.
+    pub fn debug(&mut self, debugger: &mut Debugger) {
+        if debugger.will_stop(self.cpu.pc) {
+            self.clock.borrow_mut().sync_all(&mut self.bus);
+        }
+
+        debugger.step(&mut self.bus, &mut self.cpu, &self.ppu.borrow());
+    }
+
+    pub fn registers(&self) -> RegisterState {
+        self.cpu.registers()
+    }
+
+    pub fn read_byte(&mut self, address: Address) -> Byte {
+        self.clock.borrow_mut().sync(&mut self.bus, address);
+        self.bus.read_byte(address)
+    }
+
+    pub fn print_trace(&self) {
+        self.cpu.print_trace(&self.bus);
+    }
+
+    /// Snapshots the whole system. The link cable, if any, is not part of the state.
+    pub fn save_state(&mut self) -> Vec<u8> {
+        self.clock.borrow_mut().sync_all(&mut self.bus);
+
+        write_state(&[
+            (SYSTEM_TAG, self),
+            (CPU_TAG, &self.cpu),
+            (PPU_TAG, &*self.ppu.borrow()),
+            (TIMER_TAG, &*self.timer.borrow()),
+            (SERIAL_TAG, &*self.serial.borrow()),
+            (JOYPAD_TAG, &*self.joypad.borrow()),
+            (AUDIO_TAG, &*self.audio.borrow()),
+            (RAM_TAG, &*self.ram.borrow()),
+            (HRAM_TAG, &*self.hram.borrow()),
+            (CARTRIDGE_TAG, &*self.cartridge.borrow()),
+        ])
+    }
+
+    /// Restores a snapshot taken by `save_state`. The system is left untouched if it fails.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let file = StateFile::parse(state)?;
+        let backup = self.save_state();
+
+        let result = self.load_sections(&file);
+        if result.is_err() {
+            self.load_sections(&StateFile::parse(&backup).unwrap()).unwrap();
+        }
+
+        self.reschedule();
+        result?;
+
+        // Show the restored frame straight away.
+        self.frame_pending = true;
+        Ok(())
+    }
+
+    /// Components are scheduled from the state they were left in, at the restored speed.
+    fn reschedule(&mut self) {
+        let mut clock = self.clock.borrow_mut();
+        clock.set_double_speed(self.cpu.is_double_speed());
+        clock.reschedule();
+    }
+
+    fn load_sections(&mut self, file: &StateFile<'_>) -> Result<(), StateError> {
+        file.load(SYSTEM_TAG, self)?;
+        file.load(CPU_TAG, &mut self.cpu)?;
+        file.load(PPU_TAG, &mut *self.ppu.borrow_mut())?;
+        file.load(TIMER_TAG, &mut *self.timer.borrow_mut())?;
+        file.load(SERIAL_TAG, &mut *self.serial.borrow_mut())?;
+        file.load(JOYPAD_TAG, &mut *self.joypad.borrow_mut())?;
+        file.load(AUDIO_TAG, &mut *self.audio.borrow_mut())?;
+        file.load(RAM_TAG, &mut *self.ram.borrow_mut())?;
+        file.load(HRAM_TAG, &mut *self.hram.borrow_mut())?;
+        file.load(CARTRIDGE_TAG, &mut *self.cartridge.borrow_mut())
+    }
+}
+
+/// The frame counter and the last frame presented, which the PPU no longer holds.
+impl SaveState for GameBoy {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u64(self.frames);
+
+        for pixel in &self.frame.pixels {
+            out.u32(*pixel);
+        }
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.frames = input.u64()?;
+
+        for pixel in self.frame.pixels.iter_mut() {
+            *pixel = input.u32()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::{CartridgeError, Speed, StepOutcome};
+    use super::GameBoy;
+
+    /// ROM-only cartridge with the given program at the entry point.
+    fn rom(program: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        rom
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut gameboy = GameBoy::from_rom(rom(&[
+            0x3E, 0x41, // ld A, $41
+            0xE0, 0x01, // ld ($FF01), A
+            0x3E, 0x81, // ld A, $81
+            0xE0, 0x02, // ld ($FF02), A
+            0x18, 0xFE, // jr $0108
+        ])).unwrap();
+
+        gameboy.run_frame();
+
+        assert_eq!(&*gameboy.serial_output(), b"A");
+        assert_eq!(gameboy.framebuffer().len(), 160 * 144);
+        assert_eq!(gameboy.frames(), 1);
+    }
+
+    #[test]
+    fn test_from_rom() {
+        assert_eq!(GameBoy::from_rom(vec![0; 0x100]).err(), Some(CartridgeError::TooSmall(0x100)));
+
+        let mut unsupported = rom(&[]);
+        unsupported[0x147] = 0xFD;
+        assert_eq!(GameBoy::from_rom(unsupported).err(), Some(CartridgeError::UnsupportedType(0xFD)));
+
+        // A ROM that ends after the header, with a title that isn't valid UTF-8.
+        let mut short = rom(&[0x18, 0xFE]);
+        short.truncate(0x150);
+        short[0x134..0x138].copy_from_slice(&[0xC3, 0x28, 0xFF, 0x80]);
+
+        let mut gameboy = GameBoy::from_rom(short).unwrap();
+        assert_eq!(gameboy.read_byte(0x014F), 0x00);
+        assert_eq!(gameboy.read_byte(0x0150), 0xFF);
+        assert_eq!(gameboy.read_byte(0x7FFF), 0xFF);
+    }
+
+    #[test]
+    fn test_rtc() {
+        let mut rom = rom(&[
+            0x3E, 0x0A,       // ld A, $0A
+            0xEA, 0x00, 0x00, // ld ($0000), A
+            0x3E, 0x08,       // ld A, $08
+            0xEA, 0x00, 0x40, // ld ($4000), A
+            0xAF,             // xor A
+            0xEA, 0x00, 0x60, // ld ($6000), A
+            0x3C,             // inc A
+            0xEA, 0x00, 0x60, // ld ($6000), A
+            0x18, 0xF6,       // jr $010A
+        ]);
+        rom[0x147] = 0x10; // MBC3+TIMER+RAM+BATTERY
+        rom[0x149] = 0x03;
+
+        // The clock counts emulated time: a second is 4194304 T-cycles, or 239 PPU frames.
+        let mut gameboy = GameBoy::from_rom(rom).unwrap();
+        while gameboy.frames() < 200 {
+            gameboy.run_frame();
+        }
+        assert_eq!(gameboy.read_byte(0xA000), 0);
+
+        let state = gameboy.save_state();
+        while gameboy.frames() < 300 {
+            gameboy.run_frame();
+        }
+        assert_eq!(gameboy.read_byte(0xA000), 1);
+
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.read_byte(0xA000), 0);
+        while gameboy.frames() < 300 {
+            gameboy.run_frame();
+        }
+        assert_eq!(gameboy.read_byte(0xA000), 1);
+    }
+
+    #[test]
+    fn test_locked() {
+        let mut gameboy = GameBoy::from_rom(rom(&[
+            0x00, // nop
+            0xD3, // illegal
+        ])).unwrap();
+
+        gameboy.step_instruction();
+        let locked = StepOutcome::Locked { opcode: 0xD3, address: 0x0101 };
+        assert_eq!(gameboy.step_instruction(), locked);
+        assert_eq!(gameboy.step_instruction(), locked);
+
+        // The rest of the system keeps running.
+        let div = gameboy.read_byte(0xFF04);
+        gameboy.run_frame();
+        assert_eq!(gameboy.frames(), 1);
+        assert_ne!(gameboy.read_byte(0xFF04), div);
+        assert_eq!(gameboy.step_instruction(), locked);
+        assert_eq!(gameboy.registers().pc, 0x0102);
+    }
+
+    #[test]
+    fn test_oam_write_during_scan() {
+        let mut gameboy = GameBoy::from_rom(rom(&[
+            0xAF,             // xor A
+            0xE0, 0x40,       // ld ($FF40), A
+            0x3E, 0x42,       // ld A, $42
+            0xEA, 0x01, 0xFE, // ld ($FE01), A
+            0x3E, 0x91,       // ld A, $91
+            0xE0, 0x40,       // ld ($FF40), A
+            0xEA, 0x00, 0xFE, // ld ($FE00), A
+            0x18, 0xFE,       // jr $0111
+        ])).unwrap();
+
+        // The write with the LCD off goes through, the one during the OAM scan of line 0 is ignored.
+        for _ in 0..7 {
+            gameboy.step_instruction();
+        }
+        assert_eq!(gameboy.read_byte(0xFE00), 0x00);
+        assert_eq!(gameboy.read_byte(0xFE01), 0x42);
+    }
+
+    #[test]
+    fn test_stop() {
+        let mut gameboy = GameBoy::from_rom(rom(&[
+            0x3E, 0x01, // ld A, $01
+            0xE0, 0x4D, // ld ($FF4D), A
+            0x10, 0x00, // stop
+            0x18, 0xFE, // jr $0106
+        ])).unwrap();
+
+        // Without KEY1, preparing a speed switch does nothing.
+        for _ in 0..2 {
+            gameboy.step_instruction();
+        }
+        assert_eq!(gameboy.read_byte(0xFF4D), 0xFF);
+
+        // No buttons are pressed, so the CPU stays stopped.
+        gameboy.step_instruction();
+        assert!(gameboy.cpu.is_stopped());
+
+        // Blank frames are still presented at the usual rate.
+        for _ in 0..1000 {
+            gameboy.step_instruction();
+        }
+        assert_eq!(gameboy.frames(), 0);
+
+        gameboy.run_frame();
+        assert!(gameboy.cpu.is_stopped());
+        assert!(gameboy.framebuffer().iter().all(|&pixel| pixel == 0xFFFFFF));
+    }
+
+    #[test]
+    fn test_double_speed() {
+        let mut switching = rom(&[
+            0x3E, 0x01, // ld A, $01
+            0xE0, 0x4D, // ld ($FF4D), A
+            0x10, 0x00, // stop
+            0x18, 0xFE, // jr $0106
+        ]);
+        switching[0x143] = 0x80;
+
+        // Instructions executed during the next frame.
+        let steps_per_frame = |gameboy: &mut GameBoy| {
+            let frames = gameboy.frames();
+            let mut steps = 0u32;
+            while gameboy.frames() == frames {
+                gameboy.step_instruction();
+                steps += 1;
+            }
+            steps
+        };
+
+        let mut normal_rom = rom(&[0x18, 0xFE]);
+        normal_rom[0x143] = 0x80;
+
+        let mut gameboy = GameBoy::from_rom(normal_rom).unwrap();
+        gameboy.run_frame();
+        let normal = steps_per_frame(&mut gameboy);
+        assert_eq!(gameboy.read_byte(0xFF4D), 0x7E);
+
+        // DIV increments during a frame.
+        let div_per_frame = |gameboy: &mut GameBoy| {
+            let div = gameboy.read_byte(0xFF04);
+            gameboy.run_frame();
+            gameboy.read_byte(0xFF04).wrapping_sub(div)
+        };
+        let normal_div = div_per_frame(&mut gameboy);
+
+        // The prepared switch is performed instead of stopping, and the CPU runs twice as fast.
+        let mut gameboy = GameBoy::from_rom(switching.clone()).unwrap();
+        for _ in 0..3 {
+            gameboy.step_instruction();
+        }
+        assert!(!gameboy.cpu.is_stopped());
+        assert_eq!(gameboy.read_byte(0xFF4D), 0xFE);
+
+        gameboy.run_frame();
+        let double = steps_per_frame(&mut gameboy);
+        assert!(double.abs_diff(2 * normal) <= 1, "{double} steps per frame, {normal} at normal speed");
+
+        // So does the timer, which is clocked by the CPU.
+        let double_div = div_per_frame(&mut gameboy);
+        assert!(double_div.abs_diff(2 * normal_div) <= 1, "DIV {double_div} per frame, {normal_div} at normal speed");
+
+        // The speed is restored along with the CPU.
+        let state = gameboy.save_state();
+        let mut gameboy = GameBoy::from_rom(switching).unwrap();
+        gameboy.load_state(&state).unwrap();
+        assert!(steps_per_frame(&mut gameboy).abs_diff(double) <= 1);
+        assert!(div_per_frame(&mut gameboy).abs_diff(double_div) <= 1);
+    }
+
+    #[test]
+    fn test_speed() {
+        let mut gameboy = GameBoy::from_rom(rom(&[0x18, 0xFE])).unwrap();
+        assert_eq!(gameboy.clock.borrow().pace_target(), None);
+
+        gameboy.set_speed(Speed::Multiplier(100.0));
+        assert_eq!(gameboy.clock.borrow().pace_target(), Some(Duration::ZERO));
+
+        // Six frames take 100 ms on the hardware, so 1 ms at this speed, even with the LCD off.
+        for _ in 0..6 {
+            gameboy.run_frame();
+        }
+        let target = gameboy.clock.borrow().pace_target().unwrap();
+        assert_eq!(target.as_micros(), 1004);
+
+        gameboy.set_speed(Speed::Unthrottled);
+        assert_eq!(gameboy.clock.borrow().pace_target(), None);
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let program = [
+            0x3C,             // inc A
+            0xEA, 0x00, 0xC0, // ld ($C000), A
+            0x18, 0xFA,       // jr $0100
+        ];
+        let mut gameboy = GameBoy::from_rom(rom(&program)).unwrap();
+
+        gameboy.run_frame();
+        let state = gameboy.save_state();
+        let registers = gameboy.registers();
+
+        gameboy.run_frame();
+        assert_ne!(gameboy.registers(), registers);
+
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.registers(), registers);
+        assert_eq!(gameboy.read_byte(0xC000), registers.a);
+        assert_eq!(gameboy.frames(), 1);
+        assert_eq!(gameboy.save_state(), state);
+    }
+
+    #[test]
+    fn test_load_state_from_other_cartridge() {
+        let mut other = rom(&[0x18, 0xFE]);
+        other[0x14D] = 0xAA;
+
+        let state = GameBoy::from_rom(other).unwrap().save_state();
+
+        let mut gameboy = GameBoy::from_rom(rom(&[0x18, 0xFE])).unwrap();
+        gameboy.run_frame();
+        let before = gameboy.save_state();
+
+        assert!(gameboy.load_state(&state).is_err());
+        assert_eq!(gameboy.save_state(), before);
+    }
+}
@@ -0,0 +1,455 @@
+//! Best Effort Save State (BESS) import and export, for exchanging states with other emulators.
+//!
+//! A BESS file starts with whatever the emulator that wrote it likes, followed by raw memory
+//! dumps, a list of blocks and a footer:
+//!
+//! ```text
+//! ... | memory dumps | block* | "END " block | first block offset: u32 | "BESS"
+//! block = id: [u8; 4] | length: u32 | payload
+//! ```
+//!
+//! We write our own save state at the start, so a state exported here restores exactly when
+//! imported here. States from other emulators are restored from the CORE block and the dumps it
+//! points at, which loses whatever the format does not describe (e.g. the PPU's position within
+//! a line). MBC blocks are replayed onto the cartridge, and the RTC block restores the clock's
+//! registers; its timestamp is ignored, as our clock counts emulated time. Unknown blocks are
+//! ignored.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bus::*;
+use crate::cpu::RegisterState;
+use crate::gameboy::GameBoy;
+use crate::save_state::{StateError, Tag};
+
+const FOOTER_MAGIC: &[u8; 4] = b"BESS";
+
+const NAME_BLOCK: Tag = *b"NAME";
+const INFO_BLOCK: Tag = *b"INFO";
+const CORE_BLOCK: Tag = *b"CORE";
+const MBC_BLOCK: Tag = *b"MBC ";
+const RTC_BLOCK: Tag = *b"RTC ";
+const END_BLOCK: Tag = *b"END ";
+
+const CORE_MAJOR_VERSION: u16 = 1;
+const CORE_MINOR_VERSION: u16 = 1;
+const CORE_LENGTH: usize = 0xD0;
+
+// Offsets within the CORE block.
+const CORE_MODEL: usize = 0x04;
+const CORE_REGISTERS: usize = 0x08;
+const CORE_IME: usize = 0x14;
+const CORE_IE: usize = 0x15;
+const CORE_EXECUTION_STATE: usize = 0x16;
+const CORE_IO: usize = 0x18;
+const CORE_BUFFERS: usize = 0x98;
+
+// Memory dumps, in the order the CORE block lists them.
+const BUFFER_RAM: usize = 0;
+const BUFFER_VRAM: usize = 1;
+const BUFFER_MBC_RAM: usize = 2;
+const BUFFER_OAM: usize = 3;
+const BUFFER_HRAM: usize = 4;
+const BUFFERS: usize = 7;
+
+const EXECUTION_RUNNING: u8 = 0;
+const EXECUTION_HALTED: u8 = 1;
+const EXECUTION_STOPPED: u8 = 2;
+
+const RAM_SIZE: usize = 0x2000;
+const VRAM_SIZE: usize = 0x2000;
+const OAM_SIZE: usize = 0xA0;
+const HRAM_SIZE: usize = 0x7F;
+
+// The RTC block holds the counting and the latched registers as 32-bit values, followed by a
+// 64-bit UNIX timestamp.
+const RTC_LENGTH: usize = 0x30;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_block(out: &mut Vec<u8>, id: Tag, payload: &[u8]) {
+    out.extend(id);
+    out.extend((payload.len() as u32).to_le_bytes());
+    out.extend(payload);
+}
+
+/// Appends a memory dump, returning its size and offset for the CORE block.
+fn write_buffer(out: &mut Vec<u8>, bytes: &[u8]) -> (u32, u32) {
+    let offset = out.len() as u32;
+    out.extend(bytes);
+    (bytes.len() as u32, offset)
+}
+
+/// The blocks of a BESS file, up to the END block.
+fn parse_blocks(bytes: &[u8]) -> Result<Vec<(Tag, &[u8])>, StateError> {
+    if bytes.len() < 8 || !bytes.ends_with(FOOTER_MAGIC) {
+        return Err(StateError::NotAState);
+    }
+
+    let end = bytes.len() - 8;
+    let mut offset = read_u32(bytes, end) as usize;
+    let mut blocks = Vec::new();
+
+    loop {
+        if offset + 8 > end {
+            return Err(StateError::Truncated);
+        }
+
+        let id: Tag = bytes[offset..offset + 4].try_into().unwrap();
+        let length = read_u32(bytes, offset + 4) as usize;
+        let start = offset + 8;
+
+        if start + length > end {
+            return Err(StateError::Truncated);
+        }
+        if id == END_BLOCK {
+            return Ok(blocks);
+        }
+
+        blocks.push((id, &bytes[start..start + length]));
+        offset = start + length;
+    }
+}
+
+/// The RTC block, for the counting and the latched registers.
+fn rtc_block((registers, latched): &([Byte; 5], [Byte; 5])) -> Vec<u8> {
+    let mut rtc: Vec<u8> = registers.iter().chain(latched)
+        .flat_map(|register| (*register as u32).to_le_bytes())
+        .collect();
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    rtc.extend(timestamp.to_le_bytes());
+    rtc
+}
+
+/// A memory dump listed in the CORE block, checked against the file.
+fn buffer<'a>(bytes: &'a [u8], core: &[u8], index: usize) -> Result<&'a [u8], StateError> {
+    let size = read_u32(core, CORE_BUFFERS + index * 8) as usize;
+    let offset = read_u32(core, CORE_BUFFERS + index * 8 + 4) as usize;
+
+    bytes.get(offset..offset + size).ok_or(StateError::Truncated)
+}
+
+impl GameBoy {
+    /// Snapshots the whole system in the BESS format.
+    pub fn export_bess(&mut self) -> Vec<u8> {
+        let mut out = self.save_state();
+
+        let ram: Vec<Byte> = (0xC000..=0xDFFF).map(|address| self.ram.borrow().bus_read(address)).collect();
+        let hram: Vec<Byte> = (0xFF80..=0xFFFE).map(|address| self.hram.borrow().bus_read(address)).collect();
+
+        let mut buffers = [(0, 0); BUFFERS];
+        buffers[BUFFER_RAM] = write_buffer(&mut out, &ram);
+        buffers[BUFFER_VRAM] = write_buffer(&mut out, self.ppu.borrow().vram());
+        buffers[BUFFER_MBC_RAM] = write_buffer(&mut out, self.cartridge.borrow().ram());
+        buffers[BUFFER_OAM] = write_buffer(&mut out, self.ppu.borrow().oam());
+        buffers[BUFFER_HRAM] = write_buffer(&mut out, &hram);
+
+        let first_block = out.len() as u32;
+
+        let name = format!("Emerald {}", env!("CARGO_PKG_VERSION"));
+        write_block(&mut out, NAME_BLOCK, name.as_bytes());
+
+        // Title and global checksum from the cartridge header.
+        let info: Vec<Byte> = (0x134..0x144).chain(0x14E..0x150)
+            .map(|address| self.cartridge.borrow().bus_read(address))
+            .collect();
+        write_block(&mut out, INFO_BLOCK, &info);
+
+        write_block(&mut out, CORE_BLOCK, &self.core_block(&buffers));
+
+        let writes = self.cartridge.borrow().bank_writes();
+        if !writes.is_empty() {
+            let mbc: Vec<u8> = writes.iter()
+                .flat_map(|(address, value)| address.to_le_bytes().into_iter().chain([*value]))
+                .collect();
+            write_block(&mut out, MBC_BLOCK, &mbc);
+        }
+
+        if let Some(rtc) = self.cartridge.borrow().rtc() {
+            write_block(&mut out, RTC_BLOCK, &rtc_block(&rtc.borrow().registers()));
+        }
+
+        write_block(&mut out, END_BLOCK, &[]);
+
+        out.extend(first_block.to_le_bytes());
+        out.extend(FOOTER_MAGIC);
+        out
+    }
+
+    fn core_block(&self, buffers: &[(u32, u32); BUFFERS]) -> Vec<u8> {
+        let r = self.cpu.registers();
+        let join = |hi: Byte, lo: Byte| ((hi as Word) << 8) | lo as Word;
+
+        let execution_state = if self.cpu.is_stopped() {
+            EXECUTION_STOPPED
+        }
+        else if self.cpu.is_halted() {
+            EXECUTION_HALTED
+        }
+        else {
+            EXECUTION_RUNNING
+        };
+
+        let mut core = Vec::with_capacity(CORE_LENGTH);
+        core.extend(CORE_MAJOR_VERSION.to_le_bytes());
+        core.extend(CORE_MINOR_VERSION.to_le_bytes());
+        core.extend(b"GD  ");
+
+        for register in [r.pc, join(r.a, r.f), join(r.b, r.c), join(r.d, r.e), join(r.h, r.l), r.sp] {
+            core.extend(register.to_le_bytes());
+        }
+
+        core.extend([r.ime as u8, self.bus.read_byte(0xFFFF), execution_state, 0]);
+
+        // Registers we do not model read as open bus.
+        core.extend((0xFF00..=0xFF7F).map(|address| {
+            if self.bus.is_register_attached(address) { self.bus.read_byte(address) } else { 0xFF }
+        }));
+
+        for (size, offset) in buffers {
+            core.extend(size.to_le_bytes());
+            core.extend(offset.to_le_bytes());
+        }
+
+        core
+    }
+
+    /// Restores a BESS state, either exported by `export_bess` or by another emulator. The
+    /// system is left untouched if the state is rejected.
+    pub fn import_bess(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let blocks = parse_blocks(bytes)?;
+
+        let core = blocks.iter()
+            .find(|(id, _)| *id == CORE_BLOCK)
+            .map(|(_, payload)| *payload)
+            .ok_or(StateError::MissingSection(CORE_BLOCK))?;
+
+        if core.len() < CORE_LENGTH {
+            return Err(StateError::Truncated);
+        }
+
+        let major = read_u16(core, 0);
+        if major != CORE_MAJOR_VERSION {
+            return Err(StateError::UnsupportedVersion(major));
+        }
+
+        // DMG and SGB states only; the CGB has more memory and registers than we model.
+        let model = &core[CORE_MODEL..CORE_MODEL + 4];
+        if !matches!(model[0], b'G' | b'S') {
+            return Err(StateError::Invalid(format!("unsupported model {}", String::from_utf8_lossy(model))));
+        }
+
+        let ram = buffer(bytes, core, BUFFER_RAM)?;
+        let vram = buffer(bytes, core, BUFFER_VRAM)?;
+        let mbc_ram = buffer(bytes, core, BUFFER_MBC_RAM)?;
+        let oam = buffer(bytes, core, BUFFER_OAM)?;
+        let hram = buffer(bytes, core, BUFFER_HRAM)?;
+
+        if ram.len() < RAM_SIZE || vram.len() < VRAM_SIZE || oam.len() < OAM_SIZE || hram.len() < HRAM_SIZE {
+            return Err(StateError::Invalid(String::from("memory dumps are too small")));
+        }
+
+        // Our own exports carry a complete save state before the memory dumps.
+        let dumps_start = (0..BUFFERS)
+            .filter(|&index| read_u32(core, CORE_BUFFERS + index * 8) != 0)
+            .map(|index| read_u32(core, CORE_BUFFERS + index * 8 + 4) as usize)
+            .min()
+            .unwrap_or(0);
+
+        if self.load_state(&bytes[..dumps_start]).is_ok() {
+            return Ok(());
+        }
+
+        self.restore_core(core, &ram[..RAM_SIZE], &vram[..VRAM_SIZE], &oam[..OAM_SIZE], &hram[..HRAM_SIZE]);
+
+        self.restore_cartridge(&blocks, mbc_ram);
+
+        self.reschedule();
+        self.frame_pending = true;
+        Ok(())
+    }
+
+    fn restore_cartridge(&mut self, blocks: &[(Tag, &[u8])], ram: &[Byte]) {
+        let mut cartridge = self.cartridge.borrow_mut();
+
+        for (_, payload) in blocks.iter().filter(|(id, _)| *id == MBC_BLOCK) {
+            for write in payload.chunks_exact(3) {
+                let address = read_u16(write, 0);
+                cartridge.bus_write(&mut self.bus, address, write[2]);
+            }
+        }
+
+        // The dump may be smaller or larger than our RAM if the headers disagree.
+        let cartridge_ram = cartridge.ram_mut();
+        let length = cartridge_ram.len().min(ram.len());
+        cartridge_ram[..length].copy_from_slice(&ram[..length]);
+
+        let payload = blocks.iter().find(|(id, _)| *id == RTC_BLOCK).map(|(_, payload)| *payload);
+        if let (Some(rtc), Some(payload)) = (cartridge.rtc(), payload) {
+            if payload.len() >= RTC_LENGTH {
+                let register = |index: usize| read_u32(payload, index * 4) as Byte;
+                rtc.borrow_mut().restore(std::array::from_fn(register), std::array::from_fn(|i| register(i + 5)));
+            }
+        }
+    }
+
+    fn restore_core(&mut self, core: &[u8], ram: &[Byte], vram: &[Byte], oam: &[Byte], hram: &[Byte]) {
+        let register = |index: usize| read_u16(core, CORE_REGISTERS + index * 2);
+        let split = |value: Word| ((value >> 8) as Byte, value as Byte);
+
+        let (a, f) = split(register(1));
+        let (b, c) = split(register(2));
+        let (d, e) = split(register(3));
+        let (h, l) = split(register(4));
+
+        self.cpu.set_registers(&RegisterState {
+            a, f, b, c, d, e, h, l,
+            sp: register(5),
+            pc: register(0),
+            ime: core[CORE_IME] != 0,
+        });
+        self.cpu.set_halted(core[CORE_EXECUTION_STATE] == EXECUTION_HALTED);
+        self.cpu.set_stopped(core[CORE_EXECUTION_STATE] == EXECUTION_STOPPED);
+
+        let io = &core[CORE_IO..CORE_IO + 0x80];
+
+        // Writes conflicting with a transfer in progress would be dropped.
+        self.bus.set_oam_dma(None);
+
+        self.bus.write_byte(0xFFFF, core[CORE_IE]);
+        self.bus.write_byte(0xFF0F, io[0x0F]);
+        self.bus.write_byte(0xFF00, io[0x00]);
+
+        for address in 0xFF10..=0xFF3F {
+            self.audio.borrow_mut().bus_write(&mut self.bus, address, io[address as usize - 0xFF00]);
+        }
+
+        self.serial.borrow_mut().restore(io[0x01], io[0x02]);
+        self.timer.borrow_mut().restore(io[0x04], io[0x05], io[0x06], io[0x07]);
+        self.ppu.borrow_mut().restore(io[0x40..0x4C].try_into().unwrap(), vram, oam);
+
+        for (value, address) in ram.iter().zip(0xC000..) {
+            self.ram.borrow_mut().bus_write(&mut self.bus, address, *value);
+        }
+        for (value, address) in hram.iter().zip(0xFF80..) {
+            self.hram.borrow_mut().bus_write(&mut self.bus, address, *value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gameboy::GameBoy;
+    use crate::save_state::StateError;
+    use super::{read_u32, write_block, END_BLOCK};
+
+    fn rom() -> Vec<u8> {
+        let program = [
+            0x3C,             // inc A
+            0xEA, 0x00, 0xC0, // ld ($C000), A
+            0xE0, 0x90,       // ld ($FF90), A
+            0x18, 0xF8,       // jr $0100
+        ];
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        rom
+    }
+
+    #[test]
+    fn test_import_from_core_block() {
+        let mut gameboy = GameBoy::from_rom(rom()).unwrap();
+        gameboy.run_frame();
+        let registers = gameboy.registers();
+
+        // Without our own state at the start, the import has to go through the CORE block.
+        let mut state = gameboy.export_bess();
+        state[..8].fill(0);
+
+        let mut other = GameBoy::from_rom(rom()).unwrap();
+        other.import_bess(&state).unwrap();
+
+        assert_eq!(other.registers(), registers);
+        assert_eq!(other.read_byte(0xC000), gameboy.read_byte(0xC000));
+        assert_eq!(other.read_byte(0xFF90), gameboy.read_byte(0xFF90));
+        assert_eq!(other.read_byte(0xFF40), gameboy.read_byte(0xFF40));
+        assert_eq!(other.read_byte(0xFF47), gameboy.read_byte(0xFF47));
+    }
+
+    #[test]
+    fn test_import_cartridge_state() {
+        let program = [
+            0x3E, 0x0A,       // ld A, $0A
+            0xEA, 0x00, 0x00, // ld ($0000), A
+            0x3E, 0x02,       // ld A, $02
+            0xEA, 0x00, 0x20, // ld ($2000), A
+            0x3E, 0x42,       // ld A, $42
+            0xEA, 0x00, 0xA0, // ld ($A000), A
+            0x3E, 0x08,       // ld A, $08
+            0xEA, 0x00, 0x40, // ld ($4000), A
+            0x3E, 0x2A,       // ld A, $2A
+            0xEA, 0x00, 0xA0, // ld ($A000), A
+            0x18, 0xFE,       // jr $0119
+        ];
+
+        // MBC3+TIMER+RAM+BATTERY, with each ROM bank starting with its number.
+        let mut rom = vec![0; 4 * 0x4000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        rom[0x147] = 0x10;
+        rom[0x149] = 0x03;
+        for bank in 1..4 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+
+        let mut gameboy = GameBoy::from_rom(rom.clone()).unwrap();
+        gameboy.run_frame();
+
+        let mut state = gameboy.export_bess();
+        state[..8].fill(0);
+
+        let mut other = GameBoy::from_rom(rom).unwrap();
+        other.import_bess(&state).unwrap();
+
+        // ROM bank 2, the RTC seconds in place of RAM, and the RAM behind them.
+        assert_eq!(other.read_byte(0x4000), 2);
+        assert_eq!(other.read_byte(0xA000), 0x2A);
+        assert_eq!(other.cartridge.borrow().ram()[0], 0x42);
+    }
+
+    #[test]
+    fn test_skip_unknown_blocks() {
+        let mut gameboy = GameBoy::from_rom(rom()).unwrap();
+        gameboy.run_frame();
+
+        let state = gameboy.export_bess();
+        let registers = gameboy.registers();
+
+        // Insert an unknown block before the others.
+        let first_block = read_u32(&state, state.len() - 8) as usize;
+        let mut edited = state[..first_block].to_vec();
+        write_block(&mut edited, *b"XYZW", &[1, 2, 3]);
+        edited.extend(&state[first_block..state.len() - 8]);
+        edited.extend((first_block as u32).to_le_bytes());
+        edited.extend(b"BESS");
+
+        gameboy.run_frame();
+        gameboy.import_bess(&edited).unwrap();
+        assert_eq!(gameboy.registers(), registers);
+
+        // A state with nothing but an END block has no CORE block to restore from.
+        let mut empty = Vec::new();
+        write_block(&mut empty, END_BLOCK, &[]);
+        empty.extend(0u32.to_le_bytes());
+        empty.extend(b"BESS");
+
+        assert_eq!(gameboy.import_bess(&empty), Err(StateError::MissingSection(*b"CORE")));
+        assert_eq!(gameboy.import_bess(b"not a state"), Err(StateError::NotAState));
+    }
+}
@@ -12,7 +12,14 @@ pub fn encode_rgb(r: u8, g: u8, b: u8) -> u32 {
 pub trait GraphicsDriver {
     fn draw(&mut self, y: u16, x: u16, colour: u32);
     fn render(&mut self, pixel_buffer: &[u32]);
+
+    /// Invoked instead of render when the LCD does not output a frame (display disabled or the
+    /// first frame after it is re-enabled). The screen should be filled with the given colour.
+    fn blank(&mut self, colour: u32);
     fn is_closed(&self) -> bool;
+
+    /// Shows the emulator's status, for drivers with a window.
+    fn set_title(&mut self, title: &str) {}
 }
 
 pub type GraphicsDriverCell = Box<dyn GraphicsDriver>;
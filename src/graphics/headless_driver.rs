@@ -0,0 +1,91 @@
+use std::io;
+use std::io::Write;
+
+use crate::graphics::graphics_driver::*;
+use crate::ppu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::{JoypadButtons, JoypadDriver};
+
+/// Driver for running without a display: frames are counted and dropped, and no buttons are ever
+/// pressed. Reports itself closed once the requested number of frames has been rendered.
+pub struct HeadlessDriver {
+    frames: u64,
+    frame_limit: Option<u64>,
+}
+
+impl HeadlessDriver {
+    pub fn new(frame_limit: Option<u64>) -> Self {
+        Self {
+            frames: 0,
+            frame_limit,
+        }
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+}
+
+impl GraphicsDriver for HeadlessDriver {
+    fn draw(&mut self, y: u16, x: u16, colour: u32) {}
+
+    fn render(&mut self, pixel_buffer: &[u32]) {
+        self.frames += 1;
+    }
+
+    fn blank(&mut self, colour: u32) {
+        self.frames += 1;
+    }
+
+    fn is_closed(&self) -> bool {
+        self.frame_limit.is_some_and(|limit| self.frames >= limit)
+    }
+}
+
+impl JoypadDriver for HeadlessDriver {
+    fn get_buttons(&mut self) -> Vec<JoypadButtons> {
+        Vec::new()
+    }
+}
+
+/// Writes a 160x144 framebuffer as a binary PPM image.
+pub fn write_ppm(pixels: &[u32], out: &mut dyn Write) -> io::Result<()> {
+    write!(out, "P6\n{} {}\n255\n", DISPLAY_WIDTH, DISPLAY_HEIGHT)?;
+
+    let bytes: Vec<u8> = pixels.iter()
+        .flat_map(|&pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8])
+        .collect();
+
+    out.write_all(&bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_ppm, HeadlessDriver};
+    use crate::graphics::graphics_driver::GraphicsDriver;
+
+    #[test]
+    fn test_frame_limit() {
+        let mut driver = HeadlessDriver::new(Some(2));
+
+        driver.render(&[0; 160 * 144]);
+        assert!(!driver.is_closed());
+
+        driver.blank(0xFFFFFF);
+        assert!(driver.is_closed());
+        assert_eq!(driver.frames(), 2);
+    }
+
+    #[test]
+    fn test_write_ppm() {
+        let mut pixels = vec![0; 160 * 144];
+        pixels[0] = 0x123456;
+
+        let mut out = Vec::new();
+        write_ppm(&pixels, &mut out).unwrap();
+
+        let header = b"P6\n160 144\n255\n";
+        assert_eq!(&out[..header.len()], header);
+        assert_eq!(&out[header.len()..header.len() + 3], &[0x12, 0x34, 0x56]);
+        assert_eq!(out.len(), header.len() + 160 * 144 * 3);
+    }
+}
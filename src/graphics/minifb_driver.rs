@@ -1,7 +1,7 @@
 extern crate minifb;
 
 use crate::graphics::graphics_driver::*;
-use minifb::{Key, Scale, Window, WindowOptions};
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
 use crate::{JoypadButtons, JoypadDriver};
 use crate::joypad::{Button, Direction};
 
@@ -10,6 +10,9 @@ pub struct MiniFbDriver {
     height: u16,
     width: u16,
     disable_pause: bool,
+
+    // Keys are only polled when the window is updated, so each hotkey press is reported once.
+    hotkeys_reported: Vec<Key>,
 }
 
 impl MiniFbDriver {
@@ -29,9 +32,19 @@ impl MiniFbDriver {
             window,
             height,
             width,
-            disable_pause: false
+            disable_pause: false,
+            hotkeys_reported: Vec::new(),
         }
     }
+
+    fn hotkey_pressed(&mut self, key: Key) -> bool {
+        if self.hotkeys_reported.contains(&key) || !self.window.is_key_pressed(key, KeyRepeat::No) {
+            return false;
+        }
+
+        self.hotkeys_reported.push(key);
+        true
+    }
 }
 
 impl GraphicsDriver for MiniFbDriver {
@@ -47,16 +60,26 @@ impl GraphicsDriver for MiniFbDriver {
             .unwrap();
 
         self.disable_pause = false;
+        self.hotkeys_reported.clear();
+    }
+
+    fn blank(&mut self, colour: u32) {
+        let buffer = vec![colour; self.width as usize * self.height as usize];
+        self.render(&buffer);
     }
 
     fn is_closed(&self) -> bool {
         !self.window.is_open()
     }
+
+    fn set_title(&mut self, title: &str) {
+        self.window.set_title(title);
+    }
 }
 
 impl JoypadDriver for MiniFbDriver {
     fn get_buttons(&mut self) -> Vec<JoypadButtons> {
-        self.window.get_keys().iter().filter_map(|&key| {
+        let mut buttons = self.window.get_keys().iter().filter_map(|&key| {
             match key {
                 Key::W => Some(JoypadButtons::Direction(Direction::Up)),
                 Key::A => Some(JoypadButtons::Direction(Direction::Left)),
@@ -66,6 +89,8 @@ impl JoypadDriver for MiniFbDriver {
                 Key::E => Some(JoypadButtons::Button(Button::B)),
                 Key::Key1 => Some(JoypadButtons::Button(Button::Start)),
                 Key::Key2 => Some(JoypadButtons::Button(Button::Select)),
+                Key::Backspace => Some(JoypadButtons::Rewind),
+                Key::Tab => Some(JoypadButtons::FastForward),
                 Key::F2 => {
                     if self.disable_pause {
                         return None;
@@ -76,6 +101,26 @@ impl JoypadDriver for MiniFbDriver {
                 _ => None
             }
         }
-        ).collect::<Vec<JoypadButtons>>()
+        ).collect::<Vec<JoypadButtons>>();
+
+        let hotkeys = [
+            (Key::F5, JoypadButtons::SaveState),
+            (Key::F6, JoypadButtons::PreviousSlot),
+            (Key::F7, JoypadButtons::NextSlot),
+            (Key::F8, JoypadButtons::LoadState),
+            (Key::F12, JoypadButtons::Screenshot),
+            (Key::Minus, JoypadButtons::SpeedDown),
+            (Key::Equal, JoypadButtons::SpeedUp),
+            (Key::P, JoypadButtons::TogglePause),
+            (Key::N, JoypadButtons::FrameAdvance),
+        ];
+
+        for (key, hotkey) in hotkeys {
+            if self.hotkey_pressed(key) {
+                buttons.push(hotkey);
+            }
+        }
+
+        buttons
     }
 }
@@ -1,2 +1,4 @@
 pub mod graphics_driver;
+pub mod headless_driver;
 pub mod minifb_driver;
+pub mod screenshot;
@@ -0,0 +1,131 @@
+use std::io;
+use std::io::{Read, Write};
+
+use crate::graphics::graphics_driver::encode_rgb;
+use crate::ppu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+const PIXELS: usize = DISPLAY_WIDTH as usize * DISPLAY_HEIGHT as usize;
+
+/// Frame that differs from its reference image.
+pub struct Mismatch {
+    pub pixels: usize,
+
+    /// The frame, faded, with the differing pixels in red.
+    pub diff: Vec<u32>,
+}
+
+/// Writes a 160x144 framebuffer as an RGB PNG image.
+pub fn write_png(pixels: &[u32], out: &mut dyn Write) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(out, DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let bytes: Vec<u8> = pixels.iter()
+        .flat_map(|&pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8])
+        .collect();
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&bytes)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Reads a 160x144 PNG image into a framebuffer.
+pub fn read_png(input: &mut dyn Read) -> io::Result<Vec<u32>> {
+    let mut decoder = png::Decoder::new(input);
+
+    // Palettes and bit depths other than 8 are expanded to 8-bit grey or RGB(A).
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+
+    if info.width != DISPLAY_WIDTH as u32 || info.height != DISPLAY_HEIGHT as u32 {
+        let error = format!("Expected a 160x144 image, got {}x{}", info.width, info.height);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+    }
+
+    let channels = info.color_type.samples();
+    let pixels = buffer[..info.buffer_size()]
+        .chunks_exact(channels)
+        .map(|pixel| match pixel {
+            [grey] | [grey, _] => encode_rgb(*grey, *grey, *grey),
+            [r, g, b] | [r, g, b, _] => encode_rgb(*r, *g, *b),
+            _ => unreachable!(),
+        })
+        .collect();
+
+    Ok(pixels)
+}
+
+/// Shade from 0 (white) to 3 (black). Reference images from other emulators use different
+/// greys, so frames are compared by shade rather than by colour.
+fn shade(pixel: u32) -> u8 {
+    let level = ((pixel >> 16 & 0xFF) + (pixel >> 8 & 0xFF) + (pixel & 0xFF)) / 3;
+
+    match level {
+        0xE0..=0xFF => 0,
+        0x80..=0xDF => 1,
+        0x20..=0x7F => 2,
+        _ => 3,
+    }
+}
+
+/// Compares a frame against a reference image, returning the differences if there are any.
+pub fn compare(frame: &[u32], reference: &[u32]) -> Option<Mismatch> {
+    assert_eq!(frame.len(), PIXELS);
+    assert_eq!(reference.len(), PIXELS);
+
+    let mut pixels = 0;
+    let diff = frame.iter().zip(reference).map(|(&actual, &expected)| {
+        if shade(actual) == shade(expected) {
+            // Fade towards white so that the differences stand out.
+            let level = 0xC0 + (3 - shade(actual) as u32) * 0x15;
+            level << 16 | level << 8 | level
+        }
+        else {
+            pixels += 1;
+            0xFF0000
+        }
+    }).collect();
+
+    if pixels == 0 {
+        None
+    }
+    else {
+        Some(Mismatch { pixels, diff })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compare, read_png, write_png, PIXELS};
+
+    #[test]
+    fn test_png_round_trip() {
+        let palette = [0xFFFFFF, 0xC0C0C0, 0x404040, 0x000000];
+        let pixels: Vec<u32> = (0..PIXELS).map(|i| palette[i % 4]).collect();
+
+        let mut png = Vec::new();
+        write_png(&pixels, &mut png).unwrap();
+
+        assert_eq!(read_png(&mut png.as_slice()).unwrap(), pixels);
+    }
+
+    #[test]
+    fn test_compare_by_shade() {
+        let frame = vec![0xC0C0C0; PIXELS];
+        let mut reference = vec![0xAAAAAA; PIXELS];
+
+        // Different greys for the same shade match.
+        assert!(compare(&frame, &reference).is_none());
+
+        reference[10] = 0x555555;
+        let mismatch = compare(&frame, &reference).unwrap();
+
+        assert_eq!(mismatch.pixels, 1);
+        assert_eq!(mismatch.diff[10], 0xFF0000);
+        assert_ne!(mismatch.diff[11], 0xFF0000);
+    }
+}
@@ -1,6 +1,7 @@
 use std::cell::RefCell;
 use std::rc::Rc;
-use crate::{Address, Attach, Bus, BusListener, Byte, Debugger};
+use crate::{Address, Attach, Bus, BusListener, Byte};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 const SELECT_BUTTONS: u8 = 1 << 5;
 const SELECT_DPAD: u8 = 1 << 4;
@@ -40,7 +41,38 @@ pub enum Direction {
 pub enum JoypadButtons {
     Button(Button),
     Direction(Direction),
-    Pause
+    Pause,
+    Screenshot,
+    SaveState,
+    LoadState,
+    PreviousSlot,
+    NextSlot,
+
+    /// Held to step back in time.
+    Rewind,
+
+    /// Held to run unthrottled.
+    FastForward,
+    SpeedDown,
+    SpeedUp,
+
+    /// Stops emulation, unlike `Pause` which breaks into the debugger.
+    TogglePause,
+    FrameAdvance
+}
+
+impl JoypadButtons {
+    /// The buttons held in a state returned by `Joypad::buttons`.
+    pub fn from_state(state: Byte) -> Vec<JoypadButtons> {
+        let buttons = [Button::Start, Button::Select, Button::A, Button::B].into_iter()
+            .filter(|&button| (state >> BUTTONS_SHIFT) & button as u8 != 0)
+            .map(JoypadButtons::Button);
+        let directions = [Direction::Down, Direction::Up, Direction::Left, Direction::Right].into_iter()
+            .filter(|&direction| (state >> DPAD_SHIFT) & direction as u8 != 0)
+            .map(JoypadButtons::Direction);
+
+        buttons.chain(directions).collect()
+    }
 }
 
 pub trait JoypadDriver {
@@ -88,6 +120,18 @@ impl BusListener for Joypad {
     }
 }
 
+/// Only the select lines are saved; the buttons belong to whoever is holding the controller.
+impl SaveState for Joypad {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.joypad_register);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.joypad_register = input.u8()?;
+        Ok(())
+    }
+}
+
 impl Joypad {
     pub fn new() -> Self {
         Self {
@@ -96,14 +140,20 @@ impl Joypad {
         }
     }
 
-    pub fn update(&mut self, driver: &mut dyn JoypadDriver, debugger: &mut Debugger) {
+    /// Pressed buttons in the high nibble and directions in the low nibble, 1 = pressed.
+    pub fn buttons(&self) -> Byte {
+        self.buttons
+    }
+
+    /// Replaces the set of pressed buttons. Hotkeys are handled by the frontend and ignored here.
+    pub fn set_buttons(&mut self, input: &[JoypadButtons]) {
         let mut buttons: u8 = 0;
         let mut directions: u8 = 0;
-        driver.get_buttons().iter().for_each(|input | {
+        input.iter().for_each(|input | {
             match input {
                 JoypadButtons::Button(button) => buttons |= *button as u8,
                 JoypadButtons::Direction(direction) => directions |= *direction as u8,
-                JoypadButtons::Pause => debugger.stop(),
+                _ => {},
             }
         });
         self.buttons = (buttons << BUTTONS_SHIFT) | (directions << DPAD_SHIFT);
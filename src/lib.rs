@@ -0,0 +1,47 @@
+#![deny(elided_lifetimes_in_paths)]
+//#![feature(exclusive_range_pattern)]
+#![allow(non_snake_case)]
+#![allow(unused_variables)]
+
+mod bus;
+use crate::bus::*;
+
+mod timer;
+
+mod cpu;
+use crate::cpu::*;
+
+pub mod ppu;
+use crate::ppu::*;
+
+pub mod graphics;
+
+mod cartridge;
+
+pub mod debug;
+pub mod serial;
+mod clock;
+
+pub mod joypad;
+pub mod link;
+mod ram;
+
+pub mod save_state;
+pub mod rewind;
+pub mod movie;
+
+mod gameboy;
+
+#[cfg(test)]
+mod test;
+
+use crate::joypad::*;
+use crate::graphics::*;
+#[cfg(test)]
+use crate::ram::{DummyRAM, RAM};
+
+pub use crate::bus::{Address, Byte, Word};
+pub use crate::cartridge::CartridgeError;
+pub use crate::clock::Speed;
+pub use crate::cpu::{RegisterState, StepOutcome};
+pub use crate::gameboy::GameBoy;
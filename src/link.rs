@@ -0,0 +1,252 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+use log::info;
+
+use crate::bus::*;
+
+// The two instances exchange messages every this many T-cycles, and neither runs more than one
+// window ahead of the other. Two windows fit in a single byte transfer at 8192 Hz, so the reply
+// to a transfer always arrives before the transfer would have completed.
+const LINK_WINDOW_CYCLES: u32 = 2048;
+
+// How long to wait for the peer to reach a window boundary before giving up on it.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MESSAGE_START: u8 = 1;
+const MESSAGE_REPLY: u8 = 2;
+const MESSAGE_SYNC: u8 = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LinkMessage {
+    /// The peer started a transfer on its internal clock, sending this byte.
+    Start(Byte),
+
+    /// The peer's answer to a transfer we started.
+    Reply(Byte),
+}
+
+trait LinkStream: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl LinkStream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl LinkStream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Link cable between two emulator instances, over TCP (`host:port`) or a Unix socket
+/// (`unix:path`).
+///
+/// Messages are only exchanged at window boundaries, and each side waits for the other to reach
+/// the same boundary. Messages are therefore always seen at the same point in emulated time,
+/// regardless of how fast either instance runs. A peer that doesn't reach the boundary within
+/// the timeout is considered disconnected.
+pub struct LinkCable {
+    stream: Box<dyn LinkStream>,
+    timeout: Duration,
+    cycles: u32,
+    outgoing: Vec<LinkMessage>,
+}
+
+impl LinkCable {
+    fn new(stream: Box<dyn LinkStream>) -> io::Result<Self> {
+        stream.set_read_timeout(Some(DEFAULT_TIMEOUT))?;
+
+        Ok(Self {
+            stream,
+            timeout: DEFAULT_TIMEOUT,
+            cycles: 0,
+            outgoing: Vec::new(),
+        })
+    }
+
+    /// Waits for the other instance to connect.
+    pub fn listen(address: &str) -> io::Result<Self> {
+        info!("Waiting for link cable connection on {address}...");
+
+        let stream: Box<dyn LinkStream> = match address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => {
+                // Remove the socket left behind by a previous session.
+                let _ = std::fs::remove_file(path);
+                Box::new(UnixListener::bind(path)?.accept()?.0)
+            },
+            #[cfg(not(unix))]
+            Some(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not supported")),
+            None => return Self::accept(&TcpListener::bind(address)?),
+        };
+
+        info!("Link cable connected.");
+        Self::new(stream)
+    }
+
+    /// Waits for the other instance to connect to a listener that is already bound.
+    pub fn accept(listener: &TcpListener) -> io::Result<Self> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+
+        info!("Link cable connected.");
+        Self::new(Box::new(stream))
+    }
+
+    pub fn connect(address: &str) -> io::Result<Self> {
+        let stream: Box<dyn LinkStream> = match address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Box::new(UnixStream::connect(path)?),
+            #[cfg(not(unix))]
+            Some(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not supported")),
+            None => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            },
+        };
+
+        info!("Link cable connected to {address}.");
+        Self::new(stream)
+    }
+
+    /// Sets how long to wait for the peer at each window boundary.
+    pub fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Queues a message for the next window boundary.
+    pub fn send(&mut self, message: LinkMessage) {
+        self.outgoing.push(message);
+    }
+
+    /// Returns the number of T-cycles until the next exchange with the peer.
+    pub fn cycles_until_exchange(&self) -> u32 {
+        LINK_WINDOW_CYCLES - self.cycles
+    }
+
+    /// Advances the cable by the given number of T-cycles. When a window boundary is crossed,
+    /// the queued messages are exchanged and the messages sent by the peer are returned.
+    pub fn tick(&mut self, cycles: u32) -> io::Result<Vec<LinkMessage>> {
+        self.cycles += cycles;
+
+        if self.cycles < LINK_WINDOW_CYCLES {
+            return Ok(Vec::new());
+        }
+
+        self.cycles -= LINK_WINDOW_CYCLES;
+        self.exchange()
+    }
+
+    fn exchange(&mut self) -> io::Result<Vec<LinkMessage>> {
+        let mut buffer = Vec::with_capacity(2 * self.outgoing.len() + 2);
+
+        for message in self.outgoing.drain(..) {
+            match message {
+                LinkMessage::Start(data) => buffer.extend([MESSAGE_START, data]),
+                LinkMessage::Reply(data) => buffer.extend([MESSAGE_REPLY, data]),
+            }
+        }
+
+        buffer.extend([MESSAGE_SYNC, 0]);
+        self.stream.write_all(&buffer)?;
+        self.stream.flush()?;
+
+        let mut incoming = Vec::new();
+
+        loop {
+            let mut message = [0u8; 2];
+            self.stream.read_exact(&mut message).map_err(|e| match e.kind() {
+                // Depending on the platform, a read timeout is reported as either of these.
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("No answer from the link partner within {:?}", self.timeout),
+                ),
+                _ => e,
+            })?;
+
+            match message {
+                [MESSAGE_START, data] => incoming.push(LinkMessage::Start(data)),
+                [MESSAGE_REPLY, data] => incoming.push(LinkMessage::Reply(data)),
+                [MESSAGE_SYNC, _] => return Ok(incoming),
+                [kind, _] => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown link message {kind:02X}")));
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::thread;
+    use std::io;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use super::{LinkCable, LinkMessage, LINK_WINDOW_CYCLES};
+
+    #[test]
+    fn test_exchange_at_window_boundary() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let peer = thread::spawn(move || {
+            let mut cable = LinkCable::accept(&listener).unwrap();
+            cable.send(LinkMessage::Reply(0x24));
+
+            let mut received = Vec::new();
+            for _ in 0..LINK_WINDOW_CYCLES / 4 {
+                received.extend(cable.tick(4).unwrap());
+            }
+            received
+        });
+
+        // The listener is bound, so the connection is queued until the peer accepts it.
+        let mut cable = LinkCable::connect(&address).unwrap();
+        cable.send(LinkMessage::Start(0x42));
+
+        // Nothing is exchanged before the end of the window.
+        for _ in 0..LINK_WINDOW_CYCLES / 4 - 1 {
+            assert!(cable.tick(4).unwrap().is_empty());
+        }
+
+        assert_eq!(cable.cycles_until_exchange(), 4);
+        assert_eq!(cable.tick(4).unwrap(), vec![LinkMessage::Reply(0x24)]);
+        assert_eq!(cable.cycles_until_exchange(), LINK_WINDOW_CYCLES);
+        assert_eq!(peer.join().unwrap(), vec![LinkMessage::Start(0x42)]);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // The peer stays connected, but never reaches the window boundary.
+        let (done, wait) = mpsc::channel::<()>();
+        let peer = thread::spawn(move || {
+            let _cable = LinkCable::accept(&listener).unwrap();
+            let _ = wait.recv();
+        });
+
+        let mut cable = LinkCable::connect(&address).unwrap();
+        cable.set_timeout(Duration::from_millis(50)).unwrap();
+
+        let error = cable.tick(LINK_WINDOW_CYCLES).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+        done.send(()).unwrap();
+        peer.join().unwrap();
+    }
+}
@@ -1,59 +1,87 @@
 #![deny(elided_lifetimes_in_paths)]
-//#![feature(exclusive_range_pattern)]
-#![allow(non_snake_case)]
-#![allow(unused_variables)]
 
-mod bus;
-use crate::bus::*;
+use emerald::debug::Debugger;
+use emerald::graphics::graphics_driver::GraphicsDriver;
+use emerald::graphics::headless_driver::{write_ppm, HeadlessDriver};
+use emerald::graphics::minifb_driver::MiniFbDriver;
+use emerald::graphics::screenshot::write_png;
+use emerald::joypad::{JoypadButtons, JoypadDriver};
+use emerald::link::LinkCable;
+use emerald::movie;
+use emerald::movie::{Movie, MoviePlayer};
+use emerald::ppu::{DISPLAY_HEIGHT, DISPLAY_WIDTH, Renderer};
+use emerald::rewind::RewindBuffer;
+use emerald::save_state::StateError;
+use emerald::{GameBoy, Speed, StepOutcome};
 
-mod timer;
-use crate::timer::*;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
 
-mod cpu;
-use crate::cpu::*;
+const STATE_SLOTS: u8 = 10;
 
-mod ppu;
-use crate::ppu::*;
+// Rewind history: a snapshot every `rewind_interval` frames, with a keyframe once a second.
+const DEFAULT_REWIND_BUDGET_MB: usize = 64;
+const REWIND_KEYFRAME_INTERVAL: usize = 60;
 
-mod graphics;
+// Speeds stepped through by the speed hotkeys, starting at full speed.
+const SPEEDS: [Speed; 5] = [
+    Speed::Multiplier(0.5),
+    Speed::Multiplier(1.0),
+    Speed::Multiplier(2.0),
+    Speed::Multiplier(4.0),
+    Speed::Unthrottled,
+];
+const DEFAULT_SPEED: usize = 1;
 
-mod cartridge;
+// How often the window is polled for input while paused.
+const PAUSED_POLL_INTERVAL: Duration = Duration::from_millis(16);
 
-mod debug;
-mod serial;
-mod clock;
 
-mod joypad;
-mod ram;
+struct Options {
+    enable_debugger: bool,
+    enable_trace: bool,
+    enable_serial: bool,
+    raster_log_path: Option<String>,
+    renderer: Renderer,
+    link: Option<Link>,
 
-use crate::debug::Debugger;
-use crate::joypad::*;
-use crate::clock::Clock;
-use crate::graphics::*;
-use crate::minifb_driver::MiniFbDriver;
-use crate::cartridge::load;
-use crate::graphics_driver::GraphicsDriver;
-use crate::InterruptType::Joypad;
-use crate::ram::{DummyRAM, RAM, RegisterHoles};
+    // Run without a window, for the given number of frames (or until the debugger quits).
+    headless: bool,
+    frames: Option<u64>,
+    dump_path: Option<String>,
 
-use std::borrow::BorrowMut;
+    // Frame to take a screenshot at, and where to save it.
+    screenshot: Option<(u64, String)>,
 
-use std::env;
-use std::cell::RefCell;
-use std::rc::Rc;
+    load_state_path: Option<String>,
+    save_bess_path: Option<String>,
 
-fn rc<T>(t: T) -> Rc<RefCell<T>> {
-    Rc::new(RefCell::new(t))
-}
+    // Memory for the rewind history (0 disables rewinding), and frames between snapshots.
+    rewind_budget_mb: usize,
+    rewind_interval: u64,
 
+    record_movie_path: Option<String>,
+    play_movie_path: Option<String>,
 
-struct Options {
-    enable_debugger: bool,
-    enable_trace: bool,
-    enable_serial: bool,
     cartridge_path: String,
 }
 
+/// Input movie being recorded or played back.
+enum MovieMode {
+    Record(Movie),
+    Play(MoviePlayer),
+}
+
+enum Link {
+    Listen(String),
+    Connect(String),
+}
+
 fn parse_args() -> Options {
     let args: Vec<String> = env::args().collect();
 
@@ -61,106 +89,412 @@ fn parse_args() -> Options {
         enable_debugger: false,
         enable_trace: false,
         enable_serial: false,
+        raster_log_path: None,
+        renderer: Renderer::Fifo,
+        link: None,
+        headless: false,
+        frames: None,
+        dump_path: None,
+        screenshot: None,
+        load_state_path: None,
+        save_bess_path: None,
+        rewind_budget_mb: DEFAULT_REWIND_BUDGET_MB,
+        rewind_interval: 1,
+        record_movie_path: None,
+        play_movie_path: None,
         cartridge_path: args.last().expect("Expected a file path.").clone(),
     };
 
-    for arg in &args {
+    let mut args_iter = args.iter();
+
+    while let Some(arg) = args_iter.next() {
         match arg.as_str() {
             "-d" | "--enable-debugger" => options.enable_debugger = true,
             "-t" | "--enable-trace" => options.enable_trace = true,
             "-s" | "--enable-serial" => options.enable_serial = true,
+            "--raster-log" => {
+                let path = args_iter.next().expect("Expected a path after --raster-log.");
+                options.raster_log_path = Some(path.clone());
+            },
+            "--link-listen" => {
+                let address = args_iter.next().expect("Expected an address after --link-listen.");
+                options.link = Some(Link::Listen(address.clone()));
+            },
+            "--link-connect" => {
+                let address = args_iter.next().expect("Expected an address after --link-connect.");
+                options.link = Some(Link::Connect(address.clone()));
+            },
+            "--headless" => options.headless = true,
+            "--frames" => {
+                let frames = args_iter.next().and_then(|s| s.parse().ok());
+                options.frames = Some(frames.expect("Expected a frame count after --frames."));
+            },
+            "--dump-frame" => {
+                let path = args_iter.next().expect("Expected a path after --dump-frame.");
+                options.dump_path = Some(path.clone());
+            },
+            "--screenshot-at-frame" => {
+                let frame = args_iter.next().and_then(|s| s.parse().ok());
+                let frame = frame.expect("Expected a frame number after --screenshot-at-frame.");
+                let path = args_iter.next().expect("Expected a path after --screenshot-at-frame N.");
+                options.screenshot = Some((frame, path.clone()));
+            },
+            "--load-state" => {
+                let path = args_iter.next().expect("Expected a path after --load-state.");
+                options.load_state_path = Some(path.clone());
+            },
+            "--save-bess" => {
+                let path = args_iter.next().expect("Expected a path after --save-bess.");
+                options.save_bess_path = Some(path.clone());
+            },
+            "--rewind-budget" => {
+                let megabytes = args_iter.next().and_then(|s| s.parse().ok());
+                options.rewind_budget_mb = megabytes.expect("Expected a size in MB after --rewind-budget.");
+            },
+            "--rewind-interval" => {
+                let frames = args_iter.next().and_then(|s| s.parse().ok()).filter(|&frames| frames > 0);
+                options.rewind_interval = frames.expect("Expected a frame count after --rewind-interval.");
+            },
+            "--record-movie" => {
+                let path = args_iter.next().expect("Expected a path after --record-movie.");
+                options.record_movie_path = Some(path.clone());
+            },
+            "--play-movie" => {
+                let path = args_iter.next().expect("Expected a path after --play-movie.");
+                options.play_movie_path = Some(path.clone());
+            },
+            "--renderer" => {
+                options.renderer = match args_iter.next().map(|s| s.as_str()) {
+                    Some("fifo") => Renderer::Fifo,
+                    Some("scanline") => Renderer::Scanline,
+                    _ => panic!("Expected --renderer fifo|scanline."),
+                };
+            },
             _ => {},
         }
     }
 
+    // Without a frame count, a headless run ends once its screenshot is taken.
+    if options.headless && options.frames.is_none() {
+        options.frames = options.screenshot.as_ref().map(|(frame, _)| *frame);
+    }
+
     options
 }
 
+fn save_screenshot(gameboy: &GameBoy, path: &str) {
+    match File::create(path).and_then(|mut file| write_png(gameboy.framebuffer(), &mut file)) {
+        Ok(()) => println!("Saved screenshot to {path}."),
+        Err(e) => println!("Failed to save screenshot to {path}: {e}"),
+    }
+}
 
-fn main() {
-    env_logger::init();
+/// Save state slots live next to the ROM: `game.gb` uses `game.ss0` to `game.ss9`.
+fn state_path(cartridge_path: &str, slot: u8) -> String {
+    Path::new(cartridge_path).with_extension(format!("ss{slot}")).display().to_string()
+}
 
-    let options = parse_args();
+fn save_state(gameboy: &mut GameBoy, path: &str) {
+    match fs::write(path, gameboy.save_state()) {
+        Ok(()) => println!("Saved state to {path}."),
+        Err(e) => println!("Failed to save state to {path}: {e}"),
+    }
+}
 
-    let mut minifb_driver = MiniFbDriver::new(
-        DISPLAY_WIDTH as u16,
-        DISPLAY_HEIGHT as u16,
-        minifb::Scale::X4,
-    );
+/// Restores either one of our own save states or a BESS state from another emulator, which
+/// ends with a "BESS" footer.
+fn restore_state(gameboy: &mut GameBoy, state: &[u8]) -> Result<(), StateError> {
+    if state.ends_with(b"BESS") {
+        gameboy.import_bess(state)
+    }
+    else {
+        gameboy.load_state(state)
+    }
+}
 
-    // The bus handles the "wiring" between all components.
-    let mut bus = Bus::new();
-    let mut clk = Clock::new();
+fn load_state(gameboy: &mut GameBoy, path: &str) {
+    let result = fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|state| restore_state(gameboy, &state).map_err(|e| e.to_string()));
 
-    let mut cpu = CPU::new();
-    cpu.attach_to_bus(&mut bus);
+    match result {
+        Ok(()) => println!("Loaded state from {path}."),
+        Err(e) => println!("Failed to load state from {path}: {e}"),
+    }
+}
 
-    // Graphics processor.
-    let ppu = rc(PPU::new());
-    bus.attach(ppu.clone());
-    clk.attach(ppu.clone());
+fn window_title(speed: Speed, paused: bool) -> String {
+    match speed {
+        _ if paused => String::from("Emerald - Paused"),
+        Speed::Unthrottled => String::from("Emerald - Fast forward"),
+        Speed::Multiplier(multiplier) if multiplier == 1.0 => String::from("Emerald"),
+        Speed::Multiplier(multiplier) => format!("Emerald - {multiplier}x"),
+    }
+}
 
-    let timer = rc(Timer::new());
-    bus.attach(timer.clone());
-    clk.attach(timer.clone());
+fn run<D: GraphicsDriver + JoypadDriver>(
+    gameboy: &mut GameBoy,
+    driver: &mut D,
+    options: &Options,
+    movie: &mut Option<MovieMode>,
+) {
+    let mut debugger = Debugger::new(options.enable_debugger);
 
-    let cartridge = load(options.cartridge_path.as_str());
-    bus.attach(cartridge.clone());
+    // MAIN LOOP //
 
-    let joypad = rc(joypad::Joypad::new());
-    bus.attach(joypad.clone());
+    let mut cpu_locked = false;
+    let mut slot: u8 = 0;
 
-    let audio = rc(DummyRAM::new(0x10, 0x3F, true));
-    bus.attach(audio.clone());
+    // Going back in time would break a movie.
+    let mut rewind = match options.rewind_budget_mb {
+        0 => None,
+        _ if movie.is_some() => None,
+        megabytes => Some(RewindBuffer::new(megabytes << 20, REWIND_KEYFRAME_INTERVAL)),
+    };
+    let mut rewinding = false;
 
-    let serial = rc(serial::SerialInterface::new(!options.enable_serial));
-    bus.attach(serial.clone());
+    // Movies hold one set of buttons per frame, so input is only applied at the start of a frame.
+    let latch_input = movie.is_some();
+    let mut input_frame = None;
 
-    let ram = rc(RAM::new());
-    bus.attach(ram.clone());
+    // Without a window, the system runs as fast as it can.
+    let mut speed_index = DEFAULT_SPEED;
+    let mut paused = false;
+    let mut advance_frame = false;
+    let mut status = None;
 
-    let unused_registers = rc(RegisterHoles::new());
-    bus.attach(unused_registers.clone());
+    if options.enable_trace {
+        gameboy.print_trace();
+    }
 
-    let hram = rc(DummyRAM::new(0xFF, 0xFF, false));
-    bus.attach(hram.clone());
+    while !driver.is_closed() {
+        let running = !paused || advance_frame;
 
-    let mut debugger = Debugger::new(options.enable_debugger);
+        if running {
+            gameboy.debug(&mut debugger);
+        }
 
-    // MAIN LOOP //
+        if debugger.has_quit() {
+            break;
+        }
 
-    if options.enable_trace {
-        cpu.print_trace(&bus);
-    }
+        // Without a window, playback ends with the movie.
+        if let Some(MovieMode::Play(player)) = movie {
+            if options.headless && player.is_finished() {
+                break;
+            }
+        }
+
+        let frames = gameboy.frames();
+
+        if running {
+            // The CPU clocks the rest of the system as it executes.
+            if let StepOutcome::Locked { opcode, address } = gameboy.step_instruction() {
+                if !cpu_locked {
+                    cpu_locked = true;
+                    println!("CPU locked up: illegal instruction {opcode:02X} at {address:04X}.");
+
+                    if options.enable_debugger {
+                        debugger.stop();
+                    }
+                }
+            }
+
+            if options.enable_trace {
+                gameboy.print_trace();
+            }
+        }
+        else {
+            // Nothing runs while paused, but the window has to be updated for its keys to be read.
+            driver.render(gameboy.framebuffer());
+            thread::sleep(PAUSED_POLL_INTERVAL);
+        }
 
-    while !cpu.is_halted() && !minifb_driver.is_closed() {
-        // if options.enable_debugger {
-        {
-            let ppu = &*ppu.as_ref().borrow();
-            debugger.step(&mut bus, &mut cpu, ppu);
+        if gameboy.frames() != frames {
+            advance_frame = false;
 
-            if debugger.has_quit() {
-                return;
+            match movie {
+                Some(MovieMode::Record(recording)) => recording.record_frame(gameboy),
+                Some(MovieMode::Play(player)) => {
+                    // The first desync is reported once the movie is over.
+                    let _ = player.verify_frame(gameboy);
+                },
+                None => {},
             }
         }
 
-        clk.cycle_start();
-        let cycles = cpu.step(&mut bus);
+        if let Some(rewind) = rewind.as_mut().filter(|_| gameboy.frames() != frames) {
+            // While rewinding, each new frame is replaced by an older one, so that the
+            // presented frames step back in time.
+            if rewinding {
+                if let Some(state) = rewind.pop() {
+                    gameboy.load_state(&state).expect("Failed to restore rewind snapshot.");
+                }
+            }
+            else if gameboy.frames() % options.rewind_interval == 0 {
+                rewind.push(&gameboy.save_state());
+            }
+        }
 
-        if options.enable_trace {
-            cpu.print_trace(&bus);
+        if let Some((frame, path)) = &options.screenshot {
+            if gameboy.frames() != frames && gameboy.frames() == *frame {
+                save_screenshot(gameboy, path);
+            }
+        }
+
+        gameboy.present(driver);
+
+        let mut input = driver.get_buttons();
+
+        if let Some(MovieMode::Play(player)) = movie {
+            // Hotkeys still come from the driver.
+            input.retain(|button| !matches!(button, JoypadButtons::Button(_) | JoypadButtons::Direction(_)));
+            input.extend(player.get_buttons());
+        }
+
+        rewinding = input.iter().any(|button| matches!(button, JoypadButtons::Rewind));
+        let fast_forward = input.iter().any(|button| matches!(button, JoypadButtons::FastForward));
+
+        for button in &input {
+            match button {
+                JoypadButtons::Pause => debugger.stop(),
+                JoypadButtons::Screenshot => {
+                    let name = Path::new(&options.cartridge_path).file_stem().unwrap().to_string_lossy();
+                    save_screenshot(gameboy, &format!("{name}-{}.png", gameboy.frames()));
+                },
+                JoypadButtons::SaveState => save_state(gameboy, &state_path(&options.cartridge_path, slot)),
+                JoypadButtons::LoadState => load_state(gameboy, &state_path(&options.cartridge_path, slot)),
+                JoypadButtons::PreviousSlot | JoypadButtons::NextSlot => {
+                    let step = if matches!(button, JoypadButtons::NextSlot) { 1 } else { STATE_SLOTS - 1 };
+                    slot = (slot + step) % STATE_SLOTS;
+                    println!("Save state slot {slot}.");
+                },
+                JoypadButtons::SpeedDown => speed_index = speed_index.saturating_sub(1),
+                JoypadButtons::SpeedUp => speed_index = (speed_index + 1).min(SPEEDS.len() - 1),
+                JoypadButtons::TogglePause => paused = !paused,
+                JoypadButtons::FrameAdvance => {
+                    paused = true;
+                    advance_frame = true;
+                },
+                _ => {},
+            }
         }
 
-        clk.increment(&mut bus, cycles);
-        // clk.cycle_end();
+        if !options.headless {
+            let speed = if fast_forward { Speed::Unthrottled } else { SPEEDS[speed_index] };
 
-        ppu.as_ref()
-           .borrow_mut()
-           .update(&mut minifb_driver);
+            if status != Some((speed, paused)) {
+                status = Some((speed, paused));
+                gameboy.set_speed(speed);
+                driver.set_title(&window_title(speed, paused));
+            }
+        }
 
-        joypad.as_ref()
-              .borrow_mut()
-              .update(&mut minifb_driver, &mut debugger);
+        if !latch_input || input_frame != Some(gameboy.frames()) {
+            gameboy.set_buttons(&input);
+            input_frame = Some(gameboy.frames());
+        }
     }
     // END LOOP //
 }
+
+fn main() {
+    env_logger::init();
+
+    let options = parse_args();
+
+    let rom = fs::read(&options.cartridge_path).expect("Failed to read cartridge.");
+    let rom_hash = movie::hash(&rom);
+    let mut gameboy = GameBoy::from_rom(rom).expect("Failed to load cartridge.");
+
+    gameboy.ppu().set_renderer(options.renderer);
+
+    if options.raster_log_path.is_some() {
+        gameboy.ppu().enable_raster_log();
+    }
+
+    gameboy.serial().set_quiet(!options.enable_serial);
+
+    if let Some(path) = &options.load_state_path {
+        let state = fs::read(path).expect("Failed to read save state.");
+        restore_state(&mut gameboy, &state).expect("Failed to load save state.");
+    }
+
+    if let Some(link) = &options.link {
+        let cable = match link {
+            Link::Listen(address) => LinkCable::listen(address),
+            Link::Connect(address) => LinkCable::connect(address),
+        };
+
+        gameboy.serial().connect_link(cable.expect("Couldn't connect the link cable"));
+    }
+
+    // Playback starts from the movie's own state; a recording starts from wherever we are now.
+    let mut movie = if let Some(path) = &options.play_movie_path {
+        let mut file = File::open(path).expect("Failed to open movie.");
+        let mut player = MoviePlayer::new(Movie::read(&mut file).expect("Failed to read movie."));
+
+        player.start(rom_hash, &mut gameboy).expect("Failed to start movie playback.");
+        Some(MovieMode::Play(player))
+    }
+    else {
+        options.record_movie_path.as_ref().map(|_| MovieMode::Record(Movie::record(rom_hash, &mut gameboy)))
+    };
+
+    if options.headless {
+        run(&mut gameboy, &mut HeadlessDriver::new(options.frames), &options, &mut movie);
+    }
+    else {
+        let mut minifb_driver = MiniFbDriver::new(
+            DISPLAY_WIDTH as u16,
+            DISPLAY_HEIGHT as u16,
+            minifb::Scale::X4,
+        );
+
+        run(&mut gameboy, &mut minifb_driver, &options, &mut movie);
+    }
+
+    if let Some(path) = &options.save_bess_path {
+        fs::write(path, gameboy.export_bess()).expect("Failed to write BESS save state.");
+    }
+
+    if let Some(path) = &options.dump_path {
+        let mut file = File::create(path).expect("Failed to create frame dump.");
+        write_ppm(gameboy.framebuffer(), &mut file).expect("Failed to write frame dump.");
+    }
+
+    if let Some(path) = &options.raster_log_path {
+        let ppu = gameboy.ppu();
+        let log = ppu.raster_log().unwrap();
+        let mut file = File::create(path).expect("Failed to create raster log.");
+
+        // The format is chosen by extension; anything other than .json is written as CSV.
+        if path.ends_with(".json") {
+            log.write_json(&mut file)
+        }
+        else {
+            log.write_csv(&mut file)
+        }.expect("Failed to write raster log.");
+
+        if log.dropped() > 0 {
+            println!("The raster log only holds the last events; {} older ones were dropped.", log.dropped());
+        }
+    }
+
+    match &movie {
+        Some(MovieMode::Record(recording)) => {
+            let path = options.record_movie_path.as_ref().unwrap();
+            let mut file = File::create(path).expect("Failed to create movie.");
+            recording.write(&mut file).expect("Failed to write movie.");
+            println!("Recorded {} frames to {path}.", recording.frames.len());
+        },
+        Some(MovieMode::Play(player)) => {
+            if let Some(desync) = player.desync() {
+                println!("Movie desynced: {desync}.");
+                process::exit(1);
+            }
+            println!("Played back {} frames without a desync.", player.frame());
+        },
+        None => {},
+    }
+}
@@ -0,0 +1,295 @@
+//! Input movies: the buttons held during each frame, recorded from a save state, so that a
+//! session can be replayed exactly.
+//!
+//! ```text
+//! "EMRLDMOV" | version: u16 | ROM hash: u64 | state length: u32 | state | frame count: u32 | frame*
+//! frame = buttons: u8 | framebuffer hash: u64
+//! ```
+//!
+//! Integers are little-endian. The framebuffer hash of each frame is checked on playback, which
+//! catches the emulator drifting from the recording as soon as it shows on screen.
+
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+use crate::joypad::{JoypadButtons, JoypadDriver};
+use crate::{Byte, GameBoy};
+
+pub const MOVIE_VERSION: u16 = 1;
+const MOVIE_MAGIC: &[u8; 8] = b"EMRLDMOV";
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` gives the same hash in every build.
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF29CE484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001B3))
+}
+
+fn framebuffer_hash(pixels: &[u32]) -> u64 {
+    let bytes: Vec<u8> = pixels.iter().flat_map(|pixel| pixel.to_le_bytes()).collect();
+    hash(&bytes)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MovieFrame {
+    /// As returned by `Joypad::buttons`.
+    pub buttons: Byte,
+    pub framebuffer_hash: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub start_state: Vec<u8>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    /// Starts recording from the current state of the system.
+    pub fn record(rom_hash: u64, gameboy: &mut GameBoy) -> Self {
+        Self {
+            rom_hash,
+            start_state: gameboy.save_state(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Records the frame just presented, and the buttons held while it was emulated.
+    pub fn record_frame(&mut self, gameboy: &GameBoy) {
+        self.frames.push(MovieFrame {
+            buttons: gameboy.buttons(),
+            framebuffer_hash: framebuffer_hash(gameboy.framebuffer()),
+        });
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(MOVIE_MAGIC)?;
+        out.write_all(&MOVIE_VERSION.to_le_bytes())?;
+        out.write_all(&self.rom_hash.to_le_bytes())?;
+        out.write_all(&(self.start_state.len() as u32).to_le_bytes())?;
+        out.write_all(&self.start_state)?;
+        out.write_all(&(self.frames.len() as u32).to_le_bytes())?;
+
+        for frame in &self.frames {
+            out.write_all(&[frame.buttons])?;
+            out.write_all(&frame.framebuffer_hash.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read(input: &mut dyn Read) -> io::Result<Self> {
+        fn read_array<const N: usize>(input: &mut dyn Read) -> io::Result<[u8; N]> {
+            let mut bytes = [0; N];
+            input.read_exact(&mut bytes)?;
+            Ok(bytes)
+        }
+
+        let invalid = |error: String| io::Error::new(io::ErrorKind::InvalidData, error);
+
+        if &read_array::<8>(input)? != MOVIE_MAGIC {
+            return Err(invalid(String::from("Not a movie")));
+        }
+
+        let version = u16::from_le_bytes(read_array(input)?);
+        if version != MOVIE_VERSION {
+            return Err(invalid(format!("Movie version {version} is not supported (expected {MOVIE_VERSION})")));
+        }
+
+        let rom_hash = u64::from_le_bytes(read_array(input)?);
+
+        let mut start_state = vec![0; u32::from_le_bytes(read_array(input)?) as usize];
+        input.read_exact(&mut start_state)?;
+
+        let frame_count = u32::from_le_bytes(read_array(input)?);
+        let frames = (0..frame_count).map(|_| {
+            let [buttons] = read_array(input)?;
+            let framebuffer_hash = u64::from_le_bytes(read_array(input)?);
+            Ok(MovieFrame { buttons, framebuffer_hash })
+        }).collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Self { rom_hash, start_state, frames })
+    }
+}
+
+/// First frame of a playback that differs from the recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Desync {
+    pub frame: usize,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame {} differs from the recording (hash {:016X}, expected {:016X})", self.frame, self.actual, self.expected)
+    }
+}
+
+/// Plays a movie back by holding the recorded buttons, one frame at a time.
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+    desync: Option<Desync>,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        Self {
+            movie,
+            frame: 0,
+            desync: None,
+        }
+    }
+
+    /// Restores the state the movie was recorded from.
+    pub fn start(&mut self, rom_hash: u64, gameboy: &mut GameBoy) -> Result<(), String> {
+        if rom_hash != self.movie.rom_hash {
+            return Err(String::from("The movie was recorded with a different ROM"));
+        }
+
+        gameboy.load_state(&self.movie.start_state).map_err(|e| e.to_string())?;
+        self.frame = 0;
+        self.desync = None;
+        Ok(())
+    }
+
+    /// Checks the frame just presented against the recording and moves on to the next one. The
+    /// first frame that differs is kept for `desync`.
+    pub fn verify_frame(&mut self, gameboy: &GameBoy) -> Result<(), Desync> {
+        let Some(recorded) = self.movie.frames.get(self.frame) else {
+            return Ok(());
+        };
+
+        let frame = self.frame;
+        self.frame += 1;
+
+        let actual = framebuffer_hash(gameboy.framebuffer());
+        if actual != recorded.framebuffer_hash {
+            let desync = Desync { frame, expected: recorded.framebuffer_hash, actual };
+            self.desync.get_or_insert(desync.clone());
+            return Err(desync);
+        }
+
+        Ok(())
+    }
+
+    pub fn desync(&self) -> Option<&Desync> {
+        self.desync.as_ref()
+    }
+
+    /// Frames played back so far.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+}
+
+impl JoypadDriver for MoviePlayer {
+    fn get_buttons(&mut self) -> Vec<JoypadButtons> {
+        match self.movie.frames.get(self.frame) {
+            Some(frame) => JoypadButtons::from_state(frame.buttons),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::joypad::{JoypadButtons, JoypadDriver};
+    use crate::GameBoy;
+    use super::{hash, Movie, MoviePlayer};
+
+    /// Copies the joypad state to the screen, as the first row of tile 0.
+    fn rom() -> Vec<u8> {
+        let program = [
+            0x3E, 0xE4,       // ld A, $E4
+            0xE0, 0x47,       // ld ($FF47), A
+            0x3E, 0x91,       // ld A, $91
+            0xE0, 0x40,       // ld ($FF40), A
+            0x3E, 0x10,       // ld A, $10
+            0xE0, 0x00,       // ld ($FF00), A
+            0xF0, 0x00,       // ld A, ($FF00)
+            0xEA, 0x00, 0x80, // ld ($8000), A
+            0x18, 0xF5,       // jr $0108
+        ];
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        rom
+    }
+
+    /// Runs a frame with the buttons the driver holds, as the frontend does.
+    fn run_frame(gameboy: &mut GameBoy, driver: &mut dyn JoypadDriver) {
+        gameboy.set_buttons(&driver.get_buttons());
+        gameboy.run_frame();
+    }
+
+    struct Script(Vec<Vec<JoypadButtons>>);
+
+    impl JoypadDriver for Script {
+        fn get_buttons(&mut self) -> Vec<JoypadButtons> {
+            self.0.remove(0)
+        }
+    }
+
+    #[test]
+    fn test_record_and_play_back() {
+        let rom = rom();
+        let mut gameboy = GameBoy::from_rom(rom.clone()).unwrap();
+        gameboy.run_frame();
+
+        let mut script = Script(JoypadButtons::from_state(0xFF).into_iter().map(|button| vec![button]).collect());
+        let frames = script.0.len();
+        let mut movie = Movie::record(hash(&rom), &mut gameboy);
+
+        for _ in 0..frames {
+            run_frame(&mut gameboy, &mut script);
+            movie.record_frame(&gameboy);
+        }
+
+        let mut file = Vec::new();
+        movie.write(&mut file).unwrap();
+        let movie = Movie::read(&mut file.as_slice()).unwrap();
+        assert_eq!(movie.frames.len(), frames);
+
+        let mut gameboy = GameBoy::from_rom(rom.clone()).unwrap();
+        let mut player = MoviePlayer::new(movie);
+        player.start(hash(&rom), &mut gameboy).unwrap();
+
+        while !player.is_finished() {
+            run_frame(&mut gameboy, &mut player);
+            player.verify_frame(&gameboy).unwrap();
+        }
+
+        assert!(player.start(hash(b"other ROM"), &mut gameboy).is_err());
+    }
+
+    #[test]
+    fn test_detect_desync() {
+        let rom = rom();
+        let mut gameboy = GameBoy::from_rom(rom.clone()).unwrap();
+        let mut movie = Movie::record(hash(&rom), &mut gameboy);
+
+        for _ in 0..3 {
+            gameboy.run_frame();
+            movie.record_frame(&gameboy);
+        }
+
+        // Pressing a button changes the tile, and with it the frame.
+        movie.frames[1].buttons = 0x10;
+
+        let mut gameboy = GameBoy::from_rom(rom.clone()).unwrap();
+        let mut player = MoviePlayer::new(movie);
+        player.start(hash(&rom), &mut gameboy).unwrap();
+
+        run_frame(&mut gameboy, &mut player);
+        assert!(player.verify_frame(&gameboy).is_ok());
+
+        run_frame(&mut gameboy, &mut player);
+        assert_eq!(player.verify_frame(&gameboy).unwrap_err().frame, 1);
+        assert_eq!(player.desync().unwrap().frame, 1);
+    }
+}
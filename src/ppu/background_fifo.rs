@@ -1,13 +1,8 @@
 use crate::{Address, Byte, Registers};
 use crate::ppu::fifo::{FifoState, FifoState::*, PixelFifo};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 use crate::ppu::{LCDC_TILE_DATA_SELECT, LCDC_TILE_MAP_SELECT, LCDC_WINDOW_TILE_MAP_SELECT, Point, TILE_DATA_BLOCK_BASE, TILE_MAP_HI_BASE, TILE_MAP_LO_BASE};
 
-#[derive(Clone, Copy, PartialEq)]
-enum TileDataBlock {
-    BLK0,
-    BLK1,
-}
-
 // TODO: Move this to its own file.
 #[derive(Debug)]
 pub struct BackgroundFifo {
@@ -21,6 +16,12 @@ pub struct BackgroundFifo {
     // Discard columns when scrolled
     discard_columns: u8,
 
+    // Set once the window has started on the current line.
+    window: bool,
+
+    // Line of the window to draw next; only advances on lines where the window is drawn.
+    window_line: u8,
+
     tile_data: (u8, u8),
     tile_data_address: Address,
 }
@@ -35,6 +36,9 @@ impl BackgroundFifo {
             column: 0,
             discard_columns: 0,
 
+            window: false,
+            window_line: 0,
+
             tile_data: (0, 0),
             tile_data_address: 0,
         }
@@ -44,17 +48,8 @@ impl BackgroundFifo {
         use FifoState::*;
         match &self.state {
             FetchTileNo => {
-                // Check whether the window is enabled and the column is within window bounds
-                // TODO: Implement window.
-                /*
-                let window_active = registers.LCDC & LCDC_WINDOW_ENABLE != 1
-                    && (self.column as i8) <= (registers.WX as i8) - 7
-                    && self.column <= registers.WX + DISPLAY_WIDTH;
-                 */
-                let window_active = false;
-
                 // Select active tile map (either tile map of the window or the background)
-                let tile_map_select = if window_active {
+                let tile_map_select = if self.window {
                     LCDC_WINDOW_TILE_MAP_SELECT
                 } else {
                     LCDC_TILE_MAP_SELECT
@@ -67,40 +62,51 @@ impl BackgroundFifo {
                     TILE_MAP_HI_BASE
                 };
 
-                // Discard first SCX columns
-                if self.column != 0 {
-                    self.discard_columns = registers.SCX;
+                if self.window {
+                    // The window is not scrolled; it is drawn from its top left corner.
+                    self.offset = Point {
+                        x: self.column as u16,
+                        y: self.window_line as u16,
+                    };
                 }
+                else {
+                    // Fine scroll is latched once per line when the first tile is fetched;
+                    // the first SCX % 8 pixels of the line are discarded.
+                    if self.column == 0 {
+                        self.discard_columns = registers.SCX & 0x07;
 
-                self.offset = Point {
-                    x: (self.column as u16 + registers.SCX as u16) % 0xFF,
-                    y: (registers.LY as u16 + registers.SCY as u16) % 0xFF,
-                };
+                        if registers.LY == 0 {
+                            self.window_line = 0;
+                        }
+                    }
 
-                //let map_index = ((self.offset.y >> 3) << 5) + (self.offset.x >> 3);
-                let map_index: u16 = ((self.offset.y & 0xF8) << 2) + (self.offset.x >> 3);
+                    // SCX and SCY are re-read for every tile. The background map is 256x256 pixels
+                    // (32x32 tiles) and wraps around in both directions.
+                    let tile_x = ((registers.SCX >> 3).wrapping_add(self.column >> 3) & 0x1F) as u16;
+                    self.offset = Point {
+                        x: tile_x << 3,
+                        y: registers.LY.wrapping_add(registers.SCY) as u16,
+                    };
+                }
+
+                let tile_x = (self.offset.x >> 3) & 0x1F;
+
+                let map_index: u16 = ((self.offset.y >> 3) << 5) + tile_x;
 
                 // Lookup the tile number to load from the appropriate tile map.
                 let tile_no: u8 = vram[(tile_map_base + map_index) as usize];
 
                 // Each tile takes up 16 bytes, so tile_no is multiplied by 16.
                 // Each pixel takes up 2 bits, so the y offset must be multiplied by 2.
-                let tile_index: u16 = ((tile_no as u16) << 4) + ((self.offset.y % 8) << 1);
-
-                self.tile_data_address = if registers.LCDC & LCDC_TILE_DATA_SELECT == 0 {
-                    // Tile Block 1: Integer Indexing
-                    // Treat tile_no as a signed int.
-                    if tile_no & 0x80 == 0 {
-                        TILE_DATA_BLOCK_BASE[1] + tile_index
-                    }
-                    else {
-                        // Remove the sign bit before subtracting the index from the base address.
-                        TILE_DATA_BLOCK_BASE[1] - (tile_index ^ 0x0800)
-                    }
+                let tile_base = if registers.LCDC & LCDC_TILE_DATA_SELECT == 0 {
+                    // Tile numbers are signed relative to 0x1000, reaching down into block 1.
+                    (TILE_DATA_BLOCK_BASE[2] as i32 + ((tile_no as i8 as i32) << 4)) as Address
                 } else {
                     // Tile Block 0: Natural Indexing
-                    TILE_DATA_BLOCK_BASE[0] + tile_index as u16
+                    TILE_DATA_BLOCK_BASE[0] + ((tile_no as Address) << 4)
                 };
+
+                self.tile_data_address = tile_base + ((self.offset.y % 8) << 1);
             }
             FetchTileLo => {
                 self.tile_data.0 = vram[self.tile_data_address as usize];
@@ -137,8 +143,162 @@ impl BackgroundFifo {
     }
 
     pub fn reset(&mut self, column: u8) {
+        if self.window {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+
         self.fifo.clear();
         self.state = FetchTileNo;
         self.column = column;
+        self.window = false;
+    }
+
+    pub fn is_window(&self) -> bool {
+        self.window
+    }
+
+    /// Switches to the window for the rest of the line. Background pixels still queued are
+    /// dropped, and so are the columns of a window that starts left of the screen (WX < 7).
+    pub fn start_window(&mut self, registers: Registers) {
+        self.fifo.clear();
+        self.state = FetchTileNo;
+        self.column = 0;
+        self.discard_columns = 7u8.saturating_sub(registers.WX);
+        self.window = true;
+    }
+}
+
+impl SaveState for BackgroundFifo {
+    fn save_state(&self, out: &mut StateWriter) {
+        self.fifo.save_state(out);
+        out.u8(self.state as u8);
+        out.u16(self.offset.x);
+        out.u16(self.offset.y);
+        out.u8(self.column);
+        out.u8(self.discard_columns);
+        out.bool(self.window);
+        out.u8(self.window_line);
+        out.u8(self.tile_data.0);
+        out.u8(self.tile_data.1);
+        out.u16(self.tile_data_address);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.fifo.load_state(input)?;
+        self.state = FifoState::from_u8(input.u8()?);
+        self.offset.x = input.u16()?;
+        self.offset.y = input.u16()?;
+        self.column = input.u8()?;
+        self.discard_columns = input.u8()?;
+        self.window = input.bool()?;
+        self.window_line = input.u8()?;
+        self.tile_data = (input.u8()?, input.u8()?);
+        self.tile_data_address = input.u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BackgroundFifo;
+    use crate::ppu::{Registers, LCDC_TILE_DATA_SELECT, TILE_MAP_LO_BASE};
+
+    fn registers(scx: u8, scy: u8, ly: u8) -> Registers {
+        Registers {
+            LCDC: LCDC_TILE_DATA_SELECT,
+            STAT: 0,
+            SCY: scy,
+            SCX: scx,
+            LX: 0,
+            LY: ly,
+            LYC: 0,
+            WY: 0,
+            WX: 0,
+            BGP: 0,
+            OBP0: 0,
+            OBP1: 0,
+        }
+    }
+
+    // Tile 1 is filled with colour 1, tile 2 with colour 2.
+    fn vram() -> Vec<u8> {
+        let mut vram = vec![0u8; 0x2000];
+        for row in 0..8 {
+            vram[0x10 + row * 2] = 0xFF;
+            vram[0x20 + row * 2 + 1] = 0xFF;
+        }
+        vram
+    }
+
+    fn fetch(vram: &[u8], registers: Registers, count: usize) -> Vec<u8> {
+        let mut fifo = BackgroundFifo::new();
+        let mut pixels = Vec::new();
+
+        for _ in 0..1000 {
+            fifo.step(vram, registers);
+            while let Some(pixel) = fifo.pop() {
+                pixels.push(pixel);
+            }
+            if pixels.len() >= count {
+                break;
+            }
+        }
+
+        pixels.truncate(count);
+        pixels
+    }
+
+    #[test]
+    fn test_scx_wraps_at_256() {
+        let mut vram = vram();
+        vram[(TILE_MAP_LO_BASE + 31) as usize] = 1;
+        vram[TILE_MAP_LO_BASE as usize] = 2;
+
+        // SCX = 252 starts half way into the last map column and wraps to column 0.
+        let pixels = fetch(&vram, registers(252, 0, 0), 8);
+        assert_eq!(pixels, vec![1, 1, 1, 1, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_fine_scroll_discarded_once() {
+        let mut vram = vram();
+        vram[TILE_MAP_LO_BASE as usize] = 1;
+        vram[(TILE_MAP_LO_BASE + 1) as usize] = 2;
+        vram[(TILE_MAP_LO_BASE + 2) as usize] = 1;
+
+        let pixels = fetch(&vram, registers(3, 0, 0), 16);
+        assert_eq!(pixels, vec![1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_signed_tile_data() {
+        let mut vram = vec![0u8; 0x2000];
+        // Tile 1 at 0x1010 is filled with colour 1, tile -1 at 0x0FF0 with colour 2.
+        for row in 0..8 {
+            vram[0x1010 + row * 2] = 0xFF;
+            vram[0x0FF0 + row * 2 + 1] = 0xFF;
+        }
+        vram[TILE_MAP_LO_BASE as usize] = 1;
+        vram[(TILE_MAP_LO_BASE + 1) as usize] = 0xFF;
+
+        let mut registers = registers(0, 0, 0);
+        registers.LCDC = 0;
+        let pixels = fetch(&vram, registers, 16);
+        assert_eq!(pixels, vec![1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_scy_wraps_at_256() {
+        let mut vram = vram();
+        vram[TILE_MAP_LO_BASE as usize] = 1;
+        vram[(TILE_MAP_LO_BASE + (31 << 5)) as usize] = 2;
+
+        // LY 10 + SCY 250 = 260, which wraps to map row 0.
+        let pixels = fetch(&vram, registers(0, 250, 10), 8);
+        assert_eq!(pixels, vec![1; 8]);
+
+        // LY 0 + SCY 250 lands in map row 31.
+        let pixels = fetch(&vram, registers(0, 250, 0), 8);
+        assert_eq!(pixels, vec![2; 8]);
     }
 }
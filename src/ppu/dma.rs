@@ -0,0 +1,282 @@
+use crate::bus::*;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// Number of bytes copied into OAM by a single transfer.
+const OAM_DMA_LENGTH: u8 = 160;
+
+// M-cycles between the write to FF46 and the first byte being copied.
+const OAM_DMA_STARTUP_CYCLES: u8 = 1;
+
+const VRAM_BASE_ADDRESS: Address = 0x8000;
+const VRAM_END_ADDRESS: Address = 0x9FFF;
+
+#[derive(Debug, Copy, Clone)]
+struct Transfer {
+    source: Address,
+    index: u8,
+}
+
+/// OAM DMA controller.
+/// Copies 160 bytes from `XX00` into OAM, one byte per M-cycle.
+#[derive(Debug)]
+pub struct OamDma {
+    // Last value written to FF46.
+    register: Byte,
+
+    // Transfer requested by a write to FF46 which has not started yet (page, delay).
+    // A transfer that is already running continues until this one takes over.
+    pending: Option<(Byte, u8)>,
+    active: Option<Transfer>,
+
+    // Last byte read by the DMA unit; this is what the CPU sees on a conflicting bus.
+    value: Byte,
+
+    // T-cycles not yet consumed by a full M-cycle.
+    cycles: u8,
+}
+
+impl OamDma {
+    pub fn new() -> Self {
+        Self {
+            register: 0xFF,
+            pending: None,
+            active: None,
+            value: 0xFF,
+            cycles: 0,
+        }
+    }
+
+    pub fn read(&self) -> Byte {
+        self.register
+    }
+
+    /// Sets FF46 without starting a transfer, cancelling any transfer in progress.
+    pub fn restore(&mut self, register: Byte) {
+        *self = Self::new();
+        self.register = register;
+    }
+
+    /// Writing to FF46 (re)starts a transfer from the given page.
+    pub fn start(&mut self, page: Byte) {
+        self.register = page;
+        self.pending = Some((page, OAM_DMA_STARTUP_CYCLES));
+    }
+
+    /// The DMA unit only has 13 address lines into the external bus, so pages E0-FF mirror
+    /// work RAM (C0-DF) instead of reaching echo RAM, OAM or the IO registers.
+    fn source_address(page: Byte) -> Address {
+        let page = if page >= 0xE0 { page - 0x20 } else { page };
+        (page as Address) << 8
+    }
+
+    /// Whether a transfer is running or about to start.
+    pub fn is_active(&self) -> bool {
+        self.pending.is_some() || self.active.is_some()
+    }
+
+    pub fn tick(&mut self, bus: &mut Bus, cycles: u32, vram: &[Byte], oam: &mut [Byte]) {
+        let mut cycles = self.cycles as u32 + cycles;
+
+        // Steps without a transfer do nothing, so an idle unit only keeps count.
+        while cycles >= 4 && self.is_active() {
+            cycles -= 4;
+            self.step(bus, vram, oam);
+        }
+
+        self.cycles = (cycles % 4) as u8;
+
+        bus.set_oam_dma(self.active.map(|transfer| (transfer.source, self.value)));
+    }
+
+    fn step(&mut self, bus: &mut Bus, vram: &[Byte], oam: &mut [Byte]) {
+        // A (re)started transfer replaces the current one once its startup delay has passed.
+        if let Some((page, delay)) = self.pending {
+            if delay == 0 {
+                self.pending = None;
+                self.active = Some(Transfer {
+                    source: Self::source_address(page),
+                    index: 0,
+                });
+            }
+            else {
+                self.pending = Some((page, delay - 1));
+            }
+        }
+
+        let mut transfer = match self.active {
+            Some(transfer) => transfer,
+            None => return,
+        };
+
+        let address = transfer.source + transfer.index as Address;
+
+        // VRAM belongs to the PPU which is already borrowed while it is being clocked.
+        self.value = match address {
+            VRAM_BASE_ADDRESS..=VRAM_END_ADDRESS => vram[(address - VRAM_BASE_ADDRESS) as usize],
+            _ => bus.dma_read_byte(address),
+        };
+
+        oam[transfer.index as usize] = self.value;
+        transfer.index += 1;
+
+        self.active = if transfer.index < OAM_DMA_LENGTH {
+            Some(transfer)
+        }
+        else {
+            None
+        };
+    }
+}
+
+impl SaveState for OamDma {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.register);
+
+        let (page, delay) = self.pending.unwrap_or((0, 0));
+        out.bool(self.pending.is_some());
+        out.u8(page);
+        out.u8(delay);
+
+        let transfer = self.active.unwrap_or(Transfer { source: 0, index: 0 });
+        out.bool(self.active.is_some());
+        out.u16(transfer.source);
+        out.u8(transfer.index);
+
+        out.u8(self.value);
+        out.u8(self.cycles);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.register = input.u8()?;
+
+        let pending = input.bool()?;
+        let (page, delay) = (input.u8()?, input.u8()?);
+        self.pending = if pending { Some((page, delay)) } else { None };
+
+        let active = input.bool()?;
+        let transfer = Transfer { source: input.u16()?, index: input.u8()? };
+        self.active = if active { Some(transfer) } else { None };
+
+        if transfer.index >= OAM_DMA_LENGTH {
+            return Err(StateError::Invalid(String::from("OAM DMA index out of range")));
+        }
+
+        self.value = input.u8()?;
+        self.cycles = input.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::{Bus, DummyRAM};
+    use super::OamDma;
+
+    /// Bus with work RAM at C000-DFFF, filled so that neighbouring bytes and pages differ.
+    fn init_bus() -> (Bus, Rc<RefCell<DummyRAM>>) {
+        let mut bus = Bus::new();
+        let ram = Rc::new(RefCell::new(DummyRAM::new(0xC0, 0xDF, false)));
+
+        for (i, byte) in ram.borrow_mut().data.iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_add(((i >> 8) as u8).wrapping_mul(0x11));
+        }
+
+        bus.attach(ram.clone());
+        (bus, ram)
+    }
+
+    fn page(ram: &Rc<RefCell<DummyRAM>>, page: u8) -> Vec<u8> {
+        let start = (page - 0xC0) as usize * 0x100;
+        ram.borrow().data[start..start + 0xA0].to_vec()
+    }
+
+    /// Runs the unit for the given number of M-cycles.
+    fn run(dma: &mut OamDma, bus: &mut Bus, oam: &mut [u8], m_cycles: usize) {
+        let vram = vec![0; 0x2000];
+        for _ in 0..m_cycles {
+            dma.tick(bus, 4, &vram, oam);
+        }
+    }
+
+    #[test]
+    fn test_startup_delay_and_rate() {
+        let (mut bus, ram) = init_bus();
+        let mut dma = OamDma::new();
+        let mut oam = vec![0; 0xA0];
+        let source = page(&ram, 0xC1);
+
+        dma.start(0xC1);
+        assert!(dma.is_active());
+
+        // Nothing is copied during the startup delay.
+        run(&mut dma, &mut bus, &mut oam, 1);
+        assert!(oam.iter().all(|&byte| byte == 0));
+
+        // Then one byte per M-cycle, counting T-cycles across ticks.
+        dma.tick(&mut bus, 2, &[], &mut oam);
+        assert_eq!(oam[0], 0);
+        dma.tick(&mut bus, 2, &[], &mut oam);
+        assert_eq!(oam[0], source[0]);
+        assert_eq!(oam[1], 0);
+
+        run(&mut dma, &mut bus, &mut oam, 158);
+        assert!(dma.is_active());
+        assert_eq!(oam[0x9F], 0);
+
+        run(&mut dma, &mut bus, &mut oam, 1);
+        assert!(!dma.is_active());
+        assert_eq!(oam, source);
+        assert_eq!(dma.read(), 0xC1);
+    }
+
+    #[test]
+    fn test_source_mirroring() {
+        // Pages E0-FF read work RAM rather than echo RAM, OAM or the IO registers.
+        for (register, mirrored) in [(0xE1, 0xC1), (0xFE, 0xDE), (0xFF, 0xDF)] {
+            let (mut bus, ram) = init_bus();
+            let mut dma = OamDma::new();
+            let mut oam = vec![0; 0xA0];
+
+            dma.start(register);
+            run(&mut dma, &mut bus, &mut oam, 161);
+            assert_eq!(oam, page(&ram, mirrored), "Page {register:02X}.");
+            assert_eq!(dma.read(), register);
+        }
+
+        // VRAM is read from the PPU's copy.
+        let (mut bus, _ram) = init_bus();
+        let mut dma = OamDma::new();
+        let mut oam = vec![0; 0xA0];
+        let vram: Vec<u8> = (0..0x2000).map(|i| (i >> 4) as u8).collect();
+
+        dma.start(0x81);
+        for _ in 0..161 {
+            dma.tick(&mut bus, 4, &vram, &mut oam);
+        }
+        assert_eq!(oam, vram[0x100..0x1A0]);
+    }
+
+    #[test]
+    fn test_restart() {
+        let (mut bus, ram) = init_bus();
+        let mut dma = OamDma::new();
+        let mut oam = vec![0; 0xA0];
+
+        dma.start(0xC0);
+        run(&mut dma, &mut bus, &mut oam, 50);
+
+        // The running transfer carries on during the new one's startup delay.
+        dma.start(0xC2);
+        run(&mut dma, &mut bus, &mut oam, 1);
+        assert_eq!(oam[..50], page(&ram, 0xC0)[..50]);
+
+        // The new transfer then starts over from the first byte.
+        run(&mut dma, &mut bus, &mut oam, 159);
+        assert!(dma.is_active());
+        run(&mut dma, &mut bus, &mut oam, 1);
+        assert!(!dma.is_active());
+        assert_eq!(oam, page(&ram, 0xC2));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug)]
 pub enum FifoState {
@@ -69,7 +71,36 @@ impl PixelFifo {
         self.size += 1;
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.size == 0
+    /// Queues a pixel `index` places after the first one, or draws it over the pixel already
+    /// there if that one is transparent (colour 0).
+    pub fn mix(&mut self, index: usize, pixel: u8) {
+        if index < self.size {
+            let queued = &mut self.pixels[(self.pos + index) % 16];
+            if *queued & 0x03 == 0 {
+                *queued = pixel;
+            }
+        }
+        else {
+            self.push(pixel);
+        }
+    }
+}
+
+impl SaveState for PixelFifo {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.bytes(&self.pixels);
+        out.u8(self.size as u8);
+        out.u8(self.pos as u8);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        input.bytes(&mut self.pixels)?;
+        self.size = input.u8()? as usize;
+        self.pos = input.u8()? as usize;
+
+        if self.size > 16 || self.pos >= 16 {
+            return Err(StateError::Invalid(String::from("pixel FIFO position out of range")));
+        }
+        Ok(())
     }
 }
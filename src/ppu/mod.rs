@@ -1,10 +1,14 @@
 mod fifo;
 mod background_fifo;
 mod sprite_fifo;
+mod dma;
+mod raster_log;
+mod scanline;
 
 use crate::bus::*;
 use crate::cpu::{interrupt, InterruptType};
 use crate::clock::ClockListener;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 use std::fmt;
 
 use std::cell::RefCell;
@@ -13,6 +17,10 @@ use std::rc::Rc;
 use crate::graphics_driver::GraphicsDriver;
 use crate::ppu::background_fifo::BackgroundFifo;
 use crate::ppu::sprite_fifo::SpriteFifo;
+use crate::ppu::dma::OamDma;
+use crate::ppu::raster_log::RasterEventKind;
+pub use crate::ppu::raster_log::RasterLog;
+use crate::ppu::scanline::ScanlineRenderer;
 
 pub const DISPLAY_WIDTH: u8 = 160;
 pub const DISPLAY_HEIGHT: u8 = 144;
@@ -27,9 +35,14 @@ const LCDC_TILE_MAP_SELECT: u8 = 1 << 3;
 
 const LCDC_SPRITE_SIZE: u8 = 1 << 2; // 1: Double height
 const LCDC_SPRITE_ENABLE: u8 = 1 << 1;
-// const LCDC_BG_VS_WINDOW_PRIORITY: u8 = 1 << 1;
+const LCDC_BG_ENABLE: u8 = 1 << 0; // DMG: 0 blanks the background and window
 const MAX_SPRITES_PER_LINE: usize = 10;
 
+const SPRITE_PRIORITY: u8 = 1 << 7; // 1: Behind non-zero background colours
+const SPRITE_Y_FLIP: u8 = 1 << 6;
+const SPRITE_X_FLIP: u8 = 1 << 5;
+const SPRITE_PALETTE: u8 = 1 << 4; // 1: OBP1
+
 const STAT_LYC_INTERRUPT: u8 = 1 << 6;
 const STAT_OAM_INTERRUPT: u8 = 1 << 5;
 const STAT_VBLANK_INTERRUPT: u8 = 1 << 4;
@@ -60,6 +73,16 @@ enum Mode {
     Draw,
 }
 
+/// Selects how pixels are produced during mode 3.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Renderer {
+    /// Pixel FIFO pipeline, stepped every dot.
+    Fifo,
+
+    /// Whole line rendered at the end of mode 3.
+    Scanline,
+}
+
 #[derive(Debug, Copy, Clone)]
 struct Point {
     x: u16,
@@ -84,10 +107,18 @@ pub struct Registers {
     BGP: Byte,
     OBP0: Byte,
     OBP1: Byte,
+}
+
+impl Registers {
+    /// Whether the window covers part of the current line.
+    fn window_visible(&self) -> bool {
+        self.LCDC & LCDC_WINDOW_ENABLE != 0 && self.WY <= self.LY && self.WX <= 166
+    }
+}
 
-    dma_active: bool,
-    dma_address: Byte,
-    dma_counter: Byte,
+/// Maps a colour index to a shade through BGP, OBP0 or OBP1.
+fn shade(palette: Byte, index: u8) -> u8 {
+    (palette >> (index << 1)) & 0x03
 }
 
 #[derive(Debug)]
@@ -101,13 +132,27 @@ pub struct PPU {
     palette_buffer: [u32; 4],
     render_flag: bool,
 
+    // Set when the LCD is switched on. The first line skips the OAM scan (STAT reports mode 0)
+    // and the first frame is not displayed.
+    skip_oam_scan: bool,
+    blank_frame: bool,
+
+    // Cycles towards the next blank frame while the system clock is stopped, see `idle`.
+    idle_clock: u16,
+
     VRAM: [Byte; 0x2000],
     OAM: [Byte; 0x100],
 
     registers: Registers,
 
+    renderer: Renderer,
     bgfifo: BackgroundFifo,
     spfifo: SpriteFifo,
+    scanline: ScanlineRenderer,
+
+    dma: OamDma,
+
+    raster_log: Option<RasterLog>,
 }
 
 impl PPU {
@@ -122,6 +167,11 @@ impl PPU {
             palette_buffer: [0xFFFFFF, 0xC0C0C0, 0x404040, 0x000000],
             render_flag: true,
 
+            skip_oam_scan: false,
+            blank_frame: false,
+
+            idle_clock: 0,
+
             VRAM: [0; 0x2000],
             OAM: [0; 0x100],
 
@@ -141,14 +191,16 @@ impl PPU {
                 BGP: 0,
                 OBP0: 0,
                 OBP1: 0,
-
-                dma_active: false,
-                dma_counter: 0,
-                dma_address: 0,
             },
 
+            renderer: Renderer::Fifo,
             bgfifo: BackgroundFifo::new(),
             spfifo: SpriteFifo::new(),
+            scanline: ScanlineRenderer::new(),
+
+            dma: OamDma::new(),
+
+            raster_log: None,
         }
     }
 
@@ -157,15 +209,10 @@ impl PPU {
             return;
         }
 
-        let start = crate::graphics_driver::Point { x: 0, y: 0 };
-        let end = crate::graphics_driver::Point {
-            x: DISPLAY_WIDTH as u16,
-            y: DISPLAY_HEIGHT as u16,
-        };
-
-        if !self.on {
-            let screen: [u32; PITCH] = [0; PITCH];
-            driver.render(&screen);
+        if !self.on || self.blank_frame {
+            // The LCD shows colour 0 (white on DMG) while it is not outputting a frame.
+            driver.blank(self.palette_buffer[0]);
+            self.blank_frame = false;
         }
         else {
             driver.render(&self.pixel_buffer);
@@ -201,10 +248,141 @@ impl PPU {
         }
     }
 
-    pub fn reset(&mut self, bus: &mut Bus) {
-        self.set_mode(bus, Mode::OAM);
+    /// Whether a frame is waiting to be presented by `update`.
+    pub fn frame_ready(&self) -> bool {
+        self.render_flag
+    }
+
+    /// Advances time while the system clock is stopped (STOP mode). As with the LCD off, a blank
+    /// frame is presented once a whole screen has passed; the PPU itself resumes where it stopped.
+    pub fn idle(&mut self, cycles: u32) {
+        self.idle_clock += cycles as u16;
+
+        if SCREEN_CYCLES < self.idle_clock {
+            self.idle_clock -= SCREEN_CYCLES;
+            self.render_flag = true;
+            self.blank_frame = true;
+        }
+    }
+
+    /// Disabling the LCD stops the PPU: LY is held at 0 and STAT reports mode 0.
+    fn lcd_off(&mut self) {
+        self.on = false;
+        self.mode = Mode::HBlank;
+        self.registers.STAT &= 0xFF ^ STAT_MODE_MASK;
         self.registers.LY = 0;
+        self.registers.LX = 0;
         self.clock = 0;
+
+        self.bgfifo.reset(0);
+        self.spfifo.reset();
+
+        // Present a blank frame immediately rather than freezing the last one.
+        self.render_flag = true;
+    }
+
+    /// Re-enabling the LCD restarts at line 0, which begins without an OAM scan.
+    fn lcd_on(&mut self) {
+        self.on = true;
+        self.mode = Mode::OAM;
+        self.registers.STAT &= 0xFF ^ STAT_MODE_MASK;
+        self.registers.LY = 0;
+        self.registers.LX = 0;
+        self.clock = 0;
+
+        self.skip_oam_scan = true;
+        self.blank_frame = true;
+
+        self.log_line_start();
+    }
+
+    /// Restores FF40-FF4B, VRAM and OAM from a save state that does not include the PPU's
+    /// internal state. The current line restarts from the beginning of its mode.
+    pub fn restore(&mut self, registers: &[Byte; 12], vram: &[Byte], oam: &[Byte]) {
+        let [LCDC, STAT, SCY, SCX, LY, LYC, DMA, BGP, OBP0, OBP1, WY, WX] = *registers;
+        self.registers = Registers { LCDC, STAT, SCY, SCX, LX: 0, LY, LYC, WY, WX, BGP, OBP0, OBP1 };
+        self.dma.restore(DMA);
+
+        self.on = LCDC & LCDC_DISPLAY_ENABLE != 0;
+        self.mode = match STAT & STAT_MODE_MASK {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OAM,
+            _ => Mode::Draw,
+        };
+        self.clock = 0;
+        self.skip_oam_scan = false;
+        self.blank_frame = false;
+
+        self.bgfifo.reset(0);
+        self.spfifo.reset();
+
+        self.VRAM[..vram.len()].copy_from_slice(vram);
+        self.OAM[..oam.len()].copy_from_slice(oam);
+    }
+
+    pub fn vram(&self) -> &[Byte] {
+        &self.VRAM
+    }
+
+    /// Sprite attribute table, without the unused area after it.
+    pub fn oam(&self) -> &[Byte] {
+        &self.OAM[..0xA0]
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    fn render_scanline(&mut self) {
+        let start = self.registers.LY as usize * DISPLAY_WIDTH as usize;
+        let line = &mut self.pixel_buffer[start..start + DISPLAY_WIDTH as usize];
+
+        self.scanline.render_line(&self.VRAM, &self.OAM, &self.registers, &self.palette_buffer, line);
+        self.registers.LX = DISPLAY_WIDTH;
+    }
+
+    /// Start collecting a per-scanline log of the rendering registers.
+    pub fn enable_raster_log(&mut self) {
+        self.raster_log = Some(RasterLog::new());
+    }
+
+    pub fn raster_log(&self) -> Option<&RasterLog> {
+        self.raster_log.as_ref()
+    }
+
+    fn log_line_start(&mut self) {
+        if let Some(log) = &mut self.raster_log {
+            if self.registers.LY == 0 {
+                log.next_frame();
+            }
+            log.record(RasterEventKind::LineStart, self.registers.LY, 0, 0, &self.registers);
+        }
+    }
+
+    /// Line, dot and next pixel if pixels are being pushed, which are the only writes of interest.
+    /// Taken before the write is applied, as turning the LCD off ends the line.
+    fn raster_position(&self) -> Option<(Byte, u16, Byte)> {
+        if self.raster_log.is_none() || !self.on || self.mode != Mode::Draw {
+            return None;
+        }
+
+        Some((self.registers.LY, OAM_CYCLES + self.clock, self.registers.LX))
+    }
+
+    fn log_raster_write(&mut self, address: Address, position: Option<(Byte, u16, Byte)>) {
+        let Some((line, dot, x)) = position else {
+            return;
+        };
+
+        match address {
+            0xFF40 | 0xFF42 | 0xFF43 | 0xFF47..=0xFF4B => {},
+            _ => return,
+        }
+
+        if let Some(log) = &mut self.raster_log {
+            log.record(RasterEventKind::Write(address), line, dot, x, &self.registers);
+        }
     }
 }
 
@@ -259,7 +437,7 @@ impl BusListener for PPU {
             0xFF44 => self.registers.LY,
             0xFF45 => self.registers.LYC,
 
-            0xFF46 => self.registers.dma_address,
+            0xFF46 => self.dma.read(),
             0xFF47 => self.registers.BGP,
             0xFF48 => self.registers.OBP0,
             0xFF49 => self.registers.OBP1,
@@ -274,6 +452,14 @@ impl BusListener for PPU {
     }
 
     fn bus_write(&mut self, _bus: &mut Bus, address: Address, value: Byte) {
+        let position = self.raster_position();
+        self.write_register(address, value);
+        self.log_raster_write(address, position);
+    }
+}
+
+impl PPU {
+    fn write_register(&mut self, address: Address, value: Byte) {
         match address {
             // 0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6B => return, // TODO
             0xFEA0..=0xFEFF => return, // This range is unusable
@@ -283,6 +469,24 @@ impl BusListener for PPU {
                 self.registers.STAT = (value & 0xF8) | (stat & 0x07);
                 return;
             }
+            // Writing to the DMA Transfer Register initializes transfer
+            0xFF46 => {
+                self.dma.start(value);
+                return;
+            }
+            0xFF40 => {
+                let was_on = self.registers.LCDC & LCDC_DISPLAY_ENABLE != 0;
+                let is_on = value & LCDC_DISPLAY_ENABLE != 0;
+                self.registers.LCDC = value;
+
+                if was_on && !is_on {
+                    self.lcd_off();
+                }
+                else if !was_on && is_on {
+                    self.lcd_on();
+                }
+                return;
+            }
             _ => {},
         }
 
@@ -290,13 +494,14 @@ impl BusListener for PPU {
             0x8000..=0x9FFF => &mut self.VRAM[(address - 0x8000) as usize],
 
             0xFE00..=0xFE9F => {
-                if self.mode == Mode::OAM || self.mode == Mode::Draw {
-                    panic!("Illegal write to OAM table.");
+                // The PPU owns OAM during modes 2 and 3; the CPU's writes are ignored.
+                if self.on && (self.mode == Mode::OAM || self.mode == Mode::Draw) {
+                    return;
                 }
                 &mut self.OAM[(address - 0xFE00) as usize]
             },
 
-            0xFF40 => &mut self.registers.LCDC,
+            // 0xFF40 HANDLED ABOVE //
             // 0xFF41 HANDLED ABOVE //
 
             0xFF42 => &mut self.registers.SCY,
@@ -311,14 +516,7 @@ impl BusListener for PPU {
 
             0xFF4A => &mut self.registers.WY,
             0xFF4B => &mut self.registers.WX,
-
-            // Writing to the DMA Transfer Register initializes transfer
-            0xFF46 => {
-                self.registers.dma_active = true;
-                self.registers.dma_counter = 0;
-                assert!(value <= 0xF1);
-                &mut self.registers.dma_address
-            },
+            // 0xFF46 HANDLED ABOVE //
 
             _ => panic!("PPU Address ({:04X}) Not Implemented", address),
         };
@@ -326,10 +524,73 @@ impl BusListener for PPU {
     }
 }
 
+impl SaveState for PPU {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.bool(self.on);
+        out.u8(self.mode as u8);
+        out.u16(self.clock);
+
+        for pixel in &self.pixel_buffer {
+            out.u32(*pixel);
+        }
+        out.bool(self.render_flag);
+        out.bool(self.skip_oam_scan);
+        out.bool(self.blank_frame);
+        out.u16(self.idle_clock);
+
+        out.bytes(&self.VRAM);
+        out.bytes(&self.OAM);
+
+        let r = &self.registers;
+        out.bytes(&[r.LCDC, r.STAT, r.SCY, r.SCX, r.LX, r.LY, r.LYC, r.WY, r.WX, r.BGP, r.OBP0, r.OBP1]);
+
+        self.bgfifo.save_state(out);
+        self.spfifo.save_state(out);
+        self.scanline.save_state(out);
+        self.dma.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.on = input.bool()?;
+        self.mode = match input.u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OAM,
+            3 => Mode::Draw,
+            mode => return Err(StateError::Invalid(format!("unknown PPU mode {mode}"))),
+        };
+        self.clock = input.u16()?;
+
+        for pixel in self.pixel_buffer.iter_mut() {
+            *pixel = input.u32()?;
+        }
+        self.render_flag = input.bool()?;
+        self.skip_oam_scan = input.bool()?;
+        self.blank_frame = input.bool()?;
+        self.idle_clock = input.u16()?;
+
+        input.bytes(&mut self.VRAM)?;
+        input.bytes(&mut self.OAM)?;
+
+        let mut registers = [0; 12];
+        input.bytes(&mut registers)?;
+        let [LCDC, STAT, SCY, SCX, LX, LY, LYC, WY, WX, BGP, OBP0, OBP1] = registers;
+        self.registers = Registers { LCDC, STAT, SCY, SCX, LX, LY, LYC, WY, WX, BGP, OBP0, OBP1 };
+
+        self.bgfifo.load_state(input)?;
+        self.spfifo.load_state(input)?;
+        self.scanline.load_state(input)?;
+        self.dma.load_state(input)
+    }
+}
+
 impl ClockListener for PPU {
-    fn callback(&mut self, bus: &mut Bus, cycles: u8) {
-        if self.registers.LCDC & LCDC_DISPLAY_ENABLE == 0 {
-            self.on = false;
+    fn callback(&mut self, bus: &mut Bus, cycles: u32) {
+        // OAM DMA runs regardless of whether the display is enabled.
+        self.dma.tick(bus, cycles, &self.VRAM, &mut self.OAM);
+
+        if !self.on {
+            // Keep presenting blank frames at the usual rate while the LCD is off.
             self.clock += cycles as u16;
 
             if SCREEN_CYCLES < self.clock {
@@ -339,33 +600,16 @@ impl ClockListener for PPU {
 
             return;
         }
-        else if !self.on {
-            self.reset(bus);
-            self.on = true;
-        }
-
-        // DMA Transfer Loop
-        for _ in 0..cycles {
-            // DMA may terminate in the middle of this loop.
-            if !self.registers.dma_active {
-                break;
-            }
-
-            let dma_counter = self.registers.dma_counter as u16;
-            let data = bus.read_byte(((self.registers.dma_address as Address) << 8) | dma_counter);
-            self.OAM[dma_counter as usize] = data;
-
-            self.registers.dma_counter += 1;
-            self.registers.dma_active = self.registers.dma_counter < DISPLAY_WIDTH;
-        }
 
         self.clock += cycles as u16;
 
         use Mode::*;
         match self.mode {
             OAM => {
-                for _ in 0..(cycles << 1) {
-                    self.spfifo.scan_next_oam_table_entry(&self.OAM, &self.registers);
+                if !self.skip_oam_scan && self.renderer == Renderer::Fifo {
+                    for _ in 0..(cycles << 1) {
+                        self.spfifo.scan_next_oam_table_entry(&self.OAM, &self.registers);
+                    }
                 }
 
                 if self.clock < OAM_CYCLES {
@@ -373,38 +617,60 @@ impl ClockListener for PPU {
                 }
 
                 self.clock -= OAM_CYCLES;
+                self.skip_oam_scan = false;
                 self.set_mode(bus, Draw);
             }
             Draw => {
+                if self.renderer == Renderer::Scanline && DRAW_CYCLES <= self.clock {
+                    self.render_scanline();
+                }
+
+                let fifo_cycles = if self.renderer == Renderer::Fifo { cycles << 1 } else { 0 };
+
                 // Render cycle: Push pixels onto the screen.
-                for _ in 0..(cycles << 1) {
+                for _ in 0..fifo_cycles {
                     self.bgfifo.step(&self.VRAM, self.registers);
-                    self.spfifo.step(&self.VRAM, self.registers);
 
                     for _ in 0..2 {
-                        // TODO: Window Handling
-
                         if DISPLAY_WIDTH <= self.registers.LX {
                             break;
                         }
 
-                        let mut pixel_index = 0u8;
+                        // WX is offset by 7; values below 7 start the window off screen.
+                        if !self.bgfifo.is_window()
+                            && self.registers.window_visible()
+                            && self.registers.LX as i16 >= self.registers.WX as i16 - 7
+                        {
+                            self.bgfifo.start_window(self.registers);
+                            break;
+                        }
 
-                        match self.bgfifo.pop() {
+                        let mut background = match self.bgfifo.pop() {
                             None => break,
-                            Some(index) => pixel_index = index,
+                            Some(index) => index,
+                        };
+
+                        if self.registers.LCDC & LCDC_BG_ENABLE == 0 {
+                            background = 0;
                         }
 
-                        let alt_palette_buffer: [u32; 4] = [0xFFFFFF, 0xCC0000, 0x440000, 0xFF0000];
-                        let mut pixel = self.palette_buffer[pixel_index as usize];
+                        let mut pixel = self.palette_buffer[shade(self.registers.BGP, background) as usize];
+
+                        self.spfifo.fetch(&self.VRAM, self.registers);
+
+                        if let Some(sprite) = self.spfifo.pop() {
+                            let index = sprite & 0x03;
 
-                        // TODO: Sprite priority.
-                        match self.spfifo.pop(self.registers.LX) {
-                            None => {},
-                            Some(index) => {
-                                pixel_index = index;
-                                pixel = alt_palette_buffer[pixel_index as usize];
-                            },
+                            // Colour 0 is transparent, and sprites with priority set are only
+                            // drawn over background colour 0.
+                            if index != 0 && (sprite & SPRITE_PRIORITY == 0 || background == 0) {
+                                let obp = if sprite & SPRITE_PALETTE == 0 {
+                                    self.registers.OBP0
+                                } else {
+                                    self.registers.OBP1
+                                };
+                                pixel = self.palette_buffer[shade(obp, index) as usize];
+                            }
                         }
 
                         let buffer_index = (self.registers.LY as u16 * DISPLAY_WIDTH as u16)
@@ -446,6 +712,7 @@ impl ClockListener for PPU {
                 self.spfifo.reset();
                 self.registers.LX = 0;
                 self.registers.LY += 1;
+                self.log_line_start();
 
                 if self.registers.LY >= DISPLAY_HEIGHT {
                     self.set_mode(bus, VBlank);
@@ -463,14 +730,46 @@ impl ClockListener for PPU {
                 self.registers.LY += 1;
 
                 if self.registers.LY < VIRTUAL_DISPLAY_HEIGHT {
+                    self.log_line_start();
                     return;
                 }
 
                 self.render_flag = true;
 
                 self.registers.LY = 0;
+                self.log_line_start();
                 self.set_mode(bus, OAM);
             }
         }
     }
+
+    fn next_event(&self) -> u32 {
+        // A running OAM DMA affects every CPU access.
+        if self.dma.is_active() {
+            return 1;
+        }
+
+        // While the LCD is off, a blank frame is only presented once a whole screen has passed.
+        let end = if !self.on {
+            SCREEN_CYCLES + 1
+        }
+        else {
+            match self.mode {
+                Mode::OAM => OAM_CYCLES,
+                Mode::Draw => DRAW_CYCLES,
+                Mode::HBlank => HBLANK_CYCLES,
+                Mode::VBlank => VBLANK_LINE_CYCLES,
+            }
+        };
+
+        let until_end = end.saturating_sub(self.clock) as u32;
+
+        // The pixel FIFO pushes at most four pixels per cycle, so the line cannot be finished sooner.
+        if self.on && self.mode == Mode::Draw && self.renderer == Renderer::Fifo {
+            let pixels = DISPLAY_WIDTH.saturating_sub(self.registers.LX) as u32;
+            return until_end.max(pixels.div_ceil(4)).max(1);
+        }
+
+        until_end
+    }
 }
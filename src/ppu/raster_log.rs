@@ -0,0 +1,254 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+
+use crate::bus::*;
+use crate::ppu::Registers;
+
+/// Registers that affect rendering mid-frame.
+#[derive(Debug, Copy, Clone)]
+pub struct RasterRegisters {
+    pub LCDC: Byte,
+    pub SCX: Byte,
+    pub SCY: Byte,
+    pub WX: Byte,
+    pub WY: Byte,
+    pub BGP: Byte,
+    pub OBP0: Byte,
+    pub OBP1: Byte,
+}
+
+impl RasterRegisters {
+    fn from(registers: &Registers) -> Self {
+        Self {
+            LCDC: registers.LCDC,
+            SCX: registers.SCX,
+            SCY: registers.SCY,
+            WX: registers.WX,
+            WY: registers.WY,
+            BGP: registers.BGP,
+            OBP0: registers.OBP0,
+            OBP1: registers.OBP1,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RasterEventKind {
+    /// Snapshot taken at the start of a line.
+    LineStart,
+
+    /// A register was written while the line was being drawn (mode 3).
+    Write(Address),
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct RasterEvent {
+    pub frame: u32,
+    pub line: Byte,
+
+    /// PPU clock within the line and the next pixel to be output when the event occurred.
+    pub dot: u16,
+    pub x: Byte,
+
+    pub kind: RasterEventKind,
+
+    /// Register state after the event.
+    pub registers: RasterRegisters,
+}
+
+// Events kept by default, about 24 MB or two minutes of frames without mid-line writes.
+const DEFAULT_CAPACITY: usize = 1 << 20;
+
+/// Per-scanline log of rendering registers, used to track down raster effect bugs. Only the
+/// most recent events are kept, so that long sessions don't run out of memory.
+#[derive(Debug)]
+pub struct RasterLog {
+    frame: u32,
+    capacity: usize,
+    events: VecDeque<RasterEvent>,
+
+    // Events dropped to stay within capacity.
+    dropped: u64,
+}
+
+impl Default for RasterLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RasterLog {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Keeps at most `capacity` events, dropping the oldest ones once it is full.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            frame: 0,
+            capacity,
+            events: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Number of events that were dropped to make room for newer ones.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub(crate) fn next_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    /// Records an event at the given position, with the register state after it.
+    pub(crate) fn record(&mut self, kind: RasterEventKind, line: Byte, dot: u16, x: Byte, registers: &Registers) {
+        if self.events.len() >= self.capacity {
+            if self.events.pop_front().is_none() {
+                return;
+            }
+            self.dropped += 1;
+        }
+
+        self.events.push_back(RasterEvent {
+            frame: self.frame,
+            line,
+            dot,
+            x,
+            kind,
+            registers: RasterRegisters::from(registers),
+        });
+    }
+
+    fn event_name(kind: RasterEventKind) -> String {
+        match kind {
+            RasterEventKind::LineStart => String::from("line"),
+            RasterEventKind::Write(address) => format!("write {address:04X}"),
+        }
+    }
+
+    /// Register values are written in decimal, as in `write_json`.
+    pub fn write_csv(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "frame,line,dot,x,event,LCDC,SCX,SCY,WX,WY,BGP,OBP0,OBP1")?;
+
+        for event in &self.events {
+            let r = &event.registers;
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                event.frame, event.line, event.dot, event.x, Self::event_name(event.kind),
+                r.LCDC, r.SCX, r.SCY, r.WX, r.WY, r.BGP, r.OBP0, r.OBP1,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "[")?;
+
+        for (i, event) in self.events.iter().enumerate() {
+            let r = &event.registers;
+            let separator = if i + 1 < self.events.len() { "," } else { "" };
+            writeln!(
+                out,
+                concat!(
+                    "  {{\"frame\": {}, \"line\": {}, \"dot\": {}, \"x\": {}, \"event\": \"{}\", ",
+                    "\"LCDC\": {}, \"SCX\": {}, \"SCY\": {}, \"WX\": {}, \"WY\": {}, ",
+                    "\"BGP\": {}, \"OBP0\": {}, \"OBP1\": {}}}{}",
+                ),
+                event.frame, event.line, event.dot, event.x, Self::event_name(event.kind),
+                r.LCDC, r.SCX, r.SCY, r.WX, r.WY, r.BGP, r.OBP0, r.OBP1, separator,
+            )?;
+        }
+
+        writeln!(out, "]")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::{Bus, BusListener};
+    use crate::ppu::{Mode, Registers, PPU};
+    use super::{RasterEventKind, RasterLog};
+
+    fn registers(line: u8, scx: u8) -> Registers {
+        Registers {
+            LCDC: 0x91,
+            STAT: 0,
+            SCY: 0x10,
+            SCX: scx,
+            LX: 8,
+            LY: line,
+            LYC: 0,
+            WY: 0,
+            WX: 7,
+            BGP: 0xE4,
+            OBP0: 0xD2,
+            OBP1: 0x1B,
+        }
+    }
+
+    /// Three events in a log that only has room for the last two.
+    fn log() -> RasterLog {
+        let mut log = RasterLog::with_capacity(2);
+        log.record(RasterEventKind::LineStart, 0, 0, 0, &registers(0, 0));
+        log.next_frame();
+        log.record(RasterEventKind::LineStart, 1, 0, 0, &registers(1, 0));
+        log.record(RasterEventKind::Write(0xFF43), 1, 90, 8, &registers(1, 0x2A));
+        log
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let log = log();
+        assert_eq!(log.dropped(), 1);
+
+        let mut out = Vec::new();
+        log.write_csv(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "frame,line,dot,x,event,LCDC,SCX,SCY,WX,WY,BGP,OBP0,OBP1\n",
+            "1,1,0,0,line,145,0,16,7,0,228,210,27\n",
+            "1,1,90,8,write FF43,145,42,16,7,0,228,210,27\n",
+        ));
+    }
+
+    #[test]
+    fn test_lcd_off_write() {
+        let mut ppu = PPU::new();
+        ppu.enable_raster_log();
+        ppu.bus_write(&mut Bus::new(), 0xFF40, 0x91);
+        ppu.mode = Mode::Draw;
+        ppu.registers.LY = 5;
+        ppu.registers.LX = 12;
+
+        // The write is logged where it landed, even though it ends the line.
+        ppu.bus_write(&mut Bus::new(), 0xFF40, 0x11);
+
+        let event = ppu.raster_log().unwrap().events.back().copied().unwrap();
+        assert_eq!(event.kind, RasterEventKind::Write(0xFF40));
+        assert_eq!((event.line, event.x), (5, 12));
+        assert_eq!(event.registers.LCDC, 0x11);
+    }
+
+    #[test]
+    fn test_write_json() {
+        let mut out = Vec::new();
+        log().write_json(&mut out).unwrap();
+
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let events = json.as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["event"], "write FF43");
+        assert_eq!(events[1]["dot"], 90);
+        assert_eq!(events[1]["SCX"], 0x2A);
+        assert_eq!(events[0]["line"], 1);
+        assert_eq!(events[0]["BGP"], 0xE4);
+
+        // An empty log is still a valid document.
+        let mut out = Vec::new();
+        RasterLog::new().write_json(&mut out).unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&out).unwrap(), serde_json::json!([]));
+    }
+}
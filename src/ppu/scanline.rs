@@ -0,0 +1,329 @@
+use crate::bus::*;
+use crate::ppu::{
+    shade, Registers, DISPLAY_WIDTH, LCDC_BG_ENABLE, LCDC_SPRITE_ENABLE, LCDC_SPRITE_SIZE,
+    LCDC_TILE_DATA_SELECT, LCDC_TILE_MAP_SELECT, LCDC_WINDOW_TILE_MAP_SELECT, MAX_SPRITES_PER_LINE,
+    SPRITE_PALETTE, SPRITE_PRIORITY, SPRITE_X_FLIP, SPRITE_Y_FLIP, TILE_DATA_BLOCK_BASE,
+    TILE_MAP_HI_BASE, TILE_MAP_LO_BASE,
+};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+#[derive(Debug, Copy, Clone)]
+struct Sprite {
+    x: i16,
+    y: i16,
+    tile: u8,
+    attributes: u8,
+}
+
+/// Renders a whole line at once from the register state at the end of mode 3.
+/// Much cheaper than the pixel FIFOs and used as a reference when debugging them.
+#[derive(Debug)]
+pub struct ScanlineRenderer {
+    // Line of the window to draw next; only advances on lines where the window is visible.
+    window_line: u8,
+}
+
+impl ScanlineRenderer {
+    pub fn new() -> Self {
+        Self {
+            window_line: 0,
+        }
+    }
+
+    pub fn render_line(
+        &mut self,
+        vram: &[Byte],
+        oam: &[Byte],
+        registers: &Registers,
+        palette: &[u32; 4],
+        line: &mut [u32],
+    ) {
+        if registers.LY == 0 {
+            self.window_line = 0;
+        }
+
+        // Background colour indices are kept around for sprite priority.
+        let mut background = [0u8; DISPLAY_WIDTH as usize];
+
+        if registers.LCDC & LCDC_BG_ENABLE != 0 {
+            self.render_background(vram, registers, &mut background);
+        }
+
+        for x in 0..DISPLAY_WIDTH as usize {
+            line[x] = palette[shade(registers.BGP, background[x]) as usize];
+        }
+
+        if registers.LCDC & LCDC_SPRITE_ENABLE != 0 {
+            Self::render_sprites(vram, oam, registers, palette, &background, line);
+        }
+    }
+
+    fn tile_map_base(registers: &Registers, select: u8) -> Address {
+        if registers.LCDC & select == 0 { TILE_MAP_LO_BASE } else { TILE_MAP_HI_BASE }
+    }
+
+    /// Returns the colour index of pixel (x, y) of a background or window tile.
+    fn tile_pixel(vram: &[Byte], registers: &Registers, tile_no: u8, x: u8, y: u8) -> u8 {
+        let base = if registers.LCDC & LCDC_TILE_DATA_SELECT != 0 {
+            TILE_DATA_BLOCK_BASE[0] + ((tile_no as Address) << 4)
+        }
+        else {
+            // Tile numbers are signed relative to 0x1000.
+            (TILE_DATA_BLOCK_BASE[2] as i32 + ((tile_no as i8 as i32) << 4)) as Address
+        };
+
+        let address = (base + ((y as Address) << 1)) as usize;
+        Self::pixel(vram[address], vram[address + 1], x)
+    }
+
+    fn pixel(lo: Byte, hi: Byte, x: u8) -> u8 {
+        let bit = 7 - x;
+        (((hi >> bit) & 1) << 1) | ((lo >> bit) & 1)
+    }
+
+    fn render_background(&mut self, vram: &[Byte], registers: &Registers, background: &mut [u8]) {
+        let window_visible = registers.window_visible();
+
+        // WX is offset by 7; values below 7 start the window off screen.
+        let window_start = if window_visible { registers.WX as i16 - 7 } else { DISPLAY_WIDTH as i16 };
+
+        let map_base = Self::tile_map_base(registers, LCDC_TILE_MAP_SELECT);
+        let y = registers.LY.wrapping_add(registers.SCY);
+
+        for x in 0..DISPLAY_WIDTH {
+            if x as i16 >= window_start {
+                break;
+            }
+
+            let map_x = x.wrapping_add(registers.SCX);
+            let map_index = (((y >> 3) as Address) << 5) + (map_x >> 3) as Address;
+            let tile_no = vram[(map_base + map_index) as usize];
+            background[x as usize] = Self::tile_pixel(vram, registers, tile_no, map_x & 7, y & 7);
+        }
+
+        if !window_visible {
+            return;
+        }
+
+        let map_base = Self::tile_map_base(registers, LCDC_WINDOW_TILE_MAP_SELECT);
+        let y = self.window_line;
+
+        for x in window_start.max(0)..DISPLAY_WIDTH as i16 {
+            let window_x = (x - window_start) as u8;
+            let map_index = (((y >> 3) as Address) << 5) + (window_x >> 3) as Address;
+            let tile_no = vram[(map_base + map_index) as usize];
+            background[x as usize] = Self::tile_pixel(vram, registers, tile_no, window_x & 7, y & 7);
+        }
+
+        self.window_line += 1;
+    }
+
+    fn render_sprites(
+        vram: &[Byte],
+        oam: &[Byte],
+        registers: &Registers,
+        palette: &[u32; 4],
+        background: &[u8],
+        line: &mut [u32],
+    ) {
+        let height: i16 = if registers.LCDC & LCDC_SPRITE_SIZE != 0 { 16 } else { 8 };
+        let ly = registers.LY as i16;
+
+        // The first ten sprites in OAM order that overlap the line are drawn.
+        let mut sprites: Vec<Sprite> = oam[..0xA0]
+            .chunks(4)
+            .map(|entry| Sprite {
+                y: entry[0] as i16 - 16,
+                x: entry[1] as i16 - 8,
+                tile: entry[2],
+                attributes: entry[3],
+            })
+            .filter(|sprite| sprite.y <= ly && ly < sprite.y + height)
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+
+        // Lower X wins; ties go to the sprite earlier in OAM (the sort is stable).
+        sprites.sort_by_key(|sprite| sprite.x);
+
+        for x in 0..DISPLAY_WIDTH as i16 {
+            for sprite in &sprites {
+                if x < sprite.x || sprite.x + 8 <= x {
+                    continue;
+                }
+
+                let mut row = ly - sprite.y;
+                if sprite.attributes & SPRITE_Y_FLIP != 0 {
+                    row = height - 1 - row;
+                }
+
+                let mut column = (x - sprite.x) as u8;
+                if sprite.attributes & SPRITE_X_FLIP != 0 {
+                    column = 7 - column;
+                }
+
+                // Bit 0 of the tile number is ignored for 8x16 sprites.
+                let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+                let address = (((tile as Address) << 4) + ((row as Address) << 1)) as usize;
+                let index = Self::pixel(vram[address], vram[address + 1], column);
+
+                // Colour 0 is transparent; the next sprite in line may still be visible.
+                if index == 0 {
+                    continue;
+                }
+
+                if sprite.attributes & SPRITE_PRIORITY == 0 || background[x as usize] == 0 {
+                    let obp = if sprite.attributes & SPRITE_PALETTE == 0 { registers.OBP0 } else { registers.OBP1 };
+                    line[x as usize] = palette[shade(obp, index) as usize];
+                }
+
+                break;
+            }
+        }
+    }
+}
+
+impl SaveState for ScanlineRenderer {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.window_line);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.window_line = input.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ppu::{Registers, DISPLAY_WIDTH, LCDC_SPRITE_ENABLE, LCDC_WINDOW_ENABLE, LCDC_WINDOW_TILE_MAP_SELECT};
+    use super::ScanlineRenderer;
+
+    // Shades are output as is, so that lines can be compared against colour indices.
+    const PALETTE: [u32; 4] = [0, 1, 2, 3];
+
+    const WINDOW_MAP: usize = 0x1C00;
+
+    /// Tiles 1 to 3 are filled with their own colour, tile 4 has its left half transparent.
+    fn vram() -> Vec<u8> {
+        let mut vram = vec![0; 0x2000];
+
+        for (tile, (lo, hi)) in [(1, (0xFF, 0x00)), (2, (0x00, 0xFF)), (3, (0xFF, 0xFF)), (4, (0x0F, 0x00))] {
+            for row in 0..8 {
+                vram[tile * 16 + row * 2] = lo;
+                vram[tile * 16 + row * 2 + 1] = hi;
+            }
+        }
+
+        vram
+    }
+
+    fn registers(lcdc: u8) -> Registers {
+        Registers {
+            LCDC: 0x91 | lcdc,
+            STAT: 0,
+            SCY: 0,
+            SCX: 0,
+            LX: 0,
+            LY: 0,
+            LYC: 0,
+            WY: 0,
+            WX: 0,
+            BGP: 0xE4,
+            OBP0: 0xE4,
+            OBP1: 0xE4,
+        }
+    }
+
+    fn render(renderer: &mut ScanlineRenderer, vram: &[u8], oam: &[u8], registers: &Registers) -> Vec<u32> {
+        let mut line = vec![0xFF; DISPLAY_WIDTH as usize];
+        renderer.render_line(vram, oam, registers, &PALETTE, &mut line);
+        line
+    }
+
+    #[test]
+    fn test_background() {
+        let mut vram = vram();
+        vram[0x1800] = 1;
+        vram[0x1801] = 2;
+
+        let mut registers = registers(0);
+        registers.SCX = 4;
+        let line = render(&mut ScanlineRenderer::new(), &vram, &[0; 0xA0], &registers);
+        assert_eq!(line[..14], [1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 0, 0]);
+
+        // Scrolling wraps around at 256 pixels, and colours go through BGP.
+        registers.SCX = 252;
+        registers.BGP = 0x1B;
+        let line = render(&mut ScanlineRenderer::new(), &vram, &[0; 0xA0], &registers);
+        assert_eq!(line[..14], [3, 3, 3, 3, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1]);
+    }
+
+    #[test]
+    fn test_window_line_counter() {
+        let mut vram = vram();
+        vram[WINDOW_MAP..WINDOW_MAP + 32].fill(1);
+        vram[WINDOW_MAP + 32..WINDOW_MAP + 64].fill(2);
+
+        let mut renderer = ScanlineRenderer::new();
+        let mut registers = registers(LCDC_WINDOW_ENABLE | LCDC_WINDOW_TILE_MAP_SELECT);
+        registers.WX = 7 + 80;
+
+        for ly in 0..16 {
+            registers.LY = ly;
+
+            // The window is moved off screen for lines 4 to 11.
+            registers.WX = if (4..12).contains(&ly) { 200 } else { 7 + 80 };
+            let line = render(&mut renderer, &vram, &[0; 0xA0], &registers);
+
+            assert_eq!(line[79], 0);
+            let expected = match ly {
+                4..=11 => 0,
+                // Its fifth line is drawn on line 12, not its thirteenth.
+                0..=3 | 12..=15 => 1,
+                _ => unreachable!(),
+            };
+            assert_eq!(line[80], expected, "Line {ly}.");
+        }
+
+        // The next window line is the ninth.
+        registers.LY = 16;
+        assert_eq!(render(&mut renderer, &vram, &[0; 0xA0], &registers)[80], 2);
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let vram = vram();
+        let mut oam = vec![0; 0xA0];
+
+        // Eleven sprites on line 0, side by side.
+        for i in 0..11 {
+            oam[i * 4..i * 4 + 4].copy_from_slice(&[16, 8 + 10 * i as u8, 3, 0]);
+        }
+
+        let line = render(&mut ScanlineRenderer::new(), &vram, &oam, &registers(LCDC_SPRITE_ENABLE));
+
+        for i in 0..10 {
+            assert_eq!(line[i * 10..i * 10 + 8], [3; 8], "Sprite {i}.");
+        }
+        assert_eq!(line[100..108], [0; 8]);
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let vram = vram();
+        let mut oam = vec![0; 0xA0];
+
+        // The sprite further left wins, even if it comes later in OAM, except where it is
+        // transparent.
+        oam[0..4].copy_from_slice(&[16, 8 + 12, 3, 0]);
+        oam[4..8].copy_from_slice(&[16, 8 + 10, 4, 0]);
+
+        // On the same X, the sprite earlier in OAM wins.
+        oam[8..12].copy_from_slice(&[16, 8 + 40, 3, 0]);
+        oam[12..16].copy_from_slice(&[16, 8 + 40, 2, 0]);
+
+        let line = render(&mut ScanlineRenderer::new(), &vram, &oam, &registers(LCDC_SPRITE_ENABLE));
+        assert_eq!(line[10..20], [0, 0, 3, 3, 1, 1, 1, 1, 3, 3]);
+        assert_eq!(line[40..48], [3; 8]);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{Address, Byte, Registers};
-use crate::ppu::fifo::{FifoState, FifoState::*, PixelFifo};
-use crate::ppu::{LCDC_SPRITE_ENABLE, LCDC_SPRITE_SIZE, LCDC_TILE_DATA_SELECT, LCDC_TILE_MAP_SELECT, LCDC_WINDOW_TILE_MAP_SELECT, MAX_SPRITES_PER_LINE, Point, TILE_DATA_BLOCK_BASE, TILE_MAP_HI_BASE, TILE_MAP_LO_BASE};
+use crate::ppu::fifo::PixelFifo;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+use crate::ppu::{LCDC_SPRITE_ENABLE, LCDC_SPRITE_SIZE, MAX_SPRITES_PER_LINE, SPRITE_PALETTE, SPRITE_PRIORITY, SPRITE_X_FLIP, SPRITE_Y_FLIP};
 
 #[derive(Debug, Copy, Clone)]
 struct OamEntry {
@@ -24,50 +25,31 @@ impl OamEntry {
 // TODO: Move this to its own file.
 #[derive(Debug)]
 pub struct SpriteFifo {
+    // Colour index in bits 0-1, with the priority and palette bits of the sprite's attributes.
     fifo: PixelFifo,
-    entry_fifo: PixelFifo,
-
-    state: FifoState,
 
     // OAM Entries
     oam_table: [OamEntry; MAX_SPRITES_PER_LINE],
     oam_table_size: usize,
     oam_entry_index: usize,
     oam_scan_index: usize,
-
-    // Column
-    column: u8,
-
-    // Discard columns when scrolled
-    discard_columns: u8,
-
-    tile_data: (u8, u8),
-    tile_data_address: Address,
 }
 
 impl SpriteFifo {
     pub(crate) fn new() -> Self {
         Self {
             fifo: PixelFifo::new(),
-            entry_fifo: PixelFifo::new(),
-
-            state: FetchTileNo,
 
             oam_table: [OamEntry::new(); MAX_SPRITES_PER_LINE],
             oam_table_size: 0,
             oam_entry_index: 0, // OAM entry to read from while drawing
             oam_scan_index: 0, // OAM entry to read from memory
-
-            column: 0,
-            discard_columns: 0,
-
-            tile_data: (0, 0),
-            tile_data_address: 0,
         }
     }
 
     pub(crate) fn scan_next_oam_table_entry(&mut self, oam: &[Byte], registers: &Registers) {
-        if self.oam_scan_index >= 40 {
+        // Only the first ten sprites in OAM order that overlap the line are drawn.
+        if self.oam_scan_index >= 40 || self.oam_table_size >= MAX_SPRITES_PER_LINE {
             return;
         }
 
@@ -81,35 +63,21 @@ impl SpriteFifo {
             attributes: oam[index + 3],
         };
 
-        // Sprite is outside the screen (y).
-        if oam_entry.y == 0 || oam_entry.y >= 160 {
-            return;
-        }
-
-        // Sprite is outside the screen (x).
-        if oam_entry.x == 0 || oam_entry.x >= 168 {
-            return;
-        }
-
-        let ly = registers.LY + 16;
-
-        // Sprite is below current line (y).
-        if ly < oam_entry.y {
-            return;
-        }
-
         let sprite_height = if registers.LCDC & LCDC_SPRITE_SIZE != 0 { 16 } else { 8 };
+        let top = oam_entry.y as i16 - 16;
+        let ly = registers.LY as i16;
 
-        // Sprite is above current line (y).
-        if ly >= oam_entry.y + sprite_height {
+        // Sprite does not overlap the current line. Sprites outside the screen horizontally
+        // still count towards the limit.
+        if ly < top || top + sprite_height <= ly {
             return;
         }
 
-        // Sort entries by x value.
+        // Sort entries by x value; ties keep their OAM order.
         let mut tmp_entry = oam_entry;
         for i in 0..self.oam_table_size {
             let oam_entry = self.oam_table[i];
-            if oam_entry.x < tmp_entry.x {
+            if oam_entry.x <= tmp_entry.x {
                 continue;
             }
             self.oam_table[i] = tmp_entry;
@@ -117,109 +85,95 @@ impl SpriteFifo {
         }
         self.oam_table[self.oam_table_size] = tmp_entry;
         self.oam_table_size += 1;
-
-        // println!("\nOAM TABLE Y {:3}", registers.LY + 16);
-        // for i in 0..self.oam_table_size {
-        //     println!("Entry {:3} X {:3} Y {:3} I {:3}", i, self.oam_table[i].x, self.oam_table[i].y, self.oam_table[i].tile);
-        // }
-        // println!("");
     }
 
-    pub(crate) fn step(&mut self, vram: &[Byte], registers: Registers) {
-
+    /// Fetches the sprites starting at the current column into the FIFO. Sprites partly left
+    /// of the screen are fetched on column 0, without their hidden columns.
+    pub(crate) fn fetch(&mut self, vram: &[Byte], registers: Registers) {
         if registers.LCDC & LCDC_SPRITE_ENABLE == 0 {
             // Sprites are disabled.
             return;
         }
 
-        use FifoState::*;
-        match &self.state {
-            FetchTileNo => {
-                if self.oam_table_size <= self.oam_entry_index {
-                    // There are no more entries to process.
-                    return;
-                }
-
-                let oam_entry = self.oam_table[self.oam_entry_index];
-
-                for pixel_offset in 0..8 {
-                    if self.column < oam_entry.x {
-                        self.column += 1;
-                        break;
-                    }
-
-                    if pixel_offset == 7 {
-                        return;
-                    }
-                }
-
-                if oam_entry.x < 8 {
-                    self.discard_columns = 8 - oam_entry.x;
-                }
-
-                let tile_no: u8 = oam_entry.tile;
-
-                // Each tile takes up 16 bytes, so tile_no is multiplied by 16.
-                // Each pixel takes up 2 bits, so the y offset must be multiplied by 2.
-                // Sprites can be 16 pixels tall and are offset by this height.
-                let entry_y = oam_entry.y as i16 - 16;
-                let height = (registers.LY as i16) - entry_y;
-                self.tile_data_address = ((tile_no as u16) << 4) + ((height as u16) << 1);
-            }
-            FetchTileLo => {
-                self.tile_data.0 = vram[self.tile_data_address as usize];
-            }
-            FetchTileHi => {
-                self.tile_data.1 = vram[(self.tile_data_address + 1) as usize];
+        let height: u8 = if registers.LCDC & LCDC_SPRITE_SIZE != 0 { 16 } else { 8 };
+
+        while self.oam_entry_index < self.oam_table_size {
+            let oam_entry = self.oam_table[self.oam_entry_index];
+
+            if registers.LX as u16 + 8 < oam_entry.x as u16 {
+                // The table is sorted, so no other sprite starts here either.
+                return;
             }
-            PushTile => {
-                if self.fifo.size > 8 {
-                    return;
-                }
-
-                for mask_bit in (0..8).rev() {
-                    if self.discard_columns != 0 {
-                        self.discard_columns -= 1;
-                        continue;
-                    }
-
-                    // TODO add priority and palette information.
-                    let pixel = (self.tile_data.1.overflowing_shr(mask_bit).0 & 1) << 1
-                        | (self.tile_data.0.overflowing_shr(mask_bit).0 & 1);
-
-                    self.entry_fifo.push(self.oam_table[self.oam_entry_index].x as u8);
-                    self.fifo.push(pixel);
-                }
-
-                self.oam_entry_index += 1;
-                self.column += 8 - self.discard_columns;
+
+            self.oam_entry_index += 1;
+
+            // The mask only matters if the sprite size was changed since the OAM scan.
+            let mut row = registers.LY.wrapping_add(16).wrapping_sub(oam_entry.y) & (height - 1);
+            if oam_entry.attributes & SPRITE_Y_FLIP != 0 {
+                row = height - 1 - row;
             }
-        }
 
-        self.state.next();
-    }
+            // Bit 0 of the tile number is ignored for 8x16 sprites.
+            let tile_no = if height == 16 { oam_entry.tile & 0xFE } else { oam_entry.tile };
 
-    pub fn pop(&mut self, x: u8) -> Option<u8> {
-        if self.entry_fifo.is_empty() {
-            return None;
-        }
+            // Each tile takes up 16 bytes, so tile_no is multiplied by 16.
+            // Each pixel takes up 2 bits, so the y offset must be multiplied by 2.
+            let address = (((tile_no as Address) << 4) + ((row as Address) << 1)) as usize;
+            let tile_data = (vram[address], vram[address + 1]);
 
-        let ex = *self.entry_fifo.top();
-        if x + 8 < ex {
-            return None;
+            let hidden = registers.LX + 8 - oam_entry.x;
+            let attributes = oam_entry.attributes & (SPRITE_PRIORITY | SPRITE_PALETTE);
+
+            for column in hidden..8 {
+                let mask_bit = if oam_entry.attributes & SPRITE_X_FLIP != 0 { column } else { 7 - column };
+                let pixel = (tile_data.1 >> mask_bit & 1) << 1 | (tile_data.0 >> mask_bit & 1);
+
+                // Sprites fetched earlier keep their pixels, except where they are transparent.
+                self.fifo.mix((column - hidden) as usize, pixel | attributes);
+            }
         }
+    }
 
-        self.entry_fifo.pop();
+    pub fn pop(&mut self) -> Option<u8> {
         self.fifo.pop()
     }
 
     pub fn reset(&mut self) {
         self.fifo.clear();
-        self.entry_fifo.clear();
-        self.state = FetchTileNo;
-        self.column = 0;
         self.oam_entry_index = 0;
         self.oam_scan_index = 0;
         self.oam_table_size = 0;
     }
 }
+
+impl SaveState for SpriteFifo {
+    fn save_state(&self, out: &mut StateWriter) {
+        self.fifo.save_state(out);
+
+        for entry in &self.oam_table {
+            out.bytes(&[entry.x, entry.y, entry.tile, entry.attributes]);
+        }
+        out.u8(self.oam_table_size as u8);
+        out.u8(self.oam_entry_index as u8);
+        out.u8(self.oam_scan_index as u8);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.fifo.load_state(input)?;
+
+        for entry in &mut self.oam_table {
+            let mut bytes = [0; 4];
+            input.bytes(&mut bytes)?;
+            let [x, y, tile, attributes] = bytes;
+            *entry = OamEntry { x, y, tile, attributes };
+        }
+        self.oam_table_size = input.u8()? as usize;
+        self.oam_entry_index = input.u8()? as usize;
+        self.oam_scan_index = input.u8()? as usize;
+
+        if self.oam_table_size > MAX_SPRITES_PER_LINE || self.oam_scan_index > 40 {
+            return Err(StateError::Invalid(String::from("sprite FIFO OAM index out of range")));
+        }
+        Ok(())
+    }
+}
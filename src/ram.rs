@@ -1,4 +1,5 @@
 use crate::{Address, Bus, BusListener, Byte, Attach};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 const RAM_BASE_ADDRESS: Address = 0xC000;
 const BANKED_RAM_BASE_ADDRESS: Address = 0xD000;
@@ -38,6 +39,16 @@ impl BusListener for RAM {
     }
 }
 
+impl SaveState for RAM {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.bytes(&self.data);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        input.bytes(&mut self.data)
+    }
+}
+
 
 pub struct DummyRAM {
     pub data: Vec<u8>,
@@ -112,11 +123,29 @@ impl BusListener for DummyRAM {
     }
 }
 
-pub struct RegisterHoles;
+impl SaveState for DummyRAM {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.bytes(&self.data);
+        out.bool(self.locked);
+        out.u8(self.lock_value);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        input.bytes(&mut self.data)?;
+        self.locked = input.bool()?;
+        self.lock_value = input.u8()?;
+        Ok(())
+    }
+}
+
+/// IO registers that don't exist. KEY1 only exists in CGB mode, see `SpeedSwitch`.
+pub struct RegisterHoles {
+    cgb: bool,
+}
 
 impl RegisterHoles {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(cgb: bool) -> Self {
+        Self { cgb }
     }
 }
 
@@ -125,11 +154,16 @@ impl BusListener for RegisterHoles {
         vec![
             Attach::RegisterRange(0x08, 0x0E),
             //Attach::RegisterRange(0x27, 0x2F),
-            Attach::RegisterRange(0x4C, 0x7F),
+            Attach::RegisterRange(0x4C, if self.cgb { 0x4C } else { 0x4D }),
+            Attach::RegisterRange(0x4E, 0x7F),
         ]
     }
 
     fn bus_read(&self, address: Address) -> Byte {
+        // KEY1 reads as open bus outside CGB mode; the other holes read as 0 for now.
+        if address == 0xFF4D {
+            return 0xFF;
+        }
         0
     }
 
@@ -0,0 +1,240 @@
+//! History of recent save states, for stepping back in time.
+//!
+//! Consecutive states differ in few bytes, so most snapshots are stored as the XOR of the state
+//! and the last keyframe, which is mostly zeros. Every snapshot is then compressed by
+//! run-length encoding its zeros. Once the history outgrows its memory budget, the oldest
+//! keyframe is dropped together with the deltas against it.
+
+use std::collections::VecDeque;
+
+struct Snapshot {
+    keyframe: bool,
+
+    // Compressed state for keyframes, compressed XOR against the previous keyframe otherwise.
+    data: Vec<u8>,
+}
+
+pub struct RewindBuffer {
+    budget: usize,
+    keyframe_interval: usize,
+    snapshots: VecDeque<Snapshot>,
+
+    // Compressed bytes held in `snapshots`.
+    size: usize,
+
+    // The newest keyframe, uncompressed.
+    keyframe: Vec<u8>,
+}
+
+impl RewindBuffer {
+    /// Keeps roughly `budget` bytes of history, with a keyframe every `keyframe_interval`
+    /// snapshots. The newest keyframe and its deltas are always kept.
+    pub fn new(budget: usize, keyframe_interval: usize) -> Self {
+        Self {
+            budget,
+            keyframe_interval: keyframe_interval.max(1),
+            snapshots: VecDeque::new(),
+            size: 0,
+            keyframe: Vec::new(),
+        }
+    }
+
+    /// Number of snapshots held.
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Compressed bytes held.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn deltas_since_keyframe(&self) -> usize {
+        self.snapshots.iter().rev().take_while(|snapshot| !snapshot.keyframe).count()
+    }
+
+    pub fn push(&mut self, state: &[u8]) {
+        // States only have the same length when they come from the same cartridge.
+        let keyframe = self.snapshots.is_empty()
+            || state.len() != self.keyframe.len()
+            || self.deltas_since_keyframe() + 1 >= self.keyframe_interval;
+
+        let data = if keyframe {
+            self.keyframe = state.to_vec();
+            compress(state)
+        }
+        else {
+            compress(&xor(state, &self.keyframe))
+        };
+
+        self.size += data.len();
+        self.snapshots.push_back(Snapshot { keyframe, data });
+
+        while self.size > self.budget && self.snapshots.iter().skip(1).any(|snapshot| snapshot.keyframe) {
+            self.drop_oldest_keyframe();
+        }
+    }
+
+    fn drop_oldest_keyframe(&mut self) {
+        while let Some(snapshot) = self.snapshots.pop_front() {
+            self.size -= snapshot.data.len();
+
+            if self.snapshots.front().is_some_and(|next| next.keyframe) {
+                break;
+            }
+        }
+    }
+
+    /// Removes and returns the newest state.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let snapshot = self.snapshots.pop_back()?;
+        self.size -= snapshot.data.len();
+
+        if !snapshot.keyframe {
+            return Some(xor(&decompress(&snapshot.data), &self.keyframe));
+        }
+
+        let state = decompress(&snapshot.data);
+
+        // Deltas pushed from now on are against the keyframe before this one.
+        self.keyframe = match self.snapshots.iter().rev().find(|snapshot| snapshot.keyframe) {
+            Some(previous) => decompress(&previous.data),
+            None => Vec::new(),
+        };
+
+        Some(state)
+    }
+}
+
+fn xor(state: &[u8], keyframe: &[u8]) -> Vec<u8> {
+    state.iter().zip(keyframe).map(|(a, b)| a ^ b).collect()
+}
+
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        out.push(length as u8 | 0x80);
+        length >>= 7;
+    }
+    out.push(length as u8);
+}
+
+fn read_length(input: &mut &[u8]) -> usize {
+    let mut length = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = input[0];
+        *input = &input[1..];
+
+        length |= (byte as usize & 0x7F) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return length;
+        }
+    }
+}
+
+/// Encodes `bytes` as a sequence of (zero count, literal count, literals).
+fn compress(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let zeros = bytes[i..].iter().take_while(|&&byte| byte == 0).count();
+        i += zeros;
+
+        // Single zeros are cheaper to keep as literals than to start a new run for.
+        let start = i;
+        while i < bytes.len() && !(bytes[i] == 0 && bytes.get(i + 1).is_none_or(|&next| next == 0)) {
+            i += 1;
+        }
+
+        write_length(&mut out, zeros);
+        write_length(&mut out, i - start);
+        out.extend_from_slice(&bytes[start..i]);
+    }
+
+    out
+}
+
+fn decompress(mut input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    while !input.is_empty() {
+        let zeros = read_length(&mut input);
+        out.resize(out.len() + zeros, 0);
+
+        let literals = read_length(&mut input);
+        out.extend_from_slice(&input[..literals]);
+        input = &input[literals..];
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compress, decompress, RewindBuffer};
+
+    fn state(seed: u8) -> Vec<u8> {
+        let mut state = vec![0; 1000];
+        state[seed as usize] = seed;
+        state[500..504].copy_from_slice(&[seed, 1, 2, 3]);
+        state
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let mut bytes = vec![0; 300];
+        bytes.extend([1, 0, 2, 3, 0, 0, 4]);
+        bytes.extend(vec![0; 200]);
+        bytes.push(5);
+
+        let compressed = compress(&bytes);
+        assert!(compressed.len() < 20);
+        assert_eq!(decompress(&compressed), bytes);
+        assert_eq!(decompress(&compress(&[])), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_pop_in_reverse_order() {
+        let mut buffer = RewindBuffer::new(usize::MAX, 4);
+
+        for seed in 0..10 {
+            buffer.push(&state(seed));
+        }
+
+        // Pushing again after stepping back deltas against the right keyframe.
+        for seed in (6..10).rev() {
+            assert_eq!(buffer.pop(), Some(state(seed)));
+        }
+        buffer.push(&state(20));
+        buffer.push(&state(21));
+
+        for seed in [21, 20, 5, 4, 3, 2, 1, 0] {
+            assert_eq!(buffer.pop(), Some(state(seed)));
+        }
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.size(), 0);
+    }
+
+    #[test]
+    fn test_budget_drops_oldest_keyframe() {
+        let mut buffer = RewindBuffer::new(1, 3);
+
+        for seed in 0..8 {
+            buffer.push(&state(seed));
+        }
+
+        // Only the newest keyframe (6) and its delta remain.
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.pop(), Some(state(7)));
+        assert_eq!(buffer.pop(), Some(state(6)));
+        assert!(buffer.is_empty());
+    }
+}
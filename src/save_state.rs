@@ -0,0 +1,262 @@
+//! Snapshots of the whole system.
+//!
+//! A save state is a header followed by one tagged section per component:
+//!
+//! ```text
+//! "EMRLDSAV" | version: u16 | section*
+//! section = tag: [u8; 4] | length: u32 | payload
+//! ```
+//!
+//! Integers are little-endian. Each component reads and writes its own payload, so sections can
+//! be added without touching the others; sections a build does not know about are skipped.
+
+use std::fmt;
+
+pub const STATE_VERSION: u16 = 1;
+const STATE_MAGIC: &[u8; 8] = b"EMRLDSAV";
+
+pub type Tag = [u8; 4];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    MissingSection(Tag),
+    Truncated,
+    Invalid(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "save state version {version} is not supported (expected {STATE_VERSION})")
+            },
+            StateError::MissingSection(tag) => {
+                write!(f, "save state has no {} section", String::from_utf8_lossy(tag).trim_end())
+            },
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Invalid(error) => write!(f, "invalid save state: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// A component whose state can be saved and restored.
+pub trait SaveState {
+    fn save_state(&self, out: &mut StateWriter);
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError>;
+}
+
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.bytes.len() < length {
+            return Err(StateError::Truncated);
+        }
+
+        let (bytes, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(StateError::Invalid(format!("{value} is not a boolean"))),
+        }
+    }
+
+    /// Fills `out` entirely.
+    pub fn bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+}
+
+/// Serialises the given components, each into its own section.
+pub fn write_state(sections: &[(Tag, &dyn SaveState)]) -> Vec<u8> {
+    let mut out = StateWriter::new();
+    out.bytes(STATE_MAGIC);
+    out.u16(STATE_VERSION);
+
+    for (tag, component) in sections {
+        let mut payload = StateWriter::new();
+        component.save_state(&mut payload);
+
+        out.bytes(tag);
+        out.u32(payload.bytes.len() as u32);
+        out.bytes(&payload.bytes);
+    }
+
+    out.bytes
+}
+
+/// The sections of a save state, checked against the header but not yet loaded.
+pub struct StateFile<'a> {
+    sections: Vec<(Tag, &'a [u8])>,
+}
+
+impl<'a> StateFile<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, StateError> {
+        let mut input = StateReader::new(bytes);
+
+        if input.take(STATE_MAGIC.len()).ok() != Some(STATE_MAGIC.as_slice()) {
+            return Err(StateError::NotAState);
+        }
+
+        let version = input.u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let mut sections = Vec::new();
+
+        while !input.bytes.is_empty() {
+            let tag: Tag = input.take(4)?.try_into().unwrap();
+            let length = input.u32()? as usize;
+            sections.push((tag, input.take(length)?));
+        }
+
+        Ok(Self { sections })
+    }
+
+    /// Restores a component from its section, which it must consume entirely.
+    pub fn load(&self, tag: Tag, component: &mut dyn SaveState) -> Result<(), StateError> {
+        let payload = self.sections.iter()
+            .find(|(section, _)| *section == tag)
+            .map(|(_, payload)| *payload)
+            .ok_or(StateError::MissingSection(tag))?;
+
+        let mut input = StateReader::new(payload);
+        component.load_state(&mut input)?;
+
+        if !input.bytes.is_empty() {
+            let tag = String::from_utf8_lossy(&tag).into_owned();
+            return Err(StateError::Invalid(format!("{} unexpected bytes in {}", input.bytes.len(), tag.trim_end())));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_state, SaveState, StateError, StateFile, StateReader, StateWriter};
+
+    #[derive(Debug, PartialEq)]
+    struct Component {
+        a: u8,
+        b: u16,
+        c: bool,
+    }
+
+    impl SaveState for Component {
+        fn save_state(&self, out: &mut StateWriter) {
+            out.u8(self.a);
+            out.u16(self.b);
+            out.bool(self.c);
+        }
+
+        fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+            self.a = input.u8()?;
+            self.b = input.u16()?;
+            self.c = input.bool()?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let first = Component { a: 1, b: 0x1234, c: true };
+        let second = Component { a: 2, b: 0xABCD, c: false };
+        let state = write_state(&[(*b"ONE ", &first), (*b"TWO ", &second)]);
+
+        let mut component = Component { a: 0, b: 0, c: false };
+        let file = StateFile::parse(&state).unwrap();
+
+        file.load(*b"TWO ", &mut component).unwrap();
+        assert_eq!(component, second);
+
+        file.load(*b"ONE ", &mut component).unwrap();
+        assert_eq!(component, first);
+
+        assert_eq!(file.load(*b"SIX ", &mut component), Err(StateError::MissingSection(*b"SIX ")));
+    }
+
+    #[test]
+    fn test_rejects_bad_input() {
+        let component = Component { a: 1, b: 2, c: true };
+        let mut state = write_state(&[(*b"ONE ", &component)]);
+
+        assert_eq!(StateFile::parse(&state[..state.len() - 1]).err(), Some(StateError::Truncated));
+        assert_eq!(StateFile::parse(b"not a state").err(), Some(StateError::NotAState));
+
+        state[8] = 99;
+        assert_eq!(StateFile::parse(&state).err(), Some(StateError::UnsupportedVersion(99)));
+    }
+}
@@ -1,32 +1,148 @@
 use std::cell::RefCell;
+use std::io;
+use std::io::Write;
 use std::rc::{Rc, Weak};
 use crate::{Address, Attach, Bus, BusListener, Byte};
+use log::warn;
 use crate::clock::ClockListener;
+use crate::cpu::{interrupt, InterruptType};
+use crate::link::{LinkCable, LinkMessage};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 const SERIAL_CONTROL_TRANSFER_START: u8 = 1 << 7; // 1 = Active or Requested
 const SERIAL_CONTROL_CLOCK_SPEED: u8 = 1 << 1; // 1 = Fast
 const SERIAL_CONTROL_SHIFT_CLOCK: u8 = 1 << 0; // 0 = Ext Clock, 1 = Int Clock
 
+// Bits of SC that exist; the clock speed is only there in CGB mode. The rest read as 1.
+const SERIAL_CONTROL_DMG_MASK: u8 = SERIAL_CONTROL_TRANSFER_START | SERIAL_CONTROL_SHIFT_CLOCK;
+const SERIAL_CONTROL_CGB_MASK: u8 = SERIAL_CONTROL_DMG_MASK | SERIAL_CONTROL_CLOCK_SPEED;
+
+// T-cycles per bit at 8192 Hz and (CGB only) 262144 Hz.
 const SERIAL_LO_SPEED_CYCLES: u16 = 512;
 const SERIAL_HI_SPEED_CYCLES: u16 = 16;
 
-#[derive(Clone)]
 pub struct SerialInterface {
+    // When not quiet, every byte sent is also printed to the console.
     quiet: bool,
+
+    // Whether the cartridge runs in CGB mode, which adds the fast clock.
+    cgb: bool,
+
+    // Every byte sent on the internal clock, for frontends reading output from test ROMs.
+    output: Vec<Byte>,
+
+    // T-cycles since the last bit was shifted, and bits left in the current transfer.
     cycles: u16,
+    bits: u8,
+
     transfer_data: u8,
     transfer_control: u8,
+
+    // Other instance connected through a link cable, and its answer to our current transfer.
+    link: Option<LinkCable>,
+    reply: Option<Byte>,
 }
 
 impl SerialInterface {
-    pub fn new(quiet: bool) -> Self {
+    pub fn new(quiet: bool, cgb: bool) -> Self {
         Self {
             quiet: quiet,
+            cgb,
+            output: Vec::new(),
             cycles: 0,
+            bits: 0,
             transfer_data: 0,
             transfer_control: 0,
+            link: None,
+            reply: None,
         }
     }
+
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    pub fn output(&self) -> &[Byte] {
+        &self.output
+    }
+
+    /// Restores SB and SC without the side effects of writing them; a transfer in progress
+    /// starts over.
+    pub fn restore(&mut self, sb: Byte, sc: Byte) {
+        self.transfer_data = sb;
+        self.transfer_control = sc & self.control_mask();
+        self.cycles = 0;
+        self.bits = if sc & SERIAL_CONTROL_TRANSFER_START != 0 { 8 } else { 0 };
+        self.reply = None;
+    }
+
+    pub fn connect_link(&mut self, link: LinkCable) {
+        self.link = Some(link);
+    }
+
+    fn control_mask(&self) -> u8 {
+        if self.cgb { SERIAL_CONTROL_CGB_MASK } else { SERIAL_CONTROL_DMG_MASK }
+    }
+
+    /// Whether a transfer on the internal clock is in progress.
+    fn is_clocking(&self) -> bool {
+        self.transfer_control & SERIAL_CONTROL_TRANSFER_START != 0
+            && self.transfer_control & SERIAL_CONTROL_SHIFT_CLOCK != 0
+    }
+
+    fn bit_cycles(&self) -> u16 {
+        if self.transfer_control & SERIAL_CONTROL_CLOCK_SPEED == 0 {
+            SERIAL_LO_SPEED_CYCLES
+        }
+        else {
+            SERIAL_HI_SPEED_CYCLES
+        }
+    }
+
+    fn complete_transfer(&mut self, bus: &mut Bus) {
+        self.transfer_control &= !SERIAL_CONTROL_TRANSFER_START;
+        interrupt(bus, InterruptType::Serial);
+    }
+
+    fn update_link(&mut self, bus: &mut Bus, cycles: u32) {
+        let mut link = match self.link.take() {
+            Some(link) => link,
+            None => return,
+        };
+
+        let messages = match link.tick(cycles) {
+            Ok(messages) => messages,
+            Err(e) => {
+                // Carry on as if the cable was unplugged; a transfer in progress receives 0xFF.
+                warn!("Link cable disconnected: {e}");
+                self.reply = Some(0xFF);
+                return;
+            },
+        };
+
+        for message in messages {
+            match message {
+                LinkMessage::Start(data) => {
+                    // The peer drives the clock; our side only takes part if a transfer on the
+                    // external clock is waiting.
+                    let waiting = self.transfer_control & SERIAL_CONTROL_TRANSFER_START != 0
+                        && self.transfer_control & SERIAL_CONTROL_SHIFT_CLOCK == 0;
+
+                    if waiting {
+                        link.send(LinkMessage::Reply(self.transfer_data));
+                        self.transfer_data = data;
+                        self.complete_transfer(bus);
+                    }
+                    else {
+                        link.send(LinkMessage::Reply(0xFF));
+                    }
+                },
+                LinkMessage::Reply(data) => self.reply = Some(data),
+            }
+        }
+
+        self.link = Some(link);
+    }
 }
 
 impl BusListener for SerialInterface {
@@ -37,7 +153,7 @@ impl BusListener for SerialInterface {
     fn bus_read(&self, address: Address) -> Byte {
         match address {
             0xFF01 => self.transfer_data,
-            0xFF02 => self.transfer_control,
+            0xFF02 => self.transfer_control | !self.control_mask(),
             _ => panic!("{} is not part of the serial interface!", address)
         }
     }
@@ -46,9 +162,24 @@ impl BusListener for SerialInterface {
         match address {
             0xFF01 => self.transfer_data = value,
             0xFF02 => {
-                self.transfer_control = value;
-                if !self.quiet && value & SERIAL_CONTROL_TRANSFER_START != 0 {
-                    print!("{}", self.transfer_data as char);
+                self.transfer_control = value & self.control_mask();
+                if value & SERIAL_CONTROL_TRANSFER_START != 0 {
+                    self.cycles = 0;
+                    self.bits = 8;
+
+                    if value & SERIAL_CONTROL_SHIFT_CLOCK != 0 {
+                        self.output.push(self.transfer_data);
+
+                        if !self.quiet {
+                            print!("{}", self.transfer_data as char);
+                            io::stdout().flush().unwrap();
+                        }
+
+                        if let Some(link) = &mut self.link {
+                            self.reply = None;
+                            link.send(LinkMessage::Start(self.transfer_data));
+                        }
+                    }
                 }
             },
             _ => panic!("{} is not part of the serial interface!", address)
@@ -57,28 +188,175 @@ impl BusListener for SerialInterface {
 }
 
 impl ClockListener for SerialInterface {
-    fn callback(&mut self, _bus: &mut Bus, _cycles: u8) {
-        return;
-        /*
-        if self.transfer_control & SERIAL_CONTROL_TRANSFER_START == 0 {
+    fn callback(&mut self, bus: &mut Bus, cycles: u32) {
+        self.update_link(bus, cycles);
+
+        // Externally clocked transfers are completed by the link partner, if there is one.
+        if !self.is_clocking() {
             return;
         }
 
-        let transfer_cycles = if self.transfer_control & SERIAL_CONTROL_CLOCK_SPEED == 0 {
-            SERIAL_LO_SPEED_CYCLES
-        }
-        else {
-            SERIAL_HI_SPEED_CYCLES
-        };
-
+        let bit_cycles = self.bit_cycles();
         self.cycles += cycles as u16;
 
-        if self.cycles < transfer_cycles {
+        while self.cycles >= bit_cycles && self.bits > 0 {
+            self.cycles -= bit_cycles;
+
+            // Without a link partner, the input line is pulled high.
+            if self.link.is_none() {
+                self.transfer_data = (self.transfer_data << 1) | 1;
+            }
+            self.bits -= 1;
+        }
+
+        if self.bits > 0 {
             return;
         }
 
-        self.cycles = 0;
-        self.transfer_control ^= SERIAL_CONTROL_TRANSFER_START;
-         */
+        // A linked transfer also has to wait for the peer's byte.
+        if self.link.is_some() || self.reply.is_some() {
+            match self.reply.take() {
+                Some(data) => self.transfer_data = data,
+                None => return,
+            }
+        }
+
+        self.complete_transfer(bus);
+    }
+
+    fn next_event(&self) -> u32 {
+        // The link cable exchanges messages at the end of every window.
+        let exchange = self.link.as_ref().map_or(u32::MAX, |link| link.cycles_until_exchange());
+
+        // A linked transfer with all bits shifted only waits for the peer's byte.
+        if !self.is_clocking() || self.bits == 0 {
+            return exchange;
+        }
+
+        let transfer = (self.bits as u32 * self.bit_cycles() as u32).saturating_sub(self.cycles as u32);
+        transfer.min(exchange)
+    }
+
+    // The shift clock is derived from the CPU clock.
+    fn cpu_clocked(&self) -> bool {
+        true
+    }
+}
+
+/// The link cable itself is not part of the state; it stays connected across loads.
+impl SaveState for SerialInterface {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u16(self.cycles);
+        out.u8(self.bits);
+        out.u8(self.transfer_data);
+        out.u8(self.transfer_control);
+        out.bool(self.reply.is_some());
+        out.u8(self.reply.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.cycles = input.u16()?;
+        self.bits = input.u8()?;
+        self.transfer_data = input.u8()?;
+        self.transfer_control = input.u8()?;
+
+        let has_reply = input.bool()?;
+        let reply = input.u8()?;
+        self.reply = if has_reply { Some(reply) } else { None };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::{Bus, BusListener, DummyRAM};
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::clock::ClockListener;
+    use crate::link::LinkCable;
+    use super::SerialInterface;
+
+    type RcRC<T> = Rc<RefCell<T>>;
+
+    fn init_bus() -> (Bus, RcRC<DummyRAM>) {
+        let mut bus = Bus::new();
+        let interrupt_flags = Rc::new(RefCell::new(DummyRAM::new(0x0F, 0x0F, true)));
+        bus.attach(interrupt_flags.clone());
+        (bus, interrupt_flags)
+    }
+
+    #[test]
+    fn test_transfer_without_partner() {
+        let (mut bus, _interrupt_flags) = init_bus();
+
+        let mut serial = SerialInterface::new(true, false);
+        serial.bus_write(&mut bus, 0xFF01, 0x42);
+        serial.bus_write(&mut bus, 0xFF02, 0x81);
+
+        // Eight bits at 8192 Hz; 0xFF is shifted in.
+        for _ in 0..(8 * 512 / 4) - 1 {
+            serial.callback(&mut bus, 4);
+        }
+        assert_eq!(serial.bus_read(0xFF02), 0xFF);
+        assert_eq!(bus.read_byte(0xFF0F), 0x00);
+
+        serial.callback(&mut bus, 4);
+        assert_eq!(serial.bus_read(0xFF01), 0xFF);
+        assert_eq!(serial.bus_read(0xFF02), 0x7F);
+        assert_eq!(bus.read_byte(0xFF0F), 0x08);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_clock_speed() {
+        // (CGB mode, T-cycles per bit) with the fast clock selected.
+        for (cgb, bit_cycles) in [(false, 512), (true, 16)] {
+            let (mut bus, _interrupt_flags) = init_bus();
+
+            let mut serial = SerialInterface::new(true, cgb);
+            serial.bus_write(&mut bus, 0xFF02, 0x83);
+            assert_eq!(serial.bus_read(0xFF02), 0xFF);
+
+            serial.callback(&mut bus, 8 * bit_cycles - 4);
+            assert_eq!(bus.read_byte(0xFF0F), 0x00, "CGB mode: {cgb}");
+
+            serial.callback(&mut bus, 4);
+            assert_eq!(bus.read_byte(0xFF0F), 0x08, "CGB mode: {cgb}");
+        }
+    }
+
+    #[test]
+    fn test_linked_transfer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // Both sides run for the duration of one transfer at 8192 Hz.
+        let run = |serial: &mut SerialInterface, bus: &mut Bus| {
+            for _ in 0..8 * 512 / 4 {
+                serial.callback(bus, 4);
+            }
+        };
+
+        let slave = thread::spawn(move || {
+            let (mut bus, _interrupt_flags) = init_bus();
+            let mut serial = SerialInterface::new(true, false);
+            serial.connect_link(LinkCable::accept(&listener).unwrap());
+            serial.bus_write(&mut bus, 0xFF01, 0x24);
+            serial.bus_write(&mut bus, 0xFF02, 0x80);
+            run(&mut serial, &mut bus);
+            (serial.bus_read(0xFF01), serial.bus_read(0xFF02), bus.read_byte(0xFF0F))
+        });
+
+        let (mut bus, _interrupt_flags) = init_bus();
+        let mut serial = SerialInterface::new(true, false);
+        serial.connect_link(LinkCable::connect(&address).unwrap());
+
+        serial.bus_write(&mut bus, 0xFF01, 0x42);
+        serial.bus_write(&mut bus, 0xFF02, 0x81);
+        run(&mut serial, &mut bus);
+
+        assert_eq!((serial.bus_read(0xFF01), serial.bus_read(0xFF02), bus.read_byte(0xFF0F)), (0x24, 0x7F, 0x08));
+        assert_eq!(slave.join().unwrap(), (0x42, 0x7E, 0x08));
+    }
+}
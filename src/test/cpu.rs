@@ -1,3 +1,215 @@
-use crate::Bus;
+//! Runs the single-step SM83 test vectors (https://github.com/SingleStepTests/sm83) against the CPU.
+//! Each file holds the tests for one opcode, with the initial and final register and RAM state and
+//! the bus activity of every M-cycle. The vectors are not checked in, so the test is ignored by
+//! default: place the `v1` directory at `tests/sm83` (or point `SM83_TESTS` to it) and run
+//! `cargo test -- --ignored test_sm83_vectors`. Without them it fails rather than passing without
+//! having run anything.
+
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde_json::Value;
+
+use crate::bus::*;
 use crate::cpu::*;
+use crate::test::panic_message;
+
+const DEFAULT_TEST_DIRECTORY: &str = "tests/sm83";
+
+/// 64 KiB of RAM covering the whole address space, except for the interrupt registers
+/// which belong to the CPU. All accesses are logged.
+struct FlatRAM {
+    data: Vec<Byte>,
+    accesses: RefCell<Vec<Access>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Access {
+    address: Address,
+    value: Byte,
+    write: bool,
+}
+
+impl FlatRAM {
+    fn new() -> Self {
+        Self {
+            data: vec![0; 0x10000],
+            accesses: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl BusListener for FlatRAM {
+    fn bus_attach(&mut self) -> Vec<Attach> {
+        vec![
+            Attach::BlockRange(0x00, 0xFF),
+            Attach::RegisterRange(0x00, 0x0E),
+            Attach::RegisterRange(0x10, 0x7F),
+        ]
+    }
+
+    fn bus_read(&self, address: Address) -> Byte {
+        let value = self.data[address as usize];
+        self.accesses.borrow_mut().push(Access { address, value, write: false });
+        value
+    }
+
+    fn bus_write(&mut self, _bus: &mut Bus, address: Address, value: Byte) {
+        self.data[address as usize] = value;
+        self.accesses.borrow_mut().push(Access { address, value, write: true });
+    }
+}
+
+fn number(state: &Value, key: &str) -> Result<u64, String> {
+    state[key].as_u64().ok_or_else(|| format!("missing field {key}"))
+}
+
+fn registers(state: &Value) -> Result<RegisterState, String> {
+    let byte = |key| number(state, key).map(|value| value as Byte);
+
+    Ok(RegisterState {
+        a: byte("a")?,
+        f: byte("f")?,
+        b: byte("b")?,
+        c: byte("c")?,
+        d: byte("d")?,
+        e: byte("e")?,
+        h: byte("h")?,
+        l: byte("l")?,
+        sp: number(state, "sp")? as Word,
+        pc: number(state, "pc")? as Word,
+        ime: number(state, "ime")? != 0,
+    })
+}
+
+fn memory(state: &Value) -> Result<Vec<(Address, Byte)>, String> {
+    let entries = state["ram"].as_array().ok_or("missing field ram")?;
+
+    entries.iter()
+        .map(|entry| match (entry[0].as_u64(), entry[1].as_u64()) {
+            (Some(address), Some(value)) => Ok((address as Address, value as Byte)),
+            _ => Err(format!("invalid RAM entry {entry}")),
+        })
+        .collect()
+}
+
+/// Memory accesses expected during the instruction. Each cycle is `[address, value, "rwm"]`;
+/// internal cycles have no value and are only counted.
+fn accesses(cycles: &[Value]) -> Vec<Access> {
+    cycles.iter()
+        .filter_map(|cycle| {
+            let kind = cycle[2].as_str()?;
+            let access = Access {
+                address: cycle[0].as_u64()? as Address,
+                value: cycle[1].as_u64()? as Byte,
+                write: kind.contains('w'),
+            };
+
+            (kind.contains('r') || kind.contains('w')).then_some(access)
+        })
+        .collect()
+}
+
+/// Runs a single test, returning a description of the first mismatch.
+fn run_test(test: &Value) -> Result<(), String> {
+    let initial = &test["initial"];
+    let expected = &test["final"];
+    let cycles = test["cycles"].as_array().ok_or("missing field cycles")?;
+
+    let mut bus = Bus::new();
+    let mut cpu = CPU::new();
+    let ram = Rc::new(RefCell::new(FlatRAM::new()));
+    cpu.attach_to_bus(&mut bus);
+    bus.attach(ram.clone());
+
+    cpu.set_registers(&registers(initial)?);
+    bus.write_byte(0xFFFF, number(initial, "ie")? as Byte);
+    for (address, value) in memory(initial)? {
+        bus.write_byte(address, value);
+    }
+    ram.borrow().accesses.borrow_mut().clear();
+
+    let outcome = cpu.step(&mut bus);
+    let log = ram.borrow().accesses.take();
+
+    let result = cpu.registers();
+    let expected_registers = registers(expected)?;
+    if result != expected_registers {
+        return Err(format!("registers {result:X?}, expected {expected_registers:X?}"));
+    }
+
+    for (address, value) in memory(expected)? {
+        let result = bus.read_byte(address);
+        if result != value {
+            return Err(format!("{address:04X} = {result:02X}, expected {value:02X}"));
+        }
+    }
+
+    let expected_accesses = accesses(cycles);
+    if log != expected_accesses {
+        return Err(format!("bus activity {log:X?}, expected {expected_accesses:X?}"));
+    }
+
+    match outcome {
+        StepOutcome::Cycles(t_cycles) if t_cycles as usize == cycles.len() << 2 => Ok(()),
+        outcome => Err(format!("{outcome:?}, expected {} M-cycles", cycles.len())),
+    }
+}
+
+/// Runs all tests in a file. Returns the number of tests and the failures.
+fn run_file(path: &Path) -> (usize, Vec<String>) {
+    let tests: Value = match fs::read_to_string(path).map_err(|e| e.to_string())
+        .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string())) {
+        Ok(tests) => tests,
+        Err(e) => return (0, vec![format!("couldn't load tests: {e}")]),
+    };
+
+    let tests = tests.as_array().map(Vec::as_slice).unwrap_or_default();
+    let failures = tests.iter()
+        .filter_map(|test| {
+            let name = test["name"].as_str().unwrap_or("?");
+            run_test(test).err().map(|e| format!("{name}: {e}"))
+        })
+        .collect();
+
+    (tests.len(), failures)
+}
+
+#[test]
+#[ignore = "needs the SM83 test vectors in tests/sm83 or SM83_TESTS"]
+fn test_sm83_vectors() {
+    let directory = env::var("SM83_TESTS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| Path::new(env!("CARGO_MANIFEST_DIR")).join(DEFAULT_TEST_DIRECTORY));
+
+    let mut files: Vec<PathBuf> = fs::read_dir(&directory)
+        .unwrap_or_else(|e| panic!("Couldn't read SM83 test vectors from {}: {e}", directory.display()))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect();
+    files.sort();
+
+    assert!(!files.is_empty(), "No SM83 test vectors in {}.", directory.display());
+
+    let mut failed = 0;
+
+    for path in &files {
+        let opcode = path.file_stem().unwrap().to_string_lossy();
+
+        // A panicking opcode fails on its own instead of hiding the others.
+        let (total, failures) = panic::catch_unwind(|| run_file(path))
+            .unwrap_or_else(|panic| (0, vec![format!("panicked: {}", panic_message(panic))]));
+
+        if !failures.is_empty() {
+            failed += 1;
+            println!("{opcode:<6} {:>5}/{total:<5} failed, first: {}", failures.len(), failures[0]);
+        }
+    }
 
+    println!("{}/{} opcodes passed.", files.len() - failed, files.len());
+    assert_eq!(failed, 0, "{failed} opcode(s) failed.");
+}
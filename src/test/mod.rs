@@ -1 +1,11 @@
-pub mod cpu;
\ No newline at end of file
+use std::any::Any;
+
+pub mod cpu;
+pub mod reference;
+
+/// The message of a panic caught with `catch_unwind`.
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    panic.downcast_ref::<&str>().map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,127 @@
+//! Compares frames against reference images, such as the one for dmg-acid2, to catch rendering
+//! regressions. Each `name.gb` in `tests/reference` (or `REFERENCE_ROMS`) is run for a fixed number
+//! of frames and its last frame is compared to `name.png`. On a mismatch, an image highlighting
+//! the differing pixels is written to the temporary directory. Finding no reference ROMs at all
+//! is a failure, so that a missing directory doesn't pass silently.
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::panic;
+use std::path::{Path, PathBuf};
+
+use crate::GameBoy;
+use crate::test::panic_message;
+use crate::graphics::screenshot::{compare, read_png, write_png, Mismatch};
+use crate::ppu::Renderer;
+
+const DEFAULT_REFERENCE_DIRECTORY: &str = "tests/reference";
+
+// Enough for the usual reference ROMs to finish drawing.
+const REFERENCE_FRAMES: u64 = 120;
+
+/// Lists the reference ROMs, failing if there are none.
+fn reference_roms() -> Vec<PathBuf> {
+    let directory = env::var("REFERENCE_ROMS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| Path::new(env!("CARGO_MANIFEST_DIR")).join(DEFAULT_REFERENCE_DIRECTORY));
+
+    let mut roms: Vec<PathBuf> = fs::read_dir(&directory)
+        .unwrap_or_else(|e| panic!("Couldn't read reference ROMs from {}: {e}", directory.display()))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "gb"))
+        .collect();
+    roms.sort();
+
+    assert!(!roms.is_empty(), "No reference ROMs in {}.", directory.display());
+    roms
+}
+
+/// Runs `check` on every reference ROM, and fails with the number of ROMs it rejected.
+fn check_roms(what: &str, check: fn(&Path) -> Result<(), String>) {
+    let roms = reference_roms();
+    let mut failed = 0;
+
+    for path in &roms {
+        let name = path.file_stem().unwrap().to_string_lossy();
+
+        // A ROM that crashes the emulator fails on its own instead of hiding the others.
+        let result = panic::catch_unwind(|| check(path))
+            .unwrap_or_else(|panic| Err(format!("panicked: {}", panic_message(panic))));
+
+        if let Err(e) = result {
+            failed += 1;
+            println!("{name}: {e}");
+        }
+    }
+
+    println!("{}/{} {what} matched.", roms.len() - failed, roms.len());
+    assert_eq!(failed, 0, "{failed} {what} differ.");
+}
+
+/// Runs the ROM with the given renderer and returns its last frame.
+fn run_frames(rom_path: &Path, renderer: Renderer) -> Result<Vec<u32>, String> {
+    let rom = fs::read(rom_path).map_err(|e| format!("couldn't read ROM: {e}"))?;
+
+    let mut gameboy = GameBoy::from_rom(rom).map_err(|e| format!("couldn't load ROM: {e}"))?;
+    gameboy.ppu().set_renderer(renderer);
+
+    while gameboy.frames() < REFERENCE_FRAMES {
+        gameboy.run_frame();
+    }
+
+    Ok(gameboy.framebuffer().to_vec())
+}
+
+/// Writes the image of a mismatch to the temporary directory and describes it.
+fn report(mismatch: Mismatch, file_name: &str) -> String {
+    let diff_path = env::temp_dir().join(file_name);
+
+    match File::create(&diff_path).and_then(|mut file| write_png(&mismatch.diff, &mut file)) {
+        Ok(()) => format!("{} pixels differ, see {}", mismatch.pixels, diff_path.display()),
+        Err(e) => format!("{} pixels differ, couldn't write {}: {e}", mismatch.pixels, diff_path.display()),
+    }
+}
+
+/// Runs the ROM and compares its last frame to the reference image next to it.
+fn run_reference(rom_path: &Path) -> Result<(), String> {
+    let reference_path = rom_path.with_extension("png");
+
+    let reference = File::open(&reference_path)
+        .and_then(|mut file| read_png(&mut file))
+        .map_err(|e| format!("couldn't read {}: {e}", reference_path.display()))?;
+
+    let frame = run_frames(rom_path, Renderer::Fifo)?;
+
+    match compare(&frame, &reference) {
+        Some(mismatch) => {
+            let name = rom_path.file_stem().unwrap().to_string_lossy();
+            Err(report(mismatch, &format!("{name}.diff.png")))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Runs the ROM with both renderers and compares their last frames.
+fn run_renderers(rom_path: &Path) -> Result<(), String> {
+    let fifo = run_frames(rom_path, Renderer::Fifo)?;
+    let scanline = run_frames(rom_path, Renderer::Scanline)?;
+
+    match compare(&scanline, &fifo) {
+        Some(mismatch) => {
+            let name = rom_path.file_stem().unwrap().to_string_lossy();
+            Err(report(mismatch, &format!("{name}.renderers.diff.png")))
+        }
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn test_reference_images() {
+    check_roms("reference image(s)", run_reference);
+}
+
+#[test]
+fn test_renderers_match() {
+    check_roms("renderer frame(s)", run_renderers);
+}
@@ -3,21 +3,39 @@ use std::cell::RefCell;
 use std::rc::{Rc, Weak};
 use std::time::{Duration, Instant};
 use crate::clock::ClockListener;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 
 use crate::bus::*;
 
 const TIMER_CONTROL_ENABLE: u8 = 1 << 2;
-const TIMER_CONTROL_CLOCK_SELECT_MASK: u8 = 2;
+const TIMER_CONTROL_CLOCK_SELECT_MASK: u8 = 3;
 
-const TIMER_CLOCK_BITS: [u16; 4] = [ 1 << 10, 1 << 7, 1 << 5, 1 << 3 ];
+// Bit of the system counter whose falling edge increments TIMA, by clock select:
+// 4096 Hz, 262144 Hz, 65536 Hz and 16384 Hz.
+const TIMER_CLOCK_BITS: [u16; 4] = [ 1 << 9, 1 << 3, 1 << 5, 1 << 7 ];
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Reload {
+    None,
+
+    // TIMA overflowed during the last M-cycle and reads 00. Writing TIMA now cancels the reload.
+    Pending,
+
+    // TIMA was just reloaded from TMA. Writes to TIMA are ignored and writes to TMA go
+    // through to TIMA as well.
+    Reloading,
+}
 
 pub struct Timer {
-    cycles: u16, // DIV is high nibble
+    cycles: u16, // System counter; DIV is the high byte
     counter_register: Byte, // TIMA
     modulo_register: Byte, // TMA
     control_register: Byte, // TAC
+    reload: Reload,
+
+    // T-cycles not yet consumed by a full M-cycle.
+    remainder: u8,
 }
 
 impl Timer {
@@ -27,8 +45,88 @@ impl Timer {
             counter_register: 0,
             modulo_register: 0,
             control_register: 0,
+            reload: Reload::None,
+            remainder: 0,
         }
     }
+
+    /// Restores the registers from a save state that only includes DIV rather than the whole
+    /// system counter.
+    pub fn restore(&mut self, div: Byte, tima: Byte, tma: Byte, tac: Byte) {
+        self.cycles = (div as u16) << 8;
+        self.counter_register = tima;
+        self.modulo_register = tma;
+        self.control_register = tac;
+        self.reload = Reload::None;
+        self.remainder = 0;
+    }
+
+    /// Input to the falling edge detector: the selected counter bit, gated by the enable bit.
+    fn signal(&self) -> bool {
+        let bit = TIMER_CLOCK_BITS[(self.control_register & TIMER_CONTROL_CLOCK_SELECT_MASK) as usize];
+        self.control_register & TIMER_CONTROL_ENABLE != 0 && self.cycles & bit != 0
+    }
+
+    /// Changes the system counter or TAC, incrementing TIMA if this produces a falling edge.
+    /// This is why writing DIV or TAC can increment TIMA.
+    fn update(&mut self, change: impl FnOnce(&mut Self)) {
+        let signal = self.signal();
+        change(self);
+
+        if signal && !self.signal() {
+            self.increment();
+        }
+    }
+
+    fn increment(&mut self) {
+        let (value, overflow) = self.counter_register.overflowing_add(1);
+        self.counter_register = value;
+
+        // TMA is only loaded (and the interrupt requested) one M-cycle later.
+        if overflow {
+            self.reload = Reload::Pending;
+        }
+    }
+
+    /// Period of the selected counter bit's falling edges, or None while the timer is disabled.
+    fn period(&self) -> Option<u32> {
+        let bit = TIMER_CLOCK_BITS[(self.control_register & TIMER_CONTROL_CLOCK_SELECT_MASK) as usize];
+        (self.control_register & TIMER_CONTROL_ENABLE != 0).then_some(2 * bit as u32)
+    }
+
+    /// M-cycles until the one in which TIMA overflows.
+    fn steps_until_overflow(&self) -> Option<u32> {
+        let period = self.period()?;
+        let cycles = self.cycles as u32;
+        let overflow = (cycles / period + 256 - self.counter_register as u32) * period;
+        Some((overflow - cycles).div_ceil(4))
+    }
+
+    /// Runs M-cycles in which TIMA neither overflows nor is reloaded, by counting the falling
+    /// edges they contain instead of stepping through them.
+    fn advance(&mut self, steps: u32) {
+        let cycles = self.cycles as u32;
+        let end = cycles + 4 * steps;
+
+        if let Some(period) = self.period() {
+            self.counter_register += (end / period - cycles / period) as u8;
+        }
+
+        self.cycles = end as u16;
+    }
+
+    fn step(&mut self, bus: &mut Bus) {
+        self.reload = match self.reload {
+            Reload::Pending => {
+                self.counter_register = self.modulo_register; // Set TIMA to TMA
+                interrupt(bus, InterruptType::Timer);
+                Reload::Reloading
+            },
+            _ => Reload::None,
+        };
+
+        self.update(|timer| timer.cycles = timer.cycles.wrapping_add(4));
+    }
 }
 
 impl BusListener for Timer {
@@ -41,48 +139,96 @@ impl BusListener for Timer {
             0xFF04 => (self.cycles >> 8) as Byte,
             0xFF05 => self.counter_register,
             0xFF06 => self.modulo_register,
-            0xFF07 => self.control_register,
+            0xFF07 => self.control_register | 0xF8,
             _ => panic!("{} is not a timer register!", address)
         }
     }
 
     fn bus_write(&mut self, bus: &mut Bus, address: Address, value: Byte) {
         match address {
-            0xFF04 => self.cycles = 0,
-            0xFF05 => self.counter_register = value,
-            0xFF06 => self.modulo_register = value,
-            0xFF07 => self.control_register = value,
+            0xFF04 => self.update(|timer| timer.cycles = 0),
+            0xFF05 => match self.reload {
+                Reload::None => self.counter_register = value,
+                Reload::Pending => {
+                    self.counter_register = value;
+                    self.reload = Reload::None;
+                },
+                Reload::Reloading => {},
+            },
+            0xFF06 => {
+                self.modulo_register = value;
+                if self.reload == Reload::Reloading {
+                    self.counter_register = value;
+                }
+            },
+            0xFF07 => self.update(|timer| timer.control_register = value & 0x07),
             _ => panic!("{} is not a timer register!", address)
         }
     }
 }
 
 impl ClockListener for Timer {
-    fn callback(&mut self, bus: &mut Bus, cycles: u8) {
-        for _ in 0..cycles {
-            if self.cycles == 0xFFFF {
-                self.cycles = 0;
+    fn callback(&mut self, bus: &mut Bus, cycles: u32) {
+        let cycles = self.remainder as u32 + cycles;
+        let mut steps = cycles / 4;
+        self.remainder = (cycles % 4) as u8;
+
+        // Only the M-cycles around an overflow have to be stepped through one by one.
+        while steps > 0 {
+            let skip = match (self.reload, self.steps_until_overflow()) {
+                (Reload::None, Some(until_overflow)) => (until_overflow - 1).min(steps),
+                (Reload::None, None) => steps,
+                _ => 0,
+            };
+
+            if skip > 0 {
+                self.advance(skip);
+                steps -= skip;
             }
             else {
-                self.cycles += 1;
+                self.step(bus);
+                steps -= 1;
             }
+        }
+    }
 
-            if self.control_register & TIMER_CONTROL_ENABLE != 0 {
-                let clock_select = self.control_register & TIMER_CONTROL_CLOCK_SELECT_MASK;
-
-                if TIMER_CLOCK_BITS[clock_select as usize] != 0 {
-                    // if counter overflows...
-                    if self.counter_register == 0xFF {
-                        self.counter_register = self.modulo_register; // Set TIMA to TMA
-                        interrupt(bus, InterruptType::Timer);
-                    }
-                    else {
-                        self.counter_register += 1;
-                    }
-                }
-            }
+    fn next_event(&self) -> u32 {
+        match (self.reload, self.steps_until_overflow()) {
+            (Reload::None, Some(until_overflow)) => 4 * until_overflow - self.remainder as u32,
+            (Reload::None, None) => u32::MAX,
+            _ => 4 - self.remainder as u32,
         }
     }
+
+    fn cpu_clocked(&self) -> bool {
+        true
+    }
+}
+
+impl SaveState for Timer {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.u16(self.cycles);
+        out.u8(self.counter_register);
+        out.u8(self.modulo_register);
+        out.u8(self.control_register);
+        out.u8(self.reload as u8);
+        out.u8(self.remainder);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.cycles = input.u16()?;
+        self.counter_register = input.u8()?;
+        self.modulo_register = input.u8()?;
+        self.control_register = input.u8()?;
+        self.reload = match input.u8()? {
+            0 => Reload::None,
+            1 => Reload::Pending,
+            2 => Reload::Reloading,
+            reload => return Err(StateError::Invalid(format!("unknown timer reload state {reload}"))),
+        };
+        self.remainder = input.u8()?;
+        Ok(())
+    }
 }
 
 use std::fmt;
@@ -92,3 +238,122 @@ impl fmt::Debug for Timer {
         write!(f, "Error")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::{Bus, BusListener, DummyRAM};
+    use crate::clock::ClockListener;
+    use super::Timer;
+
+    const IF_ADDRESS: u16 = 0xFF0F;
+
+    fn init_timer() -> (Bus, Timer, Rc<RefCell<DummyRAM>>) {
+        let mut bus = Bus::new();
+        let interrupt_flags = Rc::new(RefCell::new(DummyRAM::new(0x0F, 0x0F, true)));
+        bus.attach(interrupt_flags.clone());
+        (bus, Timer::new(), interrupt_flags)
+    }
+
+    #[test]
+    fn test_clock_select() {
+        for (select, period) in [(0u8, 1024u32), (1, 16), (2, 64), (3, 256)] {
+            let (mut bus, mut timer, _) = init_timer();
+            timer.bus_write(&mut bus, 0xFF07, 0x04 | select);
+
+            for _ in 0..(period * 3) / 4 {
+                timer.callback(&mut bus, 4);
+            }
+
+            assert_eq!(timer.bus_read(0xFF05), 3, "Clock select {select}.");
+        }
+    }
+
+    #[test]
+    fn test_run_until_next_event() {
+        let (mut bus, mut timer, _interrupt_flags) = init_timer();
+        let (mut stepped_bus, mut stepped, _stepped_interrupt_flags) = init_timer();
+
+        for timer in [&mut timer, &mut stepped] {
+            timer.bus_write(&mut bus, 0xFF05, 0xF0);
+            timer.bus_write(&mut bus, 0xFF06, 0xA0);
+            timer.bus_write(&mut bus, 0xFF07, 0x05);
+        }
+
+        // Running up to each event at once gives the same result as running every M-cycle.
+        for _ in 0..3 {
+            let cycles = timer.next_event();
+            timer.callback(&mut bus, cycles);
+
+            for _ in 0..cycles / 4 {
+                stepped.callback(&mut stepped_bus, 4);
+            }
+
+            assert_eq!(timer.bus_read(0xFF04), stepped.bus_read(0xFF04));
+            assert_eq!(timer.bus_read(0xFF05), stepped.bus_read(0xFF05));
+        }
+
+        // Overflow, reload with the interrupt, then back to counting up from TMA.
+        assert_eq!(timer.bus_read(0xFF05), 0xA0);
+        assert_eq!(bus.read_byte(IF_ADDRESS), 0x04);
+
+        // The system counter is half way through a period of the selected bit.
+        assert_eq!(timer.next_event(), 96 * 16 - 8);
+
+        timer.bus_write(&mut bus, 0xFF07, 0x00);
+        assert_eq!(timer.next_event(), u32::MAX);
+    }
+
+    #[test]
+    fn test_div_write_glitch() {
+        let (mut bus, mut timer, _) = init_timer();
+        timer.bus_write(&mut bus, 0xFF07, 0x05);
+
+        // Bit 3 of the system counter is set after 8 T-cycles; resetting DIV clears it.
+        timer.callback(&mut bus, 8);
+        assert_eq!(timer.bus_read(0xFF05), 0);
+        timer.bus_write(&mut bus, 0xFF04, 0);
+        assert_eq!(timer.bus_read(0xFF05), 1);
+
+        // Same for disabling the timer.
+        timer.callback(&mut bus, 8);
+        timer.bus_write(&mut bus, 0xFF07, 0x01);
+        assert_eq!(timer.bus_read(0xFF05), 2);
+    }
+
+    #[test]
+    fn test_delayed_reload() {
+        let (mut bus, mut timer, interrupt_flags) = init_timer();
+        timer.bus_write(&mut bus, 0xFF05, 0xFF);
+        timer.bus_write(&mut bus, 0xFF06, 0x42);
+        timer.bus_write(&mut bus, 0xFF07, 0x05);
+
+        // TIMA reads 00 for one M-cycle after overflowing.
+        timer.callback(&mut bus, 16);
+        assert_eq!(timer.bus_read(0xFF05), 0x00);
+        assert_eq!(bus.read_byte(IF_ADDRESS), 0x00);
+
+        // TMA is then loaded and the interrupt requested. Writes to TIMA are ignored during that
+        // cycle, while writes to TMA also go to TIMA.
+        timer.callback(&mut bus, 4);
+        assert_eq!(timer.bus_read(0xFF05), 0x42);
+        assert_eq!(bus.read_byte(IF_ADDRESS), 0x04);
+        timer.bus_write(&mut bus, 0xFF05, 0x10);
+        assert_eq!(timer.bus_read(0xFF05), 0x42);
+        timer.bus_write(&mut bus, 0xFF06, 0x24);
+        assert_eq!(timer.bus_read(0xFF05), 0x24);
+
+        // Writing TIMA right after the overflow cancels both the reload and the interrupt.
+        timer.callback(&mut bus, 4);
+        bus.write_byte(IF_ADDRESS, 0);
+        timer.bus_write(&mut bus, 0xFF04, 0);
+        timer.bus_write(&mut bus, 0xFF05, 0xFF);
+        timer.callback(&mut bus, 16);
+        assert_eq!(timer.bus_read(0xFF05), 0x00);
+        timer.bus_write(&mut bus, 0xFF05, 0x10);
+        timer.callback(&mut bus, 4);
+        assert_eq!(timer.bus_read(0xFF05), 0x10);
+        assert_eq!(bus.read_byte(IF_ADDRESS), 0x00);
+    }
+}
@@ -0,0 +1,337 @@
+#!/usr/bin/env python3
+"""Generates the reference ROMs and their expected images.
+
+Usage: python3 gen.py [output directory, defaults to this one]
+
+Each ROM copies random tiles, tile maps and sprites into VRAM and OAM, sets the display
+registers, turns the LCD on and halts. The expected image is computed here from the same data
+and registers, independently of the emulator. Only the standard library is used, and the
+output is deterministic, so running this again reproduces the checked-in files.
+"""
+
+import os
+import struct
+import sys
+import zlib
+
+# Shades of colours 0-3 in the reference images.
+PALETTE = [0xFF, 0xC0, 0x40, 0x00]
+
+WIDTH = 160
+HEIGHT = 144
+
+# Where the program and the data it copies are placed in the ROM.
+PROGRAM = 0x0150
+TILE_DATA = 0x0300
+TILE_MAP = 0x0400
+TILE_DATA_HI = 0x0800
+WINDOW_MAP = 0x0C00
+OAM_DATA = 0x1000
+
+LCDC_WINDOW_TILE_MAP_SELECT = 1 << 6
+LCDC_WINDOW_ENABLE = 1 << 5
+LCDC_TILE_DATA_SELECT = 1 << 4
+LCDC_SPRITE_SIZE = 1 << 2
+LCDC_SPRITE_ENABLE = 1 << 1
+LCDC_BG_ENABLE = 1 << 0
+
+SPRITE_PRIORITY = 1 << 7
+SPRITE_Y_FLIP = 1 << 6
+SPRITE_X_FLIP = 1 << 5
+SPRITE_PALETTE = 1 << 4
+
+
+class Fixture:
+    def __init__(self, name, scx=0, scy=0, raster=False, lcdc=0x91, bgp=0xE4,
+                 wx=0, wy=0, obp0=0, obp1=0, window=False, sprites=False):
+        self.name = name
+        self.scx = scx
+        self.scy = scy
+        # SCX is set to LY by the HBlank STAT interrupt.
+        self.raster = raster
+        self.lcdc = lcdc
+        self.bgp = bgp
+        self.wx = wx
+        self.wy = wy
+        self.obp0 = obp0
+        self.obp1 = obp1
+        # Fill a second tile map at $9C00 for the window.
+        self.window = window
+        # Fill OAM with sprites.
+        self.sprites = sprites
+
+    def signed(self):
+        return self.lcdc & LCDC_TILE_DATA_SELECT == 0
+
+
+FIXTURES = [
+    Fixture('scroll-fine', scx=3, scy=5),
+    Fixture('scroll-wrap', scx=200, scy=180),
+    Fixture('scroll-raster', scy=0x4C, raster=True),
+    # Window at (40, 32) over a scrolled background, with signed tile numbers and BGP = $27.
+    Fixture('window', scx=3, scy=5, lcdc=0xE1, bgp=0x27, wx=47, wy=32, window=True),
+    # Window starting 4 columns left of the screen.
+    Fixture('window-left', scx=100, lcdc=0xF1, wx=3, wy=100, window=True),
+    Fixture('sprites', scx=3, scy=5, lcdc=0x93, obp0=0xE4, obp1=0x1B, sprites=True),
+    Fixture('sprites-tall', scx=3, scy=5, lcdc=0x97, obp0=0xE4, obp1=0x1B, sprites=True),
+]
+
+
+def lcg(seed):
+    """A fixed pseudo-random sequence, so the fixtures do not depend on Python's RNG."""
+    while True:
+        seed = (seed * 1103515245 + 12345) & 0x7FFFFFFF
+        yield seed >> 16
+
+
+def encode(tiles):
+    """Encodes tiles of 64 colours each as 2bpp tile data."""
+    data = bytearray()
+    for tile in tiles:
+        for row in range(8):
+            lo = hi = 0
+            for x in range(8):
+                colour = tile[row * 8 + x]
+                lo |= (colour & 1) << (7 - x)
+                hi |= (colour >> 1) << (7 - x)
+            data += bytes([lo, hi])
+    return bytes(data)
+
+
+def tiles_and_map():
+    """Returns 16 tiles of random colours, their 2bpp encoding, and a map using them."""
+    r = lcg(29)
+    pixels = [[next(r) & 3 for _ in range(64)] for _ in range(16)]
+    tile_map = bytes(next(r) & 15 for _ in range(1024))
+    return encode(pixels), tile_map
+
+
+def extra_tiles_and_map():
+    """Returns 16 more tiles, for tile numbers $80-$8F, and a window map using all 32."""
+    r = lcg(30)
+    pixels = [[next(r) & 3 for _ in range(64)] for _ in range(16)]
+    window_map = bytes((next(r) & 15) | (next(r) & 0x80) for _ in range(1024))
+    return encode(pixels), window_map
+
+
+def oam():
+    """Returns 40 sprites: 26 placed at random, 2 cut off by the left and right edges, and 12
+    side by side on lines 80-87, the first of them also cut off by the left edge."""
+    r = lcg(31)
+    data = bytearray()
+    for _ in range(26):
+        data += bytes([next(r) % 176, next(r) % 176, next(r) & 15, next(r) & 0xF0])
+    data += bytes([16 + 40, 2, next(r) & 15, next(r) & 0xF0])
+    data += bytes([16 + 40, 164, next(r) & 15, next(r) & 0xF0])
+    for i in range(12):
+        data += bytes([16 + 80, 4 + 12 * i, next(r) & 15, next(r) & 0xF0])
+    return bytes(data)
+
+
+def copy(source, destination, length):
+    """ld hl, destination; ld de, source; ld bc, length; .copy: ld a, (de); ld (hl+), a;
+    inc de; dec bc; ld a, b; or c; jr nz, .copy"""
+    return [
+        0x21, destination & 0xFF, destination >> 8,
+        0x11, source & 0xFF, source >> 8,
+        0x01, length & 0xFF, length >> 8,
+        0x1A, 0x22, 0x13, 0x0B, 0x78, 0xB1, 0x20, 0xF8,
+    ]
+
+
+def write(register, value):
+    """ld a, value; ldh (register), a"""
+    return [0x3E, value, 0xE0, register]
+
+
+def program(fixture):
+    """Assembles the setup code run from $0150."""
+    # Signed tile numbers 0-15 are read from $9000.
+    tiles = 0x90 if fixture.signed() else 0x80
+    code = [
+        0x31, 0xFE, 0xFF,                          # ld sp, $FFFE
+        0xAF, 0xE0, 0x40,                          # xor a; ldh (LCDC), a
+        0x21, 0x00, tiles,                         # ld hl, $8000 or $9000
+        0x11, TILE_DATA & 0xFF, TILE_DATA >> 8,    # ld de, TILE_DATA
+        0x06, 0x00,                                # ld b, 256
+        0x1A, 0x22, 0x13, 0x05, 0x20, 0xFA,        # .tiles: ld a, (de); ld (hl+), a; inc de; dec b; jr nz, .tiles
+    ]
+    code += copy(TILE_MAP, 0x9800, 0x400)
+    if fixture.window:
+        code += copy(TILE_DATA_HI, 0x8800, 0x100)
+        code += copy(WINDOW_MAP, 0x9C00, 0x400)
+    if fixture.sprites:
+        code += copy(OAM_DATA, 0xFE00, 0xA0)
+    code += write(0x43, fixture.scx)
+    code += write(0x42, fixture.scy)
+    code += write(0x47, fixture.bgp)
+    if fixture.window:
+        code += write(0x4B, fixture.wx)
+        code += write(0x4A, fixture.wy)
+    if fixture.sprites:
+        code += write(0x48, fixture.obp0)
+        code += write(0x49, fixture.obp1)
+    code += write(0x40, fixture.lcdc)
+    if fixture.raster:
+        code += write(0x41, 0x08)                  # STAT: HBlank interrupt
+        code += write(0xFF, 0x02)                  # IE: STAT
+        code += [0xFB]                             # ei
+    code += [0x76, 0x18, 0xFD]                     # .halt: halt; jr .halt
+    return bytes(code)
+
+
+def rom(fixture):
+    """Builds a ROM and the VRAM and OAM contents it sets up."""
+    data, tile_map = tiles_and_map()
+    extra_data, window_map = extra_tiles_and_map()
+    sprites = oam()
+
+    rom = bytearray(0x8000)
+    rom[0x0100:0x0104] = bytes([0x00, 0xC3, PROGRAM & 0xFF, PROGRAM >> 8])  # nop; jp PROGRAM
+    rom[0x0134:0x013A] = b'SCROLL'
+    if fixture.raster:
+        # STAT handler: ldh a, (LY); ldh (SCX), a; reti
+        rom[0x0048:0x004D] = bytes([0xF0, 0x44, 0xE0, 0x43, 0xD9])
+    code = program(fixture)
+    rom[PROGRAM:PROGRAM + len(code)] = code
+    rom[TILE_DATA:TILE_DATA + len(data)] = data
+    rom[TILE_MAP:TILE_MAP + len(tile_map)] = tile_map
+
+    vram = bytearray(0x2000)
+    tiles = 0x1000 if fixture.signed() else 0x0000
+    vram[tiles:tiles + len(data)] = data
+    vram[0x1800:0x1C00] = tile_map
+
+    if fixture.window:
+        rom[TILE_DATA_HI:TILE_DATA_HI + len(extra_data)] = extra_data
+        rom[WINDOW_MAP:WINDOW_MAP + len(window_map)] = window_map
+        vram[0x0800:0x0800 + len(extra_data)] = extra_data
+        vram[0x1C00:0x2000] = window_map
+
+    if fixture.sprites:
+        rom[OAM_DATA:OAM_DATA + len(sprites)] = sprites
+    else:
+        sprites = bytes(0xA0)
+
+    checksum = 0
+    for byte in rom[0x0134:0x014D]:
+        checksum = (checksum - byte - 1) & 0xFF
+    rom[0x014D] = checksum
+
+    return bytes(rom), vram, sprites
+
+
+def pixel(vram, address, x):
+    """Colour of column x of the tile row at address."""
+    lo, hi = vram[address], vram[address + 1]
+    return ((hi >> (7 - x)) & 1) << 1 | ((lo >> (7 - x)) & 1)
+
+
+def tile_pixel(vram, lcdc, tile, x, y):
+    """Colour of pixel (x, y) of a background or window tile."""
+    if lcdc & LCDC_TILE_DATA_SELECT:
+        base = tile * 16
+    else:
+        base = 0x1000 + (tile - 256 if tile >= 128 else tile) * 16
+    return pixel(vram, base + y * 2, x)
+
+
+def shade(palette, colour):
+    return (palette >> (colour * 2)) & 3
+
+
+def image(fixture, vram, sprites):
+    """Computes the last frame from the display registers, VRAM and OAM."""
+    lcdc = fixture.lcdc
+    height = 16 if lcdc & LCDC_SPRITE_SIZE else 8
+    window_map = 0x1C00 if lcdc & LCDC_WINDOW_TILE_MAP_SELECT else 0x1800
+    window_line = 0
+    image = []
+
+    for y in range(HEIGHT):
+        # The handler sets SCX to the line just drawn, so line y is drawn with SCX = y - 1
+        # (line 0 with 143, from the previous frame).
+        scx = (y - 1) % HEIGHT if fixture.raster else fixture.scx
+
+        # Background colours, before BGP.
+        line = [0] * WIDTH
+        if lcdc & LCDC_BG_ENABLE:
+            map_y = (y + fixture.scy) & 0xFF
+            for x in range(WIDTH):
+                map_x = (x + scx) & 0xFF
+                tile = vram[0x1800 + (map_y >> 3) * 32 + (map_x >> 3)]
+                line[x] = tile_pixel(vram, lcdc, tile, map_x & 7, map_y & 7)
+
+            # The window has its own line counter, which only advances when it is drawn.
+            if lcdc & LCDC_WINDOW_ENABLE and fixture.wy <= y and fixture.wx <= 166:
+                left = fixture.wx - 7
+                for x in range(max(left, 0), WIDTH):
+                    window_x = x - left
+                    tile = vram[window_map + (window_line >> 3) * 32 + (window_x >> 3)]
+                    line[x] = tile_pixel(vram, lcdc, tile, window_x & 7, window_line & 7)
+                window_line += 1
+
+        shades = [shade(fixture.bgp, colour) for colour in line]
+
+        if lcdc & LCDC_SPRITE_ENABLE:
+            # The first ten sprites in OAM order on this line are drawn. The one with the
+            # lowest X, then the lowest OAM index, wins where it is not transparent.
+            visible = []
+            for index in range(40):
+                sprite_y, sprite_x, tile, attributes = sprites[index * 4:index * 4 + 4]
+                if sprite_y - 16 <= y < sprite_y - 16 + height:
+                    visible.append((sprite_x, index, sprite_y, tile, attributes))
+            visible = sorted(visible[:10])
+
+            for x in range(WIDTH):
+                for sprite_x, _, sprite_y, tile, attributes in visible:
+                    column = x - (sprite_x - 8)
+                    if not 0 <= column < 8:
+                        continue
+                    row = y - (sprite_y - 16)
+                    if attributes & SPRITE_Y_FLIP:
+                        row = height - 1 - row
+                    if attributes & SPRITE_X_FLIP:
+                        column = 7 - column
+                    if height == 16:
+                        tile &= 0xFE
+                    colour = pixel(vram, tile * 16 + row * 2, column)
+                    if colour == 0:
+                        continue
+                    if not attributes & SPRITE_PRIORITY or line[x] == 0:
+                        palette = fixture.obp1 if attributes & SPRITE_PALETTE else fixture.obp0
+                        shades[x] = shade(palette, colour)
+                    break
+
+        image += [PALETTE[s] for s in shades]
+    return image
+
+
+def png(image):
+    """Encodes a list of grey shades as an 8-bit RGB PNG."""
+    rows = []
+    for y in range(HEIGHT):
+        row = image[y * WIDTH:(y + 1) * WIDTH]
+        rows.append(b'\x00' + bytes(shade for grey in row for shade in (grey, grey, grey)))
+
+    def chunk(kind, data):
+        crc = zlib.crc32(kind + data) & 0xFFFFFFFF
+        return struct.pack('>I', len(data)) + kind + data + struct.pack('>I', crc)
+
+    header = struct.pack('>IIBBBBB', WIDTH, HEIGHT, 8, 2, 0, 0, 0)
+    return (b'\x89PNG\r\n\x1a\n' + chunk(b'IHDR', header)
+            + chunk(b'IDAT', zlib.compress(b''.join(rows), 9)) + chunk(b'IEND', b''))
+
+
+def main():
+    out = sys.argv[1] if len(sys.argv) > 1 else os.path.dirname(os.path.abspath(__file__))
+    for fixture in FIXTURES:
+        data, vram, sprites = rom(fixture)
+        with open(os.path.join(out, f'{fixture.name}.gb'), 'wb') as f:
+            f.write(data)
+        with open(os.path.join(out, f'{fixture.name}.png'), 'wb') as f:
+            f.write(png(image(fixture, vram, sprites)))
+
+
+if __name__ == '__main__':
+    main()